@@ -0,0 +1,8 @@
+ALTER TABLE apartments
+    DROP COLUMN build_year,
+    DROP COLUMN floor,
+    DROP COLUMN building_type,
+    DROP COLUMN latitude,
+    DROP COLUMN longitude,
+    DROP COLUMN rent_lower,
+    DROP COLUMN rent_upper
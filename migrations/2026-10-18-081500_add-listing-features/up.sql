@@ -0,0 +1,8 @@
+ALTER TABLE apartments
+    ADD build_year INT,
+    ADD floor INT,
+    ADD building_type TEXT,
+    ADD latitude FLOAT,
+    ADD longitude FLOAT,
+    ADD rent_lower INT,
+    ADD rent_upper INT
@@ -0,0 +1,3 @@
+DROP TABLE apartment_price_history;
+
+ALTER TABLE apartments DROP COLUMN status;
@@ -0,0 +1,12 @@
+ALTER TABLE apartments ADD status INT;
+
+CREATE TABLE apartment_price_history (
+    id SERIAL PRIMARY KEY,
+    card_id INT NOT NULL REFERENCES apartments(card_id) ON DELETE CASCADE,
+    price INT,
+    additional_costs INT,
+    status INT,
+    observed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX apartment_price_history_card_id_idx ON apartment_price_history (card_id, observed_at);
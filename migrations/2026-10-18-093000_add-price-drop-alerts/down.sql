@@ -0,0 +1,3 @@
+ALTER TABLE apartment_watchlist DROP COLUMN sent_price;
+
+ALTER TABLE watchlists DROP COLUMN min_price_drop_percent;
@@ -0,0 +1,9 @@
+ALTER TABLE watchlists ADD min_price_drop_percent FLOAT NOT NULL DEFAULT 5;
+
+ALTER TABLE apartment_watchlist ADD sent_price INT;
+
+UPDATE apartment_watchlist
+SET sent_price = apartments.price
+FROM apartments
+WHERE apartments.card_id = apartment_watchlist.card_id
+    AND apartment_watchlist.has_been_sent;
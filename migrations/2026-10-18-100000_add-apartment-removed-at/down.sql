@@ -0,0 +1 @@
+ALTER TABLE apartments DROP COLUMN removed_at;
@@ -0,0 +1 @@
+ALTER TABLE apartments ADD removed_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,18 @@
+CREATE TABLE jobs (
+    id SERIAL PRIMARY KEY,
+    job_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    dedup_key TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT,
+    run_after TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    locked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- At most one queued or running job per dedup key
+CREATE UNIQUE INDEX jobs_dedup_key_idx ON jobs (dedup_key) WHERE status IN ('pending', 'running');
+
+CREATE INDEX jobs_pending_idx ON jobs (run_after) WHERE status = 'pending';
@@ -0,0 +1,4 @@
+ALTER TABLE watchlists
+    DROP COLUMN last_refreshed_at,
+    DROP COLUMN next_refresh_at,
+    DROP COLUMN refresh_interval_minutes;
@@ -0,0 +1,6 @@
+ALTER TABLE watchlists
+    ADD last_refreshed_at TIMESTAMP WITH TIME ZONE,
+    ADD next_refresh_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    ADD refresh_interval_minutes INT NOT NULL DEFAULT 60;
+
+CREATE INDEX watchlists_next_refresh_at_idx ON watchlists (next_refresh_at);
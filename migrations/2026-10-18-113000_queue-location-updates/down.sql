@@ -0,0 +1,3 @@
+DELETE FROM jobs WHERE job_type = 'update_location';
+
+UPDATE jobs SET payload = payload - 'type';
@@ -0,0 +1,13 @@
+-- Watchlists are now updated per location. Drop queued per-watchlist updates and let the
+-- producer queue their locations right away.
+UPDATE watchlists
+SET next_refresh_at = NOW()
+WHERE id IN (
+    SELECT (payload->>'watchlist_id')::INT FROM jobs
+    WHERE job_type = 'update_watchlist' AND status IN ('pending', 'running')
+);
+
+DELETE FROM jobs WHERE job_type = 'update_watchlist';
+
+-- Payloads carry their task type
+UPDATE jobs SET payload = payload || jsonb_build_object('type', job_type);
@@ -0,0 +1 @@
+ALTER TABLE watchlists DROP COLUMN paused_at;
@@ -0,0 +1 @@
+ALTER TABLE watchlists ADD paused_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,9 @@
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX api_tokens_chat_id_idx ON api_tokens (chat_id);
@@ -0,0 +1,13 @@
+ALTER TABLE apartments
+    DROP COLUMN plot_ownership,
+    DROP COLUMN debt_share;
+
+ALTER TABLE watchlists
+    DROP COLUMN max_debt_share_percent,
+    DROP COLUMN exclude_rented_plot,
+    DROP COLUMN exclude_ground_floor,
+    DROP COLUMN min_build_year,
+    DROP COLUMN max_fee_per_m2,
+    DROP COLUMN max_price,
+    DROP COLUMN max_rooms,
+    DROP COLUMN min_rooms;
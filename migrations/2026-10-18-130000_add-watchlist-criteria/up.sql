@@ -0,0 +1,13 @@
+ALTER TABLE watchlists
+    ADD min_rooms INT,
+    ADD max_rooms INT,
+    ADD max_price INT,
+    ADD max_fee_per_m2 FLOAT,
+    ADD min_build_year INT,
+    ADD exclude_ground_floor BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD exclude_rented_plot BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD max_debt_share_percent FLOAT;
+
+ALTER TABLE apartments
+    ADD debt_share INT,
+    ADD plot_ownership TEXT;
@@ -0,0 +1,19 @@
+DELETE FROM jobs WHERE job_type = 'update_location';
+
+ALTER TABLE watchlists
+    ADD location_id INT,
+    ADD location_level INT,
+    ADD location_name TEXT;
+
+-- Watchlists keep only their first location
+UPDATE watchlists
+SET location_id = (locations->0->>'id')::INT,
+    location_level = (locations->0->>'level')::INT,
+    location_name = locations->0->>'name',
+    next_refresh_at = NOW();
+
+ALTER TABLE watchlists
+    ALTER COLUMN location_id SET NOT NULL,
+    ALTER COLUMN location_level SET NOT NULL,
+    ALTER COLUMN location_name SET NOT NULL,
+    DROP COLUMN locations;
@@ -0,0 +1,25 @@
+-- Location updates now search every location of a watchlist at once. Drop the queued
+-- per-location updates and let the producer queue their watchlists right away.
+UPDATE watchlists
+SET next_refresh_at = NOW()
+WHERE (location_id, location_level) IN (
+    SELECT (payload->>'location_id')::INT, (payload->>'location_level')::INT FROM jobs
+    WHERE job_type = 'update_location' AND status IN ('pending', 'running')
+);
+
+DELETE FROM jobs WHERE job_type = 'update_location';
+
+ALTER TABLE watchlists ADD locations JSONB;
+
+UPDATE watchlists
+SET locations = jsonb_build_array(
+    jsonb_build_object('id', location_id, 'level', location_level, 'name', location_name)
+);
+
+ALTER TABLE watchlists
+    ALTER COLUMN locations SET NOT NULL,
+    ADD CONSTRAINT watchlists_locations_not_empty
+        CHECK (jsonb_typeof(locations) = 'array' AND jsonb_array_length(locations) > 0),
+    DROP COLUMN location_id,
+    DROP COLUMN location_level,
+    DROP COLUMN location_name;
@@ -0,0 +1 @@
+DROP TABLE user_apartment_state;
@@ -0,0 +1,9 @@
+CREATE TABLE user_apartment_state (
+    id SERIAL PRIMARY KEY,
+    chat_id BIGINT NOT NULL,
+    card_id INT NOT NULL REFERENCES apartments(card_id) ON DELETE CASCADE,
+    status TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE (chat_id, card_id)
+);
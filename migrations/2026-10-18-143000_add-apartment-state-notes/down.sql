@@ -0,0 +1,5 @@
+DROP INDEX user_apartment_state_chat_id_status_idx;
+
+ALTER TABLE user_apartment_state DROP CONSTRAINT user_apartment_state_status_check;
+
+ALTER TABLE user_apartment_state DROP COLUMN notes;
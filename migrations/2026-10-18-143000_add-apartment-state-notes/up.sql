@@ -0,0 +1,6 @@
+ALTER TABLE user_apartment_state ADD COLUMN notes TEXT;
+
+ALTER TABLE user_apartment_state ADD CONSTRAINT user_apartment_state_status_check
+    CHECK (status IN ('new', 'saved', 'dismissed', 'contacted', 'bid', 'bought'));
+
+CREATE INDEX user_apartment_state_chat_id_status_idx ON user_apartment_state (chat_id, status);
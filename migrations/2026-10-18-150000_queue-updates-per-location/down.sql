@@ -0,0 +1,14 @@
+UPDATE watchlists
+SET next_refresh_at = NOW()
+WHERE EXISTS (
+    SELECT 1 FROM jobs
+    WHERE job_type = 'update_location' AND status IN ('pending', 'running')
+        AND watchlists.locations @> jsonb_build_array(
+            jsonb_build_object(
+                'id', jobs.payload->'location'->'id',
+                'level', jobs.payload->'location'->'level'
+            )
+        )
+);
+
+DELETE FROM jobs WHERE job_type = 'update_location';
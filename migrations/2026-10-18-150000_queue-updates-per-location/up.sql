@@ -0,0 +1,13 @@
+-- Location updates now search one location for every watchlist containing it. Drop the
+-- queued updates of location sets and let the producer queue their watchlists right away.
+UPDATE watchlists
+SET next_refresh_at = NOW()
+WHERE EXISTS (
+    SELECT 1 FROM jobs, jsonb_array_elements(jobs.payload->'locations') AS queued
+    WHERE job_type = 'update_location' AND status IN ('pending', 'running')
+        AND watchlists.locations @> jsonb_build_array(
+            jsonb_build_object('id', queued->'id', 'level', queued->'level')
+        )
+);
+
+DELETE FROM jobs WHERE job_type = 'update_location';
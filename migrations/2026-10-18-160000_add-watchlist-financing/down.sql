@@ -0,0 +1,6 @@
+ALTER TABLE apartment_watchlist DROP COLUMN estimated_yield;
+
+ALTER TABLE watchlists
+    DROP COLUMN down_payment_percent,
+    DROP COLUMN loan_years,
+    DROP COLUMN renovation_costs;
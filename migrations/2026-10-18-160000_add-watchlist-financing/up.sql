@@ -0,0 +1,8 @@
+-- Financing assumptions of the watchlist. NULL uses the configured default.
+ALTER TABLE watchlists
+    ADD COLUMN down_payment_percent INT,
+    ADD COLUMN loan_years INT,
+    ADD COLUMN renovation_costs INT;
+
+-- Yield of the apartment with the financing of a watchlist that has its own
+ALTER TABLE apartment_watchlist ADD COLUMN estimated_yield DOUBLE PRECISION;
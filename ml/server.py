@@ -1,10 +1,11 @@
 import logging
+import os
 import time
 from pathlib import Path
 from typing import Optional
 
 import numpy as np
-from fastapi import FastAPI, HTTPException
+from fastapi import Depends, FastAPI, Header, HTTPException
 from pydantic import BaseModel
 
 try:
@@ -14,6 +15,8 @@ except ImportError:  # pragma: no cover - optional dependency
 
 MODEL_PATH = Path("models/rent_model.keras")
 MODEL = None
+# Optional shared secret; when set, requests must send `Authorization: Bearer <token>`
+AUTH_TOKEN = os.environ.get("APATO_ML_TOKEN")
 
 LOGGER = logging.getLogger("apato.ml-server")
 if not LOGGER.handlers:
@@ -44,10 +47,24 @@ class RentPredictionRequest(BaseModel):
     rooms: Optional[int] = None
     price: Optional[float] = None
     maintenance_fee: Optional[float] = None
+    build_year: Optional[int] = None
+    floor: Optional[int] = None
+    building_type: Optional[str] = None
+    latitude: Optional[float] = None
+    longitude: Optional[float] = None
 
 
 class RentPredictionResponse(BaseModel):
     rent: int
+    rent_lower: Optional[int] = None
+    rent_upper: Optional[int] = None
+
+
+def _check_auth(authorization: Optional[str] = Header(default=None)) -> None:
+    if not AUTH_TOKEN:
+        return
+    if authorization != f"Bearer {AUTH_TOKEN}":
+        raise HTTPException(status_code=401, detail="Invalid or missing bearer token")
 
 
 def _baseline_rent_estimate(size: float, rooms: Optional[int], maintenance_fee: Optional[float]) -> int:
@@ -60,17 +77,29 @@ def _baseline_rent_estimate(size: float, rooms: Optional[int], maintenance_fee:
     return max(int(round(estimate)), 0)
 
 
-@app.post("/predict", response_model=RentPredictionResponse)
+@app.get("/health", dependencies=[Depends(_check_auth)])
+async def health():
+    return {"status": "ok", "model_loaded": MODEL is not None}
+
+
+@app.post(
+    "/predict",
+    response_model=RentPredictionResponse,
+    dependencies=[Depends(_check_auth)],
+)
 async def predict(request: RentPredictionRequest):
     start_time = time.perf_counter()
     LOGGER.info(
-        "Received prediction request: location_id=%s location_level=%s size=%.2f rooms=%s price=%s maintenance_fee=%s",
+        "Received prediction request: location_id=%s location_level=%s size=%.2f rooms=%s price=%s maintenance_fee=%s build_year=%s floor=%s building_type=%s",
         request.location_id,
         request.location_level,
         request.size,
         request.rooms,
         request.price,
         request.maintenance_fee,
+        request.build_year,
+        request.floor,
+        request.building_type,
     )
     try:
         if MODEL is None:
@@ -81,6 +110,7 @@ async def predict(request: RentPredictionRequest):
                 rent_estimate,
                 duration,
             )
+            # Neither the heuristic nor the model estimate an interval yet
             return RentPredictionResponse(rent=rent_estimate)
 
         input_vector = np.array(
@@ -0,0 +1,327 @@
+//! Buttons on apartment notifications: the cash flows and sensitivity behind the yield,
+//! saving or dismissing the listing, and re-scoring it.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use teloxide::{
+    requests::Requester,
+    types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup},
+    Bot,
+};
+
+use crate::{
+    config::Config,
+    consumer::calculations::{
+        CashFlowAnalysis, Sensitivity, SENSITIVITY_RATE_CHANGES, SENSITIVITY_RENT_CHANGES,
+    },
+    db::repository::SharedRepository,
+    models::{apartment::Apartment, apartment_state::ApartmentStatus},
+    services::apartments,
+};
+
+/// Callback data of the buttons is `apartment:{action}:{watchlist_id}:{card_id}`.
+const CALLBACK_PREFIX: &str = "apartment:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApartmentAction {
+    CashFlow,
+    Sensitivity,
+    Save,
+    Dismiss,
+    Recalculate,
+}
+
+impl ApartmentAction {
+    const ALL: [ApartmentAction; 5] = [
+        ApartmentAction::CashFlow,
+        ApartmentAction::Sensitivity,
+        ApartmentAction::Save,
+        ApartmentAction::Dismiss,
+        ApartmentAction::Recalculate,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ApartmentAction::CashFlow => "cash_flow",
+            ApartmentAction::Sensitivity => "sensitivity",
+            ApartmentAction::Save => "save",
+            ApartmentAction::Dismiss => "dismiss",
+            ApartmentAction::Recalculate => "recalculate",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ApartmentAction::CashFlow => "Cash flow",
+            ApartmentAction::Sensitivity => "Sensitivity",
+            ApartmentAction::Save => "Save",
+            ApartmentAction::Dismiss => "Not interested",
+            ApartmentAction::Recalculate => "Recalculate",
+        }
+    }
+}
+
+/// A button pressed on the notification of an apartment sent for a watchlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApartmentButton {
+    pub action: ApartmentAction,
+    pub watchlist_id: i32,
+    pub card_id: i32,
+}
+
+impl ApartmentButton {
+    pub fn parse(data: &str) -> Option<Self> {
+        let parts: Vec<&str> = data.strip_prefix(CALLBACK_PREFIX)?.split(':').collect();
+        let [name, watchlist_id, card_id] = parts.as_slice() else {
+            return None;
+        };
+        Some(ApartmentButton {
+            action: ApartmentAction::ALL
+                .into_iter()
+                .find(|action| action.name() == *name)?,
+            watchlist_id: watchlist_id.parse().ok()?,
+            card_id: card_id.parse().ok()?,
+        })
+    }
+
+    fn data(&self) -> String {
+        format!(
+            "{}{}:{}:{}",
+            CALLBACK_PREFIX,
+            self.action.name(),
+            self.watchlist_id,
+            self.card_id
+        )
+    }
+}
+
+/// Buttons for the notification of the apartment.
+pub fn apartment_keyboard(watchlist_id: i32, card_id: i32) -> InlineKeyboardMarkup {
+    let button = |action: ApartmentAction| {
+        let data = ApartmentButton {
+            action,
+            watchlist_id,
+            card_id,
+        }
+        .data();
+        InlineKeyboardButton::callback(action.label(), data)
+    };
+    InlineKeyboardMarkup::new([
+        vec![
+            button(ApartmentAction::CashFlow),
+            button(ApartmentAction::Sensitivity),
+        ],
+        vec![
+            button(ApartmentAction::Save),
+            button(ApartmentAction::Dismiss),
+        ],
+        vec![button(ApartmentAction::Recalculate)],
+    ])
+}
+
+pub async fn handle(
+    tg: &Bot,
+    chat_id: ChatId,
+    button: ApartmentButton,
+    repo: &SharedRepository,
+    config: &Arc<Config>,
+) -> Result<()> {
+    let card_id = button.card_id;
+    let reply = match button.action {
+        ApartmentAction::CashFlow => {
+            let (apartment, analysis) = apartments::cash_flows(config, repo, card_id).await?;
+            format_cash_flows(&apartment, &analysis)
+        }
+        ApartmentAction::Sensitivity => {
+            let (apartment, sensitivity) = apartments::sensitivity(config, repo, card_id).await?;
+            format_sensitivity(&apartment, &sensitivity)
+        }
+        ApartmentAction::Save => {
+            apartments::set_status(repo, chat_id.0, card_id, ApartmentStatus::SAVED).await?;
+            format!("Saved apartment {}.", card_id)
+        }
+        ApartmentAction::Dismiss => {
+            apartments::set_status(repo, chat_id.0, card_id, ApartmentStatus::DISMISSED).await?;
+            format!(
+                "Apartment {} will no longer be shown in your lists or sent to you.",
+                card_id
+            )
+        }
+        ApartmentAction::Recalculate => {
+            let apartment =
+                apartments::recalculate(config, repo, chat_id.0, button.watchlist_id, card_id)
+                    .await?;
+            format_recalculated(&apartment)
+        }
+    };
+    tg.send_message(chat_id, reply).await?;
+    Ok(())
+}
+
+fn format_cash_flows(apartment: &Apartment, analysis: &CashFlowAnalysis) -> String {
+    let mut message = format!(
+        "Cash flows of apartment {} in EUR, at the current interest rate \n\n Down Payment: {:.0}",
+        apartment.card_id, -analysis.down_payment
+    );
+    for year in &analysis.years {
+        message.push_str(&format!(
+            "\n Year {}: Rent {:.0}, Vacancy {:.0}, Fees {:.0}, Interest {:.0}, Principal {:.0}, Taxes {:.0}, Value {:+.0}, Cash Flow {:.0}",
+            year.year,
+            year.rent,
+            year.vacancy,
+            year.fixed_costs,
+            year.interest,
+            year.principal,
+            year.taxes,
+            year.value_increase,
+            year.cash_flow
+        ));
+    }
+    message
+}
+
+fn format_sensitivity(apartment: &Apartment, sensitivity: &Sensitivity) -> String {
+    let header: Vec<String> = SENSITIVITY_RATE_CHANGES
+        .iter()
+        .map(|change| format!("{:.2}%", (sensitivity.interest_rate + change).max(0.0)))
+        .collect();
+    let mut message = format!(
+        "Estimated yield of apartment {} with other rents and interest rates \n\n Rent \\ Interest: {}",
+        apartment.card_id,
+        header.join(" | ")
+    );
+    for (change, yields) in SENSITIVITY_RENT_CHANGES.iter().zip(&sensitivity.yields) {
+        let row: Vec<String> = yields.iter().map(|y| format!("{:.2}%", y)).collect();
+        message.push_str(&format!("\n {:+.0}%: {}", change, row.join(" | ")));
+    }
+    message
+}
+
+fn format_recalculated(apartment: &Apartment) -> String {
+    if !apartment.is_active() {
+        return format!(
+            "Apartment {} has been sold or removed from Oikotie.",
+            apartment.card_id
+        );
+    }
+    format!(
+        "Recalculated apartment {} \n\n Price: {} EUR \n Estimated Rent: {} EUR \n Estimated Yield: {:.2}%",
+        apartment.card_id,
+        apartment.price.unwrap_or(0),
+        apartment.rent.unwrap_or_default(),
+        apartment.estimated_yield.unwrap_or(0.0)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::memory::InMemoryRepository,
+        models::{apartment::InsertableApartment, watchlist::SizeTarget},
+        oikotie::oikotie::Location,
+        services::watchlists,
+        test_support::{apartment, fake_telegram},
+    };
+
+    #[test]
+    fn buttons_round_trip_through_callback_data() {
+        for action in ApartmentAction::ALL {
+            let button = ApartmentButton {
+                action,
+                watchlist_id: 3,
+                card_id: 12345678,
+            };
+            // Telegram allows at most 64 bytes of callback data
+            assert!(button.data().len() <= 64);
+            assert_eq!(ApartmentButton::parse(&button.data()), Some(button));
+        }
+        assert_eq!(ApartmentButton::parse("apartment:save:3"), None);
+        assert_eq!(ApartmentButton::parse("sub:confirm"), None);
+
+        let keyboard = apartment_keyboard(3, 12345678);
+        assert_eq!(
+            keyboard.inline_keyboard.concat().len(),
+            ApartmentAction::ALL.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn dismissed_apartments_are_left_out_of_lists() {
+        let tg = fake_telegram().await;
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let config = Arc::new(crate::config::create_test_config());
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let watchlist = repo
+            .insert_watchlist(location, 42, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        for card_id in [1, 2] {
+            let apartment = InsertableApartment {
+                size: Some(40.0),
+                price: Some(100000),
+                rent: Some(800),
+                ..apartment(card_id, 7.0)
+            };
+            repo.upsert_apartment(apartment, &[watchlist.id])
+                .await
+                .unwrap();
+        }
+
+        let press = |action, card_id| ApartmentButton {
+            action,
+            watchlist_id: watchlist.id,
+            card_id,
+        };
+        handle(
+            &tg,
+            ChatId(42),
+            press(ApartmentAction::Save, 1),
+            &repo,
+            &config,
+        )
+        .await
+        .unwrap();
+        handle(
+            &tg,
+            ChatId(42),
+            press(ApartmentAction::Dismiss, 2),
+            &repo,
+            &config,
+        )
+        .await
+        .unwrap();
+
+        let listed =
+            |apartments: Vec<Apartment>| apartments.iter().map(|a| a.card_id).collect::<Vec<_>>();
+        let all = watchlists::get_all_apartments(&repo, 42, watchlist.id, false, false)
+            .await
+            .unwrap();
+        assert_eq!(listed(all), vec![1]);
+        let matching = watchlists::get_matching_apartments(&repo, 42, watchlist.id, false)
+            .await
+            .unwrap();
+        assert_eq!(listed(matching), vec![1]);
+        assert!(apartments::is_dismissed(&repo, 42, 2).await.unwrap());
+        let all = watchlists::get_all_apartments(&repo, 42, watchlist.id, false, true)
+            .await
+            .unwrap();
+        assert_eq!(listed(all).len(), 2);
+
+        // Unknown apartments cannot be saved
+        let missing = handle(
+            &tg,
+            ChatId(42),
+            press(ApartmentAction::Save, 3),
+            &repo,
+            &config,
+        )
+        .await;
+        assert!(missing.is_err());
+    }
+}
@@ -1,23 +1,37 @@
 use crate::{
-    bot::subscribe::{check_args, subscribe_to_watchlist},
+    bot::{
+        apartment_actions::{self, ApartmentButton},
+        sub_dialogue::{self, SubAction, SubDialogue, SubStep},
+    },
     config::Config,
-    db::{self},
-    models::{apartment::Apartment, watchlist::Watchlist},
+    db::repository::SharedRepository,
+    errors::user_message,
+    models::{
+        apartment::Apartment,
+        apartment_state::ApartmentStatus,
+        price_history::drop_percent,
+        watchlist::{Financing, ListingCriteria, Watchlist, WatchlistUpdate},
+    },
+    services::{
+        apartments::{self, TrackedApartment},
+        api_tokens, sessions, watchlists,
+    },
 };
 use anyhow::Result;
 use lazy_static::lazy_static;
-use log::error;
 use regex::Regex;
-use std::sync::Arc;
+use std::{str::FromStr, sync::Arc};
 use teloxide::{
-    dispatching::{DefaultKey, HandlerExt, UpdateFilterExt},
+    dispatching::{dialogue::InMemStorage, DefaultKey, HandlerExt, UpdateFilterExt},
     dptree,
+    payloads::AnswerCallbackQuerySetters,
     prelude::{Dispatcher, LoggingErrorHandler},
     requests::Requester,
-    types::{Message, Update},
+    types::{CallbackQuery, Message, Update},
     utils::command::{BotCommands, ParseError},
     Bot,
 };
+use tracing::{error, info, instrument};
 
 use super::bot_types::SubscriptionArgs;
 
@@ -31,7 +45,7 @@ pub enum Command {
     Help,
 
     #[command(
-        description = "Subscribe to a location watchlist. Provide the args in the following format: < /sub {location name} min_size={size (m^2)} max_size={size (m^2)} yield={target yield}. > \n\n Example: \n '< /sub ullanlinna min_size=50 max_size=60 yield=10 >",
+        description = "Subscribe to a location watchlist. Send /sub alone to be asked for the details step by step, or provide the args in the following format: < /sub {location name} min_size={size (m^2)} max_size={size (m^2)} yield={target yield} drop={optional min price drop (%) to be alerted again, default 5}. > \n\n Optional criteria: min_rooms, max_rooms, max_price (EUR), max_fee (EUR/m^2), min_year (build year), max_debt (debt share %), ground_floor=no, rented_plot=no \n\n Optional financing, default from the configuration: down (down payment %), loan (years), renovation (EUR) \n\n Example: \n '< /sub ullanlinna min_size=50 max_size=60 yield=10 max_price=250000 ground_floor=no >",
         parse_with = parse_subscribe_message
     )]
     Sub(SubscriptionArgs),
@@ -45,6 +59,30 @@ pub enum Command {
     #[command(description = "List current active watchlist subscriptions")]
     ListWatchlists,
 
+    #[command(
+        description = "Set how often a watchlist is refreshed. Use watchlist ID and minutes, e.g. /interval 42 30",
+        parse_with = parse_refresh_interval_message
+    )]
+    Interval(Option<i32>, Option<i32>),
+
+    #[command(
+        description = "Change the criteria of a watchlist. Use watchlist ID and the criteria to change: yield, min_size, max_size, drop, interval or any of the optional /sub criteria and financing, e.g. /edit 42 yield=8 max_size=70 max_price=any",
+        parse_with = parse_edit_message
+    )]
+    Edit(Option<i32>, WatchlistUpdate),
+
+    #[command(
+        description = "Add a location to a watchlist. All its locations are searched together and announced by the watchlist. Use watchlist ID and location name, e.g. /addlocation 42 00510",
+        parse_with = parse_id_and_text
+    )]
+    AddLocation(Option<i32>, String),
+
+    #[command(
+        description = "Remove a location from a watchlist. Use watchlist ID and location name, e.g. /removelocation 42 00510",
+        parse_with = parse_id_and_text
+    )]
+    RemoveLocation(Option<i32>, String),
+
     #[command(description = "Get all apartments/houses in watchlist",
         parse_with = parse_string_to_int_message
     )]
@@ -55,6 +93,29 @@ pub enum Command {
         parse_with = parse_string_to_int_message
     )]
     GetMatching(Option<i32>),
+
+    #[command(
+        description = "List saved apartments, the ones you have acted on and the ones with notes"
+    )]
+    Saved,
+
+    #[command(
+        description = "Write notes on an apartment. Use the apartment's card ID from its Oikotie URL and the notes, e.g. /note 21812345 viewing on Tuesday. /note with the ID alone clears them",
+        parse_with = parse_id_and_text
+    )]
+    Note(Option<i32>, String),
+
+    #[command(
+        description = "Set the status of an apartment: new, saved, dismissed, contacted, bid or bought. Use the card ID and the status, e.g. /status 21812345 contacted",
+        parse_with = parse_id_and_text
+    )]
+    Status(Option<i32>, String),
+
+    #[command(description = "Create a token for the HTTP API. Replaces your previous token.")]
+    ApiToken,
+
+    #[command(description = "Get a one-time link that signs you in to the web console")]
+    Login,
 }
 
 pub struct ApatoTelegramBot {
@@ -63,20 +124,32 @@ pub struct ApatoTelegramBot {
 }
 
 impl ApatoTelegramBot {
-    pub async fn new(config: Arc<Config>) -> Result<Self> {
+    pub async fn new(config: Arc<Config>, repo: SharedRepository) -> Result<Self> {
         let telegram_bot_token = &config.telegram_bot_token;
 
         let tg = Arc::new(Bot::new(telegram_bot_token));
         tg.set_my_commands(Command::bot_commands()).await?;
 
-        let handler = Update::filter_message().branch(
-            dptree::entry()
-                .filter_command::<Command>()
-                .endpoint(handle_command),
-        );
+        // Commands come first, so they work in the middle of the /sub dialogue too
+        let handler = dptree::entry()
+            .branch(
+                Update::filter_message()
+                    .enter_dialogue::<Message, InMemStorage<SubStep>, SubStep>()
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .endpoint(handle_command),
+                    )
+                    .branch(dptree::endpoint(handle_dialogue_message)),
+            )
+            .branch(
+                Update::filter_callback_query()
+                    .enter_dialogue::<CallbackQuery, InMemStorage<SubStep>, SubStep>()
+                    .endpoint(handle_callback),
+            );
 
         let dispatcher = Dispatcher::builder(tg.clone(), handler)
-            .dependencies(dptree::deps![config])
+            .dependencies(dptree::deps![config, repo, InMemStorage::<SubStep>::new()])
             .error_handler(LoggingErrorHandler::with_custom_text(
                 "an error has occurred in the dispatcher",
             ))
@@ -104,17 +177,22 @@ impl ApatoTelegramBot {
     }
 }
 
+#[instrument(skip_all, fields(chat_id = message.chat.id.0))]
 pub async fn handle_command(
     message: Message,
     tg: Arc<Bot>,
     command: Command,
+    repo: SharedRepository,
     config: Arc<Config>,
+    dialogue: SubDialogue,
 ) -> Result<()> {
     async fn handle(
         message: &Message,
         tg: &Bot,
         command: Command,
-        config: &Arc<Config>,
+        repo: &SharedRepository,
+        config: &Config,
+        dialogue: &SubDialogue,
     ) -> Result<()> {
         match command {
             Command::Help => {
@@ -123,69 +201,7 @@ pub async fn handle_command(
                     .await;
             }
             Command::Sub(args) => {
-                let chat_id = message.chat.id;
-
-                let error = check_args(args.clone());
-
-                if !error.is_empty() {
-                    tg.send_message(
-                        chat_id,
-                        "Please provide the arguments needed. Check /help for guidance.",
-                    )
-                    .await?;
-                    return Ok(());
-                }
-
-                let SubscriptionArgs {
-                    location,
-                    target_yield,
-                    min_size,
-                    max_size,
-                } = args;
-
-                let message_target_yield = match target_yield {
-                    Some(value) => f64::from(value),
-                    None => {
-                        tg.send_message(
-                            chat_id,
-                            "Target yield is missing. Please provide it, e.g. yield=10.",
-                        )
-                        .await?;
-                        return Ok(());
-                    }
-                };
-
-                let size_range = match (min_size, max_size) {
-                    (Some(min), Some(max)) => (f64::from(min), f64::from(max)),
-                    _ => {
-                        tg.send_message(
-                            chat_id,
-                            "Both min_size and max_size must be provided, e.g. min_size=50 max_size=60.",
-                        )
-                        .await?;
-                        return Ok(());
-                    }
-                };
-
-                match subscribe_to_watchlist(
-                    size_range,
-                    message_target_yield,
-                    location,
-                    chat_id,
-                    tg,
-                    config.clone(),
-                )
-                .await
-                {
-                    Ok(()) => {}
-                    Err(e) => {
-                        tg.send_message(
-                            chat_id,
-                            format!("Could not subscribe. Please try again: {}", e),
-                        )
-                        .await?;
-                    }
-                }
+                sub_dialogue::start(tg, message.chat.id, repo, config, dialogue, args).await?;
             }
             Command::Unsub(watchlist_id) => {
                 let chat_id = message.chat.id.0;
@@ -199,39 +215,38 @@ pub async fn handle_command(
                     return Ok(());
                 };
 
-                // Check if watchlist for this place already exists for this chat
-                let existing: Vec<Watchlist> = db::watchlist::get_for_chat(config, chat_id)
-                    .iter()
-                    .filter(|watchlist| watchlist.id == watchlist_id)
-                    .map(|item| item.to_owned())
-                    .collect();
-
-                if existing.is_empty() {
-                    tg.send_message(message.chat.id, "You don't have a watchlist with this ID")
-                        .await?;
-                } else {
-                    db::watchlist::delete(config, watchlist_id);
-                    tg.send_message(message.chat.id, "Deleted watchlist!")
-                        .await?;
+                match watchlists::delete(repo, chat_id, watchlist_id).await {
+                    Ok(()) => {
+                        tg.send_message(message.chat.id, "Deleted watchlist!")
+                            .await?;
+                    }
+                    Err(e) => {
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
+                    }
                 }
             }
             Command::ListWatchlists => {
                 let chat_id = message.chat.id.0;
 
                 // Check if watchlist for this place already exists for this chat
-                let existing: Vec<Watchlist> = db::watchlist::get_for_chat(config, chat_id);
+                let existing: Vec<Watchlist> = watchlists::list(repo, chat_id).await?;
                 let formatted: Vec<String> = existing
                     .iter()
                     .enumerate()
                     .map(|(index, watchlist)| {
                         format!(
-                            "{}: \n Id: {} Location: {} Target Yield: {} Size: {}:{} \n\n",
+                            "{}: \n Id: {} Locations: {} Target Yield: {} Size: {}:{} Price Drop Alert: {}% Refresh: every {} min{}{}{} \n\n",
                             index + 1,
                             watchlist.id.clone(),
-                            watchlist.location_name.clone(),
+                            watchlist.locations.names(),
                             watchlist.target_yield.unwrap(),
                             watchlist.target_size_min.unwrap(),
-                            watchlist.target_size_max.unwrap()
+                            watchlist.target_size_max.unwrap(),
+                            watchlist.min_price_drop_percent,
+                            watchlist.refresh_interval_minutes,
+                            format_criteria(&watchlist.criteria),
+                            format_financing(&watchlist.financing),
+                            if watchlist.is_paused() { " (paused)" } else { "" }
                         )
                     })
                     .collect();
@@ -243,6 +258,123 @@ pub async fn handle_command(
                     tg.send_message(message.chat.id, joined_formatted).await?;
                 }
             }
+            Command::Interval(watchlist_id, minutes) => {
+                let (Some(watchlist_id), Some(minutes)) = (watchlist_id, minutes) else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the watchlist ID and the interval in minutes, e.g. /interval 42 30.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let chat_id = message.chat.id.0;
+                match watchlists::set_refresh_interval(repo, chat_id, watchlist_id, minutes).await {
+                    Ok(watchlist) => {
+                        tg.send_message(
+                            message.chat.id,
+                            format!(
+                                "Watchlist {} is now refreshed every {} minutes",
+                                watchlist.id, watchlist.refresh_interval_minutes
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
+                    }
+                }
+            }
+            Command::Edit(watchlist_id, changes) => {
+                let Some(watchlist_id) = watchlist_id.filter(|_| !changes.is_empty()) else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the watchlist ID and the criteria to change, e.g. /edit 42 yield=8 max_size=70.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let chat_id = message.chat.id.0;
+                match watchlists::update(repo, chat_id, watchlist_id, &changes).await {
+                    Ok(watchlist) => {
+                        tg.send_message(
+                            message.chat.id,
+                            format!(
+                                "Updated watchlist {}: Target Yield: {} Size: {}:{} Price Drop Alert: {}% Refresh: every {} min{}{}. Apartments are matched again with the new criteria.",
+                                watchlist.id,
+                                watchlist.target_yield.unwrap_or_default(),
+                                watchlist.target_size_min.unwrap_or_default(),
+                                watchlist.target_size_max.unwrap_or_default(),
+                                watchlist.min_price_drop_percent,
+                                watchlist.refresh_interval_minutes,
+                                format_criteria(&watchlist.criteria),
+                                format_financing(&watchlist.financing),
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
+                    }
+                }
+            }
+            Command::AddLocation(watchlist_id, location) => {
+                let Some(watchlist_id) = watchlist_id.filter(|_| !location.is_empty()) else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the watchlist ID and the location, e.g. /addlocation 42 00510.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let chat_id = message.chat.id.0;
+                match watchlists::add_location(repo, chat_id, watchlist_id, &location).await {
+                    Ok(watchlist) => {
+                        tg.send_message(
+                            message.chat.id,
+                            format!(
+                                "Watchlist {} now searches {}",
+                                watchlist.id,
+                                watchlist.locations.names()
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
+                    }
+                }
+            }
+            Command::RemoveLocation(watchlist_id, location) => {
+                let Some(watchlist_id) = watchlist_id.filter(|_| !location.is_empty()) else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the watchlist ID and the location, e.g. /removelocation 42 00510.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let chat_id = message.chat.id.0;
+                match watchlists::remove_location(repo, chat_id, watchlist_id, &location).await {
+                    Ok(watchlist) => {
+                        tg.send_message(
+                            message.chat.id,
+                            format!(
+                                "Watchlist {} now searches {}",
+                                watchlist.id,
+                                watchlist.locations.names()
+                            ),
+                        )
+                        .await?;
+                    }
+                    Err(e) => {
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
+                    }
+                }
+            }
             Command::GetAll(watchlist_id) => {
                 let Some(watchlist_id) = watchlist_id else {
                     tg.send_message(
@@ -256,14 +388,13 @@ pub async fn handle_command(
                 let chat_id = message.chat.id.0;
 
                 let all_apartments_result =
-                    db::apartment::get_all_for_watchlist(config, chat_id, watchlist_id);
+                    watchlists::get_all_apartments(repo, chat_id, watchlist_id, false, false).await;
                 let mut all_apartments: Option<Vec<Apartment>> = None;
 
                 match all_apartments_result {
                     Ok(aps) => all_apartments = Some(aps),
                     Err(e) => {
-                        tg.send_message(message.chat.id, format!("Error while fetching: {}", e))
-                            .await?;
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
                     }
                 };
 
@@ -271,6 +402,82 @@ pub async fn handle_command(
                     send_formatted_message_all(tg, message, aps).await?;
                 }
             }
+            Command::Saved => {
+                let tracked =
+                    apartments::get_tracked(repo, message.chat.id.0, &ApartmentStatus::TRACKED)
+                        .await?;
+                if tracked.is_empty() {
+                    tg.send_message(
+                        message.chat.id,
+                        "No saved apartments yet. Save one with the button under a notification or with /status.",
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                for tracked in tracked {
+                    tg.send_message(message.chat.id, format_tracked_apartment(&tracked))
+                        .await?;
+                }
+            }
+            Command::Note(card_id, notes) => {
+                let Some(card_id) = card_id else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the card ID and the notes, e.g. /note 21812345 viewing on Tuesday.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let reply =
+                    match apartments::set_notes(repo, message.chat.id.0, card_id, &notes).await {
+                        Ok(state) if state.notes.is_some() => format!("Notes on {} saved", card_id),
+                        Ok(_) => format!("Notes on {} cleared", card_id),
+                        Err(e) => user_message(&e),
+                    };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Status(card_id, status) => {
+                let Some(card_id) = card_id else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Please provide the card ID and the status, e.g. /status 21812345 contacted.",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+
+                let status = status.to_lowercase();
+                let reply =
+                    match apartments::set_status(repo, message.chat.id.0, card_id, &status).await {
+                        Ok(state) => format!("{} is now {}", card_id, state.status),
+                        Err(e) => user_message(&e),
+                    };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::ApiToken => {
+                let token = api_tokens::issue(repo, message.chat.id.0).await?;
+                tg.send_message(
+                    message.chat.id,
+                    format!(
+                        "Your API token: {}\n\nSend it as `Authorization: Bearer <token>`. It gives access to this chat's watchlists, so keep it secret. /apitoken again revokes it.",
+                        token
+                    ),
+                )
+                .await?;
+            }
+            Command::Login => {
+                let link = sessions::login_link(config, message.chat.id.0)?;
+                tg.send_message(
+                    message.chat.id,
+                    format!(
+                        "Open this link within {} minutes to sign in to the web console. It works once.\n\n{}",
+                        sessions::LOGIN_LINK_MINUTES,
+                        link
+                    ),
+                )
+                .await?;
+            }
             Command::GetMatching(watchlist_id) => {
                 let Some(watchlist_id) = watchlist_id else {
                     tg.send_message(
@@ -283,14 +490,13 @@ pub async fn handle_command(
 
                 let chat_id = message.chat.id.0;
                 let apartments_result =
-                    db::apartment::get_matching_for_watchlist(config, chat_id, watchlist_id);
+                    watchlists::get_matching_apartments(repo, chat_id, watchlist_id, false).await;
                 let mut apartments: Option<Vec<Apartment>> = None;
 
                 match apartments_result {
                     Ok(aps) => apartments = Some(aps),
                     Err(e) => {
-                        tg.send_message(message.chat.id, format!("Error while fetching: {}", e))
-                            .await?;
+                        tg.send_message(message.chat.id, user_message(&e)).await?;
                     }
                 };
 
@@ -302,54 +508,194 @@ pub async fn handle_command(
         Ok(())
     }
 
-    if let Err(err) = handle(&message, &tg, command, &config).await {
-        error!("Failed to handle message: {}", err);
-        tg.send_message(message.chat.id, "Something went wrong, please try again")
+    // Messages from the chat mean it can be reached again
+    match watchlists::resume(&repo, message.chat.id.0).await {
+        Ok(0) => {}
+        Ok(resumed) => {
+            info!(resumed, "Resumed paused watchlists");
+            tg.send_message(
+                message.chat.id,
+                format!("Welcome back! Resumed {} paused watchlists.", resumed),
+            )
             .await?;
+        }
+        Err(err) => error!("Failed to resume watchlists: {:#}", err),
+    }
+
+    if let Err(err) = handle(&message, &tg, command, &repo, &config, &dialogue).await {
+        error!("Failed to handle message: {:#}", err);
+        tg.send_message(message.chat.id, user_message(&err)).await?;
     }
 
     Ok(())
 }
 
+/// Messages that are not commands. They answer the /sub dialogue, if the chat is in it.
+#[instrument(skip_all, fields(chat_id = message.chat.id.0))]
+pub async fn handle_dialogue_message(
+    message: Message,
+    tg: Arc<Bot>,
+    repo: SharedRepository,
+    config: Arc<Config>,
+    dialogue: SubDialogue,
+    step: SubStep,
+) -> Result<()> {
+    let Some(text) = message.text() else {
+        return Ok(());
+    };
+    if let Err(err) =
+        sub_dialogue::handle_text(&tg, message.chat.id, text, &repo, &config, &dialogue, step).await
+    {
+        error!("Failed to handle message: {:#}", err);
+        tg.send_message(message.chat.id, user_message(&err)).await?;
+    }
+
+    Ok(())
+}
+
+/// Button presses of the /sub dialogue and of apartment notifications. Buttons that no
+/// longer do anything, e.g. of a finished dialogue, say so.
+#[instrument(skip_all, fields(chat_id = query.message.as_ref().map(|m| m.chat.id.0)))]
+pub async fn handle_callback(
+    query: CallbackQuery,
+    tg: Arc<Bot>,
+    repo: SharedRepository,
+    config: Arc<Config>,
+    dialogue: SubDialogue,
+    step: SubStep,
+) -> Result<()> {
+    let data = query.data.as_deref().unwrap_or_default();
+    let Some(message) = &query.message else {
+        tg.answer_callback_query(&query.id).await?;
+        return Ok(());
+    };
+    let chat_id = message.chat.id;
+
+    if let Some(button) = ApartmentButton::parse(data) {
+        tg.answer_callback_query(&query.id).await?;
+        if let Err(err) = apartment_actions::handle(&tg, chat_id, button, &repo, &config).await {
+            error!("Failed to handle button: {:#}", err);
+            tg.send_message(chat_id, user_message(&err)).await?;
+        }
+        return Ok(());
+    }
+
+    let Some(action) = SubAction::parse(data) else {
+        tg.answer_callback_query(&query.id).await?;
+        return Ok(());
+    };
+    match sub_dialogue::handle_action(&tg, chat_id, action, &repo, &config, &dialogue, step).await {
+        Ok(true) => {
+            tg.answer_callback_query(&query.id).await?;
+            tg.edit_message_reply_markup(chat_id, message.id).await?;
+        }
+        Ok(false) => {
+            tg.answer_callback_query(&query.id)
+                .text("This button has expired. Send /sub to start again.")
+                .await?;
+        }
+        Err(err) => {
+            error!("Failed to handle button: {:#}", err);
+            tg.answer_callback_query(&query.id).await?;
+            tg.send_message(chat_id, user_message(&err)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Empty input gives empty args, which starts the /sub dialogue.
 fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseError> {
     lazy_static! {
         static ref LOCATION_STRING_REGEX: Regex = Regex::new(r"^[^\s]+").unwrap();
         static ref MIN_SIZE_REGEX: Regex = Regex::new(r"\bmin_size=(\d+)\b").unwrap();
         static ref MAX_SIZE_REGEX: Regex = Regex::new(r"\bmax_size=(\d+)\b").unwrap();
-        static ref YIELD_REGEX: Regex = Regex::new(r"\byield=(\d+)\b").unwrap();
+        static ref YIELD_REGEX: Regex = Regex::new(r"\byield=(\d+(?:\.\d+)?)").unwrap();
     }
 
+    let input = input.trim();
     let location = LOCATION_STRING_REGEX
-        .find(&input)
-        .ok_or_else(|| ParseError::Custom("No location given".into()))?
-        .as_str()
-        .to_string();
-
-    let min_size: Option<u32> = MIN_SIZE_REGEX
-        .captures(&input)
-        .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse().ok());
-
-    let max_size: Option<u32> = MAX_SIZE_REGEX
-        .captures(&input)
-        .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse().ok());
-
-    let target_yield: Option<u32> = YIELD_REGEX
-        .captures(&input)
-        .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse().ok());
+        .find(input)
+        .map_or(String::new(), |m| m.as_str().to_string());
 
     let args = SubscriptionArgs {
         location,
-        target_yield,
-        min_size,
-        max_size,
+        target_yield: capture(&YIELD_REGEX, input),
+        min_size: capture(&MIN_SIZE_REGEX, input),
+        max_size: capture(&MAX_SIZE_REGEX, input),
+        min_price_drop: parse_price_drop(input),
+        criteria: parse_criteria(input),
+        financing: parse_financing(input),
     };
 
     Ok((args,))
 }
 
+/// The `drop=` option of /sub.
+pub(crate) fn parse_price_drop(input: &str) -> Option<u32> {
+    lazy_static! {
+        static ref PRICE_DROP_REGEX: Regex = Regex::new(r"\bdrop=(\d+)\b").unwrap();
+    }
+    capture(&PRICE_DROP_REGEX, input)
+}
+
+/// The optional criteria of /sub. Criteria that are not given are left unset.
+pub(crate) fn parse_criteria(input: &str) -> ListingCriteria {
+    lazy_static! {
+        static ref MIN_ROOMS_REGEX: Regex = Regex::new(r"\bmin_rooms=(\d+)\b").unwrap();
+        static ref MAX_ROOMS_REGEX: Regex = Regex::new(r"\bmax_rooms=(\d+)\b").unwrap();
+        static ref MAX_PRICE_REGEX: Regex = Regex::new(r"\bmax_price=(\d+)\b").unwrap();
+        static ref MAX_FEE_REGEX: Regex = Regex::new(r"\bmax_fee=(\d+(?:\.\d+)?)").unwrap();
+        static ref MIN_YEAR_REGEX: Regex = Regex::new(r"\bmin_year=(\d+)\b").unwrap();
+        static ref MAX_DEBT_REGEX: Regex = Regex::new(r"\bmax_debt=(\d+(?:\.\d+)?)").unwrap();
+    }
+    let (exclude_ground_floor, exclude_rented_plot) = parse_exclusions(input);
+
+    ListingCriteria {
+        min_rooms: capture(&MIN_ROOMS_REGEX, input),
+        max_rooms: capture(&MAX_ROOMS_REGEX, input),
+        max_price: capture(&MAX_PRICE_REGEX, input),
+        max_fee_per_m2: capture(&MAX_FEE_REGEX, input),
+        min_build_year: capture(&MIN_YEAR_REGEX, input),
+        exclude_ground_floor: exclude_ground_floor.unwrap_or(false),
+        exclude_rented_plot: exclude_rented_plot.unwrap_or(false),
+        max_debt_share_percent: capture(&MAX_DEBT_REGEX, input),
+    }
+}
+
+/// The financing options of /sub. Options that are not given use the defaults.
+pub(crate) fn parse_financing(input: &str) -> Financing {
+    lazy_static! {
+        static ref DOWN_PAYMENT_REGEX: Regex = Regex::new(r"\bdown=(\d+)\b").unwrap();
+        static ref LOAN_YEARS_REGEX: Regex = Regex::new(r"\bloan=(\d+)\b").unwrap();
+        static ref RENOVATION_REGEX: Regex = Regex::new(r"\brenovation=(\d+)\b").unwrap();
+    }
+
+    Financing {
+        down_payment_percent: capture(&DOWN_PAYMENT_REGEX, input),
+        loan_years: capture(&LOAN_YEARS_REGEX, input),
+        renovation_costs: capture(&RENOVATION_REGEX, input),
+    }
+}
+
+/// Whether `ground_floor=` and `rented_plot=` exclude those apartments (`no`) or allow them
+/// (`yes`). `None` if not given.
+pub(crate) fn parse_exclusions(input: &str) -> (Option<bool>, Option<bool>) {
+    lazy_static! {
+        static ref GROUND_FLOOR_REGEX: Regex = Regex::new(r"\bground_floor=(yes|no)\b").unwrap();
+        static ref RENTED_PLOT_REGEX: Regex = Regex::new(r"\brented_plot=(yes|no)\b").unwrap();
+    }
+    let excluded = |regex: &Regex| capture::<String>(regex, input).map(|allowed| allowed == "no");
+    (excluded(&GROUND_FLOOR_REGEX), excluded(&RENTED_PLOT_REGEX))
+}
+
+fn capture<T: FromStr>(regex: &Regex, input: &str) -> Option<T> {
+    regex
+        .captures(input)
+        .and_then(|caps| caps.get(1))
+        .and_then(|m| m.as_str().parse().ok())
+}
+
 fn parse_string_to_int_message(input: String) -> Result<(Option<i32>,), ParseError> {
     if input.trim().is_empty() {
         return Ok((None,));
@@ -363,6 +709,99 @@ fn parse_string_to_int_message(input: String) -> Result<(Option<i32>,), ParseErr
     }
 }
 
+fn parse_refresh_interval_message(input: String) -> Result<(Option<i32>, Option<i32>), ParseError> {
+    let mut parts = input.split_whitespace().map(|part| {
+        part.parse::<i32>()
+            .map_err(|_| ParseError::Custom("Unable to parse the supplied number.".into()))
+    });
+
+    let watchlist_id = parts.next().transpose()?;
+    let minutes = parts.next().transpose()?;
+    Ok((watchlist_id, minutes))
+}
+
+/// Parses `{watchlist_id} {location}`. The location may contain spaces.
+fn parse_id_and_text(input: String) -> Result<(Option<i32>, String), ParseError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok((None, String::new()));
+    }
+
+    let (id, location) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let watchlist_id = id
+        .parse::<i32>()
+        .map_err(|_| ParseError::Custom("Unable to parse the supplied ID.".into()))?;
+    Ok((Some(watchlist_id), location.trim().to_string()))
+}
+
+/// Parses `{watchlist_id} key=value ...`. Keys are the ones of /sub plus `interval`.
+/// Optional criteria are cleared with `any`.
+fn parse_edit_message(input: String) -> Result<(Option<i32>, WatchlistUpdate), ParseError> {
+    let mut parts = input.split_whitespace();
+    let watchlist_id = parts
+        .next()
+        .map(|part| {
+            part.parse::<i32>()
+                .map_err(|_| ParseError::Custom("Unable to parse the supplied ID.".into()))
+        })
+        .transpose()?;
+
+    let mut changes = WatchlistUpdate::default();
+    for part in parts {
+        let (key, raw) = part.split_once('=').ok_or_else(|| {
+            ParseError::Custom(format!("Expected key=value, got {}.", part).into())
+        })?;
+        match key {
+            "yield" => changes.target_yield = Some(parse_value(key, raw)?),
+            "min_size" => changes.min_size = Some(parse_value(key, raw)?),
+            "max_size" => changes.max_size = Some(parse_value(key, raw)?),
+            "drop" => changes.min_price_drop_percent = Some(parse_value(key, raw)?),
+            "interval" => changes.refresh_interval_minutes = Some(parse_value(key, raw)?),
+            "min_rooms" => changes.min_rooms = Some(parse_optional_value(key, raw)?),
+            "max_rooms" => changes.max_rooms = Some(parse_optional_value(key, raw)?),
+            "max_price" => changes.max_price = Some(parse_optional_value(key, raw)?),
+            "max_fee" => changes.max_fee_per_m2 = Some(parse_optional_value(key, raw)?),
+            "min_year" => changes.min_build_year = Some(parse_optional_value(key, raw)?),
+            "max_debt" => changes.max_debt_share_percent = Some(parse_optional_value(key, raw)?),
+            "ground_floor" => changes.exclude_ground_floor = Some(!parse_allowed(key, raw)?),
+            "rented_plot" => changes.exclude_rented_plot = Some(!parse_allowed(key, raw)?),
+            "down" => changes.down_payment_percent = Some(parse_optional_value(key, raw)?),
+            "loan" => changes.loan_years = Some(parse_optional_value(key, raw)?),
+            "renovation" => changes.renovation_costs = Some(parse_optional_value(key, raw)?),
+            _ => {
+                return Err(ParseError::Custom(
+                    format!("Unknown criterion {}.", key).into(),
+                ))
+            }
+        }
+    }
+
+    Ok((watchlist_id, changes))
+}
+
+fn parse_value<T: FromStr>(key: &str, raw: &str) -> Result<T, ParseError> {
+    raw.parse()
+        .map_err(|_| ParseError::Custom(format!("Unable to parse the value of {}.", key).into()))
+}
+
+fn parse_optional_value<T: FromStr>(key: &str, raw: &str) -> Result<Option<T>, ParseError> {
+    if raw == "any" {
+        return Ok(None);
+    }
+    parse_value(key, raw).map(Some)
+}
+
+/// `yes` or `no`.
+fn parse_allowed(key: &str, raw: &str) -> Result<bool, ParseError> {
+    match raw {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(ParseError::Custom(
+            format!("{} must be yes or no.", key).into(),
+        )),
+    }
+}
+
 async fn send_formatted_message_all_valid(
     tg: &Bot,
     message: &Message,
@@ -430,6 +869,76 @@ async fn send_formatted_message_all(
     Ok(())
 }
 
+fn format_tracked_apartment(tracked: &TrackedApartment) -> String {
+    let apartment = &tracked.apartment;
+    let mut formatted = format!(
+        "{} ({}) \n Location: {} \n Size: {:.1} m^2 \n Price: {} EUR \n Estimated Yield: {:.2}% \n Url: {}",
+        apartment.card_id,
+        tracked.state.status,
+        apartment
+            .location_name
+            .as_ref()
+            .unwrap_or(&"N/A".to_string()),
+        apartment.size.unwrap_or(0.0),
+        apartment.price.unwrap_or(0),
+        apartment.estimated_yield.unwrap_or(0.0),
+        apartment.url.as_ref().unwrap_or(&"N/A".to_string())
+    );
+    if apartment.removed_at.is_some() {
+        formatted.push_str(" \n No longer on the market");
+    }
+    if let Some(notes) = &tracked.state.notes {
+        formatted.push_str(&format!(" \n\n Notes: {}", notes));
+    }
+    formatted
+}
+
+/// The optional criteria that are set, e.g. ` Rooms: 2-3 Max Price: 250000 EUR`.
+pub(crate) fn format_criteria(criteria: &ListingCriteria) -> String {
+    let mut parts = Vec::new();
+    if criteria.min_rooms.is_some() || criteria.max_rooms.is_some() {
+        parts.push(format!(
+            "Rooms: {}-{}",
+            criteria.min_rooms.map_or(String::new(), |n| n.to_string()),
+            criteria.max_rooms.map_or(String::new(), |n| n.to_string())
+        ));
+    }
+    if let Some(price) = criteria.max_price {
+        parts.push(format!("Max Price: {} EUR", price));
+    }
+    if let Some(fee) = criteria.max_fee_per_m2 {
+        parts.push(format!("Max Fee: {} EUR/m^2", fee));
+    }
+    if let Some(year) = criteria.min_build_year {
+        parts.push(format!("Built: {} or later", year));
+    }
+    if let Some(percent) = criteria.max_debt_share_percent {
+        parts.push(format!("Max Debt Share: {}%", percent));
+    }
+    if criteria.exclude_ground_floor {
+        parts.push("No Ground Floor".to_string());
+    }
+    if criteria.exclude_rented_plot {
+        parts.push("No Rented Plot".to_string());
+    }
+    parts.iter().map(|part| format!(" {}", part)).collect()
+}
+
+/// The financing that differs from the defaults, e.g. ` Down Payment: 30% Loan: 20 years`.
+pub(crate) fn format_financing(financing: &Financing) -> String {
+    let mut parts = Vec::new();
+    if let Some(percent) = financing.down_payment_percent {
+        parts.push(format!("Down Payment: {}%", percent));
+    }
+    if let Some(years) = financing.loan_years {
+        parts.push(format!("Loan: {} years", years));
+    }
+    if let Some(costs) = financing.renovation_costs {
+        parts.push(format!("Renovation: {} EUR", costs));
+    }
+    parts.iter().map(|part| format!(" {}", part)).collect()
+}
+
 pub fn format_apartment_message(watchlist: &Watchlist, apartment: &Apartment) -> String {
     format!(
         "Found a new apartment matching your criteria for watchlist {} \n\n Location: {} \n Size: {:.1} m^2 \n Price: {} EUR \n Estimated Rent: {} EUR \n Estimated Yield: {:.2}% \n Url: {}",
@@ -446,6 +955,29 @@ pub fn format_apartment_message(watchlist: &Watchlist, apartment: &Apartment) ->
     )
 }
 
+pub fn format_price_drop_message(
+    watchlist: &Watchlist,
+    apartment: &Apartment,
+    previous_price: i32,
+) -> String {
+    let price = apartment.price.unwrap_or(0);
+    format!(
+        "Price drop in watchlist {}: {} EUR -> {} EUR (-{:.1}%) \n\n Location: {} \n Size: {:.1} m^2 \n Estimated Rent: {} EUR \n Estimated Yield: {:.2}% \n Url: {}",
+        watchlist.id,
+        previous_price,
+        price,
+        drop_percent(previous_price, price).unwrap_or_default(),
+        apartment
+            .location_name
+            .as_ref()
+            .unwrap_or(&"N/A".to_string()),
+        apartment.size.unwrap_or(0.0),
+        apartment.rent.unwrap_or_default(),
+        apartment.estimated_yield.unwrap_or(0.0),
+        apartment.url.as_ref().unwrap_or(&"N/A".to_string())
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -459,11 +991,20 @@ mod tests {
                 location: "testlocation".to_string(),
                 target_yield: None,
                 min_size: None,
-                max_size: None
+                max_size: None,
+                min_price_drop: None,
+                criteria: ListingCriteria::default(),
+                financing: Financing::default(),
             },
         )
     }
 
+    #[test]
+    fn test_parse_subscribe_message_empty_starts_dialogue() {
+        let args = parse_subscribe_message("  ".to_string()).unwrap();
+        assert_eq!(args.0, SubscriptionArgs::default());
+    }
+
     #[test]
     fn test_parse_subscribe_message_without_declarations() {
         let args = parse_subscribe_message("testlocation 60 10".to_string()).unwrap();
@@ -473,7 +1014,10 @@ mod tests {
                 location: "testlocation".to_string(),
                 target_yield: None,
                 min_size: None,
-                max_size: None
+                max_size: None,
+                min_price_drop: None,
+                criteria: ListingCriteria::default(),
+                financing: Financing::default(),
             },
         );
     }
@@ -487,10 +1031,129 @@ mod tests {
             args.0,
             SubscriptionArgs {
                 location: "testlocation".to_string(),
-                target_yield: Some(10),
+                target_yield: Some(10.0),
                 min_size: Some(50),
-                max_size: Some(65)
+                max_size: Some(65),
+                min_price_drop: None,
+                criteria: ListingCriteria::default(),
+                financing: Financing::default(),
             },
         )
     }
+
+    #[test]
+    fn test_parse_subscribe_message_with_price_drop() {
+        let args = parse_subscribe_message(
+            "testlocation yield=10 min_size=50 max_size=65 drop=3".to_string(),
+        )
+        .unwrap();
+        assert_eq!(args.0.min_price_drop, Some(3));
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_with_decimal_yield_and_financing() {
+        let args = parse_subscribe_message(
+            "testlocation yield=4.5 min_size=50 max_size=65 down=30 loan=20 renovation=10000"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(args.0.target_yield, Some(4.5));
+        assert_eq!(
+            args.0.financing,
+            Financing {
+                down_payment_percent: Some(30),
+                loan_years: Some(20),
+                renovation_costs: Some(10000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_subscribe_message_with_criteria() {
+        let args = parse_subscribe_message(
+            "testlocation yield=10 min_size=50 max_size=65 min_rooms=2 max_price=250000 max_fee=4.5 min_year=1990 ground_floor=no max_debt=30".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0.criteria,
+            ListingCriteria {
+                min_rooms: Some(2),
+                max_rooms: None,
+                max_price: Some(250000),
+                max_fee_per_m2: Some(4.5),
+                min_build_year: Some(1990),
+                exclude_ground_floor: true,
+                exclude_rented_plot: false,
+                max_debt_share_percent: Some(30.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_id_and_text() {
+        assert_eq!(
+            parse_id_and_text("42 00510".to_string()).unwrap(),
+            (Some(42), "00510".to_string())
+        );
+        assert_eq!(
+            parse_id_and_text(" 42  Kallio, Helsinki ".to_string()).unwrap(),
+            (Some(42), "Kallio, Helsinki".to_string())
+        );
+        assert_eq!(
+            parse_id_and_text("42".to_string()).unwrap(),
+            (Some(42), String::new())
+        );
+        assert_eq!(
+            parse_id_and_text(String::new()).unwrap(),
+            (None, String::new())
+        );
+        assert!(parse_id_and_text("kallio 42".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_edit_message() {
+        let (watchlist_id, changes) =
+            parse_edit_message("42 yield=7.5 max_size=70 interval=30".to_string()).unwrap();
+        assert_eq!(watchlist_id, Some(42));
+        assert_eq!(
+            changes,
+            WatchlistUpdate {
+                target_yield: Some(7.5),
+                max_size: Some(70),
+                refresh_interval_minutes: Some(30),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            parse_edit_message("".to_string()).unwrap(),
+            (None, WatchlistUpdate::default())
+        );
+        assert!(parse_edit_message("42 size=70".to_string()).is_err());
+
+        let (_, changes) = parse_edit_message("42 down=30 loan=any".to_string()).unwrap();
+        assert_eq!(changes.down_payment_percent, Some(Some(30)));
+        assert_eq!(changes.loan_years, Some(None));
+        assert!(parse_edit_message("42 yield=high".to_string()).is_err());
+        assert!(parse_edit_message("42 70".to_string()).is_err());
+
+        let (_, changes) =
+            parse_edit_message("42 max_price=any rented_plot=no".to_string()).unwrap();
+        assert_eq!(changes.max_price, Some(None));
+        assert_eq!(changes.exclude_rented_plot, Some(true));
+        assert!(parse_edit_message("42 ground_floor=maybe".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_refresh_interval_message() {
+        assert_eq!(
+            parse_refresh_interval_message("42 30".to_string()).unwrap(),
+            (Some(42), Some(30))
+        );
+        assert_eq!(
+            parse_refresh_interval_message("42".to_string()).unwrap(),
+            (Some(42), None)
+        );
+        assert!(parse_refresh_interval_message("42 often".to_string()).is_err());
+    }
 }
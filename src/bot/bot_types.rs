@@ -1,3 +1,5 @@
+use crate::models::watchlist::{Financing, ListingCriteria};
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Subscription {
     pub chat_id: i64,
@@ -7,10 +9,13 @@ pub struct Subscription {
     pub max_size: Option<u32>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct SubscriptionArgs {
     pub location: String,
-    pub target_yield: Option<u32>,
+    pub target_yield: Option<f64>,
     pub min_size: Option<u32>,
     pub max_size: Option<u32>,
+    pub min_price_drop: Option<u32>,
+    pub criteria: ListingCriteria,
+    pub financing: Financing,
 }
@@ -1,3 +1,6 @@
+pub mod apartment_actions;
+#[allow(clippy::module_inception)]
 pub mod bot;
 pub mod bot_types;
+pub mod sub_dialogue;
 pub mod subscribe;
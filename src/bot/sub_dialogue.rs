@@ -0,0 +1,787 @@
+//! The /sub dialogue. `/sub` without arguments, or with some of them missing, asks for the
+//! rest step by step with inline buttons: location, size range, target yield, optional
+//! criteria and financing.
+//!
+//! The financing defaults to the configuration. A watchlist with its own down payment, loan
+//! length or renovation costs has the yields of its matches calculated with them.
+
+use anyhow::Result;
+use teloxide::{
+    dispatching::dialogue::{Dialogue, InMemStorage},
+    payloads::SendMessageSetters,
+    requests::Requester,
+    types::{ChatId, InlineKeyboardButton, InlineKeyboardMarkup},
+    Bot,
+};
+
+use super::{
+    bot::{format_criteria, parse_criteria, parse_exclusions, parse_financing, parse_price_drop},
+    bot_types::SubscriptionArgs,
+    subscribe::{check_args, subscribe_to_watchlist},
+};
+use crate::{
+    config::Config,
+    db::repository::SharedRepository,
+    errors::user_message,
+    models::watchlist::{Financing, ListingCriteria},
+    oikotie::oikotie::Location,
+    services::watchlists::{self, LocationMatch},
+};
+
+pub type SubDialogue = Dialogue<SubStep, InMemStorage<SubStep>>;
+
+/// Callback data of the dialogue's buttons starts with `sub:`.
+const CALLBACK_PREFIX: &str = "sub:";
+/// Target yields offered as buttons, in percent.
+const YIELD_CHOICES: [f64; 5] = [4.0, 5.0, 6.0, 7.0, 8.0];
+
+/// Where a chat is in the /sub dialogue. Each step carries the answers so far.
+#[derive(Clone, Debug, Default)]
+pub enum SubStep {
+    #[default]
+    Idle,
+    /// Waiting for a location to search for.
+    Location(SubscriptionArgs),
+    /// Waiting for one of the matching locations to be chosen.
+    ChooseLocation(SubscriptionArgs, Vec<Location>),
+    /// Waiting for the size range, e.g. `40-60`.
+    Size(SubscriptionArgs, Location),
+    /// Waiting for the target yield.
+    Yield(SubscriptionArgs, Location),
+    /// Waiting for optional criteria, or for them to be skipped.
+    Criteria(SubscriptionArgs, Location),
+    /// Waiting for changes to the financing, or for it to be kept.
+    Financing(SubscriptionArgs, Location),
+    /// Waiting for the subscription to be confirmed.
+    Confirm(SubscriptionArgs, Location),
+}
+
+/// Buttons of the dialogue.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubAction {
+    Location(i32, i32),
+    Yield(f64),
+    NoMoreCriteria,
+    KeepFinancing,
+    Confirm,
+    Cancel,
+}
+
+impl SubAction {
+    pub fn parse(data: &str) -> Option<Self> {
+        let parts: Vec<&str> = data.strip_prefix(CALLBACK_PREFIX)?.split(':').collect();
+        match parts.as_slice() {
+            ["location", id, level] => {
+                Some(SubAction::Location(id.parse().ok()?, level.parse().ok()?))
+            }
+            ["yield", percent] => Some(SubAction::Yield(valid_percent(percent.parse().ok()?)?)),
+            ["criteria_done"] => Some(SubAction::NoMoreCriteria),
+            ["financing_done"] => Some(SubAction::KeepFinancing),
+            ["confirm"] => Some(SubAction::Confirm),
+            ["cancel"] => Some(SubAction::Cancel),
+            _ => None,
+        }
+    }
+
+    fn data(&self) -> String {
+        let action = match self {
+            SubAction::Location(id, level) => format!("location:{}:{}", id, level),
+            SubAction::Yield(percent) => format!("yield:{}", percent),
+            SubAction::NoMoreCriteria => "criteria_done".to_string(),
+            SubAction::KeepFinancing => "financing_done".to_string(),
+            SubAction::Confirm => "confirm".to_string(),
+            SubAction::Cancel => "cancel".to_string(),
+        };
+        format!("{}{}", CALLBACK_PREFIX, action)
+    }
+
+    fn button(&self, text: impl Into<String>) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(text, self.data())
+    }
+}
+
+/// Starts the dialogue with the arguments of /sub. Complete arguments subscribe right away.
+pub async fn start(
+    tg: &Bot,
+    chat_id: ChatId,
+    repo: &SharedRepository,
+    config: &Config,
+    dialogue: &SubDialogue,
+    args: SubscriptionArgs,
+) -> Result<()> {
+    dialogue.update(SubStep::Idle).await?;
+    if args.location.is_empty() {
+        return ask(tg, chat_id, config, dialogue, SubStep::Location(args)).await;
+    }
+    resolve(tg, chat_id, repo, config, dialogue, args).await
+}
+
+/// Answers typed while the chat is in the dialogue.
+pub async fn handle_text(
+    tg: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    repo: &SharedRepository,
+    config: &Config,
+    dialogue: &SubDialogue,
+    step: SubStep,
+) -> Result<()> {
+    let text = text.trim();
+    match step {
+        SubStep::Idle => {}
+        // Another location can be typed instead of choosing one of the buttons
+        SubStep::Location(mut args) | SubStep::ChooseLocation(mut args, _) => {
+            if text.is_empty() {
+                tg.send_message(chat_id, "Please send a location to search for.")
+                    .await?;
+                return Ok(());
+            }
+            args.location = text.to_string();
+            resolve(tg, chat_id, repo, config, dialogue, args).await?;
+        }
+        SubStep::Size(mut args, location) => {
+            let Some((min_size, max_size)) = parse_size_range(text) else {
+                tg.send_message(
+                    chat_id,
+                    "Please send the size range in m^2 with the smaller size first, e.g. 40-60.",
+                )
+                .await?;
+                return Ok(());
+            };
+            args.min_size = Some(min_size);
+            args.max_size = Some(max_size);
+            let next = if args.target_yield.is_none() {
+                SubStep::Yield(args, location)
+            } else {
+                SubStep::Criteria(args, location)
+            };
+            ask(tg, chat_id, config, dialogue, next).await?;
+        }
+        SubStep::Yield(mut args, location) => {
+            let Some(target_yield) = parse_percent(text) else {
+                tg.send_message(
+                    chat_id,
+                    "Please send the target yield in percent, e.g. 6 or 4.5.",
+                )
+                .await?;
+                return Ok(());
+            };
+            args.target_yield = Some(target_yield);
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Criteria(args, location),
+            )
+            .await?;
+        }
+        SubStep::Criteria(mut args, location) => {
+            let min_price_drop = parse_price_drop(text);
+            if parse_criteria(text) == ListingCriteria::default()
+                && parse_exclusions(text) == (None, None)
+                && min_price_drop.is_none()
+            {
+                tg.send_message(
+                    chat_id,
+                    "I did not recognise any criteria. Send them as in /sub, e.g. max_price=250000 min_year=1990, or press No more criteria.",
+                )
+                .await?;
+                return Ok(());
+            }
+            args.criteria = merge_criteria(args.criteria, text);
+            args.min_price_drop = min_price_drop.or(args.min_price_drop);
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Financing(args, location),
+            )
+            .await?;
+        }
+        SubStep::Financing(mut args, location) => {
+            let given = parse_financing(text);
+            if given == Financing::default() {
+                tg.send_message(
+                    chat_id,
+                    "I did not recognise any financing. Send it as in /sub, e.g. down=30 loan=20 renovation=10000, or press Use these.",
+                )
+                .await?;
+                return Ok(());
+            }
+            args.financing = args.financing.merge(&given);
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Confirm(args, location),
+            )
+            .await?;
+        }
+        SubStep::Confirm(..) => {
+            tg.send_message(chat_id, "Please press Subscribe or Cancel.")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Button presses. Returns false if the button does not belong to the current step, e.g.
+/// because it was pressed in an earlier dialogue.
+pub async fn handle_action(
+    tg: &Bot,
+    chat_id: ChatId,
+    action: SubAction,
+    repo: &SharedRepository,
+    config: &Config,
+    dialogue: &SubDialogue,
+    step: SubStep,
+) -> Result<bool> {
+    match (action, step) {
+        (_, SubStep::Idle) => return Ok(false),
+        (SubAction::Cancel, _) => ask(tg, chat_id, config, dialogue, SubStep::Idle).await?,
+        (SubAction::Location(id, level), SubStep::ChooseLocation(args, candidates)) => {
+            let Some(location) = candidates
+                .into_iter()
+                .find(|l| l.id == id && l.level == level)
+            else {
+                return Ok(false);
+            };
+            located(tg, chat_id, repo, config, dialogue, args, location).await?;
+        }
+        (SubAction::Yield(target_yield), SubStep::Yield(mut args, location)) => {
+            args.target_yield = Some(target_yield);
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Criteria(args, location),
+            )
+            .await?;
+        }
+        (SubAction::NoMoreCriteria, SubStep::Criteria(args, location)) => {
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Financing(args, location),
+            )
+            .await?;
+        }
+        (SubAction::KeepFinancing, SubStep::Financing(args, location)) => {
+            ask(
+                tg,
+                chat_id,
+                config,
+                dialogue,
+                SubStep::Confirm(args, location),
+            )
+            .await?;
+        }
+        (SubAction::Confirm, SubStep::Confirm(args, location)) => {
+            dialogue.update(SubStep::Idle).await?;
+            subscribe_to_watchlist(args, location, chat_id, tg, repo).await?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Looks up the location of the args. Errors leave the chat in its current step.
+async fn resolve(
+    tg: &Bot,
+    chat_id: ChatId,
+    repo: &SharedRepository,
+    config: &Config,
+    dialogue: &SubDialogue,
+    args: SubscriptionArgs,
+) -> Result<()> {
+    match watchlists::resolve_location(repo, chat_id.0, &args.location).await {
+        Ok(LocationMatch::Found(location)) => {
+            located(tg, chat_id, repo, config, dialogue, args, location).await
+        }
+        Ok(LocationMatch::Ambiguous(candidates)) => {
+            let step = SubStep::ChooseLocation(args, candidates);
+            ask(tg, chat_id, config, dialogue, step).await
+        }
+        Err(err) => {
+            tg.send_message(chat_id, user_message(&err)).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Moves on once the location is known: subscribes if /sub was complete, otherwise asks for
+/// what is missing.
+async fn located(
+    tg: &Bot,
+    chat_id: ChatId,
+    repo: &SharedRepository,
+    config: &Config,
+    dialogue: &SubDialogue,
+    args: SubscriptionArgs,
+    location: Location,
+) -> Result<()> {
+    if check_args(args.clone()).is_empty() {
+        dialogue.update(SubStep::Idle).await?;
+        tg.send_message(chat_id, format!("Location: {}", location.label()))
+            .await?;
+        return subscribe_to_watchlist(args, location, chat_id, tg, repo).await;
+    }
+
+    let next = if args.min_size.is_none() || args.max_size.is_none() {
+        SubStep::Size(args, location)
+    } else {
+        SubStep::Yield(args, location)
+    };
+    ask(tg, chat_id, config, dialogue, next).await
+}
+
+/// Moves the chat to the step and asks its question.
+async fn ask(
+    tg: &Bot,
+    chat_id: ChatId,
+    config: &Config,
+    dialogue: &SubDialogue,
+    step: SubStep,
+) -> Result<()> {
+    let (question, keyboard) = prompt(&step, config);
+    dialogue.update(step).await?;
+    let request = tg.send_message(chat_id, question);
+    match keyboard {
+        Some(keyboard) => request.reply_markup(keyboard).await?,
+        None => request.await?,
+    };
+    Ok(())
+}
+
+fn prompt(step: &SubStep, config: &Config) -> (String, Option<InlineKeyboardMarkup>) {
+    let cancel = vec![SubAction::Cancel.button("Cancel")];
+    let (question, mut rows) = match step {
+        SubStep::Idle => return ("Cancelled. Send /sub to start again.".to_string(), None),
+        SubStep::Location(_) => (
+            "Which location should the watchlist search? Send a postcode, city, district or neighbourhood, e.g. 00100 or Kallio.".to_string(),
+            vec![],
+        ),
+        SubStep::ChooseLocation(args, candidates) => (
+            format!(
+                "Several locations match '{}'. Which one did you mean? You can also send another location.",
+                args.location
+            ),
+            candidates
+                .iter()
+                .map(|l| vec![SubAction::Location(l.id, l.level).button(l.label())])
+                .collect(),
+        ),
+        SubStep::Size(_, location) => (
+            format!(
+                "Location: {}\n\nWhich sizes are you looking for? Send the range in m^2, e.g. 40-60.",
+                location.label()
+            ),
+            vec![],
+        ),
+        SubStep::Yield(..) => (
+            "What is your target yield? Choose one or send another number of percent, e.g. 4.5."
+                .to_string(),
+            vec![YIELD_CHOICES
+                .iter()
+                .map(|&percent| SubAction::Yield(percent).button(format!("{}%", percent)))
+                .collect()],
+        ),
+        SubStep::Criteria(args, _) => {
+            let mut question = "Any other criteria? Send them as in /sub, e.g. max_price=250000 min_year=1990 ground_floor=no. Available: min_rooms, max_rooms, max_price, max_fee, min_year, max_debt, ground_floor=no, rented_plot=no and drop. ground_floor=yes and rented_plot=yes allow those apartments again.".to_string();
+            let current = format_criteria(&args.criteria);
+            if !current.is_empty() {
+                question.push_str(&format!("\n\nSo far:{}", current));
+            }
+            (
+                question,
+                vec![vec![SubAction::NoMoreCriteria.button("No more criteria")]],
+            )
+        }
+        SubStep::Financing(args, _) => (
+            format!(
+                "How do you finance the purchase? {} Send the ones to change, e.g. down=30 loan=20 renovation=10000.",
+                financing_sentence(&args.financing, config)
+            ),
+            vec![vec![SubAction::KeepFinancing.button("Use these")]],
+        ),
+        SubStep::Confirm(args, location) => (
+            format!(
+                "Subscribe to {}?\n\n Size: {}-{} m^2 \n Target Yield: {}%{} \n\n {}",
+                location.label(),
+                args.min_size.unwrap_or_default(),
+                args.max_size.unwrap_or_default(),
+                args.target_yield.unwrap_or_default(),
+                format_criteria(&args.criteria),
+                financing_sentence(&args.financing, config),
+            ),
+            vec![vec![SubAction::Confirm.button("Subscribe")]],
+        ),
+    };
+    rows.push(cancel);
+    (question, Some(InlineKeyboardMarkup::new(rows)))
+}
+
+/// The financing the yield is calculated with, the defaults of the config where unset.
+fn financing_sentence(financing: &Financing, config: &Config) -> String {
+    let (down_payment, loan_years, renovation_costs) = financing.terms(config);
+    format!(
+        "The yield is calculated with a {}% down payment, a {}-year loan at the current interest rate and {} EUR of renovations.",
+        down_payment, loan_years, renovation_costs
+    )
+}
+
+/// `40-60` or `40 60`, smaller size first.
+fn parse_size_range(text: &str) -> Option<(u32, u32)> {
+    let (min, max) = text
+        .split_once('-')
+        .or_else(|| text.split_once(char::is_whitespace))?;
+    let (min, max): (u32, u32) = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+    (min <= max && max > 0).then_some((min, max))
+}
+
+/// `6`, `4.5` or `6%`.
+fn parse_percent(text: &str) -> Option<f64> {
+    valid_percent(text.trim_end_matches('%').trim().parse().ok()?)
+}
+
+fn valid_percent(percent: f64) -> Option<f64> {
+    (percent.is_finite() && percent > 0.0).then_some(percent)
+}
+
+/// The criteria given in the text, keeping the earlier ones that were not given again.
+fn merge_criteria(earlier: ListingCriteria, text: &str) -> ListingCriteria {
+    let given = parse_criteria(text);
+    let (exclude_ground_floor, exclude_rented_plot) = parse_exclusions(text);
+    ListingCriteria {
+        min_rooms: given.min_rooms.or(earlier.min_rooms),
+        max_rooms: given.max_rooms.or(earlier.max_rooms),
+        max_price: given.max_price.or(earlier.max_price),
+        max_fee_per_m2: given.max_fee_per_m2.or(earlier.max_fee_per_m2),
+        min_build_year: given.min_build_year.or(earlier.min_build_year),
+        exclude_ground_floor: exclude_ground_floor.unwrap_or(earlier.exclude_ground_floor),
+        exclude_rented_plot: exclude_rented_plot.unwrap_or(earlier.exclude_rented_plot),
+        max_debt_share_percent: given
+            .max_debt_share_percent
+            .or(earlier.max_debt_share_percent),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        config::create_test_config, db::memory::InMemoryRepository, test_support::fake_telegram,
+    };
+
+    fn kallio() -> Location {
+        Location {
+            id: 1645,
+            level: 4,
+            name: "Kallio, Helsinki".to_string(),
+        }
+    }
+
+    #[test]
+    fn actions_round_trip_through_callback_data() {
+        for action in [
+            SubAction::Location(1645, 4),
+            SubAction::Yield(6.0),
+            SubAction::Yield(4.5),
+            SubAction::NoMoreCriteria,
+            SubAction::KeepFinancing,
+            SubAction::Confirm,
+            SubAction::Cancel,
+        ] {
+            assert_eq!(SubAction::parse(&action.data()), Some(action));
+        }
+        assert_eq!(SubAction::parse("sub:yield:many"), None);
+        assert_eq!(SubAction::parse("sub:yield:NaN"), None);
+        assert_eq!(SubAction::parse("other:confirm"), None);
+    }
+
+    #[test]
+    fn size_ranges_and_yields_are_validated() {
+        assert_eq!(parse_size_range("40-60"), Some((40, 60)));
+        assert_eq!(parse_size_range("40 - 60"), Some((40, 60)));
+        assert_eq!(parse_size_range("40 60"), Some((40, 60)));
+        assert_eq!(parse_size_range("60-40"), None);
+        assert_eq!(parse_size_range("forty"), None);
+        assert_eq!(parse_percent("6%"), Some(6.0));
+        assert_eq!(parse_percent("4.5"), Some(4.5));
+        assert_eq!(parse_percent("0"), None);
+        assert_eq!(parse_percent("inf"), None);
+    }
+
+    #[test]
+    fn criteria_are_merged_and_exclusions_cleared() {
+        let earlier = merge_criteria(
+            ListingCriteria::default(),
+            "max_price=250000 ground_floor=no rented_plot=no",
+        );
+        assert!(earlier.exclude_ground_floor && earlier.exclude_rented_plot);
+
+        let merged = merge_criteria(earlier, "min_rooms=2 ground_floor=yes");
+        assert_eq!(merged.max_price, Some(250000));
+        assert_eq!(merged.min_rooms, Some(2));
+        assert!(!merged.exclude_ground_floor);
+        assert!(merged.exclude_rented_plot);
+    }
+
+    #[tokio::test]
+    async fn dialogue_steps_through_to_a_subscription() {
+        let tg = fake_telegram().await;
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let config = create_test_config();
+        let chat_id = ChatId(42);
+        let dialogue = SubDialogue::new(InMemStorage::new(), chat_id);
+        let step = || async { dialogue.get_or_default().await.unwrap() };
+
+        // An existing watchlist of the location lets it be found without Oikotie
+        let existing = watchlists::subscribe(
+            &repo,
+            42,
+            kallio(),
+            (30.0, 60.0),
+            5.0,
+            None,
+            ListingCriteria::default(),
+            Financing::default(),
+        )
+        .await
+        .unwrap();
+
+        start(
+            &tg,
+            chat_id,
+            &repo,
+            &config,
+            &dialogue,
+            SubscriptionArgs::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Location(_)));
+
+        handle_text(
+            &tg,
+            chat_id,
+            "Kallio, Helsinki",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Size(..)));
+
+        // Invalid answers are asked again
+        handle_text(
+            &tg,
+            chat_id,
+            "60-40",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Size(..)));
+        handle_text(
+            &tg,
+            chat_id,
+            "40-70",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+
+        // Buttons of other steps do nothing
+        let handled = handle_action(
+            &tg,
+            chat_id,
+            SubAction::Confirm,
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(!handled);
+
+        // Target yields can have decimals
+        handle_text(
+            &tg,
+            chat_id,
+            "4.5%",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Criteria(..)));
+        handle_text(
+            &tg,
+            chat_id,
+            "max_price=250000 ground_floor=no",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Financing(..)));
+
+        handle_text(
+            &tg,
+            chat_id,
+            "down=30 loan=20",
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Confirm(..)));
+
+        handle_action(
+            &tg,
+            chat_id,
+            SubAction::Confirm,
+            &repo,
+            &config,
+            &dialogue,
+            step().await,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(step().await, SubStep::Idle));
+
+        let stored = repo.get_watchlist(existing.id).await.unwrap().unwrap();
+        assert_eq!(stored.target_yield, Some(4.5));
+        assert_eq!(stored.target_size_min, Some(40));
+        assert_eq!(stored.target_size_max, Some(70));
+        assert_eq!(stored.criteria.max_price, Some(250000));
+        assert!(stored.criteria.exclude_ground_floor);
+        assert_eq!(
+            stored.financing,
+            Financing {
+                down_payment_percent: Some(30),
+                loan_years: Some(20),
+                renovation_costs: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn default_financing_can_be_kept() {
+        let tg = fake_telegram().await;
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let config = create_test_config();
+        let chat_id = ChatId(42);
+        let dialogue = SubDialogue::new(InMemStorage::new(), chat_id);
+        let args = SubscriptionArgs {
+            min_size: Some(40),
+            max_size: Some(70),
+            target_yield: Some(5.0),
+            ..Default::default()
+        };
+
+        ask(
+            &tg,
+            chat_id,
+            &config,
+            &dialogue,
+            SubStep::Financing(args, kallio()),
+        )
+        .await
+        .unwrap();
+        let step = dialogue.get_or_default().await.unwrap();
+        let (question, _) = prompt(&step, &config);
+        assert!(question.contains("a 20% down payment, a 25-year loan"));
+
+        // Unrecognised financing is asked again
+        handle_text(&tg, chat_id, "cash", &repo, &config, &dialogue, step)
+            .await
+            .unwrap();
+        let step = dialogue.get_or_default().await.unwrap();
+        assert!(handle_action(
+            &tg,
+            chat_id,
+            SubAction::KeepFinancing,
+            &repo,
+            &config,
+            &dialogue,
+            step
+        )
+        .await
+        .unwrap());
+        match dialogue.get_or_default().await.unwrap() {
+            SubStep::Confirm(args, _) => assert!(args.financing.is_default()),
+            step => panic!("Expected the confirm step, got {:?}", step),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_leaves_the_dialogue() {
+        let tg = fake_telegram().await;
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let config = create_test_config();
+        let chat_id = ChatId(42);
+        let dialogue = SubDialogue::new(InMemStorage::new(), chat_id);
+
+        start(
+            &tg,
+            chat_id,
+            &repo,
+            &config,
+            &dialogue,
+            SubscriptionArgs::default(),
+        )
+        .await
+        .unwrap();
+        let step = dialogue.get_or_default().await.unwrap();
+        assert!(handle_action(
+            &tg,
+            chat_id,
+            SubAction::Cancel,
+            &repo,
+            &config,
+            &dialogue,
+            step
+        )
+        .await
+        .unwrap());
+
+        let step = dialogue.get_or_default().await.unwrap();
+        assert!(matches!(step, SubStep::Idle));
+        assert!(!handle_action(
+            &tg,
+            chat_id,
+            SubAction::Cancel,
+            &repo,
+            &config,
+            &dialogue,
+            step
+        )
+        .await
+        .unwrap());
+    }
+}
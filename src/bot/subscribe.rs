@@ -1,9 +1,10 @@
-use std::sync::Arc;
-
-use log::error;
 use teloxide::{prelude::Requester, types::ChatId, Bot};
+use tracing::{error, info};
 
-use crate::{config::Config, services::watchlists};
+use crate::{
+    db::repository::SharedRepository, errors::ApatoError, oikotie::oikotie::Location,
+    services::watchlists,
+};
 use anyhow::Result;
 
 use super::bot_types::SubscriptionArgs;
@@ -22,40 +23,65 @@ pub fn check_args(args: SubscriptionArgs) -> Vec<String> {
     errors
 }
 
+/// Subscribes the chat with arguments that passed `check_args`, in the chosen location.
 pub async fn subscribe_to_watchlist(
-    size: (f64, f64),
-    new_target_yield: f64,
-    location: String,
+    args: SubscriptionArgs,
+    location: Location,
     chat_id: ChatId,
     tg: &Bot,
-    config: Arc<Config>,
+    repo: &SharedRepository,
 ) -> Result<()> {
+    let SubscriptionArgs {
+        target_yield: Some(target_yield),
+        min_size: Some(min_size),
+        max_size: Some(max_size),
+        min_price_drop,
+        criteria,
+        financing,
+        ..
+    } = args
+    else {
+        tg.send_message(
+            chat_id,
+            "Please provide the arguments needed. Check /help for guidance.",
+        )
+        .await?;
+        return Ok(());
+    };
     match watchlists::subscribe(
-        config.clone(),
+        repo,
         chat_id.0,
-        location.clone(),
-        size,
-        new_target_yield,
+        location,
+        (f64::from(min_size), f64::from(max_size)),
+        target_yield,
+        min_price_drop.map(f64::from),
+        criteria,
+        financing,
     )
     .await
     {
         Ok(watchlist) => {
+            info!(watchlist_id = watchlist.id, "Subscribed to watchlist");
             tg.send_message(
                 chat_id,
                 format!(
                     "Added watchlist {} ({}) with target yield {:.2}%",
-                    watchlist.id, watchlist.location_name, new_target_yield
+                    watchlist.id,
+                    watchlist.locations.names(),
+                    target_yield
                 ),
             )
             .await?;
         }
         Err(err) => {
-            error!("Error while subscribing: {}", err);
-            tg.send_message(
-                chat_id,
-                "Could not subscribe. Please check the details and try again.",
-            )
-            .await?;
+            error!("Error while subscribing: {:#}", err);
+            let reply = match err.downcast_ref::<ApatoError>() {
+                Some(ApatoError::UserFacing(message) | ApatoError::NotFound(message)) => {
+                    message.clone()
+                }
+                _ => "Could not subscribe. Please check the details and try again.".to_string(),
+            };
+            tg.send_message(chat_id, reply).await?;
         }
     }
 
@@ -1,5 +1,8 @@
+use crate::{
+    db::migrations::MigrationMode,
+    logger::{LogFormat, LogRotation},
+};
 use dotenvy::dotenv;
-use log::error;
 use serde::Deserialize;
 use std::env;
 
@@ -8,10 +11,17 @@ const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
 #[derive(Deserialize, Debug, Default, Clone)]
 pub struct Config {
     pub db_path: String,
+    pub db_pool_size: Option<u32>,
+    #[serde(default)]
+    pub migration_mode: MigrationMode,
     pub telegram_bot_token: String,
     pub consumer_timeout_seconds: u32,
     pub producer_timeout_seconds: u32,
     pub consumer_thread_limit: u32,
+    /// Amount of consumers taking jobs from the queue.
+    pub consumer_count: Option<u32>,
+    /// How long to wait for consumers to finish their current jobs on shutdown.
+    pub shutdown_timeout_seconds: Option<u32>,
     pub loan_duration_years: u32,
     pub down_payment_percentage: u32,
     pub avg_vacant_month_per_year: u32,
@@ -20,16 +30,35 @@ pub struct Config {
     pub avg_renovation_costs: u32,
     pub tax: u32,
     pub ml_service_url: Option<String>,
+    pub ml_service_token: Option<String>,
     pub http_bind_address: Option<String>,
+    /// Origins allowed to call the HTTP API from a browser. "*" allows any origin.
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Signs the /login links and sessions of the web console. /login is disabled if unset.
+    pub session_secret: Option<String>,
+    /// Address of the web console, which /login links point to.
+    pub web_console_url: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Level filter directives, e.g. "info,apato=debug". `RUST_LOG` overrides it.
+    pub log_level: Option<String>,
+    /// Directory of the log files. Logs only go to stdout if unset.
+    pub log_dir: Option<String>,
+    #[serde(default)]
+    pub log_rotation: LogRotation,
 }
 
 pub fn create_test_config() -> Config {
     Config {
         db_path: "xxx".to_string(),
+        db_pool_size: None,
+        migration_mode: MigrationMode::Check,
         telegram_bot_token: "xxx".to_string(),
         consumer_timeout_seconds: 60,
         producer_timeout_seconds: 60,
         consumer_thread_limit: 10,
+        consumer_count: None,
+        shutdown_timeout_seconds: None,
         loan_duration_years: 25,
         down_payment_percentage: 20,
         avg_vacant_month_per_year: 1,
@@ -38,7 +67,15 @@ pub fn create_test_config() -> Config {
         avg_renovation_costs: 5000,
         tax: 30,
         ml_service_url: None,
+        ml_service_token: None,
         http_bind_address: None,
+        cors_allowed_origins: None,
+        session_secret: None,
+        web_console_url: None,
+        log_format: LogFormat::Pretty,
+        log_level: None,
+        log_dir: None,
+        log_rotation: LogRotation::Daily,
     }
 }
 
@@ -49,7 +86,8 @@ pub fn read_config() -> Config {
         .and_then(|config_path| std::fs::read(config_path).map_err(|e| e.to_string()))
         .and_then(|bytes| toml::from_slice(&bytes).map_err(|e| e.to_string()))
         .unwrap_or_else(|err| {
-            error!("failed to read config: {err}");
+            // The logger is configured from the config, so it is not available yet.
+            eprintln!("failed to read config: {err}");
             std::process::exit(1);
         })
 }
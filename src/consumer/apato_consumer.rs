@@ -1,292 +1,1227 @@
-use anyhow::Result;
-use async_channel::Receiver;
+use anyhow::{anyhow, Result};
 
-use futures::future::TryJoinAll;
-use log::{error, info};
 use std::{
+    collections::HashMap,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     time::Instant,
 };
-use teloxide::{requests::Requester, types::ChatId, Bot};
-use tokio::sync::{broadcast, Semaphore};
+use teloxide::{payloads::SendMessageSetters, requests::Requester, types::ChatId, Bot};
+use tokio::sync::{broadcast, Notify, Semaphore};
+use tracing::{debug, error, info, instrument, warn, Instrument};
 
 use crate::{
-    bot::bot::format_apartment_message,
+    bot::{
+        apartment_actions::apartment_keyboard,
+        bot::{format_apartment_message, format_price_drop_message},
+    },
     config::Config,
-    db,
+    db::repository::SharedRepository,
+    errors::{chat_unreachable, ApatoError},
+    health::Health,
+    metrics,
     models::{
         apartment::{Apartment, InsertableApartment},
-        watchlist::{SizeTarget, Watchlist},
+        job::Job,
+        watchlist::{Financing, Locations, SearchFilter, Watchlist},
     },
-    oikotie::oikotie::Oikotie,
-    MessageTask, TaskType,
+    oikotie::oikotie::{listing_not_found, Location, Oikotie},
+    services::apartments,
+    MessageTask,
 };
 
 use super::calculations::get_estimated_irr;
 
+/// Amount of consumers started when `consumer_count` is not configured.
+pub const DEFAULT_CONSUMER_COUNT: u32 = 6;
+
 pub struct Consumer;
 
 impl Consumer {
+    /// Claims and runs jobs until shutdown.
+    ///
+    /// Jobs are taken back to back while the queue has due jobs. Once it is empty the
+    /// consumer waits until `jobs_ready` is notified, or at most `consumer_timeout_seconds`
+    /// for retried jobs to become due. On shutdown the current job is finished first.
+    #[allow(clippy::too_many_arguments)]
     pub async fn run(
         config: &Arc<Config>,
-        consumer_reciever: Receiver<MessageTask>,
+        repo: SharedRepository,
         shutdown: Arc<AtomicBool>,
         mut shutdown_rx: broadcast::Receiver<()>,
+        jobs_ready: Arc<Notify>,
         bot: Arc<Bot>,
         consumer_number: i32,
+        health: Arc<Health>,
     ) -> Result<()> {
         let interval_in_seconds = config.consumer_timeout_seconds.into();
         let interval = std::time::Duration::from_secs(interval_in_seconds);
 
-        while !shutdown.load(Ordering::Acquire) {
-            let queue_message = consumer_reciever.try_recv();
-
-            if let Ok(task) = queue_message {
-                let start = Instant::now();
-                info!("Starting run on Consumer {}", consumer_number);
-                let bot = bot.clone();
-                // TODO Handle errors from both
-                match task.task_type {
-                    TaskType::UpdateWatchlist => {
-                        let result =
-                            update_watchlist_task(config, task.watchlist, consumer_number).await;
-
-                        match result {
-                            Ok(ok) => ok,
-                            Err(e) => error!("Error in Consumer {}: {:?}", consumer_number, e),
-                        }
-                    }
-                    TaskType::SendMessage => {
-                        send_message_task(config, task.watchlist, task.apartment.unwrap(), bot)
-                            .await?
+        loop {
+            // Registered before claiming, so jobs queued in between are not missed
+            let notified = jobs_ready.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            health.consumer_heartbeat(consumer_number);
+
+            if shutdown.load(Ordering::Acquire) {
+                break;
+            }
+
+            match repo.claim_job().await {
+                Ok(Some(job)) => {
+                    if let Err(e) = run_job(config, &repo, &job, bot.clone(), consumer_number).await
+                    {
+                        error!(
+                            consumer_number,
+                            job_id = job.id,
+                            "Error while finishing job: {:?}",
+                            e
+                        );
                     }
+                    continue;
                 }
-                let duration = start.elapsed();
-                info!(
-                    "Finished run in {:?} seconds on consumer {}",
-                    duration, consumer_number
-                );
+                Ok(None) => {}
+                Err(e) => error!(consumer_number, "Error while claiming job: {:?}", e),
             }
 
             tokio::select! {
+               _ = &mut notified => {}
                _ = tokio::time::sleep(interval) => {}
                _ = shutdown_rx.recv() => {
                    break
                }
             }
         }
+
+        health.consumer_stopped(consumer_number);
+        info!(consumer_number, "Consumer stopped");
         Ok(())
     }
 }
 
-async fn send_message_task(
+/// Runs the job's task, then removes the job from the queue. Failed jobs are retried later
+/// unless their error is permanent.
+#[instrument(
+    name = "job",
+    skip_all,
+    fields(consumer_number = consumer_number, job_id = job.id, task_type = %job.job_type)
+)]
+async fn run_job(
+    config: &Arc<Config>,
+    repo: &SharedRepository,
+    job: &Job,
+    bot: Arc<Bot>,
+    consumer_number: i32,
+) -> Result<()> {
+    let start = Instant::now();
+    info!(attempt = job.attempts + 1, "Starting job");
+    let timer = metrics::TASK_DURATION
+        .with_label_values(&[&job.job_type])
+        .start_timer();
+    let result = match job.task() {
+        Ok(task) => run_task(config, repo, task, bot).await,
+        // Retrying a malformed job cannot help
+        Err(e) => Err(ApatoError::Permanent(e).into()),
+    };
+    timer.observe_duration();
+
+    match result {
+        Ok(()) => {
+            info!(elapsed = ?start.elapsed(), "Finished job");
+            metrics::TASKS_PROCESSED
+                .with_label_values(&[&job.job_type])
+                .inc();
+            repo.complete_job(job.id).await
+        }
+        Err(e) => {
+            let e = ApatoError::classify(e);
+            metrics::TASKS_FAILED
+                .with_label_values(&[&job.job_type, e.kind()])
+                .inc();
+            error!(
+                kind = e.kind(),
+                elapsed = ?start.elapsed(),
+                "Job failed: {}",
+                e
+            );
+            repo.fail_job(job, &e.to_string(), e.is_retryable()).await?;
+            Ok(())
+        }
+    }
+}
+
+async fn run_task(
     config: &Arc<Config>,
+    repo: &SharedRepository,
+    task: MessageTask,
+    bot: Arc<Bot>,
+) -> Result<()> {
+    match task {
+        MessageTask::UpdateLocation { locations } => {
+            // Watchlists may have been deleted, paused or moved to other locations after
+            // the task was queued
+            let mut watchlists = repo.get_watchlists_for_locations(&locations).await?;
+            watchlists.retain(|w| !w.is_paused());
+            if watchlists.is_empty() {
+                info!(
+                    ?locations,
+                    "No watchlists left for locations, dropping task"
+                );
+                return Ok(());
+            }
+
+            update_location_task(config, repo, &locations, &watchlists).await?;
+            for watchlist in &watchlists {
+                repo.mark_watchlist_refreshed(watchlist.id).await?;
+            }
+            Ok(())
+        }
+        MessageTask::SendMessage {
+            watchlist_id,
+            card_id,
+        } => {
+            let Some(watchlist) = repo.get_watchlist(watchlist_id).await? else {
+                info!(watchlist_id, "Watchlist no longer exists, dropping task");
+                return Ok(());
+            };
+            send_message_task(repo, watchlist, card_id, bot).await
+        }
+    }
+}
+
+#[instrument(
+    skip_all,
+    fields(watchlist_id = watchlist.id, chat_id = watchlist.chat_id, card_id)
+)]
+async fn send_message_task(
+    repo: &SharedRepository,
     watchlist: Watchlist,
-    apartment: Apartment,
+    card_id: i32,
     bot: Arc<Bot>,
 ) -> Result<()> {
     let chat_id = watchlist.chat_id;
-    let apatment_result = db::apartment::get_apartment_by_card_id(config, apartment.card_id);
-    match apatment_result {
-        Ok(a) => {
-            if let Some(ap) = a {
-                let formatted = format_apartment_message(&watchlist, &ap);
-                bot.send_message(ChatId(chat_id), formatted).await?;
-
-                db::apartment_watchlist::set_to_read(config, &watchlist, apartment.card_id);
+
+    if let Some(mut ap) = repo.get_apartment(card_id).await? {
+        if apartments::is_dismissed(repo, chat_id, card_id).await? {
+            debug!("Apartment dismissed by the chat, not sending");
+            repo.set_match_sent(watchlist.id, card_id, ap.price).await?;
+            return Ok(());
+        }
+
+        // A watchlist with its own financing announces the yield of that financing
+        let own_yield = repo
+            .get_match(watchlist.id, card_id)
+            .await?
+            .and_then(|index| index.estimated_yield);
+        if own_yield.is_some() {
+            ap.estimated_yield = own_yield;
+        }
+
+        let (kind, formatted) = match previous_price(repo, watchlist.id, &ap).await? {
+            Some(previous) => (
+                "price_drop",
+                format_price_drop_message(&watchlist, &ap, previous),
+            ),
+            None => ("new", format_apartment_message(&watchlist, &ap)),
+        };
+        let keyboard = apartment_keyboard(watchlist.id, card_id);
+        if let Err(e) = bot
+            .send_message(ChatId(chat_id), formatted)
+            .reply_markup(keyboard)
+            .await
+        {
+            if chat_unreachable(&e) {
+                let paused = repo.pause_watchlists_for_chat(chat_id).await?;
+                warn!(paused, "Paused watchlists of unreachable chat: {}", e);
             }
+            return Err(ApatoError::from(e).into());
         }
-        Err(e) => return Err(e.into()),
+        metrics::NOTIFICATIONS_SENT.with_label_values(&[kind]).inc();
+        info!(kind, "Sent apartment to chat");
+
+        repo.set_match_sent(watchlist.id, card_id, ap.price).await?;
     }
 
     Ok(())
 }
 
-/// Updates the given watchist.
-///
-/// 1) Fetches apartments from Oikotie
-/// 2)
+/// The higher price the apartment had before, if it is being sent because of a price drop.
 ///
-/// # Examples
+/// Uses the price it was last sent with, or for apartments never sent to the watchlist the
+/// highest price seen, e.g. when a drop pushed it over the target yield.
+async fn previous_price(
+    repo: &SharedRepository,
+    watchlist_id: i32,
+    apartment: &Apartment,
+) -> Result<Option<i32>> {
+    let Some(current) = apartment.price else {
+        return Ok(None);
+    };
+
+    let sent_price = repo
+        .get_match(watchlist_id, apartment.card_id)
+        .await?
+        .and_then(|index| index.sent_price);
+    let previous = match sent_price {
+        Some(sent) => Some(sent),
+        None => repo
+            .get_price_history(apartment.card_id)
+            .await?
+            .iter()
+            .filter_map(|entry| entry.price)
+            .max(),
+    };
+
+    Ok(previous.filter(|&previous| previous > current))
+}
+
+/// Updates all watchlists searching the same locations.
 ///
-/// ```ignore
-/// // You can have rust code between fences inside the comments
-/// // If you pass --test to `rustdoc`, it will even test it for you!
-/// use doc::Person;
-/// let person = Person::new("name");
-/// ```
-async fn update_watchlist_task(
+/// The locations are fetched from Oikotie with one search, using a filter covering every
+/// watchlist, and every apartment is scored once, then matched to each watchlist whose
+/// criteria it meets. Load on Oikotie scales with the amount of location sets watched,
+/// not the amount of watchlists.
+#[instrument(
+    skip_all,
+    fields(location_id = tracing::field::Empty, location = tracing::field::Empty)
+)]
+async fn update_location_task(
     config: &Arc<Config>,
-    watchlist: Watchlist,
-    consumer_number: i32,
+    repo: &SharedRepository,
+    locations: &[Location],
+    watchlists: &[Watchlist],
 ) -> Result<()> {
+    let first = locations
+        .first()
+        .ok_or_else(|| anyhow!("Location update without locations"))?;
+    let span = tracing::Span::current();
+    span.record("location_id", first.id);
+    span.record("location", Locations(locations.to_vec()).names().as_str());
     info!(
-        "Starting watchlist task run for watchlist_id: {:?} on consumer {}",
-        watchlist.id, consumer_number
+        watchlist_ids = ?watchlists.iter().map(|w| w.id).collect::<Vec<_>>(),
+        "Starting location update"
     );
 
     let mut oikotie_client = Oikotie::new().await;
 
-    let target_size = get_target_size(watchlist.target_size_min, watchlist.target_size_max);
+    let filter = SearchFilter::covering(watchlists);
 
-    // TODO make this faster ?
-    let apartments: Vec<InsertableApartment> = match oikotie_client
-        .get_apartments(config.clone(), &watchlist, target_size)
-        .await
-    {
-        Ok(aps) => aps,
-        Err(e) => return Err(e),
-    };
+    // Details of listings stored before are reused unless the listing changed
+    let stored: HashMap<i32, Apartment> = stored_in_locations(repo, watchlists, true)
+        .await?
+        .into_iter()
+        .map(|apartment| (apartment.card_id, apartment))
+        .collect();
+
+    let search = oikotie_client
+        .get_apartments(locations, &filter, &stored)
+        .await?;
+    let mut apartments: Vec<InsertableApartment> = search.apartments;
+
+    // Listings that disappeared from complete search results have been sold or removed.
+    // A search bounding prices or build years also leaves out listings whose price rose,
+    // so their cards are fetched again and they are processed like the found ones.
+    if search.complete {
+        let missing = missing_listings(repo, watchlists, &search.seen_card_ids).await?;
+        if filter.can_miss_listed() {
+            apartments.extend(refetch_missing(repo, &mut oikotie_client, &missing).await?);
+        } else {
+            let card_ids: Vec<i32> = missing.iter().map(|a| a.card_id).collect();
+            mark_removed(repo, &card_ids).await?;
+        }
+    }
 
     // Cap the amount of apartments processed at the same time
     let sem = Arc::new(Semaphore::new(
         usize::try_from(config.consumer_thread_limit).unwrap(),
     ));
+    let watchlists: Arc<[Watchlist]> = watchlists.into();
 
     let mut apartment_handles = Vec::new();
     for apartment in apartments {
+        let card_id = apartment.card_id;
         let permit = Arc::clone(&sem).acquire_owned().await;
 
         let oiko_clone = oikotie_client.clone();
-        let watchlist_clone = watchlist.clone();
+        let watchlists_clone = watchlists.clone();
         let config_clone = config.clone();
+        let repo_clone = repo.clone();
 
-        let handle = tokio::task::spawn(async move {
-            let _permit = permit;
-            match process_apartment(
-                &config_clone,
-                oiko_clone,
-                apartment,
-                watchlist_clone,
-                consumer_number,
-            )
-            .await
-            {
-                Ok(()) => Ok(()),
-                Err(e) => Err(e),
+        let handle = tokio::task::spawn(
+            async move {
+                let _permit = permit;
+                process_apartment(
+                    &config_clone,
+                    &repo_clone,
+                    oiko_clone,
+                    apartment,
+                    &watchlists_clone,
+                )
+                .await
             }
-        });
+            .in_current_span(),
+        );
 
-        apartment_handles.push(handle);
+        apartment_handles.push((card_id, handle));
     }
 
-    let join_apartment_handles = apartment_handles.into_iter().collect::<TryJoinAll<_>>();
-
-    if let Err(err) = tokio::try_join!(join_apartment_handles) {
-        return Err(err.into());
+    // A failing apartment does not fail the others. It is not stored, so it is processed
+    // again on the next refresh.
+    let mut failed = 0;
+    for (card_id, handle) in apartment_handles {
+        let result = match handle.await {
+            Ok(result) => result,
+            Err(join_error) => Err(join_error.into()),
+        };
+        if let Err(e) = result {
+            failed += 1;
+            error!(card_id, "Failed to process apartment: {:#}", e);
+        }
     }
 
-    info!(
-        "Finished watchlist task for watchlist_id: {:?} on consumer {}",
-        watchlist.id, consumer_number
-    );
+    info!(failed, "Finished location update");
 
     Ok(())
 }
 
 /// Process one apartment.
 ///
+/// Closed listings are marked removed and not processed further.
+///
 /// Checks if apartment already exists in database
-///     If yes:
-///         Is it fresh?
-///             If not:
-///                 Recalculate Yield
-///         Has it already been added to the target index?
-///             If not:
-///                 Add
-///     If not:
-///         Calculate yield and add to target index
+///     If yes, it is active and fresh and its price, maintenance fee and status are unchanged:
+///         Match it to the watchlists it meets
+///     Otherwise:
+///         Calculate rent and yield once with the rentals of the location the apartment
+///         is in, then upsert the apartment and its matches
+///         to the watchlists it meets in one transaction.
+///         Listing changes end up in the price history.
+///
+/// Watchlists with their own financing are matched by the yield of their financing, which
+/// is stored on their matches.
+#[instrument(name = "apartment", skip_all, fields(card_id = apartment.card_id))]
 async fn process_apartment(
     config: &Arc<Config>,
+    repo: &SharedRepository,
     mut oikotie: Oikotie,
     mut apartment: InsertableApartment,
-    watchlist: Watchlist,
-    consumer_number: i32,
+    watchlists: &[Watchlist],
 ) -> Result<()> {
+    let card_id = apartment.card_id;
+
     // Check if apartment already exists in db
-    let apartment_from_db_res = db::apartment::get_apartment_by_card_id(config, apartment.card_id);
+    let apartment_from_db = repo.get_apartment(card_id).await?;
+
+    if apartment.is_closed() {
+        if apartment_from_db.is_some() {
+            repo.mark_apartments_removed(&[card_id]).await?;
+        }
+        return Ok(());
+    }
+
+    if let Some(existing_apartment) = apartment_from_db {
+        // A listing found in overlapping locations, e.g. a postcode and its city, keeps its
+        // stored location until it is scored again
+        if existing_apartment.is_active()
+            && !existing_apartment.listing_changed(&apartment)
+            && repo.apartment_is_fresh(card_id).await?
+        {
+            let estimated_yield = existing_apartment.estimated_yield.unwrap_or_default();
+            let own_yields = own_financing_yields(
+                config,
+                watchlists,
+                &InsertableApartment::from(&existing_apartment),
+                estimated_yield,
+            )
+            .await?;
+            let matched_watchlists =
+                matching_watchlists(watchlists, &apartment, estimated_yield, &own_yields);
+            debug!(
+                ?matched_watchlists,
+                "Apartment unchanged, matching watchlists"
+            );
+            for watchlist_id in matched_watchlists {
+                repo.insert_match(watchlist_id, card_id).await?;
+            }
+            repo.set_match_yields(card_id, &own_yields).await?;
+            return Ok(());
+        }
+    }
 
-    let apartment_from_db = match apartment_from_db_res {
-        Ok(aps) => aps,
-        Err(e) => return Err(e.into()),
+    let irr = score_apartment(config, &mut oikotie, &mut apartment).await?;
+
+    let own_yields = own_financing_yields(config, watchlists, &apartment, irr).await?;
+    let matched_watchlists = matching_watchlists(watchlists, &apartment, irr, &own_yields);
+    debug!(irr, ?own_yields, ?matched_watchlists, "Scored apartment");
+    repo.upsert_apartment_with_yields(apartment, &matched_watchlists, &own_yields)
+        .await?;
+
+    Ok(())
+}
+
+/// Estimates the rent and yield of the apartment and sets them on it. Returns the yield.
+pub async fn score_apartment(
+    config: &Arc<Config>,
+    oikotie: &mut Oikotie,
+    apartment: &mut InsertableApartment,
+) -> Result<f64> {
+    let estimated_rent = oikotie.get_estimated_rent(config, apartment).await?;
+    apartment.rent = Some(estimated_rent.rent);
+    apartment.rent_lower = estimated_rent.lower;
+    apartment.rent_upper = estimated_rent.upper;
+    debug!(
+        rent = estimated_rent.rent,
+        lower = ?estimated_rent.lower,
+        upper = ?estimated_rent.upper,
+        "Estimated rent"
+    );
+
+    let irr = match get_estimated_irr(config, apartment.clone()).await {
+        Ok(irr) => irr,
+        Err(e) => {
+            metrics::IRR_FAILURES.inc();
+            error!("Failed to calculate IRR: {}", e);
+            return Err(e);
+        }
     };
+    apartment.estimated_yield = Some(irr);
+
+    Ok(irr)
+}
 
-    if let Some(mut existing_apartment) = apartment_from_db {
-        // Check if the entry is fresh
-        let is_fresh = match db::apartment::apartment_is_fresh(config, apartment.card_id) {
-            Ok(b) => b,
-            Err(e) => return Err(e.into()),
+/// Yields of the scored apartment with the financing of each watchlist that has its own
+/// and that the apartment otherwise meets, as watchlist ids and yields.
+///
+/// Each financing is calculated once. `estimated_yield` is the yield with the default
+/// financing, reused for financing that ends up the same.
+pub(crate) async fn own_financing_yields(
+    config: &Arc<Config>,
+    watchlists: &[Watchlist],
+    apartment: &InsertableApartment,
+    estimated_yield: f64,
+) -> Result<Vec<(i32, f64)>> {
+    let mut yields_by_terms =
+        HashMap::from([(Financing::default().terms(config), estimated_yield)]);
+    let mut own_yields = Vec::new();
+    for watchlist in watchlists.iter().filter(|w| {
+        !w.financing.is_default() && w.size_matches(apartment.size) && w.criteria.matches(apartment)
+    }) {
+        let terms = watchlist.financing.terms(config);
+        let own_yield = match yields_by_terms.get(&terms) {
+            Some(&own_yield) => own_yield,
+            None => {
+                let financed = Arc::new(watchlist.financing.apply_to(config));
+                let own_yield = get_estimated_irr(&financed, apartment.clone())
+                    .await
+                    .inspect_err(|_| metrics::IRR_FAILURES.inc())?;
+                yields_by_terms.insert(terms, own_yield);
+                own_yield
+            }
         };
+        own_yields.push((watchlist.id, own_yield));
+    }
+    Ok(own_yields)
+}
 
-        if !is_fresh {
-            let estimated_rent = oikotie.get_estimated_rent(config, &apartment).await?;
-            apartment.rent = Some(estimated_rent);
-            let new_irr = match get_estimated_irr(config, apartment.clone()).await {
-                Ok(irr) => irr,
-                Err(e) => return Err(e),
-            };
+/// Ids of the watchlists the apartment with the given estimated yield matches. Watchlists
+/// in `own_yields` are matched by their own yield instead.
+pub(crate) fn matching_watchlists(
+    watchlists: &[Watchlist],
+    apartment: &InsertableApartment,
+    estimated_yield: f64,
+    own_yields: &[(i32, f64)],
+) -> Vec<i32> {
+    watchlists
+        .iter()
+        .filter(|w| {
+            let estimated_yield = own_yields
+                .iter()
+                .find(|(id, _)| *id == w.id)
+                .map_or(estimated_yield, |&(_, own_yield)| own_yield);
+            w.matches(apartment, estimated_yield)
+        })
+        .map(|w| w.id)
+        .collect()
+}
 
-            db::apartment::update_yield(config, apartment.card_id, new_irr);
-            existing_apartment.estimated_yield = Some(new_irr);
+/// Apartments stored in the locations, which all the watchlists search.
+async fn stored_in_locations(
+    repo: &SharedRepository,
+    watchlists: &[Watchlist],
+    include_removed: bool,
+) -> Result<Vec<Apartment>> {
+    match watchlists.first() {
+        Some(first) => {
+            repo.get_apartments_for_watchlist(first, include_removed)
+                .await
         }
+        None => Ok(Vec::new()),
+    }
+}
 
-        // Check if this aparment existst in target apartments
-        let index_exists =
-            match db::apartment_watchlist::exists(config, watchlist.id, apartment.card_id) {
-                Ok(exists) => exists,
-                Err(e) => return Err(e.into()),
-            };
+/// Active apartments of the watchlists' locations that the search would have found but are
+/// no longer in its results.
+async fn missing_listings(
+    repo: &SharedRepository,
+    watchlists: &[Watchlist],
+    seen_card_ids: &[i32],
+) -> Result<Vec<Apartment>> {
+    let filter = SearchFilter::covering(watchlists);
 
-        if !index_exists {
-            // Add to watchlist index if over target yield
-            if existing_apartment.estimated_yield.unwrap_or_default()
-                > watchlist.target_yield.unwrap_or_default()
-            {
-                db::apartment_watchlist::insert(config, watchlist.id, apartment.card_id);
-            }
+    let mut missing = stored_in_locations(repo, watchlists, false).await?;
+    missing.retain(|a| filter.contains(a) && !seen_card_ids.contains(&a.card_id));
+    Ok(missing)
+}
+
+/// Fetches the listings of the missing apartments again. Listings Oikotie no longer has are
+/// marked removed, the others are returned to be processed.
+async fn refetch_missing(
+    repo: &SharedRepository,
+    oikotie: &mut Oikotie,
+    missing: &[Apartment],
+) -> Result<Vec<InsertableApartment>> {
+    let mut refetched = Vec::new();
+    let mut gone = Vec::new();
+    for apartment in missing {
+        match oikotie.refresh_apartment(apartment).await {
+            Ok(listing) => refetched.push(listing),
+            Err(e) if listing_not_found(&e) => gone.push(apartment.card_id),
+            Err(e) => error!(
+                card_id = apartment.card_id,
+                "Failed to fetch missing apartment: {:#}", e
+            ),
         }
-    } else {
-        let estimated_rent = oikotie.get_estimated_rent(config, &apartment).await?;
+    }
+    mark_removed(repo, &gone).await?;
+    Ok(refetched)
+}
 
-        apartment.rent = Some(estimated_rent);
+async fn mark_removed(repo: &SharedRepository, card_ids: &[i32]) -> Result<()> {
+    if !card_ids.is_empty() {
+        let n = repo.mark_apartments_removed(card_ids).await?;
+        info!(n, "Marked missing apartments as removed");
+    }
+    Ok(())
+}
 
-        match get_estimated_irr(config, apartment.clone()).await {
-            Ok(irr) => {
-                apartment.estimated_yield = Some(irr);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::create_test_config,
+        db::memory::InMemoryRepository,
+        models::{apartment_state::ApartmentStatus, job::JobStatus, watchlist::SizeTarget},
+        oikotie::{oikotie::Location, oikotie_types::CardStatus},
+        test_support::{apartment, fake_telegram_replying},
+    };
 
-                // Insert into apartment table
-                db::apartment::insert(config, apartment.clone());
+    async fn setup(target_yield: f64) -> (SharedRepository, Watchlist) {
+        let (_, repo, watchlist) = setup_in_memory(target_yield).await;
+        (repo, watchlist)
+    }
 
-                // Add to watchlist index if over target yield
-                if apartment.estimated_yield.unwrap_or_default()
-                    > watchlist.target_yield.unwrap_or_default()
-                {
-                    db::apartment_watchlist::insert(config, watchlist.id, apartment.card_id);
-                }
-            }
-            Err(e) => {
-                error!(
-                    "Consumer Error: While processing calculations on consumer {}: {}",
-                    consumer_number, e
-                );
-                return Err(e);
-            }
+    async fn setup_in_memory(
+        target_yield: f64,
+    ) -> (Arc<InMemoryRepository>, SharedRepository, Watchlist) {
+        let memory = Arc::new(InMemoryRepository::new());
+        let repo: SharedRepository = memory.clone();
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
         };
+        let watchlist = repo
+            .insert_watchlist(location, 42, Some(target_yield), SizeTarget::empty())
+            .await
+            .unwrap();
+        (memory, repo, watchlist)
     }
 
-    Ok(())
-}
+    fn offline_oikotie() -> Oikotie {
+        Oikotie { tokens: None }
+    }
+
+    #[tokio::test]
+    async fn fresh_apartment_above_target_is_matched() {
+        let config = Arc::new(create_test_config());
+        let (repo, watchlist) = setup(5.0).await;
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            apartment(1, 0.0),
+            std::slice::from_ref(&watchlist),
+        )
+        .await
+        .unwrap();
+
+        assert!(repo.match_exists(watchlist.id, 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn fresh_apartment_below_target_is_not_matched() {
+        let config = Arc::new(create_test_config());
+        let (repo, watchlist) = setup(10.0).await;
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            apartment(1, 0.0),
+            std::slice::from_ref(&watchlist),
+        )
+        .await
+        .unwrap();
 
-fn get_target_size(min: Option<i32>, max: Option<i32>) -> SizeTarget {
-    let mut target_size = SizeTarget::empty();
-    if let Some(min_size) = min {
-        target_size.min = Some(min_size)
+        assert!(!repo.match_exists(watchlist.id, 1).await.unwrap());
     }
-    if let Some(max_size) = max {
-        target_size.max = Some(max_size)
+
+    #[tokio::test]
+    async fn existing_match_is_not_duplicated() {
+        let config = Arc::new(create_test_config());
+        let (repo, watchlist) = setup(5.0).await;
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+        repo.insert_match(watchlist.id, 1).await.unwrap();
+        repo.set_match_sent(watchlist.id, 1, Some(200000))
+            .await
+            .unwrap();
+
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            apartment(1, 0.0),
+            std::slice::from_ref(&watchlist),
+        )
+        .await
+        .unwrap();
+
+        let index = repo.get_match(watchlist.id, 1).await.unwrap().unwrap();
+        assert!(index.has_been_sent);
+        assert!(repo
+            .get_unsent_matches(watchlist.id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_processing_matches_once() {
+        let config = Arc::new(create_test_config());
+        let (repo, watchlist) = setup(5.0).await;
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        let runs = (0..4).map(|_| {
+            process_apartment(
+                &config,
+                &repo,
+                offline_oikotie(),
+                apartment(1, 0.0),
+                std::slice::from_ref(&watchlist),
+            )
+        });
+        for result in futures::future::join_all(runs).await {
+            result.unwrap();
+        }
+
+        assert_eq!(
+            repo.get_unsent_matches(watchlist.id).await.unwrap(),
+            vec![1]
+        );
+    }
+
+    #[tokio::test]
+    async fn apartment_is_matched_to_each_watchlist_it_meets() {
+        let config = Arc::new(create_test_config());
+        let (repo, low_target) = setup(5.0).await;
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let high_target = repo
+            .insert_watchlist(location.clone(), 43, Some(9.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        let too_small = repo
+            .insert_watchlist(
+                location.clone(),
+                44,
+                Some(5.0),
+                SizeTarget {
+                    min: Some(60),
+                    max: None,
+                },
+            )
+            .await
+            .unwrap();
+        let mut too_expensive = repo
+            .insert_watchlist(location.clone(), 45, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        too_expensive.criteria.max_price = Some(150000);
+        repo.update_watchlist_criteria(&too_expensive)
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        let watchlists = repo
+            .get_watchlists_for_locations(&[location])
+            .await
+            .unwrap();
+        assert_eq!(watchlists.len(), 4);
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            apartment(1, 0.0),
+            &watchlists,
+        )
+        .await
+        .unwrap();
+
+        assert!(repo.match_exists(low_target.id, 1).await.unwrap());
+        assert!(!repo.match_exists(high_target.id, 1).await.unwrap());
+        assert!(!repo.match_exists(too_small.id, 1).await.unwrap());
+        assert!(!repo.match_exists(too_expensive.id, 1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn own_financing_yield_is_stored_on_the_match() {
+        let config = Arc::new(create_test_config());
+        let (repo, mut watchlist) = setup(5.0).await;
+        // The same terms as the configured defaults reuse the stored yield
+        watchlist.financing = Financing {
+            down_payment_percent: Some(config.down_payment_percentage as i32),
+            ..Default::default()
+        };
+        repo.update_watchlist_criteria(&watchlist).await.unwrap();
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            apartment(1, 0.0),
+            std::slice::from_ref(&watchlist),
+        )
+        .await
+        .unwrap();
+
+        let index = repo.get_match(watchlist.id, 1).await.unwrap().unwrap();
+        assert_eq!(index.estimated_yield, Some(7.5));
+    }
+
+    #[tokio::test]
+    async fn own_financing_yield_decides_the_match() {
+        let (repo, default_financing) = setup(5.0).await;
+        let own_financing = repo
+            .insert_watchlist(
+                default_financing.locations[0].clone(),
+                43,
+                Some(5.0),
+                SizeTarget::empty(),
+            )
+            .await
+            .unwrap();
+        let watchlists = [default_financing.clone(), own_financing.clone()];
+
+        let matched = matching_watchlists(
+            &watchlists,
+            &apartment(1, 7.5),
+            7.5,
+            &[(own_financing.id, 4.0)],
+        );
+        assert_eq!(matched, vec![default_financing.id]);
+
+        let matched = matching_watchlists(
+            &watchlists,
+            &apartment(1, 4.5),
+            4.5,
+            &[(own_financing.id, 6.0)],
+        );
+        assert_eq!(matched, vec![own_financing.id]);
+    }
+
+    #[tokio::test]
+    async fn listings_outside_searched_sizes_are_not_missing() {
+        let (repo, _) = setup(5.0).await;
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let small = repo
+            .insert_watchlist(
+                location.clone(),
+                43,
+                Some(5.0),
+                SizeTarget {
+                    min: None,
+                    max: Some(40),
+                },
+            )
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        // A search for small apartments only does not see the 50 m² apartment
+        assert!(missing_listings(&repo, &[small], &[])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn listings_above_max_price_are_missing() {
+        let (repo, mut watchlist) = setup(5.0).await;
+        watchlist.criteria.max_price = Some(150000);
+        repo.update_watchlist_criteria(&watchlist).await.unwrap();
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        // The stored price may be outdated, so whether the listing is gone is checked from
+        // its card
+        let watchlists = [watchlist];
+        let missing = missing_listings(&repo, &watchlists, &[]).await.unwrap();
+        assert_eq!(missing.len(), 1);
+        assert!(SearchFilter::covering(&watchlists).can_miss_listed());
+    }
+
+    #[tokio::test]
+    async fn price_drop_announces_sent_match_again() {
+        let (repo, watchlist) = setup(5.0).await;
+        for card_id in [1, 2] {
+            repo.upsert_apartment(apartment(card_id, 7.5), &[watchlist.id])
+                .await
+                .unwrap();
+            repo.set_match_sent(watchlist.id, card_id, Some(200000))
+                .await
+                .unwrap();
+        }
+
+        // 10% drop on card 1, 1% drop on card 2 with the default 5% minimum
+        for (card_id, price) in [(1, 180000), (2, 198000)] {
+            let mut cut = apartment(card_id, 8.0);
+            cut.price = Some(price);
+            repo.upsert_apartment(cut, &[]).await.unwrap();
+        }
+
+        assert_eq!(
+            repo.get_unsent_matches(watchlist.id).await.unwrap(),
+            vec![1]
+        );
+        let ap = repo.get_apartment(1).await.unwrap().unwrap();
+        assert_eq!(
+            previous_price(&repo, watchlist.id, &ap).await.unwrap(),
+            Some(200000)
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_listing_is_marked_removed() {
+        let config = Arc::new(create_test_config());
+        let (repo, watchlist) = setup(5.0).await;
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+
+        let mut closed = apartment(1, 0.0);
+        closed.status = Some(CardStatus::ACTIVE + 1);
+        process_apartment(
+            &config,
+            &repo,
+            offline_oikotie(),
+            closed,
+            std::slice::from_ref(&watchlist),
+        )
+        .await
+        .unwrap();
+
+        assert!(!repo.get_apartment(1).await.unwrap().unwrap().is_active());
+        assert!(repo
+            .get_apartments_for_watchlist(&watchlist, false)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrected_listing_details_are_stored() {
+        let (repo, _) = setup(5.0).await;
+        let mut listed = apartment(1, 7.5);
+        listed.build_year = Some(1965);
+        repo.upsert_apartment(listed, &[]).await.unwrap();
+
+        let mut corrected = apartment(1, 7.5);
+        corrected.build_year = Some(1972);
+        corrected.size = Some(48.5);
+        repo.upsert_apartment(corrected, &[]).await.unwrap();
+
+        let stored = repo.get_apartment(1).await.unwrap().unwrap();
+        assert_eq!(stored.build_year, Some(1972));
+        assert_eq!(stored.size, Some(48.5));
+    }
+
+    #[tokio::test]
+    async fn listings_missing_from_search_are_marked_removed() {
+        let (repo, watchlist) = setup(5.0).await;
+        for card_id in [1, 2] {
+            repo.upsert_apartment(apartment(card_id, 7.5), &[])
+                .await
+                .unwrap();
+        }
+
+        let missing = missing_listings(&repo, &[watchlist], &[2]).await.unwrap();
+        let card_ids: Vec<i32> = missing.iter().map(|a| a.card_id).collect();
+        assert_eq!(card_ids, vec![1]);
+        mark_removed(&repo, &card_ids).await.unwrap();
+
+        assert!(!repo.get_apartment(1).await.unwrap().unwrap().is_active());
+        assert!(repo.get_apartment(2).await.unwrap().unwrap().is_active());
+
+        // Seen again, e.g. relisted: active again
+        repo.upsert_apartment(apartment(1, 7.5), &[]).await.unwrap();
+        assert!(repo.get_apartment(1).await.unwrap().unwrap().is_active());
+    }
+
+    #[tokio::test]
+    async fn listings_of_other_locations_are_not_missing() {
+        let (repo, watchlist) = setup(5.0).await;
+        let other = Location {
+            id: 2,
+            level: 5,
+            name: "00200".to_string(),
+        };
+        let mut elsewhere = apartment(1, 7.5);
+        elsewhere.set_location(&other);
+        repo.upsert_apartment(elsewhere, &[]).await.unwrap();
+
+        // The search of the watchlist's location does not see listings of other locations
+        assert!(missing_listings(&repo, &[watchlist], &[])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Bot whose requests fail to connect.
+    async fn unreachable_telegram() -> Bot {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        Bot::new("test-token").set_api_url(url.parse().unwrap())
+    }
+
+    async fn claim_message_job(repo: &SharedRepository, watchlist: &Watchlist) -> Job {
+        repo.upsert_apartment(apartment(1, 7.5), &[watchlist.id])
+            .await
+            .unwrap();
+        repo.enqueue_task(&MessageTask::send_message(watchlist.id, 1))
+            .await
+            .unwrap();
+        repo.claim_job().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried_later() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        let job = claim_message_job(&repo, &watchlist).await;
+
+        run_job(
+            &config,
+            &repo,
+            &job,
+            Arc::new(unreachable_telegram().await),
+            0,
+        )
+        .await
+        .unwrap();
+
+        // Backing off, so not claimable right away
+        assert!(repo.claim_job().await.unwrap().is_none());
+        let jobs = memory.jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].status, JobStatus::PENDING);
+        assert_eq!(jobs[0].attempts, 1);
+        assert!(jobs[0].last_error.is_some());
+        assert!(!repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_paused());
+    }
+
+    #[tokio::test]
+    async fn dismissed_apartment_is_not_sent() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        let job = claim_message_job(&repo, &watchlist).await;
+        repo.set_apartment_status(watchlist.chat_id, 1, ApartmentStatus::DISMISSED)
+            .await
+            .unwrap();
+
+        // Sending would fail, so the job only completes if nothing is sent
+        run_job(
+            &config,
+            &repo,
+            &job,
+            Arc::new(unreachable_telegram().await),
+            0,
+        )
+        .await
+        .unwrap();
+
+        assert!(memory.jobs().is_empty());
+        assert!(repo
+            .get_unsent_matches(watchlist.id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_job_is_dead_right_away() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        repo.enqueue_task(&MessageTask::send_message(watchlist.id, 1))
+            .await
+            .unwrap();
+        let mut job = repo.claim_job().await.unwrap().unwrap();
+        job.payload = serde_json::json!({ "type": "send_message" });
+
+        run_job(&config, &repo, &job, Arc::new(Bot::new("test-token")), 0)
+            .await
+            .unwrap();
+
+        let jobs = memory.jobs();
+        assert_eq!(jobs[0].status, JobStatus::DEAD);
+        assert_eq!(jobs[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn blocked_bot_pauses_chat_watchlists() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        let job = claim_message_job(&repo, &watchlist).await;
+        let bot = fake_telegram_replying(serde_json::json!({
+            "ok": false,
+            "error_code": 403,
+            "description": "Forbidden: bot was blocked by the user"
+        }))
+        .await;
+
+        run_job(&config, &repo, &job, Arc::new(bot), 0)
+            .await
+            .unwrap();
+
+        assert_eq!(memory.jobs()[0].status, JobStatus::DEAD);
+        let paused = repo.get_watchlist(watchlist.id).await.unwrap().unwrap();
+        assert!(paused.is_paused());
+        assert!(repo.get_due_watchlists().await.unwrap().is_empty());
+        // Still unsent, so it is announced once the chat is resumed
+        assert_eq!(
+            repo.get_unsent_matches(watchlist.id).await.unwrap(),
+            vec![1]
+        );
+
+        assert_eq!(
+            repo.resume_watchlists_for_chat(watchlist.chat_id)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_paused());
+    }
+
+    #[tokio::test]
+    async fn job_for_deleted_watchlist_is_dropped() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        repo.enqueue_task(&MessageTask::update_location(&watchlist.locations))
+            .await
+            .unwrap();
+        repo.delete_watchlist(watchlist.id).await.unwrap();
+        let job = repo.claim_job().await.unwrap().unwrap();
+
+        run_job(&config, &repo, &job, Arc::new(Bot::new("test-token")), 0)
+            .await
+            .unwrap();
+
+        assert!(memory.jobs().is_empty());
+    }
+
+    async fn wait_until_drained(memory: &InMemoryRepository) {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !memory.jobs().is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("queue was not drained");
+    }
+
+    #[tokio::test]
+    async fn consumer_drains_queue_without_sleeping_and_wakes_on_new_jobs() {
+        let config = Arc::new(create_test_config());
+        let (memory, repo, watchlist) = setup_in_memory(5.0).await;
+        // Messages for a deleted watchlist are dropped without contacting Telegram
+        repo.delete_watchlist(watchlist.id).await.unwrap();
+        for card_id in [1, 2, 3] {
+            repo.enqueue_task(&MessageTask::send_message(watchlist.id, card_id))
+                .await
+                .unwrap();
+        }
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let jobs_ready = Arc::new(Notify::new());
+        let handle = {
+            let (config, repo, shutdown, jobs_ready) = (
+                config.clone(),
+                repo.clone(),
+                shutdown.clone(),
+                jobs_ready.clone(),
+            );
+            tokio::spawn(async move {
+                Consumer::run(
+                    &config,
+                    repo,
+                    shutdown,
+                    shutdown_rx,
+                    jobs_ready,
+                    Arc::new(Bot::new("test-token")),
+                    0,
+                    Arc::new(Health::default()),
+                )
+                .await
+            })
+        };
+
+        // The test config waits 60 seconds between polls
+        wait_until_drained(&memory).await;
+
+        repo.enqueue_task(&MessageTask::send_message(watchlist.id, 4))
+            .await
+            .unwrap();
+        jobs_ready.notify_waiters();
+        wait_until_drained(&memory).await;
+
+        shutdown.store(true, Ordering::Release);
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), handle)
+            .await
+            .expect("consumer did not stop")
+            .unwrap()
+            .unwrap();
     }
-    target_size
 }
@@ -43,6 +43,45 @@ pub async fn get_estimated_irr(
     Ok(irr)
 }
 
+/// One year of the cash flow analysis behind the IRR. Costs and payments are negative.
+#[derive(Debug, Clone, PartialEq)]
+pub struct YearlyCashFlow {
+    pub year: u32,
+    /// Yearly rent, growing by the estimated rent increase.
+    pub rent: f64,
+    pub vacancy: f64,
+    /// Maintenance fees.
+    pub fixed_costs: f64,
+    pub taxes: f64,
+    pub interest: f64,
+    /// Loan principal paid back.
+    pub principal: f64,
+    /// Estimated increase in the apartment's value.
+    pub value_increase: f64,
+    /// Free cash flow to equity, including the value increase.
+    pub cash_flow: f64,
+}
+
+/// The down payment followed by the cash flows of each year of the loan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CashFlowAnalysis {
+    pub down_payment: f64,
+    pub years: Vec<YearlyCashFlow>,
+}
+
+/// Rent changes, in percent, and interest rate changes, in percentage points, tried by
+/// `sensitivity`.
+pub const SENSITIVITY_RENT_CHANGES: [f64; 3] = [-10.0, 0.0, 10.0];
+pub const SENSITIVITY_RATE_CHANGES: [f64; 3] = [-1.0, 0.0, 1.0];
+
+/// IRR with the rent and interest rate changed. `yields` has a row per rent change and a
+/// column per interest rate change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sensitivity {
+    pub interest_rate: f64,
+    pub yields: Vec<Vec<f64>>,
+}
+
 /// Calculates IRR.
 ///
 /// This function does a basic Discounted Cash Flow
@@ -55,12 +94,33 @@ pub fn calculate_irr(
     additional_cost: f64,
     interest_rate: f64,
 ) -> f64 {
+    let analysis = cash_flows(config, price, rent, additional_cost, interest_rate);
+
+    let mut yearly_cash_flows: Vec<f64> = vec![-analysis.down_payment];
+    yearly_cash_flows.extend(analysis.years.iter().map(|year| year.cash_flow));
+    let irr: f64 = irr(yearly_cash_flows).unwrap_or_default() * 100.0;
+
+    // Make sure the value is within reasonable limits
+    if !(-60.0..=60.0).contains(&irr) {
+        return 0.0;
+    }
+
+    irr
+}
+
+/// The Discounted Cash Flow analysis `calculate_irr` solves the IRR of.
+pub fn cash_flows(
+    config: &Arc<Config>,
+    price: f64,
+    rent: f64,
+    additional_cost: f64,
+    interest_rate: f64,
+) -> CashFlowAnalysis {
     let loan: f64 = price + config.avg_renovation_costs as f64;
     let down_payment_amount: f64 = (config.down_payment_percentage as f64 / 100.0) * loan;
     let initial_principal: f64 = loan - down_payment_amount;
 
-    let mut yearly_cash_flows: Vec<f64> = vec![];
-    yearly_cash_flows.push(-down_payment_amount);
+    let mut years: Vec<YearlyCashFlow> = vec![];
 
     // Calculate cash flows for each year
     for year in 1..(config.loan_duration_years + 1) {
@@ -91,16 +151,52 @@ pub fn calculate_irr(
 
         let fcfe = fcf + apartment_value_increase + (-principal_payment);
 
-        yearly_cash_flows.push(fcfe);
+        years.push(YearlyCashFlow {
+            year,
+            rent: income,
+            vacancy,
+            fixed_costs,
+            taxes,
+            interest: interest_payment,
+            principal: principal_payment,
+            value_increase: apartment_value_increase,
+            cash_flow: fcfe,
+        });
     }
-    let irr: f64 = irr(yearly_cash_flows).unwrap_or_default() * 100.0;
 
-    // Make sure the value is within reasonable limits
-    if !(-60.0..=60.0).contains(&irr) {
-        return 0.0;
+    CashFlowAnalysis {
+        down_payment: down_payment_amount,
+        years,
     }
+}
 
-    irr
+/// IRR for each combination of `SENSITIVITY_RENT_CHANGES` and `SENSITIVITY_RATE_CHANGES`.
+/// Interest rates do not go below zero.
+pub fn sensitivity(
+    config: &Arc<Config>,
+    price: f64,
+    rent: f64,
+    additional_cost: f64,
+    interest_rate: f64,
+) -> Sensitivity {
+    let yields = SENSITIVITY_RENT_CHANGES
+        .iter()
+        .map(|rent_change| {
+            let changed_rent = rent * (1.0 + rent_change / 100.0);
+            SENSITIVITY_RATE_CHANGES
+                .iter()
+                .map(|rate_change| {
+                    let changed_rate = (interest_rate + rate_change).max(0.0);
+                    calculate_irr(config, price, changed_rent, additional_cost, changed_rate)
+                })
+                .collect()
+        })
+        .collect();
+
+    Sensitivity {
+        interest_rate,
+        yields,
+    }
 }
 
 fn get_rent(config: &Arc<Config>, year: u32, rent: f64) -> f64 {
@@ -262,7 +358,7 @@ pub fn get_roots(coeffs: Vec<f64>) -> Vec<f64> {
 }
 
 // Generate the companion matrix of the polynomial given
-pub fn companion_matrix(polynomial: &Vec<f64>) -> DMatrix<f64> {
+pub fn companion_matrix(polynomial: &[f64]) -> DMatrix<f64> {
     let n = polynomial.len() - 1;
 
     match n.cmp(&1) {
@@ -306,10 +402,10 @@ pub fn reverse_matrix(matrix: &DMatrix<f64>) -> DMatrix<f64> {
 
 // TODO: Figure why inverse is needed
 // https://github.com/numpy/numpy/blob/1c8b03bf2c87f081eea211a5061e423285c548af/numpy/polynomial/polyutils.py#L286
-fn _map_domain(x: &Vec<f64>, _old: (f64, f64), _new: (f64, f64)) -> Vec<f64> {
+fn _map_domain(x: &[f64], _old: (f64, f64), _new: (f64, f64)) -> Vec<f64> {
     // let off = new.0 - ((new.1 - new.0) / (old.1 - old.0)) * old.0;
     // let scl = (new.1 - new.0) / (old.1 - old.0);
 
     // TODO: figure out why roots are inverse :D
-    return x.iter().map(|&val| 1.0 / val).collect();
+    x.iter().map(|&val| 1.0 / val).collect()
 }
@@ -1,157 +1,204 @@
-use std::sync::Arc;
-
 use super::{
-    establish_connection,
-    schema::{
-        apartment_watchlist,
-        apartments::{self, dsl::*},
-    },
-    watchlist::{check_chat, get_watchlist},
+    apartment_watchlist, price_history,
+    schema::{self, apartments},
 };
-use crate::{
-    config::Config,
-    models::{apartment::Apartment, apartment::InsertableApartment},
+use crate::models::{
+    apartment::{Apartment, InsertableApartment},
+    watchlist::Watchlist,
 };
-use anyhow::anyhow;
 use chrono::{Duration, NaiveDateTime, Utc};
-use diesel::{prelude::*, result::Error};
-use log::{error, info};
+use diesel::{dsl, pg::upsert::excluded, prelude::*, result::Error};
+use tracing::info;
+
+/// Apartments updated within this many days are not re-scored.
+pub const FRESHNESS_DAYS: i64 = 5;
+
+/// Inserts the apartment, or refreshes every listed field if the card is already stored.
+/// A removed apartment that is upserted again is active again.
+pub fn upsert(conn: &mut PgConnection, apartment: InsertableApartment) -> Result<Apartment, Error> {
+    let stored = diesel::insert_into(apartments::table)
+        .values(&apartment)
+        .on_conflict(apartments::card_id)
+        .do_update()
+        .set((
+            (
+                apartments::location_id.eq(excluded(apartments::location_id)),
+                apartments::location_level.eq(excluded(apartments::location_level)),
+                apartments::location_name.eq(excluded(apartments::location_name)),
+                apartments::size.eq(excluded(apartments::size)),
+                apartments::rooms.eq(excluded(apartments::rooms)),
+                apartments::build_year.eq(excluded(apartments::build_year)),
+                apartments::floor.eq(excluded(apartments::floor)),
+                apartments::building_type.eq(excluded(apartments::building_type)),
+                apartments::latitude.eq(excluded(apartments::latitude)),
+                apartments::longitude.eq(excluded(apartments::longitude)),
+                apartments::url.eq(excluded(apartments::url)),
+            ),
+            (
+                apartments::price.eq(excluded(apartments::price)),
+                apartments::additional_costs.eq(excluded(apartments::additional_costs)),
+                apartments::rent.eq(excluded(apartments::rent)),
+                apartments::rent_lower.eq(excluded(apartments::rent_lower)),
+                apartments::rent_upper.eq(excluded(apartments::rent_upper)),
+                apartments::estimated_yield.eq(excluded(apartments::estimated_yield)),
+                apartments::status.eq(excluded(apartments::status)),
+                apartments::debt_share.eq(excluded(apartments::debt_share)),
+                apartments::plot_ownership.eq(excluded(apartments::plot_ownership)),
+                apartments::removed_at.eq(None::<NaiveDateTime>),
+                apartments::updated_at.eq(dsl::now),
+            ),
+        ))
+        .returning(Apartment::as_returning())
+        .get_result(conn)?;
+
+    info!("Upserted apartment with card_id {:?}", stored.card_id);
+    Ok(stored)
+}
 
-pub fn insert(config: &Arc<Config>, apartment: InsertableApartment) {
-    let mut conn = establish_connection(config);
+/// Upserts the apartment and, if given, its watchlist matches and their yields with the
+/// watchlists' own financing in a single transaction.
+///
+/// A price history entry is written when the card is new or its price, maintenance fee
+/// or status changed. Sent matches are then re-announced if the price dropped enough.
+pub fn upsert_with_match(
+    conn: &mut PgConnection,
+    apartment: InsertableApartment,
+    matched_watchlists: &[i32],
+    match_yields: &[(i32, f64)],
+) -> Result<Apartment, Error> {
+    conn.transaction(|conn| {
+        let previous = apartments::table
+            .filter(apartments::card_id.eq(apartment.card_id))
+            .select(Apartment::as_select())
+            .for_update()
+            .first(conn)
+            .optional()?;
+        let listing_changed = previous
+            .as_ref()
+            .is_none_or(|previous| previous.listing_changed(&apartment));
+
+        let stored = upsert(conn, apartment)?;
+        for &target_watchlist_id in matched_watchlists {
+            apartment_watchlist::insert(conn, target_watchlist_id, stored.card_id)?;
+        }
+        apartment_watchlist::set_yields(conn, stored.card_id, match_yields)?;
+        if listing_changed {
+            price_history::record(conn, &stored)?;
+            apartment_watchlist::rearm_price_drops(conn, &stored)?;
+        }
+        Ok(stored)
+    })
+}
 
-    match diesel::insert_into(apartments::table)
-        .values(apartment)
-        .execute(&mut conn)
-    {
-        Ok(n) => info!("Inserted {:?} rows into apartments table", n),
-        Err(e) => error!("Error: {:?}", e),
-    }
+/// Marks the apartments as sold or removed, unless they already are.
+pub fn mark_removed(conn: &mut PgConnection, card_ids: &[i32]) -> Result<usize, Error> {
+    let n = diesel::update(
+        apartments::table
+            .filter(apartments::card_id.eq_any(card_ids))
+            .filter(apartments::removed_at.is_null()),
+    )
+    .set(apartments::removed_at.eq(dsl::now))
+    .execute(conn)?;
+
+    info!("Marked {} apartments as removed", n);
+    Ok(n)
 }
 
 pub fn get_all_for_watchlist(
-    config: &Arc<Config>,
-    chat_id: i64,
-    watchlist_id_: i32,
-) -> Result<Vec<Apartment>, anyhow::Error> {
-    let conn = &mut establish_connection(config);
-
-    if !check_chat(config, chat_id, watchlist_id_) {
-        return Err(anyhow!("Error: Wrong chat"));
-    }
-
-    let target_watchlist = get_watchlist(config, watchlist_id_)?;
-
-    let apartments_for_watchlist = apartments::table
-        .filter(apartments::location_id.eq(Some(target_watchlist.location_id)))
-        .filter(apartments::location_level.eq(Some(target_watchlist.location_level)))
+    conn: &mut PgConnection,
+    watchlist: &Watchlist,
+    include_removed: bool,
+) -> Result<Vec<Apartment>, Error> {
+    let location_ids: Vec<i32> = watchlist.locations.iter().map(|l| l.id).collect();
+    let mut query = apartments::table
+        .filter(apartments::location_id.eq_any(location_ids))
         .select(Apartment::as_select())
-        .load::<Apartment>(conn);
-
-    Ok(apartments_for_watchlist?)
+        .into_boxed();
+    if !include_removed {
+        query = query.filter(apartments::removed_at.is_null());
+    }
+    let found = query.load::<Apartment>(conn)?;
+
+    // Ids of different location levels may collide
+    Ok(found
+        .into_iter()
+        .filter(|a| {
+            a.location_id
+                .zip(a.location_level)
+                .is_some_and(|(id, level)| watchlist.locations.contains(id, level))
+        })
+        .collect())
 }
 
+/// Active matches of the watchlist above its target yield. Without a target yield every
+/// active match.
+///
+/// Matches of a watchlist with its own financing have the yield of that financing.
 pub fn get_matching_for_watchlist(
-    config: &Arc<Config>,
-    chat_id: i64,
-    watchlist_id_: i32,
-) -> Result<Vec<Apartment>, anyhow::Error> {
-    let conn = &mut establish_connection(config);
-
-    if !check_chat(config, chat_id, watchlist_id_) {
-        return Err(anyhow!("Error: Wrong chat"));
+    conn: &mut PgConnection,
+    watchlist: &Watchlist,
+) -> Result<Vec<Apartment>, Error> {
+    let match_yield = schema::apartment_watchlist::estimated_yield;
+    let mut query = schema::apartment_watchlist::table
+        .inner_join(
+            apartments::table.on(schema::apartment_watchlist::card_id.eq(apartments::card_id)),
+        )
+        .filter(schema::apartment_watchlist::watchlist_id.eq(watchlist.id))
+        .filter(apartments::removed_at.is_null())
+        .select((Apartment::as_select(), match_yield))
+        .into_boxed();
+    if let Some(target_yield_value) = watchlist.target_yield {
+        query = query.filter(
+            match_yield.gt(target_yield_value).or(match_yield
+                .is_null()
+                .and(apartments::estimated_yield.gt(target_yield_value))),
+        );
     }
-
-    let target_watchlist = match get_watchlist(config, watchlist_id_) {
-        Ok(w) => w,
-        Err(_e) => return Err(anyhow!("No watchlist wound with this name")),
-    };
-
-    let target_yield_value = target_watchlist
-        .target_yield
-        .ok_or_else(|| anyhow!("Watchlist does not have a target yield set"))?;
-
-    let matching_apartments = apartment_watchlist::table
-        .inner_join(apartments::table.on(apartment_watchlist::card_id.eq(apartments::card_id)))
-        .filter(apartment_watchlist::watchlist_id.eq(watchlist_id_))
-        .filter(apartments::estimated_yield.gt(target_yield_value))
-        .select(Apartment::as_select())
-        .load::<Apartment>(conn);
-
-    Ok(matching_apartments?)
+    Ok(query
+        .load::<(Apartment, Option<f64>)>(conn)?
+        .into_iter()
+        .map(|(mut apartment, match_yield)| {
+            if match_yield.is_some() {
+                apartment.estimated_yield = match_yield;
+            }
+            apartment
+        })
+        .collect())
 }
 
 pub fn _get_apartments_within_period(
-    config: &Arc<Config>,
+    conn: &mut PgConnection,
     wanted_card_id: i32,
     interval_start_time: NaiveDateTime,
-) -> Result<Vec<Apartment>, anyhow::Error> {
-    let conn = &mut establish_connection(config);
-
-    let valid_apartments: Result<Vec<Apartment>, Error> = apartments::table
+) -> Result<Vec<Apartment>, Error> {
+    apartments::table
         .filter(apartments::card_id.eq(wanted_card_id))
         .filter(apartments::created_at.gt(interval_start_time))
         .select(Apartment::as_select())
-        .load(conn);
-
-    Ok(valid_apartments?)
+        .load(conn)
 }
 
 pub fn get_apartment_by_card_id(
-    config: &Arc<Config>,
+    conn: &mut PgConnection,
     target_card_id: i32,
 ) -> Result<Option<Apartment>, Error> {
-    let conn = &mut establish_connection(config);
-
-    let valid_apartments: Result<Vec<Apartment>, Error> = apartments::table
+    apartments::table
         .filter(apartments::card_id.eq(target_card_id))
         .select(Apartment::as_select())
-        .limit(1)
-        .load(conn);
-
-    match valid_apartments {
-        Ok(aps) => {
-            if !aps.is_empty() {
-                let target_ap = aps[0].clone();
-                Ok(Some(target_ap))
-            } else {
-                Ok(None)
-            }
-        }
-        Err(e) => Err(e),
-    }
+        .first(conn)
+        .optional()
 }
 
-pub fn apartment_is_fresh(config: &Arc<Config>, target_card_id: i32) -> Result<bool, Error> {
-    let conn = &mut establish_connection(config);
+pub fn apartment_is_fresh(conn: &mut PgConnection, target_card_id: i32) -> Result<bool, Error> {
     let now = Utc::now().naive_local();
-    let freshness_cutoff = now - Duration::days(5);
+    let freshness_cutoff = now - Duration::days(FRESHNESS_DAYS);
 
-    let valid_apartments: Result<Vec<Apartment>, Error> = apartments::table
+    let valid_apartments: Vec<Apartment> = apartments::table
         .filter(apartments::card_id.eq(target_card_id))
         .filter(apartments::updated_at.gt(freshness_cutoff))
         .select(Apartment::as_select())
         .limit(1)
-        .load(conn);
-
-    match valid_apartments {
-        Ok(aps) => Ok(aps.len() == 1),
-        Err(e) => Err(e),
-    }
-}
-
-pub fn update_yield(config: &Arc<Config>, target_card_id: i32, new_yield: f64) {
-    let conn = &mut establish_connection(config);
-    let update_res = diesel::update(apartments)
-        .filter(apartments::card_id.eq(target_card_id))
-        .set(apartments::estimated_yield.eq(Some(new_yield)))
-        .execute(conn);
+        .load(conn)?;
 
-    match update_res {
-        Ok(_n) => info!(
-            "Consumer set apartment with card_id {:?} to yield = {}",
-            target_card_id, new_yield
-        ),
-        Err(e) => error!("Error: {:?}", e),
-    }
+    Ok(valid_apartments.len() == 1)
 }
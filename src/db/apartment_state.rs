@@ -0,0 +1,75 @@
+use diesel::{dsl, prelude::*, result::Error, upsert::excluded};
+
+use super::schema::user_apartment_state;
+use crate::models::apartment_state::{ApartmentState, ApartmentStatus, InsertableApartmentState};
+
+/// Sets the chat's status of the card, replacing the previous one. Notes are kept.
+pub fn set_status(
+    conn: &mut PgConnection,
+    chat_id: i64,
+    card_id: i32,
+    status: &str,
+) -> Result<ApartmentState, Error> {
+    diesel::insert_into(user_apartment_state::table)
+        .values(InsertableApartmentState {
+            chat_id,
+            card_id,
+            status: status.to_string(),
+            notes: None,
+        })
+        .on_conflict((user_apartment_state::chat_id, user_apartment_state::card_id))
+        .do_update()
+        .set((
+            user_apartment_state::status.eq(excluded(user_apartment_state::status)),
+            user_apartment_state::updated_at.eq(dsl::now),
+        ))
+        .returning(ApartmentState::as_returning())
+        .get_result(conn)
+}
+
+/// Sets the chat's notes of the card, replacing the previous ones. Cards without a status
+/// get `new`.
+pub fn set_notes(
+    conn: &mut PgConnection,
+    chat_id: i64,
+    card_id: i32,
+    notes: Option<&str>,
+) -> Result<ApartmentState, Error> {
+    diesel::insert_into(user_apartment_state::table)
+        .values(InsertableApartmentState {
+            chat_id,
+            card_id,
+            status: ApartmentStatus::NEW.to_string(),
+            notes: notes.map(str::to_string),
+        })
+        .on_conflict((user_apartment_state::chat_id, user_apartment_state::card_id))
+        .do_update()
+        .set((
+            user_apartment_state::notes.eq(excluded(user_apartment_state::notes)),
+            user_apartment_state::updated_at.eq(dsl::now),
+        ))
+        .returning(ApartmentState::as_returning())
+        .get_result(conn)
+}
+
+pub fn get(
+    conn: &mut PgConnection,
+    chat_id: i64,
+    card_id: i32,
+) -> Result<Option<ApartmentState>, Error> {
+    user_apartment_state::table
+        .filter(user_apartment_state::chat_id.eq(chat_id))
+        .filter(user_apartment_state::card_id.eq(card_id))
+        .select(ApartmentState::as_select())
+        .first(conn)
+        .optional()
+}
+
+/// The chat's states, most recently changed first.
+pub fn get_for_chat(conn: &mut PgConnection, chat_id: i64) -> Result<Vec<ApartmentState>, Error> {
+    user_apartment_state::table
+        .filter(user_apartment_state::chat_id.eq(chat_id))
+        .order(user_apartment_state::updated_at.desc())
+        .select(ApartmentState::as_select())
+        .load(conn)
+}
@@ -1,103 +1,151 @@
-use std::sync::Arc;
-
 use diesel::{prelude::*, result::Error};
-use log::{error, info};
+use tracing::info;
 
-use super::{
-    establish_connection, schema::apartment_watchlist, schema::apartment_watchlist::dsl::*,
-};
-use crate::{
-    config::Config,
-    models::{
-        apartment_watchlist_model::{InsertableWatchlistApartmentIndex, WatchlistApartmentIndex},
-        watchlist::Watchlist,
-    },
+use super::{schema::apartment_watchlist, schema::apartment_watchlist::dsl::*, schema::watchlists};
+use crate::models::{
+    apartment::Apartment,
+    apartment_watchlist_model::{InsertableWatchlistApartmentIndex, WatchlistApartmentIndex},
+    watchlist::Watchlist,
 };
 
-pub fn insert(config: &Arc<Config>, target_watchlist_id: i32, target_card_id: i32) {
-    let mut conn = establish_connection(config);
-
+pub fn insert(
+    conn: &mut PgConnection,
+    target_watchlist_id: i32,
+    target_card_id: i32,
+) -> Result<usize, Error> {
     let insertable = InsertableWatchlistApartmentIndex {
         watchlist_id: target_watchlist_id,
         card_id: target_card_id,
         has_been_sent: false,
     };
 
-    match diesel::insert_into(apartment_watchlist::table)
+    let n = diesel::insert_into(apartment_watchlist::table)
         .values(insertable)
-        .execute(&mut conn)
-    {
-        Ok(n) => info!("Inserted {:?} rows into apartments table", n),
-        Err(e) => error!("Error: {:?}", e),
-    }
+        .on_conflict((watchlist_id, card_id))
+        .do_nothing()
+        .execute(conn)?;
+
+    info!("Inserted {:?} rows into apartment_watchlist table", n);
+    Ok(n)
 }
 
 pub fn get_watchlist_apartment_connector(
-    config: &Arc<Config>,
-    watchlist: &Watchlist,
+    conn: &mut PgConnection,
+    target_watchlist_id: i32,
     target_card_id: i32,
-) -> Result<Vec<WatchlistApartmentIndex>, Error> {
-    let conn = &mut establish_connection(config);
-
-    let valid_connectors: Result<Vec<WatchlistApartmentIndex>, Error> = apartment_watchlist::table
+) -> Result<Option<WatchlistApartmentIndex>, Error> {
+    apartment_watchlist::table
         .filter(apartment_watchlist::card_id.eq(target_card_id))
-        .filter(apartment_watchlist::watchlist_id.eq(watchlist.id))
+        .filter(apartment_watchlist::watchlist_id.eq(target_watchlist_id))
         .select(WatchlistApartmentIndex::as_select())
-        .load(conn);
-
-    valid_connectors
+        .first(conn)
+        .optional()
 }
 
 pub fn get_unsent_apartments(
-    config: &Arc<Config>,
-    watchlist: &Watchlist,
+    conn: &mut PgConnection,
+    target_watchlist_id: i32,
 ) -> Result<Vec<i32>, Error> {
-    let conn = &mut establish_connection(config);
-
-    let valid_connectors: Result<Vec<i32>, Error> = apartment_watchlist::table
+    apartment_watchlist::table
         .filter(apartment_watchlist::has_been_sent.eq(false))
-        .filter(apartment_watchlist::watchlist_id.eq(watchlist.id))
+        .filter(apartment_watchlist::watchlist_id.eq(target_watchlist_id))
         .select(card_id)
-        .load::<i32>(conn);
-
-    valid_connectors
+        .load::<i32>(conn)
 }
 
-pub fn set_to_read(config: &Arc<Config>, watchlist: &Watchlist, target_card_id: i32) {
-    let conn = &mut establish_connection(config);
-
-    match diesel::update(
+pub fn set_to_read(
+    conn: &mut PgConnection,
+    target_watchlist_id: i32,
+    target_card_id: i32,
+    price: Option<i32>,
+) -> Result<usize, Error> {
+    let n = diesel::update(
         apartment_watchlist
-            .filter(watchlist_id.eq(watchlist.id))
+            .filter(watchlist_id.eq(target_watchlist_id))
             .filter(card_id.eq(target_card_id)),
     )
-    .set(has_been_sent.eq(true))
-    .execute(conn)
-    {
-        Ok(_n) => info!(
-            "Consumer set watchlist {:?} and card_id {} to has_been_sent = {}",
-            watchlist.id, target_card_id, true
-        ),
-        Err(e) => error!("Error: {:?}", e),
-    }
+    .set((has_been_sent.eq(true), sent_price.eq(price)))
+    .execute(conn)?;
+
+    info!(
+        "Consumer set watchlist {:?} and card_id {} to has_been_sent = {}",
+        target_watchlist_id, target_card_id, true
+    );
+    Ok(n)
 }
 
-pub fn exists(
-    config: &Arc<Config>,
-    target_watchlist_id: i32,
+/// Stores the yields that watchlists with their own financing give the card on its matches
+/// to them.
+pub fn set_yields(
+    conn: &mut PgConnection,
     target_card_id: i32,
-) -> Result<bool, Error> {
-    let conn = &mut establish_connection(config);
+    yields: &[(i32, f64)],
+) -> Result<usize, Error> {
+    let mut n = 0;
+    for &(target_watchlist_id, match_yield) in yields {
+        n += diesel::update(
+            apartment_watchlist
+                .filter(watchlist_id.eq(target_watchlist_id))
+                .filter(card_id.eq(target_card_id)),
+        )
+        .set(estimated_yield.eq(match_yield))
+        .execute(conn)?;
+    }
+    Ok(n)
+}
 
-    let valid_apartments: Result<Vec<WatchlistApartmentIndex>, Error> = apartment_watchlist::table
-        .filter(apartment_watchlist::card_id.eq(target_card_id))
-        .filter(apartment_watchlist::watchlist_id.eq(target_watchlist_id))
-        .select(WatchlistApartmentIndex::as_select())
-        .limit(1)
-        .load(conn);
+pub fn clear_yields(conn: &mut PgConnection, target_watchlist_id: i32) -> Result<usize, Error> {
+    diesel::update(apartment_watchlist.filter(watchlist_id.eq(target_watchlist_id)))
+        .set(estimated_yield.eq(None::<f64>))
+        .execute(conn)
+}
 
-    match valid_apartments {
-        Ok(aps) => Ok(aps.len() == 1),
-        Err(e) => Err(e),
+/// Removes matches of the watchlist to the cards that have not been sent yet.
+pub fn delete_unsent(
+    conn: &mut PgConnection,
+    target_watchlist_id: i32,
+    target_card_ids: &[i32],
+) -> Result<usize, Error> {
+    diesel::delete(
+        apartment_watchlist
+            .filter(watchlist_id.eq(target_watchlist_id))
+            .filter(card_id.eq_any(target_card_ids))
+            .filter(has_been_sent.eq(false)),
+    )
+    .execute(conn)
+}
+
+/// Marks sent matches of the apartment as unsent again when its price dropped enough for
+/// the watchlist and it is still above the watchlist's target yield, with the watchlist's
+/// own financing if it has one.
+pub fn rearm_price_drops(conn: &mut PgConnection, apartment: &Apartment) -> Result<usize, Error> {
+    let sent_matches: Vec<(WatchlistApartmentIndex, Watchlist)> = apartment_watchlist::table
+        .inner_join(watchlists::table)
+        .filter(card_id.eq(apartment.card_id))
+        .filter(has_been_sent.eq(true))
+        .select((WatchlistApartmentIndex::as_select(), Watchlist::as_select()))
+        .load(conn)?;
+
+    let rearmed: Vec<i32> = sent_matches
+        .iter()
+        .filter(|(index, watchlist)| {
+            index.is_price_drop(apartment.price, watchlist.min_price_drop_percent)
+                && index.estimated_yield.or(apartment.estimated_yield) > watchlist.target_yield
+        })
+        .map(|(index, _)| index.id)
+        .collect();
+
+    if rearmed.is_empty() {
+        return Ok(0);
     }
+
+    let n = diesel::update(apartment_watchlist.filter(id.eq_any(&rearmed)))
+        .set(has_been_sent.eq(false))
+        .execute(conn)?;
+
+    info!(
+        "Price of card_id {} dropped, announcing it again to {} watchlists",
+        apartment.card_id, n
+    );
+    Ok(n)
 }
@@ -0,0 +1,30 @@
+use diesel::{dsl, prelude::*, result::Error};
+
+use super::schema::api_tokens;
+use crate::models::api_token::InsertableApiToken;
+
+/// Stores the token of the chat, revoking the chat's previous tokens.
+pub fn replace_for_chat(
+    conn: &mut PgConnection,
+    chat_id: i64,
+    token_hash: &str,
+) -> Result<usize, Error> {
+    conn.transaction(|conn| {
+        diesel::delete(api_tokens::table.filter(api_tokens::chat_id.eq(chat_id))).execute(conn)?;
+        diesel::insert_into(api_tokens::table)
+            .values(InsertableApiToken {
+                chat_id,
+                token_hash: token_hash.to_string(),
+            })
+            .execute(conn)
+    })
+}
+
+/// Chat the token was issued to, if it exists. Records the use of the token.
+pub fn use_token(conn: &mut PgConnection, token_hash: &str) -> Result<Option<i64>, Error> {
+    diesel::update(api_tokens::table.filter(api_tokens::token_hash.eq(token_hash)))
+        .set(api_tokens::last_used_at.eq(dsl::now))
+        .returning(api_tokens::chat_id)
+        .get_result(conn)
+        .optional()
+}
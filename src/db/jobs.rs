@@ -0,0 +1,160 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{dsl, prelude::*, result::Error};
+use tracing::{info, warn};
+
+use super::schema::jobs;
+use crate::models::job::{backoff, InsertableJob, Job, JobStatus, MAX_ATTEMPTS};
+
+/// Queues the job unless a job with the same dedup key is already pending or running.
+/// A dead job with the same key is replaced, so a task keeps at most its last failure.
+/// Returns whether the job was queued.
+pub fn enqueue(conn: &mut PgConnection, job: InsertableJob) -> Result<bool, Error> {
+    conn.transaction(|conn| {
+        let key = job.dedup_key.clone();
+        let n = diesel::insert_into(jobs::table)
+            .values(job)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        if n == 1 {
+            diesel::delete(
+                jobs::table
+                    .filter(jobs::dedup_key.eq(key))
+                    .filter(jobs::status.eq(JobStatus::DEAD)),
+            )
+            .execute(conn)?;
+        }
+
+        Ok(n == 1)
+    })
+}
+
+/// Dedup keys of the dead jobs of the given type.
+pub fn dead_keys(conn: &mut PgConnection, target_type: &str) -> Result<Vec<String>, Error> {
+    jobs::table
+        .filter(jobs::job_type.eq(target_type))
+        .filter(jobs::status.eq(JobStatus::DEAD))
+        .select(jobs::dedup_key)
+        .load(conn)
+}
+
+/// Deletes the dead jobs whose dedup key starts with `key_prefix`.
+pub fn delete_dead(conn: &mut PgConnection, key_prefix: &str) -> Result<usize, Error> {
+    let pattern = format!(
+        "{}%",
+        key_prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    );
+    diesel::delete(
+        jobs::table
+            .filter(jobs::status.eq(JobStatus::DEAD))
+            .filter(jobs::dedup_key.like(pattern)),
+    )
+    .execute(conn)
+}
+
+/// Takes the next due pending job and marks it running.
+///
+/// Rows locked by other workers are skipped, so concurrent workers never get the same job.
+pub fn claim(conn: &mut PgConnection) -> Result<Option<Job>, Error> {
+    conn.transaction(|conn| {
+        let next = jobs::table
+            .filter(jobs::status.eq(JobStatus::PENDING))
+            .filter(jobs::run_after.le(dsl::now))
+            .order((jobs::run_after.asc(), jobs::id.asc()))
+            .select(Job::as_select())
+            .for_update()
+            .skip_locked()
+            .first(conn)
+            .optional()?;
+
+        let Some(next) = next else {
+            return Ok(None);
+        };
+
+        diesel::update(jobs::table.find(next.id))
+            .set((
+                jobs::status.eq(JobStatus::RUNNING),
+                jobs::attempts.eq(jobs::attempts + 1),
+                jobs::locked_at.eq(dsl::now),
+                jobs::updated_at.eq(dsl::now),
+            ))
+            .returning(Job::as_returning())
+            .get_result(conn)
+            .map(Some)
+    })
+}
+
+/// Removes a finished job from the queue.
+pub fn complete(conn: &mut PgConnection, job_id: i32) -> Result<usize, Error> {
+    diesel::delete(jobs::table.find(job_id)).execute(conn)
+}
+
+/// Schedules a failed job for a retry with backoff, or moves it to the dead state once
+/// it has been attempted `MAX_ATTEMPTS` times. Returns whether the job is dead.
+pub fn fail(
+    conn: &mut PgConnection,
+    job: &Job,
+    error: &str,
+    retryable: bool,
+) -> Result<bool, Error> {
+    let dead = !retryable || job.attempts >= MAX_ATTEMPTS;
+    let (next_status, next_run) = if dead {
+        (JobStatus::DEAD, job.run_after)
+    } else {
+        (
+            JobStatus::PENDING,
+            Utc::now().naive_utc() + backoff(job.attempts),
+        )
+    };
+
+    diesel::update(jobs::table.find(job.id))
+        .set((
+            jobs::status.eq(next_status),
+            jobs::last_error.eq(error),
+            jobs::run_after.eq(next_run),
+            jobs::locked_at.eq(None::<NaiveDateTime>),
+            jobs::updated_at.eq(dsl::now),
+        ))
+        .execute(conn)?;
+
+    if dead {
+        warn!(
+            "Job {} ({}) is dead after {} attempts: {}",
+            job.id, job.dedup_key, job.attempts, error
+        );
+    }
+    Ok(dead)
+}
+
+/// Puts jobs that have been running since before `locked_before` back in the queue, e.g.
+/// after the worker running them was killed.
+pub fn release_stale(
+    conn: &mut PgConnection,
+    locked_before: NaiveDateTime,
+) -> Result<usize, Error> {
+    let n = diesel::update(
+        jobs::table
+            .filter(jobs::status.eq(JobStatus::RUNNING))
+            .filter(jobs::locked_at.lt(locked_before)),
+    )
+    .set((
+        jobs::status.eq(JobStatus::PENDING),
+        jobs::locked_at.eq(None::<NaiveDateTime>),
+        jobs::updated_at.eq(dsl::now),
+    ))
+    .execute(conn)?;
+
+    if n > 0 {
+        info!("Released {} stale jobs", n);
+    }
+    Ok(n)
+}
+
+pub fn count_by_status(conn: &mut PgConnection) -> Result<Vec<(String, i64)>, Error> {
+    jobs::table
+        .group_by(jobs::status)
+        .select((jobs::status, dsl::count_star()))
+        .load(conn)
+}
@@ -0,0 +1,845 @@
+use std::{cmp::Reverse, collections::HashSet, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use super::{
+    apartment::FRESHNESS_DAYS,
+    repository::{
+        ApartmentRepository, ApartmentStateRepository, ApiTokenRepository, JobRepository,
+        MatchRepository, WatchlistRepository,
+    },
+};
+use crate::{
+    models::{
+        apartment::{Apartment, InsertableApartment},
+        apartment_state::{ApartmentState, ApartmentStatus},
+        apartment_watchlist_model::WatchlistApartmentIndex,
+        api_token::ApiToken,
+        job::{backoff, InsertableJob, Job, JobStatus, MAX_ATTEMPTS},
+        price_history::PriceHistoryEntry,
+        watchlist::{
+            Financing, ListingCriteria, Locations, SizeTarget, Watchlist,
+            DEFAULT_MIN_PRICE_DROP_PERCENT, DEFAULT_REFRESH_INTERVAL_MINUTES,
+        },
+    },
+    oikotie::oikotie::Location,
+    MessageTask, TaskType,
+};
+
+#[derive(Default)]
+struct State {
+    apartments: Vec<Apartment>,
+    watchlists: Vec<Watchlist>,
+    matches: Vec<WatchlistApartmentIndex>,
+    price_history: Vec<PriceHistoryEntry>,
+    jobs: Vec<Job>,
+    api_tokens: Vec<ApiToken>,
+    apartment_states: Vec<ApartmentState>,
+    next_id: i32,
+}
+
+impl State {
+    fn next_id(&mut self) -> i32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// The chat's state of the card, created as `new` if missing, marked as updated now.
+    fn apartment_state(&mut self, chat_id: i64, card_id: i32) -> Result<&mut ApartmentState> {
+        if !self.apartments.iter().any(|a| a.card_id == card_id) {
+            return Err(anyhow!("Apartment {} does not exist", card_id));
+        }
+
+        let timestamp = now();
+        let position = self
+            .apartment_states
+            .iter()
+            .position(|s| s.chat_id == chat_id && s.card_id == card_id);
+        let index = match position {
+            Some(index) => index,
+            None => {
+                let id = self.next_id();
+                self.apartment_states.push(ApartmentState {
+                    id,
+                    chat_id,
+                    card_id,
+                    status: ApartmentStatus::NEW.to_string(),
+                    created_at: timestamp,
+                    updated_at: timestamp,
+                    notes: None,
+                });
+                self.apartment_states.len() - 1
+            }
+        };
+
+        let apartment_state = &mut self.apartment_states[index];
+        apartment_state.updated_at = timestamp;
+        Ok(apartment_state)
+    }
+
+    fn insert_match(&mut self, watchlist_id: i32, card_id: i32) {
+        if self
+            .matches
+            .iter()
+            .any(|m| m.watchlist_id == watchlist_id && m.card_id == card_id)
+        {
+            return;
+        }
+
+        let id = self.next_id();
+        let timestamp = now();
+        self.matches.push(WatchlistApartmentIndex {
+            id,
+            watchlist_id,
+            card_id,
+            has_been_sent: false,
+            created_at: timestamp,
+            updated_at: timestamp,
+            sent_price: None,
+            estimated_yield: None,
+        });
+    }
+
+    fn rearm_price_drops(&mut self, apartment: &Apartment) {
+        let State {
+            matches,
+            watchlists,
+            ..
+        } = self;
+        for index in matches
+            .iter_mut()
+            .filter(|m| m.card_id == apartment.card_id)
+        {
+            let Some(watchlist) = watchlists.iter().find(|w| w.id == index.watchlist_id) else {
+                continue;
+            };
+            if index.is_price_drop(apartment.price, watchlist.min_price_drop_percent)
+                && index.estimated_yield.or(apartment.estimated_yield) > watchlist.target_yield
+            {
+                index.has_been_sent = false;
+                index.updated_at = now();
+            }
+        }
+    }
+
+    fn set_match_yields(&mut self, card_id: i32, yields: &[(i32, f64)]) {
+        for index in self.matches.iter_mut().filter(|m| m.card_id == card_id) {
+            if let Some(&(_, match_yield)) = yields.iter().find(|(id, _)| *id == index.watchlist_id)
+            {
+                index.estimated_yield = Some(match_yield);
+            }
+        }
+    }
+
+    fn record_price(&mut self, apartment: &Apartment) {
+        let id = self.next_id();
+        self.price_history.push(PriceHistoryEntry {
+            id,
+            card_id: apartment.card_id,
+            price: apartment.price,
+            additional_costs: apartment.additional_costs,
+            status: apartment.status,
+            observed_at: now(),
+        });
+    }
+}
+
+/// Repository that keeps everything in memory. Used in tests in place of Postgres.
+///
+/// Mirrors the database semantics the pipeline relies on: apartments are upserted by
+/// `card_id`, listing changes are recorded in the price history, each (watchlist, card)
+/// pair is matched at most once and sent matches are re-armed on price drops.
+#[derive(Default)]
+pub struct InMemoryRepository {
+    state: Mutex<State>,
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Overrides `updated_at` of a stored apartment, e.g. to make it stale.
+    pub fn set_apartment_updated_at(&self, card_id: i32, updated_at: NaiveDateTime) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(apartment) = state.apartments.iter_mut().find(|a| a.card_id == card_id) {
+            apartment.updated_at = updated_at;
+        }
+    }
+
+    pub fn matches(&self) -> Vec<WatchlistApartmentIndex> {
+        self.state.lock().unwrap().matches.clone()
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        self.state.lock().unwrap().jobs.clone()
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+#[async_trait]
+impl ApartmentRepository for InMemoryRepository {
+    async fn upsert_apartment_with_yields(
+        &self,
+        apartment: InsertableApartment,
+        matched_watchlists: &[i32],
+        match_yields: &[(i32, f64)],
+    ) -> Result<Apartment> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp = now();
+
+        let mut listing_changed = true;
+        let stored = match state
+            .apartments
+            .iter_mut()
+            .find(|a| a.card_id == apartment.card_id)
+        {
+            Some(existing) => {
+                listing_changed = existing.listing_changed(&apartment);
+                existing.location_id = apartment.location_id;
+                existing.location_level = apartment.location_level;
+                existing.location_name = apartment.location_name.clone();
+                existing.size = apartment.size;
+                existing.rooms = apartment.rooms;
+                existing.build_year = apartment.build_year;
+                existing.floor = apartment.floor;
+                existing.building_type = apartment.building_type.clone();
+                existing.latitude = apartment.latitude;
+                existing.longitude = apartment.longitude;
+                existing.url = apartment.url.clone();
+                existing.price = apartment.price;
+                existing.additional_costs = apartment.additional_costs;
+                existing.rent = apartment.rent;
+                existing.rent_lower = apartment.rent_lower;
+                existing.rent_upper = apartment.rent_upper;
+                existing.estimated_yield = apartment.estimated_yield;
+                existing.status = apartment.status;
+                existing.debt_share = apartment.debt_share;
+                existing.plot_ownership = apartment.plot_ownership.clone();
+                existing.removed_at = None;
+                existing.updated_at = timestamp;
+                existing.clone()
+            }
+            None => {
+                let id = state.next_id();
+                let stored = Apartment {
+                    id,
+                    card_id: apartment.card_id,
+                    location_id: apartment.location_id,
+                    location_level: apartment.location_level,
+                    location_name: apartment.location_name,
+                    size: apartment.size,
+                    rooms: apartment.rooms,
+                    price: apartment.price,
+                    additional_costs: apartment.additional_costs,
+                    rent: apartment.rent,
+                    estimated_yield: apartment.estimated_yield,
+                    url: apartment.url,
+                    created_at: timestamp,
+                    updated_at: timestamp,
+                    build_year: apartment.build_year,
+                    floor: apartment.floor,
+                    building_type: apartment.building_type,
+                    latitude: apartment.latitude,
+                    longitude: apartment.longitude,
+                    rent_lower: apartment.rent_lower,
+                    rent_upper: apartment.rent_upper,
+                    status: apartment.status,
+                    removed_at: None,
+                    debt_share: apartment.debt_share,
+                    plot_ownership: apartment.plot_ownership,
+                };
+                state.apartments.push(stored.clone());
+                stored
+            }
+        };
+
+        for &watchlist_id in matched_watchlists {
+            state.insert_match(watchlist_id, stored.card_id);
+        }
+        state.set_match_yields(stored.card_id, match_yields);
+        if listing_changed {
+            state.record_price(&stored);
+            state.rearm_price_drops(&stored);
+        }
+
+        Ok(stored)
+    }
+
+    async fn get_apartment(&self, card_id: i32) -> Result<Option<Apartment>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .apartments
+            .iter()
+            .find(|a| a.card_id == card_id)
+            .cloned())
+    }
+
+    async fn apartment_is_fresh(&self, card_id: i32) -> Result<bool> {
+        let cutoff = now() - Duration::days(FRESHNESS_DAYS);
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .apartments
+            .iter()
+            .any(|a| a.card_id == card_id && a.updated_at > cutoff))
+    }
+
+    async fn mark_apartments_removed(&self, card_ids: &[i32]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp = now();
+        let mut n = 0;
+        for apartment in state
+            .apartments
+            .iter_mut()
+            .filter(|a| a.is_active() && card_ids.contains(&a.card_id))
+        {
+            apartment.removed_at = Some(timestamp);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn get_apartments_for_watchlist(
+        &self,
+        watchlist: &Watchlist,
+        include_removed: bool,
+    ) -> Result<Vec<Apartment>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .apartments
+            .iter()
+            .filter(|a| {
+                a.location_id
+                    .zip(a.location_level)
+                    .is_some_and(|(id, level)| watchlist.locations.contains(id, level))
+                    && (include_removed || a.is_active())
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn get_matching_apartments(&self, watchlist: &Watchlist) -> Result<Vec<Apartment>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .matches
+            .iter()
+            .filter(|m| m.watchlist_id == watchlist.id)
+            .filter_map(|m| {
+                let mut apartment = state
+                    .apartments
+                    .iter()
+                    .find(|a| a.card_id == m.card_id)?
+                    .clone();
+                if m.estimated_yield.is_some() {
+                    apartment.estimated_yield = m.estimated_yield;
+                }
+                Some(apartment)
+            })
+            .filter(|a| {
+                a.is_active()
+                    && watchlist
+                        .target_yield
+                        .is_none_or(|target| a.estimated_yield.is_some_and(|y| y > target))
+            })
+            .collect())
+    }
+
+    async fn get_price_history(&self, card_id: i32) -> Result<Vec<PriceHistoryEntry>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .price_history
+            .iter()
+            .filter(|entry| entry.card_id == card_id)
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl WatchlistRepository for InMemoryRepository {
+    async fn insert_watchlist(
+        &self,
+        location: Location,
+        chat_id: i64,
+        target_yield: Option<f64>,
+        target_size: SizeTarget,
+    ) -> Result<Watchlist> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        let timestamp = now();
+        let watchlist = Watchlist {
+            id,
+            locations: Locations(vec![location]),
+            chat_id,
+            target_yield,
+            created_at: timestamp,
+            updated_at: timestamp,
+            target_size_min: target_size.min,
+            target_size_max: target_size.max,
+            min_price_drop_percent: DEFAULT_MIN_PRICE_DROP_PERCENT,
+            last_refreshed_at: None,
+            next_refresh_at: timestamp,
+            refresh_interval_minutes: DEFAULT_REFRESH_INTERVAL_MINUTES,
+            paused_at: None,
+            criteria: ListingCriteria::default(),
+            financing: Financing::default(),
+        };
+        state.watchlists.push(watchlist.clone());
+        Ok(watchlist)
+    }
+
+    async fn delete_watchlist(&self, watchlist_id: i32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.watchlists.retain(|w| w.id != watchlist_id);
+        state.matches.retain(|m| m.watchlist_id != watchlist_id);
+        Ok(())
+    }
+
+    async fn get_watchlist(&self, watchlist_id: i32) -> Result<Option<Watchlist>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .find(|w| w.id == watchlist_id)
+            .cloned())
+    }
+
+    async fn get_all_watchlists(&self) -> Result<Vec<Watchlist>> {
+        Ok(self.state.lock().unwrap().watchlists.clone())
+    }
+
+    async fn get_due_watchlists(&self) -> Result<Vec<Watchlist>> {
+        let timestamp = now();
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .filter(|w| w.next_refresh_at <= timestamp && !w.is_paused())
+            .cloned()
+            .collect())
+    }
+
+    async fn schedule_watchlist_refresh(&self, watchlist_id: i32, at: NaiveDateTime) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(watchlist) = state.watchlists.iter_mut().find(|w| w.id == watchlist_id) {
+            watchlist.next_refresh_at = at;
+        }
+        Ok(())
+    }
+
+    async fn mark_watchlist_refreshed(&self, watchlist_id: i32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(watchlist) = state.watchlists.iter_mut().find(|w| w.id == watchlist_id) {
+            watchlist.last_refreshed_at = Some(now());
+        }
+        Ok(())
+    }
+
+    async fn update_watchlist_refresh_interval(
+        &self,
+        watchlist_id: i32,
+        minutes: i32,
+        next_refresh_at: NaiveDateTime,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(watchlist) = state.watchlists.iter_mut().find(|w| w.id == watchlist_id) {
+            watchlist.refresh_interval_minutes = minutes;
+            watchlist.next_refresh_at = next_refresh_at;
+            watchlist.updated_at = now();
+        }
+        Ok(())
+    }
+
+    async fn update_watchlist_criteria(&self, watchlist: &Watchlist) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stored) = state.watchlists.iter_mut().find(|w| w.id == watchlist.id) {
+            stored.target_yield = watchlist.target_yield;
+            stored.target_size_min = watchlist.target_size_min;
+            stored.target_size_max = watchlist.target_size_max;
+            stored.min_price_drop_percent = watchlist.min_price_drop_percent;
+            stored.refresh_interval_minutes = watchlist.refresh_interval_minutes;
+            stored.next_refresh_at = watchlist.next_refresh_at;
+            stored.criteria = watchlist.criteria.clone();
+            stored.financing = watchlist.financing;
+            stored.updated_at = now();
+        }
+        Ok(())
+    }
+
+    async fn get_watchlists_for_chat(&self, chat_id: i64) -> Result<Vec<Watchlist>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .filter(|w| w.chat_id == chat_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn pause_watchlists_for_chat(&self, chat_id: i64) -> Result<usize> {
+        let timestamp = now();
+        let mut state = self.state.lock().unwrap();
+        let mut n = 0;
+        for watchlist in state
+            .watchlists
+            .iter_mut()
+            .filter(|w| w.chat_id == chat_id && !w.is_paused())
+        {
+            watchlist.paused_at = Some(timestamp);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn resume_watchlists_for_chat(&self, chat_id: i64) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let mut n = 0;
+        for watchlist in state
+            .watchlists
+            .iter_mut()
+            .filter(|w| w.chat_id == chat_id && w.is_paused())
+        {
+            watchlist.paused_at = None;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn get_watchlists_for_locations(&self, locations: &[Location]) -> Result<Vec<Watchlist>> {
+        let key = Locations(locations.to_vec()).key();
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .filter(|w| w.locations.key() == key)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_watchlists_for_location(&self, location: &Location) -> Result<Vec<Watchlist>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .filter(|w| w.locations.contains(location.id, location.level))
+            .cloned()
+            .collect())
+    }
+
+    async fn update_watchlist_locations(
+        &self,
+        watchlist_id: i32,
+        locations: &Locations,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(stored) = state.watchlists.iter_mut().find(|w| w.id == watchlist_id) {
+            stored.locations = locations.clone();
+            stored.updated_at = now();
+        }
+        Ok(())
+    }
+
+    async fn get_watchlists_for_chat_and_location(
+        &self,
+        chat_id: i64,
+        location_name: &str,
+    ) -> Result<Vec<Watchlist>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .watchlists
+            .iter()
+            .filter(|w| w.chat_id == chat_id && w.locations.iter().any(|l| l.name == location_name))
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl MatchRepository for InMemoryRepository {
+    async fn insert_match(&self, watchlist_id: i32, card_id: i32) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .insert_match(watchlist_id, card_id);
+        Ok(())
+    }
+
+    async fn get_match(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+    ) -> Result<Option<WatchlistApartmentIndex>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .matches
+            .iter()
+            .find(|m| m.watchlist_id == watchlist_id && m.card_id == card_id)
+            .cloned())
+    }
+
+    async fn get_unsent_matches(&self, watchlist_id: i32) -> Result<Vec<i32>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .matches
+            .iter()
+            .filter(|m| m.watchlist_id == watchlist_id && !m.has_been_sent)
+            .map(|m| m.card_id)
+            .collect())
+    }
+
+    async fn delete_unsent_matches(&self, watchlist_id: i32, card_ids: &[i32]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.matches.len();
+        state.matches.retain(|m| {
+            m.watchlist_id != watchlist_id || m.has_been_sent || !card_ids.contains(&m.card_id)
+        });
+        Ok(before - state.matches.len())
+    }
+
+    async fn set_match_yields(&self, card_id: i32, yields: &[(i32, f64)]) -> Result<()> {
+        self.state.lock().unwrap().set_match_yields(card_id, yields);
+        Ok(())
+    }
+
+    async fn clear_match_yields(&self, watchlist_id: i32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for index in state
+            .matches
+            .iter_mut()
+            .filter(|m| m.watchlist_id == watchlist_id)
+        {
+            index.estimated_yield = None;
+        }
+        Ok(())
+    }
+
+    async fn set_match_sent(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+        price: Option<i32>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state
+            .matches
+            .iter_mut()
+            .find(|m| m.watchlist_id == watchlist_id && m.card_id == card_id)
+        {
+            index.has_been_sent = true;
+            index.sent_price = price;
+            index.updated_at = now();
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobRepository for InMemoryRepository {
+    async fn enqueue_task(&self, task: &MessageTask) -> Result<bool> {
+        let insertable = InsertableJob::from_task(task)?;
+        let mut state = self.state.lock().unwrap();
+        if state.jobs.iter().any(|job| {
+            job.dedup_key == insertable.dedup_key
+                && (job.status == JobStatus::PENDING || job.status == JobStatus::RUNNING)
+        }) {
+            return Ok(false);
+        }
+        state
+            .jobs
+            .retain(|job| job.dedup_key != insertable.dedup_key || job.status != JobStatus::DEAD);
+
+        let id = state.next_id();
+        let timestamp = now();
+        state.jobs.push(Job {
+            id,
+            job_type: insertable.job_type,
+            payload: insertable.payload,
+            dedup_key: insertable.dedup_key,
+            status: JobStatus::PENDING.to_string(),
+            attempts: 0,
+            last_error: None,
+            run_after: timestamp,
+            locked_at: None,
+            created_at: timestamp,
+            updated_at: timestamp,
+        });
+        Ok(true)
+    }
+
+    async fn claim_job(&self) -> Result<Option<Job>> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp = now();
+        let next = state
+            .jobs
+            .iter_mut()
+            .filter(|job| job.status == JobStatus::PENDING && job.run_after <= timestamp)
+            .min_by_key(|job| (job.run_after, job.id));
+
+        Ok(next.map(|job| {
+            job.status = JobStatus::RUNNING.to_string();
+            job.attempts += 1;
+            job.locked_at = Some(timestamp);
+            job.updated_at = timestamp;
+            job.clone()
+        }))
+    }
+
+    async fn complete_job(&self, job_id: i32) -> Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .jobs
+            .retain(|job| job.id != job_id);
+        Ok(())
+    }
+
+    async fn fail_job(&self, failed: &Job, error: &str, retryable: bool) -> Result<bool> {
+        let dead = !retryable || failed.attempts >= MAX_ATTEMPTS;
+        let mut state = self.state.lock().unwrap();
+        if let Some(job) = state.jobs.iter_mut().find(|job| job.id == failed.id) {
+            let timestamp = now();
+            if dead {
+                job.status = JobStatus::DEAD.to_string();
+            } else {
+                job.status = JobStatus::PENDING.to_string();
+                job.run_after = timestamp + backoff(failed.attempts);
+            }
+            job.last_error = Some(error.to_string());
+            job.locked_at = None;
+            job.updated_at = timestamp;
+        }
+        Ok(dead)
+    }
+
+    async fn release_stale_jobs(&self, locked_before: NaiveDateTime) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let mut n = 0;
+        for job in state.jobs.iter_mut().filter(|job| {
+            job.status == JobStatus::RUNNING && job.locked_at.is_some_and(|at| at < locked_before)
+        }) {
+            job.status = JobStatus::PENDING.to_string();
+            job.locked_at = None;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    async fn count_jobs_by_status(&self) -> Result<Vec<(String, i64)>> {
+        let state = self.state.lock().unwrap();
+        let mut counts: Vec<(String, i64)> = Vec::new();
+        for job in &state.jobs {
+            match counts.iter_mut().find(|(status, _)| *status == job.status) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((job.status.clone(), 1)),
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn get_dead_job_keys(&self, task_type: TaskType) -> Result<HashSet<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .jobs
+            .iter()
+            .filter(|job| job.job_type == task_type.as_str() && job.status == JobStatus::DEAD)
+            .map(|job| job.dedup_key.clone())
+            .collect())
+    }
+
+    async fn discard_dead_messages(&self, watchlist_id: i32) -> Result<usize> {
+        let prefix = MessageTask::send_message_key_prefix(watchlist_id);
+        let mut state = self.state.lock().unwrap();
+        let before = state.jobs.len();
+        state
+            .jobs
+            .retain(|job| job.status != JobStatus::DEAD || !job.dedup_key.starts_with(&prefix));
+        Ok(before - state.jobs.len())
+    }
+}
+
+#[async_trait]
+impl ApiTokenRepository for InMemoryRepository {
+    async fn replace_api_token(&self, chat_id: i64, token_hash: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.api_tokens.retain(|t| t.chat_id != chat_id);
+        let id = state.next_id();
+        state.api_tokens.push(ApiToken {
+            id,
+            chat_id,
+            token_hash: token_hash.to_string(),
+            created_at: now(),
+            last_used_at: None,
+        });
+        Ok(())
+    }
+
+    async fn use_api_token(&self, token_hash: &str) -> Result<Option<i64>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(token) = state
+            .api_tokens
+            .iter_mut()
+            .find(|t| t.token_hash == token_hash)
+        else {
+            return Ok(None);
+        };
+        token.last_used_at = Some(now());
+        Ok(Some(token.chat_id))
+    }
+}
+
+#[async_trait]
+impl ApartmentStateRepository for InMemoryRepository {
+    async fn set_apartment_status(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        status: &str,
+    ) -> Result<ApartmentState> {
+        let mut state = self.state.lock().unwrap();
+        let apartment_state = state.apartment_state(chat_id, card_id)?;
+        apartment_state.status = status.to_string();
+        Ok(apartment_state.clone())
+    }
+
+    async fn set_apartment_notes(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        notes: Option<&str>,
+    ) -> Result<ApartmentState> {
+        let mut state = self.state.lock().unwrap();
+        let apartment_state = state.apartment_state(chat_id, card_id)?;
+        apartment_state.notes = notes.map(str::to_string);
+        Ok(apartment_state.clone())
+    }
+
+    async fn get_apartment_state(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+    ) -> Result<Option<ApartmentState>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .apartment_states
+            .iter()
+            .find(|s| s.chat_id == chat_id && s.card_id == card_id)
+            .cloned())
+    }
+
+    async fn get_apartment_states(&self, chat_id: i64) -> Result<Vec<ApartmentState>> {
+        let state = self.state.lock().unwrap();
+        let mut states: Vec<ApartmentState> = state
+            .apartment_states
+            .iter()
+            .filter(|s| s.chat_id == chat_id)
+            .cloned()
+            .collect();
+        states.sort_by_key(|s| Reverse(s.updated_at));
+        Ok(states)
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use diesel::{pg::Pg, PgConnection};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::Deserialize;
+use tracing::info;
+
+use diesel::migration::MigrationSource;
+
+/// Migrations from `migrations/`, compiled into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// What to do with the database schema when the service starts.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationMode {
+    /// Apply pending migrations before starting the workers.
+    #[default]
+    Apply,
+    /// Only verify that the database is at the schema version of this binary.
+    Check,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: String,
+    pub applied: bool,
+}
+
+/// Applies all pending migrations and returns the versions that were run.
+pub fn run_pending(conn: &mut PgConnection) -> Result<Vec<String>> {
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|e| anyhow!("Failed to run migrations: {}", e))?;
+
+    Ok(applied.iter().map(|v| v.to_string()).collect())
+}
+
+/// Reverts the most recently applied migration and returns its version.
+pub fn revert_last(conn: &mut PgConnection) -> Result<String> {
+    let reverted = conn
+        .revert_last_migration(MIGRATIONS)
+        .map_err(|e| anyhow!("Failed to revert migration: {}", e))?;
+
+    Ok(reverted.to_string())
+}
+
+/// Lists every embedded migration and whether it has been applied.
+pub fn status(conn: &mut PgConnection) -> Result<Vec<MigrationStatus>> {
+    let applied = applied_versions(conn)?;
+
+    Ok(embedded_versions()?
+        .into_iter()
+        .map(|version| MigrationStatus {
+            applied: applied.contains(&version),
+            version,
+        })
+        .collect())
+}
+
+/// Fails unless the database has exactly the migrations embedded in this binary.
+pub fn check(conn: &mut PgConnection) -> Result<()> {
+    let applied = applied_versions(conn)?;
+    let embedded: HashSet<String> = embedded_versions()?.into_iter().collect();
+
+    let mut pending: Vec<&String> = embedded.difference(&applied).collect();
+    let mut unknown: Vec<&String> = applied.difference(&embedded).collect();
+    pending.sort();
+    unknown.sort();
+
+    if !pending.is_empty() {
+        return Err(anyhow!(
+            "Database schema is behind this version of apato, pending migrations: {:?}. Run `apato migrate up`.",
+            pending
+        ));
+    }
+    if !unknown.is_empty() {
+        return Err(anyhow!(
+            "Database schema is ahead of this version of apato, unknown migrations: {:?}",
+            unknown
+        ));
+    }
+
+    Ok(())
+}
+
+/// Brings the schema up to date or verifies it, depending on the configured mode.
+pub fn prepare(conn: &mut PgConnection, mode: MigrationMode) -> Result<()> {
+    if mode == MigrationMode::Apply {
+        for version in run_pending(conn)? {
+            info!("Applied migration {}", version);
+        }
+    }
+
+    check(conn)
+}
+
+fn applied_versions(conn: &mut PgConnection) -> Result<HashSet<String>> {
+    let applied = conn
+        .applied_migrations()
+        .map_err(|e| anyhow!("Failed to read applied migrations: {}", e))?;
+
+    Ok(applied.iter().map(|v| v.to_string()).collect())
+}
+
+fn embedded_versions() -> Result<Vec<String>> {
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .map_err(|e| anyhow!("Failed to load embedded migrations: {}", e))?;
+
+    let mut versions: Vec<String> = migrations
+        .iter()
+        .map(|m| m.name().version().to_string())
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
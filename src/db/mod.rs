@@ -1,17 +1,62 @@
 pub mod apartment;
+pub mod apartment_state;
 pub mod apartment_watchlist;
+pub mod api_tokens;
+pub mod jobs;
+pub mod memory;
+pub mod migrations;
+pub mod postgres;
+pub mod price_history;
+pub mod repository;
 pub mod schema;
 pub mod watchlist;
 
-use std::sync::Arc;
+use anyhow::{Context, Result};
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
 
-use diesel::{Connection, PgConnection};
+use std::time::Instant;
 
-use crate::config::Config;
+use crate::{config::Config, metrics};
 
-pub fn establish_connection(config: &Arc<Config>) -> PgConnection {
-    let database_url = &config.db_path;
+const DEFAULT_POOL_SIZE: u32 = 10;
 
-    PgConnection::establish(database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+
+/// Creates the connection pool shared by the producer, consumers, bot and HTTP API.
+pub fn create_pool(config: &Config) -> Result<DbPool> {
+    let manager = ConnectionManager::<PgConnection>::new(&config.db_path);
+
+    Pool::builder()
+        .max_size(config.db_pool_size.unwrap_or(DEFAULT_POOL_SIZE))
+        .build(manager)
+        .context("Failed to create database connection pool")
+}
+
+/// Runs a blocking Diesel query on a pooled connection without blocking the async runtime.
+pub async fn run<F, T, E>(pool: &DbPool, query: F) -> Result<T>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    anyhow::Error: From<E>,
+{
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let conn = pool.get();
+        metrics::DB_POOL_WAIT
+            .with_label_values(&[])
+            .observe(start.elapsed().as_secs_f64());
+        let state = pool.state();
+        metrics::DB_POOL_CONNECTIONS.set(state.connections.into());
+        metrics::DB_POOL_IDLE_CONNECTIONS.set(state.idle_connections.into());
+
+        let mut conn = conn.context("Failed to get a database connection from the pool")?;
+        Ok(query(&mut conn)?)
+    })
+    .await
+    .context("Database task panicked")?
 }
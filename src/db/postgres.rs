@@ -0,0 +1,431 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+
+use super::{
+    apartment, apartment_state, apartment_watchlist, api_tokens, jobs, price_history,
+    repository::{
+        ApartmentRepository, ApartmentStateRepository, ApiTokenRepository, JobRepository,
+        MatchRepository, WatchlistRepository,
+    },
+    run, watchlist, DbPool,
+};
+use crate::{
+    models::{
+        apartment::{Apartment, InsertableApartment},
+        apartment_state::ApartmentState,
+        apartment_watchlist_model::WatchlistApartmentIndex,
+        job::{InsertableJob, Job},
+        price_history::PriceHistoryEntry,
+        watchlist::{Locations, SizeTarget, Watchlist},
+    },
+    oikotie::oikotie::Location,
+    MessageTask, TaskType,
+};
+
+/// Repository backed by the Postgres connection pool.
+#[derive(Clone)]
+pub struct PgRepository {
+    pool: DbPool,
+}
+
+impl PgRepository {
+    pub fn new(pool: DbPool) -> Self {
+        PgRepository { pool }
+    }
+
+    pub fn pool(&self) -> &DbPool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl ApartmentRepository for PgRepository {
+    async fn upsert_apartment_with_yields(
+        &self,
+        new_apartment: InsertableApartment,
+        matched_watchlists: &[i32],
+        match_yields: &[(i32, f64)],
+    ) -> Result<Apartment> {
+        let matched_watchlists = matched_watchlists.to_vec();
+        let match_yields = match_yields.to_vec();
+        run(&self.pool, move |conn| {
+            apartment::upsert_with_match(conn, new_apartment, &matched_watchlists, &match_yields)
+        })
+        .await
+    }
+
+    async fn get_apartment(&self, card_id: i32) -> Result<Option<Apartment>> {
+        run(&self.pool, move |conn| {
+            apartment::get_apartment_by_card_id(conn, card_id)
+        })
+        .await
+    }
+
+    async fn apartment_is_fresh(&self, card_id: i32) -> Result<bool> {
+        run(&self.pool, move |conn| {
+            apartment::apartment_is_fresh(conn, card_id)
+        })
+        .await
+    }
+
+    async fn mark_apartments_removed(&self, card_ids: &[i32]) -> Result<usize> {
+        let card_ids = card_ids.to_vec();
+        run(&self.pool, move |conn| {
+            apartment::mark_removed(conn, &card_ids)
+        })
+        .await
+    }
+
+    async fn get_apartments_for_watchlist(
+        &self,
+        target: &Watchlist,
+        include_removed: bool,
+    ) -> Result<Vec<Apartment>> {
+        let target = target.clone();
+        run(&self.pool, move |conn| {
+            apartment::get_all_for_watchlist(conn, &target, include_removed)
+        })
+        .await
+    }
+
+    async fn get_matching_apartments(&self, target: &Watchlist) -> Result<Vec<Apartment>> {
+        let target = target.clone();
+        run(&self.pool, move |conn| {
+            apartment::get_matching_for_watchlist(conn, &target)
+        })
+        .await
+    }
+
+    async fn get_price_history(&self, card_id: i32) -> Result<Vec<PriceHistoryEntry>> {
+        run(&self.pool, move |conn| {
+            price_history::get_for_card(conn, card_id)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl WatchlistRepository for PgRepository {
+    async fn insert_watchlist(
+        &self,
+        location: Location,
+        chat_id: i64,
+        target_yield: Option<f64>,
+        target_size: SizeTarget,
+    ) -> Result<Watchlist> {
+        run(&self.pool, move |conn| {
+            watchlist::insert(conn, location, chat_id, target_yield, target_size)
+        })
+        .await
+    }
+
+    async fn delete_watchlist(&self, watchlist_id: i32) -> Result<()> {
+        run(&self.pool, move |conn| {
+            watchlist::delete(conn, watchlist_id)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_watchlist(&self, watchlist_id: i32) -> Result<Option<Watchlist>> {
+        run(&self.pool, move |conn| {
+            watchlist::get_watchlist(conn, watchlist_id)
+        })
+        .await
+    }
+
+    async fn get_all_watchlists(&self) -> Result<Vec<Watchlist>> {
+        run(&self.pool, watchlist::get_all).await
+    }
+
+    async fn get_due_watchlists(&self) -> Result<Vec<Watchlist>> {
+        run(&self.pool, watchlist::get_due).await
+    }
+
+    async fn schedule_watchlist_refresh(&self, watchlist_id: i32, at: NaiveDateTime) -> Result<()> {
+        run(&self.pool, move |conn| {
+            watchlist::schedule_refresh(conn, watchlist_id, at)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn mark_watchlist_refreshed(&self, watchlist_id: i32) -> Result<()> {
+        run(&self.pool, move |conn| {
+            watchlist::mark_refreshed(conn, watchlist_id)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn update_watchlist_refresh_interval(
+        &self,
+        watchlist_id: i32,
+        minutes: i32,
+        next_refresh_at: NaiveDateTime,
+    ) -> Result<()> {
+        run(&self.pool, move |conn| {
+            watchlist::update_refresh_interval(conn, watchlist_id, minutes, next_refresh_at)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn update_watchlist_criteria(&self, watchlist: &Watchlist) -> Result<()> {
+        let watchlist = watchlist.clone();
+        run(&self.pool, move |conn| {
+            watchlist::update_criteria(conn, &watchlist)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_watchlists_for_chat(&self, chat_id: i64) -> Result<Vec<Watchlist>> {
+        run(&self.pool, move |conn| {
+            watchlist::get_for_chat(conn, chat_id)
+        })
+        .await
+    }
+
+    async fn pause_watchlists_for_chat(&self, chat_id: i64) -> Result<usize> {
+        run(&self.pool, move |conn| {
+            watchlist::set_paused_for_chat(conn, chat_id, true)
+        })
+        .await
+    }
+
+    async fn resume_watchlists_for_chat(&self, chat_id: i64) -> Result<usize> {
+        run(&self.pool, move |conn| {
+            watchlist::set_paused_for_chat(conn, chat_id, false)
+        })
+        .await
+    }
+
+    async fn get_watchlists_for_locations(&self, locations: &[Location]) -> Result<Vec<Watchlist>> {
+        let locations = locations.to_vec();
+        run(&self.pool, move |conn| {
+            watchlist::get_for_locations(conn, &locations)
+        })
+        .await
+    }
+
+    async fn get_watchlists_for_location(&self, location: &Location) -> Result<Vec<Watchlist>> {
+        let location = location.clone();
+        run(&self.pool, move |conn| {
+            watchlist::get_for_location(conn, &location)
+        })
+        .await
+    }
+
+    async fn update_watchlist_locations(
+        &self,
+        watchlist_id: i32,
+        locations: &Locations,
+    ) -> Result<()> {
+        let locations = locations.clone();
+        run(&self.pool, move |conn| {
+            watchlist::update_locations(conn, watchlist_id, &locations)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_watchlists_for_chat_and_location(
+        &self,
+        chat_id: i64,
+        location_name: &str,
+    ) -> Result<Vec<Watchlist>> {
+        let location_name = location_name.to_string();
+        run(&self.pool, move |conn| {
+            watchlist::get_for_chat_and_location(conn, chat_id, &location_name)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl MatchRepository for PgRepository {
+    async fn insert_match(&self, watchlist_id: i32, card_id: i32) -> Result<()> {
+        run(&self.pool, move |conn| {
+            apartment_watchlist::insert(conn, watchlist_id, card_id)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_match(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+    ) -> Result<Option<WatchlistApartmentIndex>> {
+        run(&self.pool, move |conn| {
+            apartment_watchlist::get_watchlist_apartment_connector(conn, watchlist_id, card_id)
+        })
+        .await
+    }
+
+    async fn get_unsent_matches(&self, watchlist_id: i32) -> Result<Vec<i32>> {
+        run(&self.pool, move |conn| {
+            apartment_watchlist::get_unsent_apartments(conn, watchlist_id)
+        })
+        .await
+    }
+
+    async fn delete_unsent_matches(&self, watchlist_id: i32, card_ids: &[i32]) -> Result<usize> {
+        let card_ids = card_ids.to_vec();
+        run(&self.pool, move |conn| {
+            apartment_watchlist::delete_unsent(conn, watchlist_id, &card_ids)
+        })
+        .await
+    }
+
+    async fn set_match_yields(&self, card_id: i32, yields: &[(i32, f64)]) -> Result<()> {
+        let yields = yields.to_vec();
+        run(&self.pool, move |conn| {
+            apartment_watchlist::set_yields(conn, card_id, &yields)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn clear_match_yields(&self, watchlist_id: i32) -> Result<()> {
+        run(&self.pool, move |conn| {
+            apartment_watchlist::clear_yields(conn, watchlist_id)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_match_sent(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+        price: Option<i32>,
+    ) -> Result<()> {
+        run(&self.pool, move |conn| {
+            apartment_watchlist::set_to_read(conn, watchlist_id, card_id, price)
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobRepository for PgRepository {
+    async fn enqueue_task(&self, task: &MessageTask) -> Result<bool> {
+        let job = InsertableJob::from_task(task)?;
+        run(&self.pool, move |conn| jobs::enqueue(conn, job)).await
+    }
+
+    async fn claim_job(&self) -> Result<Option<Job>> {
+        run(&self.pool, jobs::claim).await
+    }
+
+    async fn complete_job(&self, job_id: i32) -> Result<()> {
+        run(&self.pool, move |conn| jobs::complete(conn, job_id)).await?;
+        Ok(())
+    }
+
+    async fn fail_job(&self, job: &Job, error: &str, retryable: bool) -> Result<bool> {
+        let job = job.clone();
+        let error = error.to_string();
+        run(&self.pool, move |conn| {
+            jobs::fail(conn, &job, &error, retryable)
+        })
+        .await
+    }
+
+    async fn release_stale_jobs(&self, locked_before: NaiveDateTime) -> Result<usize> {
+        run(&self.pool, move |conn| {
+            jobs::release_stale(conn, locked_before)
+        })
+        .await
+    }
+
+    async fn count_jobs_by_status(&self) -> Result<Vec<(String, i64)>> {
+        run(&self.pool, jobs::count_by_status).await
+    }
+
+    async fn get_dead_job_keys(&self, task_type: TaskType) -> Result<HashSet<String>> {
+        let keys = run(&self.pool, move |conn| {
+            jobs::dead_keys(conn, task_type.as_str())
+        })
+        .await?;
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn discard_dead_messages(&self, watchlist_id: i32) -> Result<usize> {
+        let prefix = MessageTask::send_message_key_prefix(watchlist_id);
+        run(&self.pool, move |conn| jobs::delete_dead(conn, &prefix)).await
+    }
+}
+
+#[async_trait]
+impl ApiTokenRepository for PgRepository {
+    async fn replace_api_token(&self, chat_id: i64, token_hash: &str) -> Result<()> {
+        let token_hash = token_hash.to_string();
+        run(&self.pool, move |conn| {
+            api_tokens::replace_for_chat(conn, chat_id, &token_hash)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn use_api_token(&self, token_hash: &str) -> Result<Option<i64>> {
+        let token_hash = token_hash.to_string();
+        run(&self.pool, move |conn| {
+            api_tokens::use_token(conn, &token_hash)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl ApartmentStateRepository for PgRepository {
+    async fn set_apartment_status(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        status: &str,
+    ) -> Result<ApartmentState> {
+        let status = status.to_string();
+        run(&self.pool, move |conn| {
+            apartment_state::set_status(conn, chat_id, card_id, &status)
+        })
+        .await
+    }
+
+    async fn set_apartment_notes(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        notes: Option<&str>,
+    ) -> Result<ApartmentState> {
+        let notes = notes.map(str::to_string);
+        run(&self.pool, move |conn| {
+            apartment_state::set_notes(conn, chat_id, card_id, notes.as_deref())
+        })
+        .await
+    }
+
+    async fn get_apartment_state(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+    ) -> Result<Option<ApartmentState>> {
+        run(&self.pool, move |conn| {
+            apartment_state::get(conn, chat_id, card_id)
+        })
+        .await
+    }
+
+    async fn get_apartment_states(&self, chat_id: i64) -> Result<Vec<ApartmentState>> {
+        run(&self.pool, move |conn| {
+            apartment_state::get_for_chat(conn, chat_id)
+        })
+        .await
+    }
+}
@@ -0,0 +1,36 @@
+use diesel::{prelude::*, result::Error};
+
+use super::schema::apartment_price_history;
+use crate::models::{
+    apartment::Apartment,
+    price_history::{InsertablePriceHistoryEntry, PriceHistoryEntry},
+};
+
+/// Records the current price, maintenance fee and status of the apartment.
+pub fn record(conn: &mut PgConnection, apartment: &Apartment) -> Result<usize, Error> {
+    let entry = InsertablePriceHistoryEntry {
+        card_id: apartment.card_id,
+        price: apartment.price,
+        additional_costs: apartment.additional_costs,
+        status: apartment.status,
+    };
+
+    diesel::insert_into(apartment_price_history::table)
+        .values(entry)
+        .execute(conn)
+}
+
+/// All recorded observations of the card, oldest first.
+pub fn get_for_card(
+    conn: &mut PgConnection,
+    target_card_id: i32,
+) -> Result<Vec<PriceHistoryEntry>, Error> {
+    apartment_price_history::table
+        .filter(apartment_price_history::card_id.eq(target_card_id))
+        .order((
+            apartment_price_history::observed_at.asc(),
+            apartment_price_history::id.asc(),
+        ))
+        .select(PriceHistoryEntry::as_select())
+        .load(conn)
+}
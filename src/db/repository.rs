@@ -0,0 +1,262 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+
+use crate::{
+    models::{
+        apartment::{Apartment, InsertableApartment},
+        apartment_state::ApartmentState,
+        apartment_watchlist_model::WatchlistApartmentIndex,
+        job::Job,
+        price_history::PriceHistoryEntry,
+        watchlist::{Locations, SizeTarget, Watchlist},
+    },
+    oikotie::oikotie::Location,
+    MessageTask, TaskType,
+};
+
+#[async_trait]
+pub trait ApartmentRepository: Send + Sync {
+    /// Inserts the apartment or updates its price, status, rent and yield if it already
+    /// exists, recording a price history entry when the listing changed and re-announcing
+    /// sent matches whose price dropped by their watchlist's minimum. The apartment is
+    /// also matched to each of `matched_watchlists` in the same transaction.
+    async fn upsert_apartment(
+        &self,
+        apartment: InsertableApartment,
+        matched_watchlists: &[i32],
+    ) -> Result<Apartment> {
+        self.upsert_apartment_with_yields(apartment, matched_watchlists, &[])
+            .await
+    }
+
+    /// Like `upsert_apartment`, also storing on the matches the yields that watchlists with
+    /// their own financing give the apartment, as watchlist ids and yields. Price drops are
+    /// re-announced to those watchlists by these yields.
+    async fn upsert_apartment_with_yields(
+        &self,
+        apartment: InsertableApartment,
+        matched_watchlists: &[i32],
+        match_yields: &[(i32, f64)],
+    ) -> Result<Apartment>;
+
+    async fn get_apartment(&self, card_id: i32) -> Result<Option<Apartment>>;
+
+    /// Whether the apartment was updated recently enough to skip re-scoring it.
+    async fn apartment_is_fresh(&self, card_id: i32) -> Result<bool>;
+
+    /// Marks the apartments as sold or removed. Returns how many were active before.
+    async fn mark_apartments_removed(&self, card_ids: &[i32]) -> Result<usize>;
+
+    /// Stored apartments in the watchlist's locations. Removed ones only if `include_removed`.
+    async fn get_apartments_for_watchlist(
+        &self,
+        watchlist: &Watchlist,
+        include_removed: bool,
+    ) -> Result<Vec<Apartment>>;
+
+    /// Active apartments matched to the watchlist that are above its target yield, or all of
+    /// them if it has none.
+    async fn get_matching_apartments(&self, watchlist: &Watchlist) -> Result<Vec<Apartment>>;
+
+    /// Observed prices, maintenance fees and statuses of the card, oldest first.
+    async fn get_price_history(&self, card_id: i32) -> Result<Vec<PriceHistoryEntry>>;
+}
+
+#[async_trait]
+pub trait WatchlistRepository: Send + Sync {
+    async fn insert_watchlist(
+        &self,
+        location: Location,
+        chat_id: i64,
+        target_yield: Option<f64>,
+        target_size: SizeTarget,
+    ) -> Result<Watchlist>;
+
+    async fn delete_watchlist(&self, watchlist_id: i32) -> Result<()>;
+
+    async fn get_watchlist(&self, watchlist_id: i32) -> Result<Option<Watchlist>>;
+
+    async fn get_all_watchlists(&self) -> Result<Vec<Watchlist>>;
+
+    /// Watchlists that are not paused and whose `next_refresh_at` has passed.
+    async fn get_due_watchlists(&self) -> Result<Vec<Watchlist>>;
+
+    async fn schedule_watchlist_refresh(&self, watchlist_id: i32, at: NaiveDateTime) -> Result<()>;
+
+    /// Records a successful update of the watchlist.
+    async fn mark_watchlist_refreshed(&self, watchlist_id: i32) -> Result<()>;
+
+    async fn update_watchlist_refresh_interval(
+        &self,
+        watchlist_id: i32,
+        minutes: i32,
+        next_refresh_at: NaiveDateTime,
+    ) -> Result<()>;
+
+    /// Stores the target yield, size range, other criteria, financing, minimum price drop,
+    /// refresh interval and next refresh of the watchlist.
+    async fn update_watchlist_criteria(&self, watchlist: &Watchlist) -> Result<()>;
+
+    async fn get_watchlists_for_chat(&self, chat_id: i64) -> Result<Vec<Watchlist>>;
+
+    /// Pauses the chat's watchlists, e.g. after the user blocked the bot.
+    /// Returns how many were paused.
+    async fn pause_watchlists_for_chat(&self, chat_id: i64) -> Result<usize>;
+
+    /// Returns how many paused watchlists were resumed.
+    async fn resume_watchlists_for_chat(&self, chat_id: i64) -> Result<usize>;
+
+    /// Every watchlist searching exactly these locations, in any order, oldest first.
+    async fn get_watchlists_for_locations(&self, locations: &[Location]) -> Result<Vec<Watchlist>>;
+
+    /// Every watchlist searching the location, alone or with others, oldest first.
+    async fn get_watchlists_for_location(&self, location: &Location) -> Result<Vec<Watchlist>>;
+
+    /// Replaces the locations of the watchlist.
+    async fn update_watchlist_locations(
+        &self,
+        watchlist_id: i32,
+        locations: &Locations,
+    ) -> Result<()>;
+
+    /// The chat's watchlists searching the named location, among others or alone.
+    async fn get_watchlists_for_chat_and_location(
+        &self,
+        chat_id: i64,
+        location_name: &str,
+    ) -> Result<Vec<Watchlist>>;
+}
+
+#[async_trait]
+pub trait MatchRepository: Send + Sync {
+    /// Matches the apartment to the watchlist. Matching an already matched pair is a no-op.
+    async fn insert_match(&self, watchlist_id: i32, card_id: i32) -> Result<()>;
+
+    async fn get_match(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+    ) -> Result<Option<WatchlistApartmentIndex>>;
+
+    async fn match_exists(&self, watchlist_id: i32, card_id: i32) -> Result<bool> {
+        Ok(self.get_match(watchlist_id, card_id).await?.is_some())
+    }
+
+    /// Card ids matched to the watchlist that have not been sent to the chat yet.
+    async fn get_unsent_matches(&self, watchlist_id: i32) -> Result<Vec<i32>>;
+
+    /// Removes the watchlist's matches to the cards that have not been sent yet. Returns
+    /// how many were removed.
+    async fn delete_unsent_matches(&self, watchlist_id: i32, card_ids: &[i32]) -> Result<usize>;
+
+    /// Stores the yields that watchlists with their own financing give the apartment, as
+    /// watchlist ids and yields. Watchlists not matched to the apartment are skipped.
+    async fn set_match_yields(&self, card_id: i32, yields: &[(i32, f64)]) -> Result<()>;
+
+    /// Forgets the yields stored on the watchlist's matches, e.g. after its financing changed.
+    async fn clear_match_yields(&self, watchlist_id: i32) -> Result<()>;
+
+    /// Marks the match as sent, remembering the price it was sent with.
+    async fn set_match_sent(
+        &self,
+        watchlist_id: i32,
+        card_id: i32,
+        price: Option<i32>,
+    ) -> Result<()>;
+}
+
+/// Durable queue of consumer tasks.
+#[async_trait]
+pub trait JobRepository: Send + Sync {
+    /// Queues the task unless the same task is already pending or running.
+    /// Returns whether it was queued.
+    async fn enqueue_task(&self, task: &MessageTask) -> Result<bool>;
+
+    /// Takes the next due job, if any, and marks it running.
+    async fn claim_job(&self) -> Result<Option<Job>>;
+
+    async fn complete_job(&self, job_id: i32) -> Result<()>;
+
+    /// Retries the job later with backoff, or marks it dead if the error is not `retryable`
+    /// or after too many attempts. Returns whether the job is dead.
+    async fn fail_job(&self, job: &Job, error: &str, retryable: bool) -> Result<bool>;
+
+    /// Re-queues jobs that have been running since before `locked_before`.
+    async fn release_stale_jobs(&self, locked_before: NaiveDateTime) -> Result<usize>;
+
+    /// Amount of jobs per status. Statuses without jobs are left out.
+    async fn count_jobs_by_status(&self) -> Result<Vec<(String, i64)>>;
+
+    /// Dedup keys of the dead jobs of the given type.
+    async fn get_dead_job_keys(&self, task_type: TaskType) -> Result<HashSet<String>>;
+
+    /// Deletes the dead SendMessage jobs of the watchlist, so that the producer queues its
+    /// unsent apartments again. Returns how many were deleted.
+    async fn discard_dead_messages(&self, watchlist_id: i32) -> Result<usize>;
+}
+
+/// Tokens of the HTTP API. Only hashes of the tokens are stored.
+#[async_trait]
+pub trait ApiTokenRepository: Send + Sync {
+    /// Stores a token for the chat, revoking its previous tokens.
+    async fn replace_api_token(&self, chat_id: i64, token_hash: &str) -> Result<()>;
+
+    /// Chat the token with the hash was issued to, if any. Records the use of the token.
+    async fn use_api_token(&self, token_hash: &str) -> Result<Option<i64>>;
+}
+
+/// Where each chat is with listings, e.g. saved or dismissed them, and its notes on them.
+#[async_trait]
+pub trait ApartmentStateRepository: Send + Sync {
+    /// Sets the chat's status of the card, replacing the previous one. Notes are kept.
+    async fn set_apartment_status(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        status: &str,
+    ) -> Result<ApartmentState>;
+
+    /// Sets the chat's notes of the card, replacing the previous ones. Cards without a
+    /// status get `new`.
+    async fn set_apartment_notes(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+        notes: Option<&str>,
+    ) -> Result<ApartmentState>;
+
+    async fn get_apartment_state(
+        &self,
+        chat_id: i64,
+        card_id: i32,
+    ) -> Result<Option<ApartmentState>>;
+
+    /// The chat's states, most recently changed first.
+    async fn get_apartment_states(&self, chat_id: i64) -> Result<Vec<ApartmentState>>;
+}
+
+/// Everything the workers, bot and HTTP API need from storage.
+pub trait Repository:
+    ApartmentRepository
+    + WatchlistRepository
+    + MatchRepository
+    + JobRepository
+    + ApiTokenRepository
+    + ApartmentStateRepository
+{
+}
+
+impl<T> Repository for T where
+    T: ApartmentRepository
+        + WatchlistRepository
+        + MatchRepository
+        + JobRepository
+        + ApiTokenRepository
+        + ApartmentStateRepository
+{
+}
+
+pub type SharedRepository = Arc<dyn Repository>;
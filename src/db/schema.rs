@@ -1,5 +1,15 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        chat_id -> Int8,
+        token_hash -> Text,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
 diesel::table! {
     apartment_watchlist (id) {
         id -> Int4,
@@ -8,6 +18,19 @@ diesel::table! {
         has_been_sent -> Bool,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        sent_price -> Nullable<Int4>,
+        estimated_yield -> Nullable<Float8>,
+    }
+}
+
+diesel::table! {
+    apartment_price_history (id) {
+        id -> Int4,
+        card_id -> Int4,
+        price -> Nullable<Int4>,
+        additional_costs -> Nullable<Int4>,
+        status -> Nullable<Int4>,
+        observed_at -> Timestamptz,
     }
 }
 
@@ -27,24 +50,85 @@ diesel::table! {
         url -> Nullable<Text>,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        build_year -> Nullable<Int4>,
+        floor -> Nullable<Int4>,
+        building_type -> Nullable<Text>,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        rent_lower -> Nullable<Int4>,
+        rent_upper -> Nullable<Int4>,
+        status -> Nullable<Int4>,
+        removed_at -> Nullable<Timestamptz>,
+        debt_share -> Nullable<Int4>,
+        plot_ownership -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    jobs (id) {
+        id -> Int4,
+        job_type -> Text,
+        payload -> Jsonb,
+        dedup_key -> Text,
+        status -> Text,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        run_after -> Timestamptz,
+        locked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_apartment_state (id) {
+        id -> Int4,
+        chat_id -> Int8,
+        card_id -> Int4,
+        status -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        notes -> Nullable<Text>,
     }
 }
 
 diesel::table! {
     watchlists (id) {
         id -> Int4,
-        location_id -> Int4,
-        location_level -> Int4,
-        location_name -> Text,
         chat_id -> Int8,
         target_yield -> Nullable<Float8>,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
         target_size_min -> Nullable<Int4>,
         target_size_max -> Nullable<Int4>,
+        min_price_drop_percent -> Float8,
+        last_refreshed_at -> Nullable<Timestamptz>,
+        next_refresh_at -> Timestamptz,
+        refresh_interval_minutes -> Int4,
+        paused_at -> Nullable<Timestamptz>,
+        min_rooms -> Nullable<Int4>,
+        max_rooms -> Nullable<Int4>,
+        max_price -> Nullable<Int4>,
+        max_fee_per_m2 -> Nullable<Float8>,
+        min_build_year -> Nullable<Int4>,
+        exclude_ground_floor -> Bool,
+        exclude_rented_plot -> Bool,
+        max_debt_share_percent -> Nullable<Float8>,
+        locations -> Jsonb,
+        down_payment_percent -> Nullable<Int4>,
+        loan_years -> Nullable<Int4>,
+        renovation_costs -> Nullable<Int4>,
     }
 }
 
 diesel::joinable!(apartment_watchlist -> watchlists (watchlist_id));
 
-diesel::allow_tables_to_appear_in_same_query!(apartment_watchlist, apartments, watchlists,);
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    apartment_price_history,
+    apartment_watchlist,
+    apartments,
+    jobs,
+    user_apartment_state,
+    watchlists,
+);
@@ -1,143 +1,202 @@
-use std::sync::Arc;
-
-use super::{establish_connection, schema::watchlists, schema::watchlists::dsl::*};
-use crate::config::Config;
-use crate::models::watchlist::{InsertableWatchlist, SizeTarget};
+use super::{schema::watchlists, schema::watchlists::dsl::*};
+use crate::models::watchlist::{InsertableWatchlist, Locations, SizeTarget};
 use crate::{models::watchlist::Watchlist, oikotie::oikotie::Location};
-use anyhow::anyhow;
-use diesel::prelude::*;
+use chrono::NaiveDateTime;
+use diesel::{dsl, prelude::*, result::Error};
 
-use log::error;
-use log::info;
+use tracing::info;
 
 pub fn insert(
-    config: &Arc<Config>,
+    conn: &mut PgConnection,
     location: Location,
     new_chat_id: i64,
     new_target_yield: Option<f64>,
     target_size: SizeTarget,
-) {
-    let mut connection = establish_connection(config);
-
+) -> Result<Watchlist, Error> {
     let watchlist: InsertableWatchlist = InsertableWatchlist {
-        location_id: location.id,
-        location_level: location.level,
-        location_name: location.name,
+        locations: Locations(vec![location]),
         chat_id: new_chat_id,
         target_yield: new_target_yield,
         target_size_min: target_size.min,
         target_size_max: target_size.max,
     };
 
-    match diesel::insert_into(watchlists::table)
+    let created = diesel::insert_into(watchlists::table)
         .values(watchlist)
-        .execute(&mut connection)
-    {
-        Ok(n) => {
-            println!("Inserted {:?} rows into watchlist table", n);
-        }
-        Err(e) => {
-            println!("Error: {:?}", e);
-        }
-    }
-}
+        .returning(Watchlist::as_returning())
+        .get_result(conn)?;
 
-pub fn delete(config: &Arc<Config>, watchlist_id: i32) {
-    let mut connection = establish_connection(config);
+    info!("Inserted watchlist {} into watchlist table", created.id);
+    Ok(created)
+}
 
-    let deletion = diesel::delete(watchlists.filter(id.eq(watchlist_id))).execute(&mut connection);
+pub fn delete(conn: &mut PgConnection, watchlist_id: i32) -> Result<usize, Error> {
+    let n = diesel::delete(watchlists.filter(id.eq(watchlist_id))).execute(conn)?;
 
-    match deletion {
-        Ok(a) => info!("Deleted row in watchlists with ID: {:?}", a),
-        Err(e) => error!("Error while deleting row in watchlists: {:?}", e),
-    }
+    info!("Deleted row in watchlists with ID: {:?}", watchlist_id);
+    Ok(n)
 }
 
-pub fn get_watchlist(config: &Arc<Config>, watchlist_id: i32) -> Result<Watchlist, anyhow::Error> {
-    let conn = &mut establish_connection(config);
-
-    let watchlist_from_db = watchlists::table
+pub fn get_watchlist(
+    conn: &mut PgConnection,
+    watchlist_id: i32,
+) -> Result<Option<Watchlist>, Error> {
+    watchlists::table
         .filter(watchlists::id.eq(watchlist_id))
         .select(Watchlist::as_select())
         .first(conn)
-        .optional();
+        .optional()
+}
 
-    match watchlist_from_db {
-        Ok(Some(w)) => Ok(w),
-        Ok(None) => Err(anyhow!("Error: Did not find a watchlist")),
-        Err(_) => Err(anyhow!("Error: Did not find a watchlist")),
-    }
+/// Watchlists whose next refresh is due.
+pub fn get_due(conn: &mut PgConnection) -> Result<Vec<Watchlist>, Error> {
+    watchlists
+        .filter(next_refresh_at.le(dsl::now))
+        .filter(paused_at.is_null())
+        .order(next_refresh_at.asc())
+        .select(Watchlist::as_select())
+        .load(conn)
 }
 
-pub async fn update_yield(
-    config: &Arc<Config>,
+pub fn schedule_refresh(
+    conn: &mut PgConnection,
     target_id: i32,
-    new_yield: f64,
-) -> Result<(), anyhow::Error> {
-    let connection = &mut establish_connection(config);
-
+    at: NaiveDateTime,
+) -> Result<usize, Error> {
     diesel::update(watchlists)
         .filter(id.eq(target_id))
-        .set(target_yield.eq(new_yield))
-        .execute(connection)?;
-
-    Ok(())
+        .set(next_refresh_at.eq(at))
+        .execute(conn)
 }
 
-pub fn get_all(config: &Arc<Config>) -> Vec<Watchlist> {
-    let connection = &mut establish_connection(config);
+pub fn mark_refreshed(conn: &mut PgConnection, target_id: i32) -> Result<usize, Error> {
+    diesel::update(watchlists)
+        .filter(id.eq(target_id))
+        .set(last_refreshed_at.eq(dsl::now))
+        .execute(conn)
+}
 
-    let all_watchlists: Result<Vec<Watchlist>, diesel::result::Error> = watchlists::table
-        .select(watchlists::table::all_columns())
-        .get_results(connection);
+pub fn update_refresh_interval(
+    conn: &mut PgConnection,
+    target_id: i32,
+    minutes: i32,
+    next_refresh: NaiveDateTime,
+) -> Result<usize, Error> {
+    diesel::update(watchlists)
+        .filter(id.eq(target_id))
+        .set((
+            refresh_interval_minutes.eq(minutes),
+            next_refresh_at.eq(next_refresh),
+        ))
+        .execute(conn)
+}
 
-    match all_watchlists {
-        Ok(w) => w,
-        Err(e) => {
-            println!("Error: {:?}", e);
-            Vec::new()
-        }
-    }
+/// Stores the criteria of the watchlist, including the optional ones, and when it is
+/// refreshed next.
+pub fn update_criteria(conn: &mut PgConnection, watchlist: &Watchlist) -> Result<usize, Error> {
+    diesel::update(watchlists)
+        .filter(id.eq(watchlist.id))
+        .set((
+            target_yield.eq(watchlist.target_yield),
+            target_size_min.eq(watchlist.target_size_min),
+            target_size_max.eq(watchlist.target_size_max),
+            min_price_drop_percent.eq(watchlist.min_price_drop_percent),
+            refresh_interval_minutes.eq(watchlist.refresh_interval_minutes),
+            next_refresh_at.eq(watchlist.next_refresh_at),
+            updated_at.eq(dsl::now),
+            &watchlist.criteria,
+            &watchlist.financing,
+        ))
+        .execute(conn)
 }
 
-pub fn get_for_chat(config: &Arc<Config>, id_: i64) -> Vec<Watchlist> {
-    let connection = &mut establish_connection(config);
+pub fn get_all(conn: &mut PgConnection) -> Result<Vec<Watchlist>, Error> {
+    watchlists::table
+        .select(Watchlist::as_select())
+        .get_results(conn)
+}
 
-    let r: Vec<Watchlist> = watchlists
+pub fn get_for_chat(conn: &mut PgConnection, id_: i64) -> Result<Vec<Watchlist>, Error> {
+    watchlists
         .filter(chat_id.eq(id_))
         .select(Watchlist::as_select())
-        .load(connection)
-        .expect("Error loading watchlists for chat}");
-
-    r
+        .load(conn)
 }
 
-pub fn get_for_chat_and_location(
-    config: &Arc<Config>,
-    id_: i64,
-    location: &String,
-) -> Vec<Watchlist> {
-    let connection = &mut establish_connection(config);
+/// Watchlists searching exactly the given locations.
+pub fn get_for_locations(
+    conn: &mut PgConnection,
+    target_locations: &[Location],
+) -> Result<Vec<Watchlist>, Error> {
+    // Locations are compared by id and level, names may have changed on Oikotie
+    let keys: Vec<serde_json::Value> = target_locations
+        .iter()
+        .map(|location| serde_json::json!({ "id": location.id, "level": location.level }))
+        .collect();
+    let key = Locations(target_locations.to_vec()).key();
+
+    let candidates = watchlists
+        .filter(locations.contains(serde_json::Value::Array(keys)))
+        .order(id.asc())
+        .select(Watchlist::as_select())
+        .load(conn)?;
+    Ok(candidates
+        .into_iter()
+        .filter(|watchlist| watchlist.locations.key() == key)
+        .collect())
+}
 
-    let r: Vec<Watchlist> = watchlists
-        .filter(chat_id.eq(id_))
-        .filter(location_name.eq(location))
+/// Watchlists searching the given location, alone or with others.
+pub fn get_for_location(
+    conn: &mut PgConnection,
+    location: &Location,
+) -> Result<Vec<Watchlist>, Error> {
+    let key = serde_json::json!([{ "id": location.id, "level": location.level }]);
+    watchlists
+        .filter(locations.contains(key))
+        .order(id.asc())
         .select(Watchlist::as_select())
-        .load(connection)
-        .expect("Error loading watchlists for chat}");
+        .load(conn)
+}
 
-    r
+pub fn update_locations(
+    conn: &mut PgConnection,
+    target_id: i32,
+    new_locations: &Locations,
+) -> Result<usize, Error> {
+    diesel::update(watchlists)
+        .filter(id.eq(target_id))
+        .set((locations.eq(new_locations), updated_at.eq(dsl::now)))
+        .execute(conn)
 }
 
-pub fn check_chat(config: &Arc<Config>, chat_id_to_check: i64, watchlist: i32) -> bool {
-    let con = &mut establish_connection(config);
+/// Pauses or resumes the chat's watchlists. Returns how many changed.
+pub fn set_paused_for_chat(
+    conn: &mut PgConnection,
+    target_chat_id: i64,
+    paused: bool,
+) -> Result<usize, Error> {
+    let chat_watchlists = watchlists.filter(chat_id.eq(target_chat_id));
+    if paused {
+        diesel::update(chat_watchlists.filter(paused_at.is_null()))
+            .set(paused_at.eq(dsl::now))
+            .execute(conn)
+    } else {
+        diesel::update(chat_watchlists.filter(paused_at.is_not_null()))
+            .set(paused_at.eq(None::<NaiveDateTime>))
+            .execute(conn)
+    }
+}
 
-    let watchlist_from_db: Vec<Watchlist> = watchlists
-        .filter(id.eq(watchlist))
-        .filter(chat_id.eq(chat_id_to_check))
+pub fn get_for_chat_and_location(
+    conn: &mut PgConnection,
+    id_: i64,
+    location: &str,
+) -> Result<Vec<Watchlist>, Error> {
+    watchlists
+        .filter(chat_id.eq(id_))
+        .filter(locations.contains(serde_json::json!([{ "name": location }])))
+        .order(id.asc())
         .select(Watchlist::as_select())
-        .load(con)
-        .expect("Error loading watchlists for chat}");
-
-    !watchlist_from_db.is_empty()
+        .load(conn)
 }
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use teloxide::{ApiError, RequestError};
+
+/// Shown to users when the actual error is not meant for them.
+pub const GENERIC_USER_MESSAGE: &str = "Something went wrong, please try again later.";
+
+/// Error classified by how it should be handled.
+///
+/// Errors are passed around as `anyhow::Error`, so functions can return an `ApatoError` where
+/// the kind of failure is known and keep using `?` everywhere else.
+#[derive(Debug)]
+pub enum ApatoError {
+    /// Temporary failure, e.g. a network error or a rate limit. Worth retrying later.
+    Transient(anyhow::Error),
+    /// Retrying cannot help, e.g. a malformed job or a chat that blocked the bot.
+    Permanent(anyhow::Error),
+    /// Caused by the user's input. The message is meant to be shown to them.
+    UserFacing(String),
+    /// The user asked for something that does not exist or is not theirs. The message is
+    /// meant to be shown to them.
+    NotFound(String),
+}
+
+impl ApatoError {
+    pub fn user_facing(message: impl Into<String>) -> Self {
+        ApatoError::UserFacing(message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApatoError::NotFound(message.into())
+    }
+
+    /// Classifies the error. Errors that have not been classified are treated as transient.
+    pub fn classify(err: anyhow::Error) -> Self {
+        match err.downcast::<ApatoError>() {
+            Ok(classified) => classified,
+            Err(err) => match err.downcast::<RequestError>() {
+                Ok(request_error) => request_error.into(),
+                Err(err) => ApatoError::Transient(err),
+            },
+        }
+    }
+
+    /// Label of the error kind in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApatoError::Transient(_) => "transient",
+            ApatoError::Permanent(_) => "permanent",
+            ApatoError::UserFacing(_) => "user_facing",
+            ApatoError::NotFound(_) => "not_found",
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ApatoError::Transient(_))
+    }
+}
+
+impl fmt::Display for ApatoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApatoError::Transient(err) => write!(f, "{:#}", err),
+            ApatoError::Permanent(err) => write!(f, "{:#}", err),
+            ApatoError::UserFacing(message) | ApatoError::NotFound(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ApatoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApatoError::Transient(err) | ApatoError::Permanent(err) => Some(err.as_ref()),
+            ApatoError::UserFacing(_) | ApatoError::NotFound(_) => None,
+        }
+    }
+}
+
+impl From<RequestError> for ApatoError {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::RetryAfter(_)
+            | RequestError::Network(_)
+            | RequestError::Io(_)
+            | RequestError::InvalidJson { .. } => ApatoError::Transient(err.into()),
+            RequestError::Api(_) | RequestError::MigrateToChatId(_) => {
+                ApatoError::Permanent(err.into())
+            }
+        }
+    }
+}
+
+/// Whether Telegram refuses to deliver messages to the chat until the user acts, e.g. after
+/// they blocked the bot or deleted the chat.
+pub fn chat_unreachable(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::Api(
+            ApiError::BotBlocked
+                | ApiError::ChatNotFound
+                | ApiError::UserDeactivated
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::GroupDeactivated
+                | ApiError::CantInitiateConversation
+                | ApiError::CantTalkWithBots
+                | ApiError::NotEnoughRightsToPostMessages
+        )
+    )
+}
+
+/// Message to show to the user for the error: the message of user-facing and not found
+/// errors, a generic one otherwise.
+pub fn user_message(err: &anyhow::Error) -> String {
+    match err.downcast_ref::<ApatoError>() {
+        Some(ApatoError::UserFacing(message) | ApatoError::NotFound(message)) => message.clone(),
+        _ => GENERIC_USER_MESSAGE.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn unclassified_errors_are_transient() {
+        assert!(ApatoError::classify(anyhow!("connection reset")).is_retryable());
+    }
+
+    #[test]
+    fn classification_survives_anyhow() {
+        let err: anyhow::Error = ApatoError::Permanent(anyhow!("bad payload")).into();
+        assert!(!ApatoError::classify(err).is_retryable());
+    }
+
+    #[test]
+    fn telegram_errors_are_classified() {
+        let blocked = RequestError::Api(ApiError::BotBlocked);
+        assert!(chat_unreachable(&blocked));
+        assert!(!ApatoError::classify(blocked.into()).is_retryable());
+
+        let rate_limited = RequestError::RetryAfter(std::time::Duration::from_secs(3));
+        assert!(!chat_unreachable(&rate_limited));
+        assert!(ApatoError::classify(rate_limited.into()).is_retryable());
+    }
+
+    #[test]
+    fn only_user_facing_messages_reach_users() {
+        let user_facing: anyhow::Error = ApatoError::user_facing("No such watchlist").into();
+        assert_eq!(user_message(&user_facing), "No such watchlist");
+        let not_found: anyhow::Error = ApatoError::not_found("Apartment 1 not found").into();
+        assert_eq!(user_message(&not_found), "Apartment 1 not found");
+        assert_eq!(
+            user_message(&anyhow!("could not connect to server")),
+            GENERIC_USER_MESSAGE
+        );
+    }
+}
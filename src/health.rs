@@ -0,0 +1,336 @@
+//! Liveness of the workers and readiness of the service, served by the HTTP API at
+//! `/healthz` and `/readyz`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use teloxide::{requests::Requester, Bot};
+
+use crate::{
+    config::Config, db::repository::SharedRepository, ml_client, models::job::JobStatus,
+    oikotie::tokens, producer::apato_producer::STALE_JOB_MINUTES,
+};
+
+/// Checks taking longer than this fail.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long results of checks against external services are reused, so frequent probes do
+/// not add load on them.
+const EXTERNAL_CHECK_TTL: Duration = Duration::from_secs(60);
+/// Tokens are fetched by every location update as well, so they are checked less often.
+const OIKOTIE_CHECK_TTL: Duration = Duration::from_secs(300);
+/// Slack on top of the worker intervals before a heartbeat counts as missed.
+const HEARTBEAT_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Degraded,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Failing,
+    /// Not configured, e.g. the ML service. Does not affect readiness.
+    Disabled,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn ok(detail: Option<String>) -> Self {
+        Check {
+            status: CheckStatus::Ok,
+            detail,
+        }
+    }
+
+    pub fn failing(detail: impl Into<String>) -> Self {
+        Check {
+            status: CheckStatus::Failing,
+            detail: Some(detail.into()),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Check {
+            status: CheckStatus::Disabled,
+            detail: None,
+        }
+    }
+}
+
+/// Status document of `/readyz`.
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = if checks.values().any(|c| c.status == CheckStatus::Failing) {
+            Status::Degraded
+        } else {
+            Status::Ok
+        };
+        Readiness { status, checks }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.status == Status::Ok
+    }
+}
+
+/// Heartbeats of the producer and consumers, and recent results of the external checks.
+///
+/// The workers report a heartbeat on every iteration of their loops. A worker that stops
+/// reporting is stuck, so the service is not ready and should be restarted.
+#[derive(Default)]
+pub struct Health {
+    producer: Mutex<Option<Instant>>,
+    consumers: Mutex<BTreeMap<i32, Instant>>,
+    checks: Mutex<HashMap<&'static str, (Instant, Check)>>,
+}
+
+impl Health {
+    pub fn producer_heartbeat(&self) {
+        *self.producer.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn producer_stopped(&self) {
+        *self.producer.lock().unwrap() = None;
+    }
+
+    pub fn consumer_heartbeat(&self, consumer_number: i32) {
+        self.consumers
+            .lock()
+            .unwrap()
+            .insert(consumer_number, Instant::now());
+    }
+
+    pub fn consumer_stopped(&self, consumer_number: i32) {
+        self.consumers.lock().unwrap().remove(&consumer_number);
+    }
+
+    /// Runs every check. The external services are only contacted if their last result is
+    /// older than its TTL.
+    pub async fn readiness(
+        &self,
+        config: &Config,
+        repo: &SharedRepository,
+        bot: &Bot,
+    ) -> Readiness {
+        let ml_service = async {
+            if config.ml_service_url.is_none() {
+                return Check::disabled();
+            }
+            self.cached("ml_service", EXTERNAL_CHECK_TTL, async {
+                ml_client::check_health(config).await.map(|()| None)
+            })
+            .await
+        };
+        let (database, oikotie, telegram, ml_service) = tokio::join!(
+            run_check(check_database(repo)),
+            self.cached("oikotie", OIKOTIE_CHECK_TTL, check_oikotie()),
+            self.cached("telegram", EXTERNAL_CHECK_TTL, check_telegram(bot)),
+            ml_service,
+        );
+
+        let now = Instant::now();
+        Readiness::new(BTreeMap::from([
+            ("database", database),
+            ("oikotie", oikotie),
+            ("telegram", telegram),
+            ("ml_service", ml_service),
+            ("producer", self.producer_check(config, now)),
+            ("consumers", self.consumers_check(config, now)),
+        ]))
+    }
+
+    /// The result of the check named `name`, reused for `ttl`.
+    async fn cached(
+        &self,
+        name: &'static str,
+        ttl: Duration,
+        check: impl Future<Output = Result<Option<String>>>,
+    ) -> Check {
+        if let Some((at, check)) = self.checks.lock().unwrap().get(name) {
+            if at.elapsed() < ttl {
+                return check.clone();
+            }
+        }
+        let result = run_check(check).await;
+        self.record(name, result.clone());
+        result
+    }
+
+    pub(crate) fn record(&self, name: &'static str, check: Check) {
+        self.checks
+            .lock()
+            .unwrap()
+            .insert(name, (Instant::now(), check));
+    }
+
+    /// The producer sleeps `producer_timeout_seconds` between its runs.
+    fn producer_check(&self, config: &Config, now: Instant) -> Check {
+        let max_age = 2 * Duration::from_secs(config.producer_timeout_seconds.into());
+        match *self.producer.lock().unwrap() {
+            None => Check::failing("Producer is not running"),
+            Some(at) => heartbeat_check(at, max_age + HEARTBEAT_GRACE, now),
+        }
+    }
+
+    /// Idle consumers wake up every `consumer_timeout_seconds`. Busy ones report before each
+    /// job, which may run until the producer re-queues it as stale.
+    fn consumers_check(&self, config: &Config, now: Instant) -> Check {
+        let max_age = Duration::from_secs(config.consumer_timeout_seconds.into())
+            + Duration::from_secs(STALE_JOB_MINUTES as u64 * 60)
+            + HEARTBEAT_GRACE;
+        let consumers = self.consumers.lock().unwrap();
+        if consumers.is_empty() {
+            return Check::failing("No consumers are running");
+        }
+
+        let stuck: Vec<String> = consumers
+            .iter()
+            .filter(|(_, &at)| now.saturating_duration_since(at) > max_age)
+            .map(|(consumer_number, _)| consumer_number.to_string())
+            .collect();
+        if stuck.is_empty() {
+            Check::ok(Some(format!("{} consumers running", consumers.len())))
+        } else {
+            Check::failing(format!(
+                "Consumers {} have not reported for over {}s",
+                stuck.join(", "),
+                max_age.as_secs()
+            ))
+        }
+    }
+}
+
+fn heartbeat_check(at: Instant, max_age: Duration, now: Instant) -> Check {
+    let age = now.saturating_duration_since(at);
+    let detail = format!("Last heartbeat {}s ago", age.as_secs());
+    if age > max_age {
+        Check::failing(detail)
+    } else {
+        Check::ok(Some(detail))
+    }
+}
+
+async fn run_check(check: impl Future<Output = Result<Option<String>>>) -> Check {
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(detail)) => Check::ok(detail),
+        Ok(Err(e)) => Check::failing(format!("{:#}", e)),
+        Err(_) => Check::failing(format!("Timed out after {}s", CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+async fn check_database(repo: &SharedRepository) -> Result<Option<String>> {
+    let pending = repo
+        .count_jobs_by_status()
+        .await?
+        .into_iter()
+        .find(|(status, _)| status == JobStatus::PENDING)
+        .map_or(0, |(_, n)| n);
+    Ok(Some(format!("{} pending jobs", pending)))
+}
+
+async fn check_oikotie() -> Result<Option<String>> {
+    tokens::get_tokens()
+        .await
+        .map(|_| None)
+        .ok_or_else(|| anyhow!("Failed to fetch Oikotie tokens"))
+}
+
+async fn check_telegram(bot: &Bot) -> Result<Option<String>> {
+    let me = bot.get_me().await?;
+    Ok(Some(format!("@{}", me.username())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    #[test]
+    fn workers_without_heartbeats_are_not_ready() {
+        let config = create_test_config();
+        let health = Health::default();
+        let now = Instant::now();
+
+        assert_eq!(
+            health.producer_check(&config, now).status,
+            CheckStatus::Failing
+        );
+        assert_eq!(
+            health.consumers_check(&config, now).status,
+            CheckStatus::Failing
+        );
+
+        health.producer_heartbeat();
+        health.consumer_heartbeat(0);
+        assert_eq!(health.producer_check(&config, now).status, CheckStatus::Ok);
+        assert_eq!(health.consumers_check(&config, now).status, CheckStatus::Ok);
+
+        health.consumer_stopped(0);
+        assert_eq!(
+            health.consumers_check(&config, now).status,
+            CheckStatus::Failing
+        );
+    }
+
+    #[test]
+    fn missed_heartbeats_are_reported() {
+        let config = create_test_config();
+        let health = Health::default();
+        health.producer_heartbeat();
+        health.consumer_heartbeat(0);
+        health.consumer_heartbeat(1);
+
+        // Producer interval of the test config is 60s
+        let later = Instant::now() + Duration::from_secs(200);
+        assert_eq!(
+            health.producer_check(&config, later).status,
+            CheckStatus::Failing
+        );
+        assert_eq!(
+            health.consumers_check(&config, later).status,
+            CheckStatus::Ok
+        );
+
+        let much_later = Instant::now() + Duration::from_secs(3600);
+        let check = health.consumers_check(&config, much_later);
+        assert_eq!(check.status, CheckStatus::Failing);
+        assert!(check.detail.unwrap().starts_with("Consumers 0, 1"));
+    }
+
+    #[test]
+    fn disabled_checks_do_not_degrade_readiness() {
+        let readiness = Readiness::new(BTreeMap::from([
+            ("database", Check::ok(None)),
+            ("ml_service", Check::disabled()),
+        ]));
+        assert!(readiness.is_ready());
+
+        let readiness = Readiness::new(BTreeMap::from([
+            ("database", Check::failing("connection refused")),
+            ("ml_service", Check::disabled()),
+        ]));
+        assert_eq!(readiness.status, Status::Degraded);
+    }
+}
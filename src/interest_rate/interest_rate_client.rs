@@ -81,7 +81,7 @@ pub async fn get_interest_rate(config: &Arc<Config>) -> Result<f64> {
 
     api_response
         .mortgages
-        .get(0)
+        .first()
         .map(|mortgage| mortgage.interest_rate)
         .ok_or_else(|| anyhow!("Nordea API returned no mortgage rates"))
 }
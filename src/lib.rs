@@ -1,30 +1,104 @@
-use models::{apartment::Apartment, watchlist::Watchlist};
 use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use oikotie::oikotie::Location;
 
 pub mod bot;
 pub mod config;
 pub mod consumer;
 pub mod db;
+pub mod errors;
+pub mod health;
 pub mod interest_rate;
 pub mod logger;
+pub mod metrics;
 pub mod ml_client;
 pub mod models;
 pub mod oikotie;
 pub mod producer;
 pub mod services;
+#[cfg(test)]
+pub(crate) mod test_support;
 pub mod web;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TaskType {
-    UpdateWatchlist,
+    UpdateLocation,
     SendMessage,
 }
 
-#[derive(Clone)]
-pub struct MessageTask {
-    task_type: TaskType,
-    watchlist: Watchlist,
-    apartment: Option<Apartment>,
+impl TaskType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskType::UpdateLocation => "update_location",
+            TaskType::SendMessage => "send_message",
+        }
+    }
+}
+
+/// Work for the consumers. Queued as a job in the `jobs` table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageTask {
+    /// Fetches the listings of the locations once for every watchlist searching them.
+    UpdateLocation {
+        locations: Vec<Location>,
+    },
+    SendMessage {
+        watchlist_id: i32,
+        card_id: i32,
+    },
+}
+
+impl MessageTask {
+    /// The locations are sorted, so the same locations are only queued once.
+    pub fn update_location(locations: &[Location]) -> Self {
+        let mut locations = locations.to_vec();
+        locations.sort_by_key(|location| (location.id, location.level));
+        MessageTask::UpdateLocation { locations }
+    }
+
+    pub fn send_message(watchlist_id: i32, card_id: i32) -> Self {
+        MessageTask::SendMessage {
+            watchlist_id,
+            card_id,
+        }
+    }
+
+    pub fn task_type(&self) -> TaskType {
+        match self {
+            MessageTask::UpdateLocation { .. } => TaskType::UpdateLocation,
+            MessageTask::SendMessage { .. } => TaskType::SendMessage,
+        }
+    }
+
+    /// Tasks with the same key are only queued once at a time.
+    pub fn dedup_key(&self) -> String {
+        match self {
+            MessageTask::UpdateLocation { locations } => format!(
+                "{}:{}",
+                self.task_type().as_str(),
+                locations
+                    .iter()
+                    .map(|location| format!("{}:{}", location.id, location.level))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            MessageTask::SendMessage {
+                watchlist_id,
+                card_id,
+            } => format!(
+                "{}{}",
+                Self::send_message_key_prefix(*watchlist_id),
+                card_id
+            ),
+        }
+    }
+
+    /// Start of the dedup keys of the SendMessage tasks of the watchlist.
+    pub fn send_message_key_prefix(watchlist_id: i32) -> String {
+        format!("{}:{}:", TaskType::SendMessage.as_str(), watchlist_id)
+    }
 }
 
 #[derive(PartialEq)]
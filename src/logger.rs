@@ -1,19 +1,90 @@
-use std::time::SystemTime;
-
-pub fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{} {} {}] {}",
-                humantime::format_rfc3339_seconds(SystemTime::now()),
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .chain(fern::log_file("output.log")?)
-        .apply()?;
-    Ok(())
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::config::Config;
+
+/// Used when neither `RUST_LOG` nor `log_level` is set.
+const DEFAULT_LOG_LEVEL: &str = "info,apato=debug";
+const LOG_FILE_PREFIX: &str = "apato.log";
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, one line per event.
+    #[default]
+    Pretty,
+    /// One JSON object per event, including the fields of the enclosing spans.
+    Json,
+}
+
+/// How often the log file is rotated.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// Installs the global subscriber, logging to stdout and, if `log_dir` is set, to a rotated
+/// file. Records of crates using `log` are forwarded to it as well.
+///
+/// The returned guard flushes the file on drop, so it has to be kept alive until exit.
+pub fn setup_logger(config: &Config) -> Result<Option<WorkerGuard>> {
+    let (file_writer, guard) = match &config.log_dir {
+        Some(dir) => {
+            let appender = match config.log_rotation {
+                LogRotation::Hourly => rolling::hourly(dir, LOG_FILE_PREFIX),
+                LogRotation::Daily => rolling::daily(dir, LOG_FILE_PREFIX),
+                LogRotation::Never => rolling::never(dir, LOG_FILE_PREFIX),
+            };
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    let stdout = format_layer(config.log_format, std::io::stdout, true);
+    let file = file_writer.map(|writer| format_layer(config.log_format, writer, false));
+
+    tracing_subscriber::registry()
+        .with(level_filter(config)?)
+        .with(stdout)
+        .with(file)
+        .try_init()
+        .context("Failed to install the logger")?;
+    Ok(guard)
+}
+
+/// `RUST_LOG` takes precedence over the configured level.
+fn level_filter(config: &Config) -> Result<EnvFilter> {
+    if let Ok(directives) = std::env::var(EnvFilter::DEFAULT_ENV) {
+        return EnvFilter::try_new(&directives)
+            .with_context(|| format!("Invalid RUST_LOG: {}", directives));
+    }
+    let directives = config.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL);
+    EnvFilter::try_new(directives).with_context(|| format!("Invalid log_level: {}", directives))
+}
+
+fn format_layer<S, W>(
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Pretty => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
 }
@@ -2,69 +2,96 @@ extern crate chrono;
 extern crate diesel;
 extern crate tokio;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use apato::{
     bot::bot::ApatoTelegramBot,
     config::{self, Config},
-    consumer::apato_consumer::Consumer,
+    consumer::apato_consumer::{Consumer, DEFAULT_CONSUMER_COUNT},
+    db::{self, migrations, postgres::PgRepository, repository::SharedRepository, DbPool},
+    health::Health,
     logger::setup_logger,
     producer::apato_producer::Producer,
+    services::sessions::RedeemedLinks,
     web::{start_http_server, AppState},
-    MessageTask,
 };
 use futures::future::TryJoinAll;
-use log::{error, info};
 use signal_hook::{
     consts::{SIGINT, SIGTERM},
     iterator::Signals,
 };
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
+use tracing::{error, info};
 
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Notify};
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u32 = 60;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_logger()?;
     let config: Arc<Config> = Arc::new(config::read_config());
-    let consumer_amount = 6;
+    let _log_guard = setup_logger(&config)?;
+    let pool = db::create_pool(&config)?;
 
-    let (producer_sender, consumer_reciever) = async_channel::unbounded::<MessageTask>();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        return run_migrate_command(&pool, args.get(1).map(String::as_str)).await;
+    }
+
+    let migration_mode = config.migration_mode;
+    if let Err(err) = db::run(&pool, move |conn| migrations::prepare(conn, migration_mode)).await {
+        error!("Refusing to start: {:#}", err);
+        std::process::exit(1);
+    }
+    let repo: SharedRepository = Arc::new(PgRepository::new(pool));
+    let consumer_amount = config.consumer_count.unwrap_or(DEFAULT_CONSUMER_COUNT);
 
     let (shutdown_tx, shutdown_rx) = broadcast::channel::<()>(1);
     let shutdown = Arc::new(AtomicBool::new(false));
+    let jobs_ready = Arc::new(Notify::new());
+    let health = Arc::new(Health::default());
 
-    let bot = ApatoTelegramBot::new(config.clone()).await?;
+    let bot = ApatoTelegramBot::new(config.clone(), repo.clone()).await?;
 
     let producer_handle = {
         let shutdown = shutdown.clone();
         let config = config.clone();
-        let tg_bot = bot.tg.clone();
+        let repo = repo.clone();
+        let jobs_ready = jobs_ready.clone();
+        let health = health.clone();
 
         tokio::task::spawn(async move {
-            Producer::run(&config, shutdown, producer_sender, tg_bot, shutdown_rx).await
+            Producer::run(&config, repo, shutdown, shutdown_rx, jobs_ready, health).await
         })
     };
 
+    info!("Starting {} consumers", consumer_amount);
     let mut consumer_handles = Vec::new();
-    for consumer in 0..consumer_amount {
+    for consumer in 0..consumer_amount as i32 {
         let consumer_handle = {
             let shutdown = shutdown.clone();
             let tg_bot = bot.tg.clone();
             let config_clone = config.clone();
+            let repo_clone = repo.clone();
             let shutdown_rx_clone = shutdown_tx.subscribe();
-            let consumer_reciever = consumer_reciever.clone();
+            let jobs_ready = jobs_ready.clone();
+            let health = health.clone();
             tokio::task::spawn(async move {
                 Consumer::run(
                     &config_clone,
-                    consumer_reciever,
+                    repo_clone,
                     shutdown,
                     shutdown_rx_clone,
+                    jobs_ready,
                     tg_bot,
                     consumer,
+                    health,
                 )
                 .await
             })
@@ -72,16 +99,51 @@ async fn main() -> Result<()> {
         consumer_handles.push(consumer_handle)
     }
 
+    let tg_bot = bot.tg.clone();
     let (bot_handle, bot_shutdown_token) = bot.spawn();
 
     let http_handle = {
         let state = AppState {
             config: config.clone(),
+            repo: repo.clone(),
+            bot: tg_bot,
+            health: health.clone(),
+            redeemed_links: Arc::new(RedeemedLinks::default()),
         };
         let http_shutdown = shutdown_tx.subscribe();
         tokio::spawn(async move { start_http_server(state, http_shutdown).await })
     };
 
+    // Consumers finish their current jobs on shutdown, for at most the shutdown timeout.
+    // Jobs still running after it are re-queued as stale on a later start.
+    let consumers_handle = {
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        let timeout = Duration::from_secs(
+            config
+                .shutdown_timeout_seconds
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS)
+                .into(),
+        );
+        let join_consumer_handles = consumer_handles.into_iter().collect::<TryJoinAll<_>>();
+
+        async move {
+            tokio::pin!(join_consumer_handles);
+            tokio::select! {
+                result = &mut join_consumer_handles => result,
+                _ = shutdown_rx.recv() => {
+                    info!("Waiting up to {:?} for consumers to finish their jobs", timeout);
+                    match tokio::time::timeout(timeout, &mut join_consumer_handles).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            error!("Consumers did not finish their jobs in {:?}", timeout);
+                            Ok(Vec::new())
+                        }
+                    }
+                }
+            }
+        }
+    };
+
     {
         let shutdown = shutdown.clone();
         std::thread::spawn(move || {
@@ -102,16 +164,42 @@ async fn main() -> Result<()> {
         });
     }
 
-    let join_consumer_handles = consumer_handles.into_iter().collect::<TryJoinAll<_>>();
-
-    if let Err(err) = tokio::try_join!(
-        producer_handle,
-        join_consumer_handles,
-        bot_handle,
-        http_handle,
-    ) {
+    if let Err(err) = tokio::try_join!(producer_handle, consumers_handle, bot_handle, http_handle,)
+    {
         error!("Error: {:?}", err)
     }
 
     Ok(())
 }
+
+/// Handles `apato migrate <up|down|status>`.
+async fn run_migrate_command(pool: &DbPool, action: Option<&str>) -> Result<()> {
+    match action {
+        Some("up") => {
+            let applied = db::run(pool, migrations::run_pending).await?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        }
+        Some("down") => {
+            let reverted = db::run(pool, migrations::revert_last).await?;
+            println!("Reverted {}", reverted);
+        }
+        Some("status") => {
+            for migration in db::run(pool, migrations::status).await? {
+                let state = if migration.applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{} {}", migration.version, state);
+            }
+        }
+        _ => return Err(anyhow!("Usage: apato migrate <up|down|status>")),
+    }
+
+    Ok(())
+}
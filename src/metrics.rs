@@ -0,0 +1,154 @@
+//! Prometheus metrics of the pipeline, served by the HTTP API at `/metrics`.
+
+use std::{future::Future, time::Instant};
+
+use lazy_static::lazy_static;
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    pub static ref TASKS_ENQUEUED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("apato_tasks_enqueued_total", "Tasks queued by the producer"),
+        &["task_type"],
+    ));
+    pub static ref TASKS_PROCESSED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_tasks_processed_total",
+            "Tasks completed by the consumers"
+        ),
+        &["task_type"],
+    ));
+    pub static ref TASKS_FAILED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_tasks_failed_total",
+            "Task runs that failed, by error kind"
+        ),
+        &["task_type", "kind"],
+    ));
+    pub static ref TASK_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("apato_task_duration_seconds", "Time spent running a task")
+            .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0]),
+        &["task_type"],
+    ));
+    pub static ref QUEUE_DEPTH: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("apato_queue_depth", "Jobs in the queue by status"),
+        &["status"],
+    ));
+    pub static ref OIKOTIE_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_oikotie_requests_total",
+            "Requests to the Oikotie API"
+        ),
+        &["endpoint", "outcome"],
+    ));
+    pub static ref OIKOTIE_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "apato_oikotie_request_duration_seconds",
+            "Latency of requests to the Oikotie API"
+        ),
+        &["endpoint"],
+    ));
+    pub static ref OIKOTIE_TOKEN_REFRESHES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_oikotie_token_refreshes_total",
+            "Fetches of Oikotie session tokens"
+        ),
+        &["outcome"],
+    ));
+    pub static ref ML_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_ml_requests_total",
+            "Requests to the ML rent prediction service"
+        ),
+        &["outcome"],
+    ));
+    pub static ref ML_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "apato_ml_request_duration_seconds",
+            "Latency of requests to the ML rent prediction service"
+        ),
+        &[],
+    ));
+    pub static ref RENT_ESTIMATES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_rent_estimates_total",
+            "Rent estimates by where they came from"
+        ),
+        &["source"],
+    ));
+    pub static ref IRR_FAILURES: IntCounter = register(IntCounter::new(
+        "apato_irr_failures_total",
+        "Apartments whose IRR could not be computed",
+    ));
+    pub static ref NOTIFICATIONS_SENT: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "apato_notifications_sent_total",
+            "Apartments announced to chats"
+        ),
+        &["kind"],
+    ));
+    pub static ref DB_POOL_CONNECTIONS: IntGauge = register(IntGauge::new(
+        "apato_db_pool_connections",
+        "Open connections in the database pool",
+    ));
+    pub static ref DB_POOL_IDLE_CONNECTIONS: IntGauge = register(IntGauge::new(
+        "apato_db_pool_idle_connections",
+        "Idle connections in the database pool",
+    ));
+    pub static ref DB_POOL_WAIT: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "apato_db_pool_wait_seconds",
+            "Time spent waiting for a database connection"
+        )
+        .buckets(vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+        &[],
+    ));
+}
+
+fn register<C, E>(collector: Result<C, E>) -> C
+where
+    C: Collector + Clone + 'static,
+    E: std::fmt::Debug,
+{
+    let collector = collector.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric registered twice");
+    collector
+}
+
+/// Records the outcome and latency of a request to the Oikotie API.
+pub async fn observe_oikotie<T, E>(
+    endpoint: &str,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let result = request.await;
+    OIKOTIE_REQUEST_DURATION
+        .with_label_values(&[endpoint])
+        .observe(start.elapsed().as_secs_f64());
+    OIKOTIE_REQUESTS
+        .with_label_values(&[endpoint, outcome(&result)])
+        .inc();
+    result
+}
+
+pub fn outcome<T, E>(result: &Result<T, E>) -> &'static str {
+    if result.is_ok() {
+        "ok"
+    } else {
+        "error"
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("metrics are valid");
+    String::from_utf8(buffer).expect("metrics are UTF-8")
+}
@@ -2,7 +2,9 @@ use anyhow::{anyhow, Context, Result};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use std::time::Instant;
+
+use crate::{config::Config, metrics};
 
 #[derive(Debug, Serialize)]
 pub struct RentPredictionRequest<'a> {
@@ -13,15 +15,38 @@ pub struct RentPredictionRequest<'a> {
     pub price: f64,
     pub maintenance_fee: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub auth_token: Option<&'a str>,
+    pub build_year: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub floor: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub building_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct RentPredictionResponse {
     rent: i32,
+    #[serde(default)]
+    rent_lower: Option<i32>,
+    #[serde(default)]
+    rent_upper: Option<i32>,
 }
 
-pub async fn predict_rent(config: &Config, payload: RentPredictionRequest<'_>) -> Result<i32> {
+/// Predicted rent together with the prediction interval, if the service provided one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RentPrediction {
+    pub rent: i32,
+    pub lower: Option<i32>,
+    pub upper: Option<i32>,
+}
+
+pub async fn predict_rent(
+    config: &Config,
+    payload: RentPredictionRequest<'_>,
+) -> Result<RentPrediction> {
     let base_url = config
         .ml_service_url
         .as_ref()
@@ -30,17 +55,35 @@ pub async fn predict_rent(config: &Config, payload: RentPredictionRequest<'_>) -
     let client = reqwest::Client::new();
     let url = format!("{}/predict", base_url.trim_end_matches('/'));
 
-    let response = client
-        .post(url)
-        .json(&payload)
-        .send()
-        .await
-        .context("Failed to reach ML prediction service")?;
+    let mut request = client.post(url).json(&payload);
+    if let Some(token) = config.ml_service_token.as_deref().filter(|t| !t.is_empty()) {
+        request = request.bearer_auth(token);
+    }
+
+    let start = Instant::now();
+    let response = request.send().await;
+    metrics::ML_REQUEST_DURATION
+        .with_label_values(&[])
+        .observe(start.elapsed().as_secs_f64());
+    let response = response.context("Failed to reach ML prediction service");
+    let outcome = match &response {
+        Ok(response) if response.status().is_success() => "ok",
+        _ => "error",
+    };
+    metrics::ML_REQUESTS.with_label_values(&[outcome]).inc();
+    let response = response?;
 
     if response.status() == StatusCode::NOT_FOUND {
         return Err(anyhow!("ML prediction endpoint not found (404)"));
     }
 
+    if response.status() == StatusCode::UNAUTHORIZED || response.status() == StatusCode::FORBIDDEN {
+        return Err(anyhow!(
+            "ML service rejected the request ({}), check ml_service_token",
+            response.status()
+        ));
+    }
+
     if !response.status().is_success() {
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
@@ -56,5 +99,92 @@ pub async fn predict_rent(config: &Config, payload: RentPredictionRequest<'_>) -
         .await
         .context("Failed to deserialize ML prediction response")?;
 
-    Ok(parsed.rent)
+    Ok(RentPrediction {
+        rent: parsed.rent,
+        lower: parsed.rent_lower,
+        upper: parsed.rent_upper,
+    })
+}
+
+/// Checks that the ML service is up and accepts our token.
+pub async fn check_health(config: &Config) -> Result<()> {
+    let base_url = config
+        .ml_service_url
+        .as_ref()
+        .ok_or_else(|| anyhow!("ML service URL not configured"))?;
+    let url = format!("{}/health", base_url.trim_end_matches('/'));
+
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(token) = config.ml_service_token.as_deref().filter(|t| !t.is_empty()) {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .context("Failed to reach ML prediction service")?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "ML service health check responded with {}",
+            response.status()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+    use axum::{http::HeaderMap, Json};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn prediction_is_requested_with_token_and_interval_parsed() {
+        let authorization = Arc::new(Mutex::new(None));
+        let seen = authorization.clone();
+        let app = axum::Router::new().route(
+            "/predict",
+            axum::routing::post(move |headers: HeaderMap| async move {
+                *seen.lock().unwrap() = headers
+                    .get("authorization")
+                    .map(|value| value.to_str().unwrap().to_string());
+                Json(serde_json::json!({ "rent": 900, "rent_lower": 820, "rent_upper": 980 }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut config = create_test_config();
+        config.ml_service_url = Some(url);
+        config.ml_service_token = Some("secret".to_string());
+        let request = RentPredictionRequest {
+            location_id: 1,
+            location_level: 5,
+            size: 50.0,
+            rooms: 2,
+            price: 200000.0,
+            maintenance_fee: 200.0,
+            build_year: Some(1960),
+            floor: None,
+            building_type: None,
+            latitude: None,
+            longitude: None,
+        };
+
+        let prediction = predict_rent(&config, request).await.unwrap();
+
+        assert_eq!(
+            prediction,
+            RentPrediction {
+                rent: 900,
+                lower: Some(820),
+                upper: Some(980),
+            }
+        );
+        assert_eq!(
+            authorization.lock().unwrap().as_deref(),
+            Some("Bearer secret")
+        );
+    }
 }
@@ -1,5 +1,9 @@
-use crate::models::watchlist::Watchlist;
-use chrono::NaiveDateTime;
+use crate::{
+    db::apartment::FRESHNESS_DAYS,
+    models::watchlist::Watchlist,
+    oikotie::{oikotie::Location, oikotie_types::CardStatus},
+};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
 use serde::Serialize;
 
@@ -17,6 +21,18 @@ pub struct InsertableApartment {
     pub rent: Option<i32>,
     pub estimated_yield: Option<f64>,
     pub url: Option<String>,
+    pub build_year: Option<i32>,
+    pub floor: Option<i32>,
+    pub building_type: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub rent_lower: Option<i32>,
+    pub rent_upper: Option<i32>,
+    pub status: Option<i32>,
+    /// Share of the housing company's debt included in the debt-free price.
+    pub debt_share: Option<i32>,
+    /// Whether the plot is owned or rented, as written in the listing.
+    pub plot_ownership: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Associations, Identifiable, Queryable, Selectable, Serialize)]
@@ -38,4 +54,140 @@ pub struct Apartment {
     pub url: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub build_year: Option<i32>,
+    pub floor: Option<i32>,
+    pub building_type: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub rent_lower: Option<i32>,
+    pub rent_upper: Option<i32>,
+    pub status: Option<i32>,
+    /// When the listing was sold or removed from Oikotie. `None` while it is active.
+    pub removed_at: Option<NaiveDateTime>,
+    /// Share of the housing company's debt included in the debt-free price.
+    pub debt_share: Option<i32>,
+    /// Whether the plot is owned or rented, as written in the listing.
+    pub plot_ownership: Option<String>,
+}
+
+impl InsertableApartment {
+    pub fn set_location(&mut self, location: &Location) {
+        self.location_id = Some(location.id);
+        self.location_level = Some(location.level);
+        self.location_name = Some(location.name.clone());
+    }
+
+    /// Whether Oikotie reports the listing as no longer for sale.
+    pub fn is_closed(&self) -> bool {
+        self.status
+            .is_some_and(|status| status != CardStatus::ACTIVE)
+    }
+
+    /// Maintenance fee per square meter, if both are known.
+    pub fn fee_per_m2(&self) -> Option<f64> {
+        match (self.additional_costs, self.size) {
+            (Some(fee), Some(size)) if size > 0.0 => Some(f64::from(fee) / size),
+            _ => None,
+        }
+    }
+
+    /// Debt share as percent of the debt-free price, if both are known.
+    pub fn debt_share_percent(&self) -> Option<f64> {
+        match (self.debt_share, self.price) {
+            (Some(debt), Some(price)) if price > 0 => {
+                Some(f64::from(debt) / f64::from(price) * 100.0)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the plot is rented rather than owned, if the listing says.
+    pub fn has_rented_plot(&self) -> Option<bool> {
+        let ownership = self.plot_ownership.as_deref()?.to_lowercase();
+        // Listings are mostly in Finnish, e.g. "Vuokralla" or "Vuokratontti"
+        Some(ownership.contains("vuokra") || ownership.contains("rent"))
+    }
+}
+
+/// The stored listing, e.g. to match it against changed watchlist criteria.
+impl From<&Apartment> for InsertableApartment {
+    fn from(apartment: &Apartment) -> Self {
+        InsertableApartment {
+            card_id: apartment.card_id,
+            location_id: apartment.location_id,
+            location_level: apartment.location_level,
+            location_name: apartment.location_name.clone(),
+            size: apartment.size,
+            rooms: apartment.rooms,
+            price: apartment.price,
+            additional_costs: apartment.additional_costs,
+            rent: apartment.rent,
+            estimated_yield: apartment.estimated_yield,
+            url: apartment.url.clone(),
+            build_year: apartment.build_year,
+            floor: apartment.floor,
+            building_type: apartment.building_type.clone(),
+            latitude: apartment.latitude,
+            longitude: apartment.longitude,
+            rent_lower: apartment.rent_lower,
+            rent_upper: apartment.rent_upper,
+            status: apartment.status,
+            debt_share: apartment.debt_share,
+            plot_ownership: apartment.plot_ownership.clone(),
+        }
+    }
+}
+
+impl Apartment {
+    pub fn is_active(&self) -> bool {
+        self.removed_at.is_none()
+    }
+
+    /// Days from first seeing the listing until it was removed, or until now if still active.
+    pub fn days_on_market(&self) -> i64 {
+        let until = self.removed_at.unwrap_or_else(|| Utc::now().naive_utc());
+        (until - self.created_at).num_days()
+    }
+
+    /// Whether the stored details still hold for the listing found in search results with
+    /// the given price, so its card does not have to be fetched again. Maintenance fee and
+    /// status changes are only seen once the details are older than `FRESHNESS_DAYS`.
+    pub fn details_current(&self, listed_price: Option<i32>) -> bool {
+        self.is_active()
+            && listed_price.is_some()
+            && self.price == listed_price
+            && self.updated_at > Utc::now().naive_utc() - Duration::days(FRESHNESS_DAYS)
+    }
+
+    /// Whether the listing's price, maintenance fee or status differs from the observed one.
+    pub fn listing_changed(&self, observed: &InsertableApartment) -> bool {
+        self.price != observed.price
+            || self.additional_costs != observed.additional_costs
+            || self.status != observed.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{memory::InMemoryRepository, repository::ApartmentRepository},
+        test_support,
+    };
+
+    #[tokio::test]
+    async fn details_are_current_until_price_changes_or_they_get_stale() {
+        let repo = InMemoryRepository::new();
+        let mut stored = repo
+            .upsert_apartment(test_support::apartment(1, 7.0), &[])
+            .await
+            .unwrap();
+
+        assert!(stored.details_current(Some(200000)));
+        assert!(!stored.details_current(Some(190000)));
+        assert!(!stored.details_current(None));
+
+        stored.updated_at = Utc::now().naive_utc() - Duration::days(FRESHNESS_DAYS + 1);
+        assert!(!stored.details_current(Some(200000)));
+    }
 }
@@ -0,0 +1,76 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::models::watchlist::present;
+
+/// Longest note kept on an apartment, in characters.
+pub const MAX_NOTES_LENGTH: usize = 2000;
+
+/// Where a chat is with a listing.
+pub struct ApartmentStatus;
+
+impl ApartmentStatus {
+    /// Not acted on yet, e.g. only noted.
+    pub const NEW: &'static str = "new";
+    /// Kept as a favourite.
+    pub const SAVED: &'static str = "saved";
+    /// Not interesting. Left out of the chat's lists and notifications.
+    pub const DISMISSED: &'static str = "dismissed";
+    /// The seller has been contacted.
+    pub const CONTACTED: &'static str = "contacted";
+    /// A bid has been made.
+    pub const BID: &'static str = "bid";
+    pub const BOUGHT: &'static str = "bought";
+
+    pub const ALL: [&'static str; 6] = [
+        Self::NEW,
+        Self::SAVED,
+        Self::DISMISSED,
+        Self::CONTACTED,
+        Self::BID,
+        Self::BOUGHT,
+    ];
+
+    /// Statuses of apartments the chat keeps track of, i.e. all but dismissed.
+    pub const TRACKED: [&'static str; 5] = [
+        Self::NEW,
+        Self::SAVED,
+        Self::CONTACTED,
+        Self::BID,
+        Self::BOUGHT,
+    ];
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::db::schema::user_apartment_state)]
+pub struct InsertableApartmentState {
+    pub chat_id: i64,
+    pub card_id: i32,
+    pub status: String,
+    pub notes: Option<String>,
+}
+
+/// A chat's own state of a listing. Listings without one have not been acted on.
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::db::schema::user_apartment_state)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApartmentState {
+    pub id: i32,
+    pub chat_id: i64,
+    pub card_id: i32,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub notes: Option<String>,
+}
+
+/// Changes to a chat's state of an apartment. Fields left out are kept.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApartmentStateUpdate {
+    pub status: Option<String>,
+    /// `null` clears the notes.
+    #[serde(default, deserialize_with = "present")]
+    pub notes: Option<Option<String>>,
+}
@@ -1,4 +1,4 @@
-use crate::models::watchlist::Watchlist;
+use crate::models::{price_history::drop_percent, watchlist::Watchlist};
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
@@ -21,4 +21,60 @@ pub struct WatchlistApartmentIndex {
     pub has_been_sent: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Price of the apartment when it was last sent to the chat.
+    pub sent_price: Option<i32>,
+    /// Yield of the apartment with the watchlist's own financing, if it has one.
+    pub estimated_yield: Option<f64>,
+}
+
+impl WatchlistApartmentIndex {
+    /// Whether an already sent match should be sent again because the price has dropped
+    /// by at least `min_drop_percent` since it was last sent.
+    pub fn is_price_drop(&self, price: Option<i32>, min_drop_percent: f64) -> bool {
+        match (self.sent_price, price) {
+            (Some(sent), Some(current)) => {
+                self.has_been_sent
+                    && drop_percent(sent, current).is_some_and(|drop| drop >= min_drop_percent)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent_match(sent_price: Option<i32>) -> WatchlistApartmentIndex {
+        WatchlistApartmentIndex {
+            id: 1,
+            watchlist_id: 1,
+            card_id: 1,
+            has_been_sent: true,
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            sent_price,
+            estimated_yield: None,
+        }
+    }
+
+    #[test]
+    fn drop_above_threshold_is_price_drop() {
+        assert!(sent_match(Some(200000)).is_price_drop(Some(190000), 5.0));
+        assert!(sent_match(Some(200000)).is_price_drop(Some(150000), 5.0));
+    }
+
+    #[test]
+    fn small_drop_or_increase_is_not_price_drop() {
+        assert!(!sent_match(Some(200000)).is_price_drop(Some(195000), 5.0));
+        assert!(!sent_match(Some(200000)).is_price_drop(Some(210000), 5.0));
+        assert!(!sent_match(None).is_price_drop(Some(100000), 5.0));
+    }
+
+    #[test]
+    fn unsent_match_is_not_price_drop() {
+        let mut index = sent_match(Some(200000));
+        index.has_been_sent = false;
+        assert!(!index.is_price_drop(Some(100000), 5.0));
+    }
 }
@@ -0,0 +1,39 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Random bytes in a token.
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::db::schema::api_tokens)]
+pub struct InsertableApiToken {
+    pub chat_id: i64,
+    pub token_hash: String,
+}
+
+/// Token for the HTTP API, bound to the chat it was issued to. Only its hash is stored.
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable)]
+#[diesel(table_name = crate::db::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: i32,
+    pub chat_id: i64,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+/// New random token, hex encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Tokens are random, so a plain SHA-256 is enough to keep them unusable if the database
+/// leaks.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use chrono::{Duration, NaiveDateTime};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::MessageTask;
+
+/// Values of `jobs.status`.
+pub struct JobStatus;
+
+impl JobStatus {
+    pub const PENDING: &'static str = "pending";
+    pub const RUNNING: &'static str = "running";
+    /// Failed too many times, kept for inspection and not retried.
+    pub const DEAD: &'static str = "dead";
+}
+
+/// Jobs are retried until they have been attempted this many times.
+pub const MAX_ATTEMPTS: i32 = 5;
+
+const BACKOFF_BASE_SECONDS: i64 = 30;
+const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::db::schema::jobs)]
+pub struct InsertableJob {
+    pub job_type: String,
+    pub payload: serde_json::Value,
+    pub dedup_key: String,
+}
+
+impl InsertableJob {
+    pub fn from_task(task: &MessageTask) -> Result<Self> {
+        Ok(InsertableJob {
+            job_type: task.task_type().as_str().to_string(),
+            payload: serde_json::to_value(task)?,
+            dedup_key: task.dedup_key(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::db::schema::jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Job {
+    pub id: i32,
+    pub job_type: String,
+    pub payload: serde_json::Value,
+    pub dedup_key: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub run_after: NaiveDateTime,
+    pub locked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Job {
+    pub fn task(&self) -> Result<MessageTask> {
+        let task: MessageTask = serde_json::from_value(self.payload.clone())
+            .with_context(|| format!("Invalid payload for {} job {}", self.job_type, self.id))?;
+        Ok(task)
+    }
+}
+
+/// How long to wait before retrying a job that has failed `attempts` times.
+pub fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.clamp(1, 16) as u32 - 1;
+    let seconds = BACKOFF_BASE_SECONDS.saturating_mul(2_i64.pow(exponent));
+    Duration::seconds(seconds.min(MAX_BACKOFF_SECONDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oikotie::oikotie::Location;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(backoff(1), Duration::seconds(30));
+        assert_eq!(backoff(2), Duration::seconds(60));
+        assert_eq!(backoff(3), Duration::seconds(120));
+        assert_eq!(backoff(20), Duration::seconds(MAX_BACKOFF_SECONDS));
+    }
+
+    #[test]
+    fn task_round_trips_through_job() {
+        let task = MessageTask::send_message(3, 42);
+        let insertable = InsertableJob::from_task(&task).unwrap();
+        let job = Job {
+            id: 1,
+            job_type: insertable.job_type,
+            payload: insertable.payload,
+            dedup_key: insertable.dedup_key,
+            status: JobStatus::PENDING.to_string(),
+            attempts: 0,
+            last_error: None,
+            run_after: NaiveDateTime::default(),
+            locked_at: None,
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+        };
+
+        assert_eq!(job.task().unwrap(), task);
+        assert_eq!(job.job_type, "send_message");
+        assert_eq!(job.dedup_key, "send_message:3:42");
+    }
+
+    fn location(id: i32, level: i32) -> Location {
+        Location {
+            id,
+            level,
+            name: id.to_string(),
+        }
+    }
+
+    #[test]
+    fn location_updates_are_deduplicated_per_location_set() {
+        let task = MessageTask::update_location(&[location(64, 5)]);
+        assert_eq!(task.dedup_key(), "update_location:64:5");
+        assert_ne!(
+            task.dedup_key(),
+            MessageTask::update_location(&[location(64, 4)]).dedup_key()
+        );
+
+        let task = MessageTask::update_location(&[location(65, 5), location(64, 5)]);
+        assert_eq!(task.dedup_key(), "update_location:64:5,65:5");
+        assert_eq!(
+            task,
+            MessageTask::update_location(&[location(64, 5), location(65, 5)])
+        );
+    }
+}
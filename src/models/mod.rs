@@ -1,3 +1,7 @@
 pub mod apartment;
+pub mod apartment_state;
 pub mod apartment_watchlist_model;
+pub mod api_token;
+pub mod job;
+pub mod price_history;
 pub mod watchlist;
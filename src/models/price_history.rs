@@ -0,0 +1,34 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::db::schema::apartment_price_history)]
+pub struct InsertablePriceHistoryEntry {
+    pub card_id: i32,
+    pub price: Option<i32>,
+    pub additional_costs: Option<i32>,
+    pub status: Option<i32>,
+}
+
+/// One observed state of a listing. A new entry is written whenever the price,
+/// maintenance fee or status of the listing changes.
+#[derive(Debug, Clone, PartialEq, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::db::schema::apartment_price_history)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PriceHistoryEntry {
+    pub id: i32,
+    pub card_id: i32,
+    pub price: Option<i32>,
+    pub additional_costs: Option<i32>,
+    pub status: Option<i32>,
+    pub observed_at: NaiveDateTime,
+}
+
+/// How many percent `current` is below `previous`. `None` if the price did not drop.
+pub fn drop_percent(previous: i32, current: i32) -> Option<f64> {
+    if previous <= 0 || current >= previous {
+        return None;
+    }
+    Some(f64::from(previous - current) / f64::from(previous) * 100.0)
+}
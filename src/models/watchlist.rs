@@ -1,13 +1,36 @@
-use chrono::NaiveDateTime;
-use diesel::prelude::*;
-use serde::Serialize;
+use std::{io::Write, ops::Deref};
+
+use chrono::{Duration, NaiveDateTime};
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Jsonb,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::apartment::{Apartment, InsertableApartment};
+use crate::{config::Config, oikotie::oikotie::Location};
+
+/// Matches the column default of `watchlists.min_price_drop_percent`.
+pub const DEFAULT_MIN_PRICE_DROP_PERCENT: f64 = 5.0;
+
+/// Matches the column default of `watchlists.refresh_interval_minutes`.
+pub const DEFAULT_REFRESH_INTERVAL_MINUTES: i32 = 60;
+
+/// Bounds for the refresh interval users can choose.
+pub const MIN_REFRESH_INTERVAL_MINUTES: i32 = 15;
+pub const MAX_REFRESH_INTERVAL_MINUTES: i32 = 7 * 24 * 60;
+
+/// Most locations one watchlist can search at once.
+pub const MAX_LOCATIONS: usize = 10;
 
 #[derive(Insertable)]
 #[diesel(table_name = crate::db::schema::watchlists)]
 pub struct InsertableWatchlist {
-    pub location_id: i32,
-    pub location_level: i32,
-    pub location_name: String,
+    pub locations: Locations,
     pub chat_id: i64,
     pub target_yield: Option<f64>,
     pub target_size_min: Option<i32>,
@@ -19,15 +42,344 @@ pub struct InsertableWatchlist {
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Watchlist {
     pub id: i32,
-    pub location_id: i32,
-    pub location_level: i32,
-    pub location_name: String,
+    /// Searched together, so the listings of all of them are announced by one watchlist.
+    pub locations: Locations,
     pub chat_id: i64,
     pub target_yield: Option<f64>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub target_size_min: Option<i32>,
     pub target_size_max: Option<i32>,
+    /// Minimum price drop, in percent, that announces an already sent apartment again.
+    pub min_price_drop_percent: f64,
+    /// When the watchlist was last updated successfully.
+    pub last_refreshed_at: Option<NaiveDateTime>,
+    /// The producer queues an update once this is in the past.
+    pub next_refresh_at: NaiveDateTime,
+    pub refresh_interval_minutes: i32,
+    /// Set when messages to the chat could not be delivered, e.g. because the user blocked
+    /// the bot. Paused watchlists are neither refreshed nor announced.
+    pub paused_at: Option<NaiveDateTime>,
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub criteria: ListingCriteria,
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub financing: Financing,
+}
+
+/// Locations of a watchlist in the order they were added, stored as a JSON array.
+#[derive(
+    Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Jsonb)]
+#[serde(transparent)]
+pub struct Locations(pub Vec<Location>);
+
+impl Locations {
+    pub fn contains(&self, location_id: i32, location_level: i32) -> bool {
+        self.0
+            .iter()
+            .any(|location| location.id == location_id && location.level == location_level)
+    }
+
+    /// Sorted ids and levels. Watchlists of the same locations have the same key.
+    pub fn key(&self) -> Vec<(i32, i32)> {
+        let mut key: Vec<(i32, i32)> = self
+            .0
+            .iter()
+            .map(|location| (location.id, location.level))
+            .collect();
+        key.sort_unstable();
+        key
+    }
+
+    /// e.g. `00100, 00120`
+    pub fn names(&self) -> String {
+        self.0
+            .iter()
+            .map(|location| location.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Deref for Locations {
+    type Target = [Location];
+
+    fn deref(&self) -> &[Location] {
+        &self.0
+    }
+}
+
+impl FromSql<Jsonb, Pg> for Locations {
+    fn from_sql(value: PgValue<'_>) -> deserialize::Result<Self> {
+        let json = <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(value)?;
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+impl ToSql<Jsonb, Pg> for Locations {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        // Version of the binary jsonb format, followed by the JSON text
+        out.write_all(&[1])?;
+        serde_json::to_writer(out, self)?;
+        Ok(IsNull::No)
+    }
+}
+
+/// Optional criteria on top of the locations, size range and target yield.
+///
+/// Bounds on rooms, price, maintenance fee and build year leave out listings that do not
+/// state them. The ground floor, rented plot and debt share exclusions only leave out
+/// listings known to fail them.
+#[derive(
+    Debug, Default, Clone, PartialEq, Queryable, Selectable, AsChangeset, Serialize, Deserialize,
+)]
+#[diesel(table_name = crate::db::schema::watchlists)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(treat_none_as_null = true)]
+#[serde(default)]
+pub struct ListingCriteria {
+    pub min_rooms: Option<i32>,
+    pub max_rooms: Option<i32>,
+    pub max_price: Option<i32>,
+    pub max_fee_per_m2: Option<f64>,
+    pub min_build_year: Option<i32>,
+    pub exclude_ground_floor: bool,
+    pub exclude_rented_plot: bool,
+    /// Highest share of the housing company's debt, in percent of the debt-free price.
+    pub max_debt_share_percent: Option<f64>,
+}
+
+impl ListingCriteria {
+    pub fn matches(&self, apartment: &InsertableApartment) -> bool {
+        within(apartment.rooms, self.min_rooms, self.max_rooms)
+            && within(apartment.price, None, self.max_price)
+            && within(apartment.fee_per_m2(), None, self.max_fee_per_m2)
+            && within(apartment.build_year, self.min_build_year, None)
+            && !(self.exclude_ground_floor && apartment.floor.is_some_and(|floor| floor <= 1))
+            && !(self.exclude_rented_plot && apartment.has_rented_plot() == Some(true))
+            && self.max_debt_share_percent.is_none_or(|max| {
+                apartment
+                    .debt_share_percent()
+                    .is_none_or(|percent| percent <= max)
+            })
+    }
+}
+
+/// Longest loan users can choose, in years.
+pub const MAX_LOAN_YEARS: i32 = 50;
+
+/// Financing assumptions the yields of a watchlist are estimated with. Assumptions left
+/// unset use the configured defaults.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Queryable,
+    Selectable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+)]
+#[diesel(table_name = crate::db::schema::watchlists)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(treat_none_as_null = true)]
+#[serde(default)]
+pub struct Financing {
+    pub down_payment_percent: Option<i32>,
+    pub loan_years: Option<i32>,
+    /// Renovation costs in euros, added to the loan.
+    pub renovation_costs: Option<i32>,
+}
+
+impl Financing {
+    /// Whether every assumption uses the configured default.
+    pub fn is_default(&self) -> bool {
+        *self == Financing::default()
+    }
+
+    /// Down payment percent, loan years and renovation costs, with the defaults of the
+    /// config in place of the unset ones.
+    pub fn terms(&self, config: &Config) -> (u32, u32, u32) {
+        let or_default = |value: Option<i32>, default: u32| {
+            value.and_then(|v| u32::try_from(v).ok()).unwrap_or(default)
+        };
+        (
+            or_default(self.down_payment_percent, config.down_payment_percentage),
+            or_default(self.loan_years, config.loan_duration_years),
+            or_default(self.renovation_costs, config.avg_renovation_costs),
+        )
+    }
+
+    /// The config with the assumptions in place of its defaults.
+    pub fn apply_to(&self, config: &Config) -> Config {
+        let (down_payment, loan_years, renovation_costs) = self.terms(config);
+        Config {
+            down_payment_percentage: down_payment,
+            loan_duration_years: loan_years,
+            avg_renovation_costs: renovation_costs,
+            ..config.clone()
+        }
+    }
+
+    /// The assumptions set in `other`, and the ones of `self` it leaves unset.
+    pub fn merge(&self, other: &Financing) -> Financing {
+        Financing {
+            down_payment_percent: other.down_payment_percent.or(self.down_payment_percent),
+            loan_years: other.loan_years.or(self.loan_years),
+            renovation_costs: other.renovation_costs.or(self.renovation_costs),
+        }
+    }
+}
+
+/// Whether the value is within the bounds. Unknown values are only within open bounds.
+fn within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    if min.is_none() && max.is_none() {
+        return true;
+    }
+    let Some(value) = value else {
+        return false;
+    };
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+impl Watchlist {
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::minutes(i64::from(self.refresh_interval_minutes))
+    }
+
+    pub fn size_target(&self) -> SizeTarget {
+        SizeTarget {
+            min: self.target_size_min,
+            max: self.target_size_max,
+        }
+    }
+
+    /// Whether an apartment of the given size falls within the watchlist's size range.
+    pub fn size_matches(&self, size: Option<f64>) -> bool {
+        self.size_target().contains(size)
+    }
+
+    /// Whether the apartment with the given estimated yield is a match, i.e. within the size
+    /// range, above the target yield and meeting the other criteria.
+    pub fn matches(&self, apartment: &InsertableApartment, estimated_yield: f64) -> bool {
+        self.size_matches(apartment.size)
+            && estimated_yield > self.target_yield.unwrap_or_default()
+            && self.criteria.matches(apartment)
+    }
+}
+
+/// Changes to the criteria of a watchlist. Criteria left out are kept.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchlistUpdate {
+    pub target_yield: Option<f64>,
+    pub min_size: Option<i32>,
+    pub max_size: Option<i32>,
+    pub min_price_drop_percent: Option<f64>,
+    pub refresh_interval_minutes: Option<i32>,
+    // The optional criteria are cleared with `null`
+    #[serde(default, deserialize_with = "present")]
+    pub min_rooms: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_rooms: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_price: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_fee_per_m2: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub min_build_year: Option<Option<i32>>,
+    pub exclude_ground_floor: Option<bool>,
+    pub exclude_rented_plot: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_debt_share_percent: Option<Option<f64>>,
+    // Financing set to `null` uses the configured default again
+    #[serde(default, deserialize_with = "present")]
+    pub down_payment_percent: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub loan_years: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "present")]
+    pub renovation_costs: Option<Option<i32>>,
+}
+
+/// Tells a field set to `null` apart from a missing one.
+pub(crate) fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl WatchlistUpdate {
+    pub fn is_empty(&self) -> bool {
+        *self == WatchlistUpdate::default()
+    }
+
+    /// Applies the changes to the watchlist.
+    pub fn apply(&self, watchlist: &mut Watchlist) {
+        if let Some(target_yield) = self.target_yield {
+            watchlist.target_yield = Some(target_yield);
+        }
+        if let Some(min_size) = self.min_size {
+            watchlist.target_size_min = Some(min_size);
+        }
+        if let Some(max_size) = self.max_size {
+            watchlist.target_size_max = Some(max_size);
+        }
+        if let Some(percent) = self.min_price_drop_percent {
+            watchlist.min_price_drop_percent = percent;
+        }
+        if let Some(minutes) = self.refresh_interval_minutes {
+            watchlist.refresh_interval_minutes = minutes;
+        }
+
+        let criteria = &mut watchlist.criteria;
+        if let Some(min_rooms) = self.min_rooms {
+            criteria.min_rooms = min_rooms;
+        }
+        if let Some(max_rooms) = self.max_rooms {
+            criteria.max_rooms = max_rooms;
+        }
+        if let Some(max_price) = self.max_price {
+            criteria.max_price = max_price;
+        }
+        if let Some(max_fee) = self.max_fee_per_m2 {
+            criteria.max_fee_per_m2 = max_fee;
+        }
+        if let Some(min_build_year) = self.min_build_year {
+            criteria.min_build_year = min_build_year;
+        }
+        if let Some(exclude) = self.exclude_ground_floor {
+            criteria.exclude_ground_floor = exclude;
+        }
+        if let Some(exclude) = self.exclude_rented_plot {
+            criteria.exclude_rented_plot = exclude;
+        }
+        if let Some(max_debt_share) = self.max_debt_share_percent {
+            criteria.max_debt_share_percent = max_debt_share;
+        }
+
+        let financing = &mut watchlist.financing;
+        if let Some(down_payment) = self.down_payment_percent {
+            financing.down_payment_percent = down_payment;
+        }
+        if let Some(loan_years) = self.loan_years {
+            financing.loan_years = loan_years;
+        }
+        if let Some(renovation_costs) = self.renovation_costs {
+            financing.renovation_costs = renovation_costs;
+        }
+    }
 }
 
 pub struct SizeTarget {
@@ -42,4 +394,281 @@ impl SizeTarget {
             max: None,
         }
     }
+
+    /// Smallest size range containing the size ranges of all the watchlists.
+    pub fn covering(watchlists: &[Watchlist]) -> Self {
+        if watchlists.is_empty() {
+            return SizeTarget::empty();
+        }
+
+        // An open bound on any watchlist leaves the union open on that side
+        SizeTarget {
+            min: watchlists
+                .iter()
+                .map(|w| w.target_size_min)
+                .try_fold(i32::MAX, |acc, min| min.map(|min| acc.min(min))),
+            max: watchlists
+                .iter()
+                .map(|w| w.target_size_max)
+                .try_fold(i32::MIN, |acc, max| max.map(|max| acc.max(max))),
+        }
+    }
+
+    /// Whether an apartment of the given size falls within the range.
+    pub fn contains(&self, size: Option<f64>) -> bool {
+        let Some(size) = size else {
+            return false;
+        };
+        self.min.is_none_or(|min| size >= f64::from(min))
+            && self.max.is_none_or(|max| size <= f64::from(max))
+    }
+}
+
+/// Criteria Oikotie filters the listings of a location by. The rest of the criteria are
+/// applied to the results.
+pub struct SearchFilter {
+    pub size: SizeTarget,
+    pub max_price: Option<i32>,
+    pub min_build_year: Option<i32>,
+}
+
+impl From<SizeTarget> for SearchFilter {
+    fn from(size: SizeTarget) -> Self {
+        SearchFilter {
+            size,
+            max_price: None,
+            min_build_year: None,
+        }
+    }
+}
+
+impl SearchFilter {
+    /// Least restrictive filter finding the listings of all the watchlists.
+    pub fn covering(watchlists: &[Watchlist]) -> Self {
+        if watchlists.is_empty() {
+            return SearchFilter::from(SizeTarget::empty());
+        }
+
+        SearchFilter {
+            size: SizeTarget::covering(watchlists),
+            max_price: watchlists
+                .iter()
+                .map(|w| w.criteria.max_price)
+                .try_fold(i32::MIN, |acc, max| max.map(|max| acc.max(max))),
+            min_build_year: watchlists
+                .iter()
+                .map(|w| w.criteria.min_build_year)
+                .try_fold(i32::MAX, |acc, min| min.map(|min| acc.min(min))),
+        }
+    }
+
+    /// Whether the search finds the apartment while it is listed. Only the size is
+    /// compared, the price or build year of the listing may have changed since it was
+    /// stored.
+    pub fn contains(&self, apartment: &Apartment) -> bool {
+        self.size.contains(apartment.size)
+    }
+
+    /// Whether the search can leave out listings that are still for sale, because their
+    /// price rose above the max price or their build year was corrected.
+    pub fn can_miss_listed(&self) -> bool {
+        self.max_price.is_some() || self.min_build_year.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn watchlist(min: Option<i32>, max: Option<i32>) -> Watchlist {
+        Watchlist {
+            id: 1,
+            locations: Locations(vec![Location {
+                id: 1,
+                level: 5,
+                name: "00100".to_string(),
+            }]),
+            chat_id: 42,
+            target_yield: None,
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            target_size_min: min,
+            target_size_max: max,
+            min_price_drop_percent: DEFAULT_MIN_PRICE_DROP_PERCENT,
+            last_refreshed_at: None,
+            next_refresh_at: NaiveDateTime::default(),
+            refresh_interval_minutes: DEFAULT_REFRESH_INTERVAL_MINUTES,
+            paused_at: None,
+            criteria: ListingCriteria::default(),
+            financing: Financing::default(),
+        }
+    }
+
+    #[test]
+    fn covering_spans_all_size_ranges() {
+        let covering =
+            SizeTarget::covering(&[watchlist(Some(30), Some(50)), watchlist(Some(40), Some(80))]);
+        assert_eq!((covering.min, covering.max), (Some(30), Some(80)));
+        assert!(covering.contains(Some(65.0)));
+        assert!(!covering.contains(Some(85.0)));
+    }
+
+    #[test]
+    fn price_bound_can_miss_listed_apartments() {
+        let mut bounded = watchlist(Some(30), Some(60));
+        bounded.criteria.max_price = Some(150000);
+        assert!(SearchFilter::covering(&[bounded]).can_miss_listed());
+
+        let unbounded = SearchFilter::covering(&[watchlist(Some(30), Some(60))]);
+        assert!(!unbounded.can_miss_listed());
+    }
+
+    #[test]
+    fn open_bound_makes_covering_open() {
+        let covering =
+            SizeTarget::covering(&[watchlist(Some(30), None), watchlist(Some(20), Some(50))]);
+        assert_eq!((covering.min, covering.max), (Some(20), None));
+
+        let covering =
+            SizeTarget::covering(&[watchlist(None, Some(40)), watchlist(Some(20), None)]);
+        assert_eq!((covering.min, covering.max), (None, None));
+    }
+
+    fn listing() -> InsertableApartment {
+        InsertableApartment {
+            rent: None,
+            estimated_yield: None,
+            build_year: Some(1985),
+            floor: Some(1),
+            debt_share: Some(50000),
+            plot_ownership: Some("Vuokralla".to_string()),
+            ..test_support::apartment(1, 0.0)
+        }
+    }
+
+    #[test]
+    fn criteria_filter_listings() {
+        let apartment = listing();
+        assert!(ListingCriteria::default().matches(&apartment));
+
+        let passing = ListingCriteria {
+            min_rooms: Some(2),
+            max_rooms: Some(3),
+            max_price: Some(200000),
+            max_fee_per_m2: Some(4.0),
+            min_build_year: Some(1980),
+            max_debt_share_percent: Some(25.0),
+            ..Default::default()
+        };
+        assert!(passing.matches(&apartment));
+
+        let failing = [
+            ListingCriteria {
+                min_rooms: Some(3),
+                ..Default::default()
+            },
+            ListingCriteria {
+                max_fee_per_m2: Some(3.5),
+                ..Default::default()
+            },
+            ListingCriteria {
+                min_build_year: Some(1990),
+                ..Default::default()
+            },
+            ListingCriteria {
+                exclude_ground_floor: true,
+                ..Default::default()
+            },
+            ListingCriteria {
+                exclude_rented_plot: true,
+                ..Default::default()
+            },
+            ListingCriteria {
+                max_debt_share_percent: Some(20.0),
+                ..Default::default()
+            },
+        ];
+        for criteria in failing {
+            assert!(!criteria.matches(&apartment), "{:?}", criteria);
+        }
+    }
+
+    #[test]
+    fn unknown_details_only_fail_bounds() {
+        let mut apartment = listing();
+        apartment.build_year = None;
+        apartment.floor = None;
+        apartment.plot_ownership = None;
+        apartment.debt_share = None;
+
+        let exclusions = ListingCriteria {
+            exclude_ground_floor: true,
+            exclude_rented_plot: true,
+            max_debt_share_percent: Some(10.0),
+            ..Default::default()
+        };
+        assert!(exclusions.matches(&apartment));
+
+        let bound = ListingCriteria {
+            min_build_year: Some(1950),
+            ..Default::default()
+        };
+        assert!(!bound.matches(&apartment));
+    }
+
+    #[test]
+    fn search_filter_covers_every_watchlist() {
+        let mut cheap = watchlist(Some(30), Some(50));
+        cheap.criteria.max_price = Some(150000);
+        cheap.criteria.min_build_year = Some(1990);
+        let mut expensive = watchlist(Some(40), Some(80));
+        expensive.criteria.max_price = Some(300000);
+        expensive.criteria.min_build_year = Some(1970);
+
+        let filter = SearchFilter::covering(&[cheap.clone(), expensive]);
+        assert_eq!(filter.max_price, Some(300000));
+        assert_eq!(filter.min_build_year, Some(1970));
+
+        // Any watchlist without a bound leaves the search open
+        let filter = SearchFilter::covering(&[cheap, watchlist(None, None)]);
+        assert_eq!((filter.max_price, filter.min_build_year), (None, None));
+    }
+
+    #[test]
+    fn financing_replaces_the_configured_defaults() {
+        let config = crate::config::create_test_config();
+        assert_eq!(
+            Financing::default().terms(&config),
+            (
+                config.down_payment_percentage,
+                config.loan_duration_years,
+                config.avg_renovation_costs
+            )
+        );
+
+        let own = Financing {
+            down_payment_percent: Some(40),
+            renovation_costs: Some(0),
+            ..Default::default()
+        };
+        let financed = own.apply_to(&config);
+        assert_eq!(financed.down_payment_percentage, 40);
+        assert_eq!(financed.loan_duration_years, config.loan_duration_years);
+        assert_eq!(financed.avg_renovation_costs, 0);
+
+        let merged = own.merge(&Financing {
+            down_payment_percent: Some(30),
+            loan_years: Some(20),
+            ..Default::default()
+        });
+        assert_eq!(
+            merged,
+            Financing {
+                down_payment_percent: Some(30),
+                loan_years: Some(20),
+                renovation_costs: Some(0),
+            }
+        );
+    }
 }
@@ -1,11 +1,21 @@
-use log::warn;
 use rand::Rng;
 use regex::Regex;
+use tracing::warn;
 
-use super::oikotie::RentalData;
+use super::oikotie::{Location, RentalData};
 
-pub fn create_location_string(id: i32, level: i32, name: String) -> String {
-    format!("[[{:?}, {:?}, {}{}{}]]", id, level, '"', name, '"')
+/// The `locations` search param, e.g. `[[1, 5, "00100"], [2, 5, "00120"]]`.
+pub fn create_location_string(locations: &[Location]) -> String {
+    let locations: Vec<String> = locations
+        .iter()
+        .map(|location| {
+            format!(
+                "[{:?}, {:?}, {}{}{}]",
+                location.id, location.level, '"', location.name, '"'
+            )
+        })
+        .collect();
+    format!("[{}]", locations.join(", "))
 }
 
 pub fn generate_random_number() -> String {
@@ -80,7 +90,7 @@ fn smaller_than_median(size: f32, median: f32) -> f32 {
     1.0 - ((median - size) / median)
 }
 
-fn calculate_median(numbers: &mut Vec<i32>) -> f32 {
+fn calculate_median(numbers: &mut [i32]) -> f32 {
     if numbers.is_empty() {
         return 0.0;
     }
@@ -88,7 +98,7 @@ fn calculate_median(numbers: &mut Vec<i32>) -> f32 {
     numbers.sort(); // Sort the vector in ascending order
 
     let len = numbers.len();
-    if len % 2 == 0 {
+    if len.is_multiple_of(2) {
         // If the length is even, take the average of the middle two values
         let mid = len / 2;
 
@@ -104,3 +114,25 @@ fn is_within_percentage(value: f32, reference: f32, percentage: f32) -> bool {
     let allowed_difference = (percentage / 100.0) * reference;
     difference <= allowed_difference
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn location_string_lists_every_location() {
+        let location = |id, name: &str| Location {
+            id,
+            level: 5,
+            name: name.to_string(),
+        };
+        assert_eq!(
+            create_location_string(&[location(1, "00100")]),
+            r#"[[1, 5, "00100"]]"#
+        );
+        assert_eq!(
+            create_location_string(&[location(1, "00100"), location(2, "00120")]),
+            r#"[[1, 5, "00100"], [2, 5, "00120"]]"#
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod helpers;
+#[allow(clippy::module_inception)]
 pub mod oikotie;
-mod oikotie_types;
+pub(crate) mod oikotie_types;
 pub mod tokens;
@@ -1,12 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::config::Config;
-use crate::db;
-use crate::db::apartment_watchlist::get_watchlist_apartment_connector;
-use crate::ml_client::{self, RentPredictionRequest};
-use crate::models::apartment::InsertableApartment;
-use crate::models::watchlist::SizeTarget;
-use crate::models::watchlist::Watchlist;
+use crate::errors::ApatoError;
+use crate::metrics;
+use crate::ml_client::{self, RentPrediction, RentPredictionRequest};
+use crate::models::apartment::{Apartment, InsertableApartment};
+use crate::models::watchlist::{SearchFilter, SizeTarget};
 use crate::oikotie::helpers;
 use crate::oikotie::tokens;
 use crate::send_request;
@@ -16,7 +18,7 @@ use crate::URLS;
 use anyhow::anyhow;
 use anyhow::{Context, Result};
 use helpers::create_location_string;
-use log::{error, warn};
+use tracing::{error, warn};
 
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::de;
@@ -27,15 +29,32 @@ use tokens::{get_tokens, OikotieTokens};
 
 use super::helpers::estimate_rent;
 use super::helpers::get_rent_regex;
-use super::oikotie_types::CardTypes;
+use super::oikotie_types::{CardTypes, LocationLevel};
 
-#[derive(Debug, Clone)]
+/// Cards asked for per request of a search.
+const CARDS_PAGE_SIZE: usize = 100;
+
+/// Most pages fetched for one search. Longer results are left incomplete.
+const MAX_CARDS_PAGES: usize = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Location {
     pub id: i32,
+    /// See [`LocationLevel`].
     pub level: i32,
     pub name: String,
 }
 
+impl Location {
+    /// Name and kind of the location, e.g. `Kallio, Helsinki (district)`.
+    pub fn label(&self) -> String {
+        match LocationLevel::name(self.level) {
+            Some(level) => format!("{} ({})", self.name, level),
+            None => self.name.clone(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LocationCard {
@@ -49,11 +68,43 @@ pub struct LocationResponse {
     pub card: LocationCard,
 }
 
+/// Where the listing of a card is.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CardLocation {
+    #[serde(default)]
+    zip_code: Option<String>,
+    #[serde(default)]
+    district: Option<String>,
+    #[serde(default)]
+    city: Option<String>,
+}
+
+impl CardLocation {
+    /// Whether the listing is in the location, comparing the name of the location with the
+    /// listing's postcode, district or city depending on the level of the location.
+    fn is_in(&self, location: &Location) -> bool {
+        let value = match location.level {
+            LocationLevel::POSTCODE => &self.zip_code,
+            LocationLevel::DISTRICT => &self.district,
+            LocationLevel::CITY => &self.city,
+            _ => return false,
+        };
+        // Names of districts also tell the city, e.g. `Kallio, Helsinki`
+        let name = location.name.split(',').next().unwrap_or_default().trim();
+        value
+            .as_deref()
+            .is_some_and(|value| value.trim().to_lowercase() == name.to_lowercase())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Card {
     id: u32,
     url: String,
     #[serde(default)]
+    location: CardLocation,
+    #[serde(default)]
     description: Option<String>,
     #[serde(default)]
     rooms: Option<u32>,
@@ -65,6 +116,24 @@ struct Card {
     size: f32,
 }
 
+impl Card {
+    /// The price shown in search results, e.g. `189 000 €`.
+    fn listed_price(&self) -> Option<i32> {
+        let whole = self.price.split([',', '.']).next().unwrap_or_default();
+        let digits: String = whole.chars().filter(char::is_ascii_digit).collect();
+        digits.parse().ok()
+    }
+
+    /// The one of the searched locations the listing is in, or the first of them if the
+    /// card does not tell.
+    fn location_in<'a>(&self, locations: &'a [Location]) -> Option<&'a Location> {
+        locations
+            .iter()
+            .find(|location| self.location.is_in(location))
+            .or(locations.first())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct CardsResponse {
     found: u32,
@@ -76,12 +145,8 @@ struct CardsResponse {
 struct Price {
     #[serde(default, deserialize_with = "deserialize_u64_or_default")]
     price: u64,
-}
-
-impl Price {
-    fn empty() -> Price {
-        Price { price: 0 }
-    }
+    #[serde(default, deserialize_with = "deserialize_optional_i32")]
+    debt_share: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -93,16 +158,20 @@ struct AdData {
     size: u64,
     #[serde(default)]
     room_configuration: String,
+    #[serde(default, deserialize_with = "deserialize_optional_i32")]
+    build_year: Option<i32>,
+    #[serde(default, deserialize_with = "deserialize_optional_i32")]
+    floor: Option<i32>,
+    #[serde(default)]
+    building_type: Option<String>,
+    #[serde(default)]
+    plot_ownership: Option<String>,
 }
 
-impl AdData {
-    fn empty() -> AdData {
-        AdData {
-            maintenance_fee: 0,
-            size: 0,
-            room_configuration: String::from(""),
-        }
-    }
+#[derive(Debug, Serialize, Deserialize)]
+struct Coordinates {
+    latitude: f64,
+    longitude: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -112,17 +181,17 @@ struct CardResponse {
     ad_data: AdData,
     price_data: Price,
     status: i32,
+    #[serde(default)]
+    coordinates: Option<Coordinates>,
 }
 
-impl CardResponse {
-    fn empty() -> CardResponse {
-        CardResponse {
-            card_id: 0,
-            ad_data: AdData::empty(),
-            price_data: Price::empty(),
-            status: 0,
-        }
-    }
+/// Result of searching the listings for sale in a location.
+pub struct ListingSearch {
+    pub apartments: Vec<InsertableApartment>,
+    /// Every card in the search results, including cards whose details could not be fetched.
+    pub seen_card_ids: Vec<i32>,
+    /// Whether the results contain every listing Oikotie found for the search.
+    pub complete: bool,
 }
 
 pub struct RentalData {
@@ -179,78 +248,109 @@ impl Oikotie {
             .ok_or_else(|| anyhow!("Failed to fetch authentication tokens from Oikotie"))
     }
 
-    /// Use Oikotie's search API to find location ID based on text query.
-    pub async fn get_locations_for_zip_code(
-        &mut self,
-        zip_code: &str,
-    ) -> Result<Vec<LocationResponse>> {
+    /// Use Oikotie's search API to find locations of any level matching a text query,
+    /// e.g. postcodes, neighbourhoods, districts and cities. Best matches come first.
+    pub async fn search_locations(&mut self, query: &str) -> Result<Vec<Location>> {
         let tokens = self.ensure_tokens().await?;
-        let locations = match fetch_location_id(tokens, zip_code).await {
-            Ok(l) => l,
-            Err(e) => {
-                error!("Error while fetching location id from Oikotie: {}", e);
-                return Err(e.into());
-            }
-        };
+        let locations =
+            match metrics::observe_oikotie("location", fetch_locations(tokens, query)).await {
+                Ok(l) => l,
+                Err(e) => {
+                    error!("Error while fetching locations from Oikotie: {}", e);
+                    return Err(e);
+                }
+            };
 
         if locations.is_empty() {
-            return Err(anyhow!(
+            return Err(ApatoError::user_facing(format!(
                 "Did not find any valid location for '{}', please try again!",
-                zip_code
-            ));
+                query
+            ))
+            .into());
         }
 
-        Ok(locations)
+        Ok(locations
+            .into_iter()
+            .map(|location| Location {
+                id: location.card.card_id as i32,
+                level: location.card.card_type as i32,
+                name: location.card.name,
+            })
+            .collect())
     }
 
-    /// Fecthes all apartments for a certain location.
+    /// Fecthes all apartments in the given locations with a single search.
+    ///
+    /// Each apartment gets the location its card is in, which is also used to look up
+    /// rentals nearby.
+    ///
+    /// The card of a listing is only fetched when it is not in `stored`, or its listed price
+    /// differs from the stored one or the stored details are stale. Otherwise the stored
+    /// details are returned.
     pub async fn get_apartments(
         &mut self,
-        config: Arc<Config>,
-        watchlist: &Watchlist,
-        size: SizeTarget,
-    ) -> Result<Vec<InsertableApartment>> {
+        locations: &[Location],
+        filter: &SearchFilter,
+        stored: &HashMap<i32, Apartment>,
+    ) -> Result<ListingSearch> {
         let tokens = self.ensure_tokens().await?.clone();
 
-        // TODO: Benchmark this function. Why so slow?
-        let location: &Location = &Location {
-            id: watchlist.location_id,
-            level: watchlist.location_level,
-            name: watchlist.location_name.clone(),
-        };
-
-        let cards_response: Result<CardsResponse> =
-            fetch_apartments_for_sale(&tokens, location.clone(), size).await;
-
-        let cards = match cards_response {
-            Ok(c) => c.cards,
-            Err(e) => return Err(e),
-        };
+        let CardsResponse { found, cards } =
+            fetch_apartments_for_sale(&tokens, locations, filter).await?;
+        let complete = cards.len() >= found as usize;
 
         let mut apartments: Vec<InsertableApartment> = Vec::new();
-
+        let mut seen_card_ids: Vec<i32> = Vec::new();
         for card in cards {
             let card_id: i32 = card
                 .id
                 .try_into()
                 .map_err(|_| anyhow!("Card id {} does not fit in i32", card.id))?;
-
-            let existing_apartment = match db::apartment::get_apartment_by_card_id(&config, card_id)
+            seen_card_ids.push(card_id);
+            let location = card
+                .location_in(locations)
+                .ok_or_else(|| anyhow!("Search without locations"))?;
+
+            if let Some(stored) = stored
+                .get(&card_id)
+                .filter(|stored| stored.details_current(card.listed_price()))
             {
-                Ok(ap) => ap,
-                Err(e) => return Err(e.into()),
-            };
-
-            let has_been_sent = has_been_sent_to_watchlist(config.clone(), watchlist, card_id)?;
-
-            if existing_apartment.is_none() && !has_been_sent {
-                let apartment: InsertableApartment =
-                    card_into_complete_apartment(&tokens, &card, location).await?;
+                let mut apartment = InsertableApartment::from(stored);
+                apartment.set_location(location);
                 apartments.push(apartment);
+                continue;
+            }
+
+            match card_into_complete_apartment(&tokens, &card, location).await {
+                Ok(apartment) => apartments.push(apartment),
+                Err(e) => error!("Skipping card {}: {:#}", card_id, e),
             }
         }
 
-        Ok(apartments)
+        Ok(ListingSearch {
+            apartments,
+            seen_card_ids,
+            complete,
+        })
+    }
+
+    /// Fetches the listing of a stored apartment again, e.g. to re-score it on request.
+    /// Size, rooms and location are kept, the rest comes from the listing.
+    pub async fn refresh_apartment(
+        &mut self,
+        apartment: &Apartment,
+    ) -> Result<InsertableApartment> {
+        let tokens = self.ensure_tokens().await?;
+        let card_data: CardResponse =
+            metrics::observe_oikotie("card", fetch_card(tokens, apartment.card_id.to_string()))
+                .await
+                .with_context(|| {
+                    format!("Did not fetch card data for card {}", apartment.card_id)
+                })?;
+
+        let mut refreshed = InsertableApartment::from(apartment);
+        apply_card_data(&mut refreshed, &card_data)?;
+        Ok(refreshed)
     }
 
     /// Fecthes all rental apartments for a certain location
@@ -267,7 +367,7 @@ impl Oikotie {
         };
 
         let oikotie_rental_cards_response: Result<CardsResponse> =
-            fetch_apartments_for_rent(&tokens, location.clone(), size_range).await;
+            fetch_apartments_for_rent(&tokens, &[location], size_range).await;
 
         let oikotie_rental_cards = match oikotie_rental_cards_response {
             Ok(c) => c.cards,
@@ -297,7 +397,7 @@ impl Oikotie {
         &mut self,
         config: &Arc<Config>,
         apartment: &InsertableApartment,
-    ) -> Result<i32> {
+    ) -> Result<RentPrediction> {
         let location = &Location {
             id: apartment.location_id.unwrap(),
             level: apartment.location_level.unwrap(),
@@ -326,11 +426,18 @@ impl Oikotie {
                 rooms,
                 price: price as f64,
                 maintenance_fee: maintenance_fee as f64,
-                auth_token: None,
+                build_year: apartment.build_year,
+                floor: apartment.floor,
+                building_type: apartment.building_type.as_deref(),
+                latitude: apartment.latitude,
+                longitude: apartment.longitude,
             };
 
             match ml_client::predict_rent(config.as_ref(), request).await {
-                Ok(prediction) if prediction > 0 => return Ok(prediction),
+                Ok(prediction) if prediction.rent > 0 => {
+                    metrics::RENT_ESTIMATES.with_label_values(&["ml"]).inc();
+                    return Ok(prediction);
+                }
                 Ok(_) => warn!(
                     "ML service returned non-positive rent for card {}, using heuristic fallback",
                     apartment.card_id
@@ -345,7 +452,16 @@ impl Oikotie {
         let rental_apartments_nearby = self.get_rental_data(location, size_range).await;
 
         match rental_apartments_nearby {
-            Ok(rental_data) => Ok(estimate_rent(size as f32, rental_data)),
+            Ok(rental_data) => {
+                metrics::RENT_ESTIMATES
+                    .with_label_values(&["heuristic"])
+                    .inc();
+                Ok(RentPrediction {
+                    rent: estimate_rent(size as f32, rental_data),
+                    lower: None,
+                    upper: None,
+                })
+            }
             Err(e) => Err(anyhow!(
                 "PRODUCER ERROR while calculating rent: {}",
                 e.to_string()
@@ -354,12 +470,9 @@ impl Oikotie {
     }
 }
 
-async fn fetch_location_id(
-    tokens: &OikotieTokens,
-    zip_code: &str,
-) -> Result<Vec<LocationResponse>> {
-    // Use location level 5 here to get ZIP CODE locations
-    let params: Vec<(&str, &str)> = vec![("query", zip_code), ("card_type", "5")];
+async fn fetch_locations(tokens: &OikotieTokens, query: &str) -> Result<Vec<LocationResponse>> {
+    // Without a card type, locations of every level are returned
+    let params: Vec<(&str, &str)> = vec![("query", query)];
 
     let headers = build_authenticated_headers(tokens)?;
 
@@ -385,51 +498,125 @@ async fn fetch_card(tokens: &OikotieTokens, card_id: String) -> Result<CardRespo
         .get(oikotie_cards_api_url)
         .headers(headers)
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
 
     let api_response: CardResponse = response.json().await?;
 
     Ok(api_response)
 }
 
+/// Whether the error is Oikotie answering that the listing does not exist, e.g. because it
+/// was deleted.
+pub fn listing_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            == Some(reqwest::StatusCode::NOT_FOUND)
+    })
+}
+
+/// Fetches the pages of the search until every card Oikotie found is fetched. A card is
+/// only returned once, even if listings published meanwhile moved it to the next page.
 async fn fetch_apartments_for_sale(
     tokens: &OikotieTokens,
-    location: Location,
-    target_size: SizeTarget,
+    locations: &[Location],
+    filter: &SearchFilter,
 ) -> Result<CardsResponse> {
-    fetch_apartments(tokens, location, target_size, String::from(CardTypes::SELL)).await
+    let mut found = 0;
+    let mut cards: Vec<Card> = Vec::new();
+    let mut seen: HashSet<u32> = HashSet::new();
+
+    for page in 0..MAX_CARDS_PAGES {
+        let response = metrics::observe_oikotie(
+            "cards",
+            fetch_apartments(
+                tokens,
+                locations,
+                filter,
+                String::from(CardTypes::SELL),
+                page * CARDS_PAGE_SIZE,
+            ),
+        )
+        .await?;
+        found = response.found;
+        let page_len = response.cards.len();
+        cards.extend(
+            response
+                .cards
+                .into_iter()
+                .filter(|card| seen.insert(card.id)),
+        );
+
+        if page_len < CARDS_PAGE_SIZE || cards.len() >= found as usize {
+            break;
+        }
+    }
+
+    Ok(CardsResponse { found, cards })
 }
 
 async fn fetch_apartments_for_rent(
     tokens: &OikotieTokens,
-    location: Location,
+    locations: &[Location],
     target_size: SizeTarget,
 ) -> Result<CardsResponse> {
-    fetch_apartments(tokens, location, target_size, String::from(CardTypes::RENT)).await
+    metrics::observe_oikotie(
+        "rental_cards",
+        fetch_apartments(
+            tokens,
+            locations,
+            &SearchFilter::from(target_size),
+            String::from(CardTypes::RENT),
+            0,
+        ),
+    )
+    .await
+}
+
+/// Query of a page of the cards search. Bounds of the filter are only sent when given.
+fn search_params(
+    locations: &[Location],
+    filter: &SearchFilter,
+    card_type: &str,
+    offset: usize,
+) -> Vec<(&'static str, String)> {
+    let mut params: Vec<(&'static str, String)> = vec![
+        ("cardType", card_type.to_string()),
+        ("locations", create_location_string(locations)),
+        ("limit", CARDS_PAGE_SIZE.to_string()),
+        ("offset", offset.to_string()),
+        // Newest first, so listings published meanwhile only repeat cards on the next page
+        ("sortBy", "published_sort_desc".to_string()),
+    ];
+
+    let bounds = [
+        ("size[min]", filter.size.min),
+        ("size[max]", filter.size.max),
+        ("price[max]", filter.max_price),
+        ("buildYear[min]", filter.min_build_year),
+    ];
+    for (key, bound) in bounds {
+        if let Some(bound) = bound {
+            params.push((key, bound.to_string()));
+        }
+    }
+    params
 }
 
 async fn fetch_apartments(
     tokens: &OikotieTokens,
-    location: Location,
-    target_size: SizeTarget,
+    locations: &[Location],
+    filter: &SearchFilter,
     card_type: String,
+    offset: usize,
 ) -> Result<CardsResponse> {
-    let min_size = target_size.min.unwrap_or_default().to_string();
-    let max_size = target_size.max.unwrap_or_default().to_string();
-    let location = create_location_string(location.id, location.level, location.name);
-
-    let mut params: Vec<(&str, &str)> = Vec::new();
-
-    params.push(("cardType", &card_type));
-    params.push(("locations", &location));
-
-    // Add size requirements to query if given
-    if !min_size.is_empty() {
-        params.push(("size[min]", &min_size));
-    }
-    if !max_size.is_empty() {
-        params.push(("size[max]", &max_size));
-    }
+    let query = search_params(locations, filter, &card_type, offset);
+    let params: Vec<(&str, &str)> = query
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect();
 
     let headers = build_authenticated_headers(tokens)?;
 
@@ -474,21 +661,48 @@ async fn card_into_complete_apartment(
 ) -> Result<InsertableApartment> {
     // TODO FIX THIS TO HANDLE 5.0 API
     // Fetch card data that includes total price information
-    let card_data: CardResponse = match fetch_card(tokens, card.id.to_string()).await {
-        Ok(c) => c,
-        Err(_e) => {
-            error!(
-                "Did not fetch card data for card {:?}. Error is {:?}",
-                card.id, _e
-            );
-            CardResponse::empty()
-        }
-    };
+    let card_data: CardResponse =
+        metrics::observe_oikotie("card", fetch_card(tokens, card.id.to_string()))
+            .await
+            .with_context(|| format!("Did not fetch card data for card {}", card.id))?;
 
     let card_id: i32 = card
         .id
         .try_into()
         .map_err(|_| anyhow!("Card id {} does not fit in i32", card.id))?;
+
+    let mut apartment = InsertableApartment {
+        card_id,
+        location_id: None,
+        location_level: None,
+        location_name: None,
+        size: Some(card.size as f64),
+        rooms: Some(card.rooms.unwrap_or_default() as i32),
+        price: None,
+        additional_costs: None,
+        rent: Some(0),
+        estimated_yield: Some(0.0),
+        url: Some(card.url.clone()),
+        build_year: None,
+        floor: None,
+        building_type: None,
+        latitude: None,
+        longitude: None,
+        rent_lower: None,
+        rent_upper: None,
+        status: None,
+        debt_share: None,
+        plot_ownership: None,
+    };
+    apartment.set_location(location);
+    apply_card_data(&mut apartment, &card_data)?;
+
+    Ok(apartment)
+}
+
+/// Sets the price, maintenance fee, status and other details of the listing's card data.
+fn apply_card_data(apartment: &mut InsertableApartment, card_data: &CardResponse) -> Result<()> {
+    let card_id = apartment.card_id;
     let price_i64 = i64::try_from(card_data.price_data.price)
         .context("Price does not fit in signed 64-bit integer")?;
     let maintenance_i64 = i64::try_from(card_data.ad_data.maintenance_fee)
@@ -514,42 +728,18 @@ async fn card_into_complete_apartment(
         maintenance_i64 as i32
     };
 
-    Ok(InsertableApartment {
-        card_id,
-        location_id: Some(location.id),
-        location_level: Some(location.level),
-        location_name: Some(location.name.clone()),
-        size: Some(card.size as f64),
-        rooms: Some(card.rooms.unwrap_or_default() as i32),
-        price: Some(price),
-        additional_costs: Some(maintenance_fee),
-        rent: Some(0),
-        estimated_yield: Some(0.0),
-        url: Some(card.url.clone()),
-    })
-}
-
-fn has_been_sent_to_watchlist(
-    config: Arc<Config>,
-    watchlist: &Watchlist,
-    card_id: i32,
-) -> Result<bool> {
-    let apartments = match get_watchlist_apartment_connector(&config, watchlist, card_id) {
-        Ok(aps) => aps,
-        Err(_e) => {
-            error!(
-                "Error while querying apartments within period for card {}: {:?}",
-                card_id, _e
-            );
-            vec![]
-        }
-    };
-
-    if apartments.is_empty() {
-        return Ok(false);
-    }
-
-    Ok(apartments[0].has_been_sent)
+    apartment.price = Some(price);
+    apartment.additional_costs = Some(maintenance_fee);
+    apartment.build_year = card_data.ad_data.build_year;
+    apartment.floor = card_data.ad_data.floor;
+    apartment.building_type = card_data.ad_data.building_type.clone();
+    apartment.latitude = card_data.coordinates.as_ref().map(|c| c.latitude);
+    apartment.longitude = card_data.coordinates.as_ref().map(|c| c.longitude);
+    apartment.status = Some(card_data.status);
+    apartment.debt_share = card_data.price_data.debt_share;
+    apartment.plot_ownership = card_data.ad_data.plot_ownership.clone();
+
+    Ok(())
 }
 
 fn price_int_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
@@ -631,3 +821,109 @@ where
         0.0
     }))
 }
+
+fn deserialize_optional_i32<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    let parsed = match value {
+        Some(Value::Number(num)) => num.as_f64().map(|v| v.round() as i32),
+        Some(Value::String(s)) => s
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '-')
+            .collect::<String>()
+            .parse::<i32>()
+            .ok(),
+        Some(Value::Null) | None => None,
+        other => {
+            warn!("Unexpected value for i32 field from API: {:?}", other);
+            None
+        }
+    };
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_price_is_parsed_from_search_results() {
+        let card = |price: &str| Card {
+            id: 1,
+            url: String::new(),
+            location: CardLocation::default(),
+            description: None,
+            rooms: None,
+            price: price.to_string(),
+            published: None,
+            size: 50.0,
+        };
+        assert_eq!(card("189 000 €").listed_price(), Some(189000));
+        assert_eq!(card("189\u{a0}000,50 €").listed_price(), Some(189000));
+        assert_eq!(card("").listed_price(), None);
+    }
+
+    #[test]
+    fn cards_get_the_searched_location_they_are_in() {
+        let location = |id, level, name: &str| Location {
+            id,
+            level,
+            name: name.to_string(),
+        };
+        let locations = [
+            location(1, LocationLevel::POSTCODE, "00100"),
+            location(2, LocationLevel::DISTRICT, "Kallio, Helsinki"),
+        ];
+        let card = |location: &str| Card {
+            id: 1,
+            url: String::new(),
+            location: serde_json::from_str(location).unwrap(),
+            description: None,
+            rooms: None,
+            price: String::new(),
+            published: None,
+            size: 50.0,
+        };
+
+        let in_kallio = card(r#"{"zipCode": "00530", "district": "kallio", "city": "Helsinki"}"#);
+        assert_eq!(in_kallio.location_in(&locations), Some(&locations[1]));
+        let in_postcode = card(r#"{"zipCode": "00100", "district": "Kamppi"}"#);
+        assert_eq!(in_postcode.location_in(&locations), Some(&locations[0]));
+        let unknown = card("{}");
+        assert_eq!(unknown.location_in(&locations), Some(&locations[0]));
+        assert_eq!(unknown.location_in(&[]), None);
+    }
+
+    #[test]
+    fn open_bounds_are_left_out_of_the_search() {
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let filter = SearchFilter {
+            size: SizeTarget {
+                min: Some(30),
+                max: None,
+            },
+            max_price: None,
+            min_build_year: Some(1990),
+        };
+
+        let params = search_params(&[location], &filter, "100", 0);
+
+        let value = |key: &str| {
+            params
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(value("size[min]"), Some("30"));
+        assert_eq!(value("size[max]"), None);
+        assert_eq!(value("price[max]"), None);
+        assert_eq!(value("buildYear[min]"), Some("1990"));
+    }
+}
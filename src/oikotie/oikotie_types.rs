@@ -4,3 +4,30 @@ impl CardTypes {
     pub const RENT: &str = "101";
     pub const SELL: &str = "100";
 }
+
+/// Values of `status` in Oikotie card responses.
+pub struct CardStatus;
+
+impl CardStatus {
+    /// The listing is published. Any other status means it is sold or closed.
+    pub const ACTIVE: i32 = 1;
+}
+
+/// Values of `card_type` in Oikotie location cards, i.e. the level of a location.
+pub struct LocationLevel;
+
+impl LocationLevel {
+    pub const DISTRICT: i32 = 4;
+    pub const POSTCODE: i32 = 5;
+    pub const CITY: i32 = 6;
+
+    /// Shown to users choosing between locations of the same name.
+    pub fn name(level: i32) -> Option<&'static str> {
+        match level {
+            LocationLevel::DISTRICT => Some("district"),
+            LocationLevel::POSTCODE => Some("postcode"),
+            LocationLevel::CITY => Some("city"),
+            _ => None,
+        }
+    }
+}
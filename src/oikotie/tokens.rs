@@ -1,8 +1,9 @@
-use log::{error, info};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::Deserialize;
+use tracing::{error, info};
 
 use super::helpers::generate_random_number;
+use crate::metrics;
 
 #[derive(Debug, Deserialize)]
 struct User {
@@ -57,7 +58,11 @@ async fn fetch_tokens() -> Result<Box<OikotieTokens>, reqwest::Error> {
 }
 
 pub async fn get_tokens() -> Option<Box<OikotieTokens>> {
-    let tokens: Result<Box<OikotieTokens>, reqwest::Error> = fetch_tokens().await;
+    let tokens: Result<Box<OikotieTokens>, reqwest::Error> =
+        metrics::observe_oikotie("user", fetch_tokens()).await;
+    metrics::OIKOTIE_TOKEN_REFRESHES
+        .with_label_values(&[metrics::outcome(&tokens)])
+        .inc();
 
     match tokens {
         Ok(tokens) => Some(tokens),
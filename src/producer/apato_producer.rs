@@ -1,40 +1,65 @@
 use crate::{
     config::Config,
-    db::{self, watchlist},
-    models::{apartment::Apartment, watchlist::Watchlist},
+    db::repository::SharedRepository,
+    health::Health,
+    metrics,
+    models::{apartment::Apartment, job::JobStatus, watchlist::Watchlist},
     MessageTask, TaskType,
 };
 use anyhow::Result;
-use async_channel::Sender;
-use log::error;
+use chrono::Utc;
 use std::{
+    collections::BTreeMap,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     time::Duration,
 };
-use teloxide::{requests::Requester, types::ChatId, Bot};
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::{broadcast::Receiver, Notify};
+use tracing::error;
+
+/// Jobs running for longer than this are assumed to belong to a dead worker and are re-queued.
+pub const STALE_JOB_MINUTES: i64 = 30;
 
 pub struct Producer;
 
 impl Producer {
     pub async fn run(
         config: &Arc<Config>,
+        repo: SharedRepository,
         shutdown: Arc<AtomicBool>,
-        producer_sender: Sender<MessageTask>,
-        bot: Arc<Bot>,
         mut shutdown_rx: Receiver<()>,
+        jobs_ready: Arc<Notify>,
+        health: Arc<Health>,
     ) -> Result<()> {
         let interval_in_seconds = config.producer_timeout_seconds as u64;
         let interval = Duration::from_secs(interval_in_seconds);
 
         while !shutdown.load(Ordering::Acquire) {
+            health.producer_heartbeat();
+            let stale_cutoff =
+                Utc::now().naive_utc() - chrono::Duration::minutes(STALE_JOB_MINUTES);
+            let released = match repo.release_stale_jobs(stale_cutoff).await {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Producer Error while releasing stale jobs: {:?}", e);
+                    0
+                }
+            };
+
             // TODO handle errors
-            handle_watchlists_tasks(config, producer_sender.clone()).await;
+            let queued =
+                handle_watchlists_tasks(&repo).await + handle_update_message_tasks(&repo).await;
+
+            // Wake up idle consumers
+            if released + queued > 0 {
+                jobs_ready.notify_waiters();
+            }
 
-            handle_update_message_tasks(config, bot.clone(), producer_sender.clone()).await;
+            if let Err(e) = record_queue_depth(&repo).await {
+                error!("Producer Error while counting jobs: {:?}", e);
+            }
 
             tokio::select! {
                _ = tokio::time::sleep(interval) => {}
@@ -43,85 +68,316 @@ impl Producer {
                }
             }
         }
+        health.producer_stopped();
         Ok(())
     }
 }
 
-/// Creates and sends UpdateWatchlist tasks to the message queue for each watchlist..
-async fn handle_watchlists_tasks(config: &Arc<Config>, producer_sender: Sender<MessageTask>) {
-    let watchlists = watchlist::get_all(&config.clone());
-    for watchlist in watchlists {
-        let _ = producer_sender
-            .send(MessageTask {
-                task_type: TaskType::UpdateWatchlist,
-                watchlist,
-                apartment: None,
-            })
-            .await;
+async fn record_queue_depth(repo: &SharedRepository) -> Result<()> {
+    let counts = repo.count_jobs_by_status().await?;
+    for status in [JobStatus::PENDING, JobStatus::RUNNING, JobStatus::DEAD] {
+        let n = counts
+            .iter()
+            .find(|(counted, _)| counted == status)
+            .map_or(0, |(_, n)| *n);
+        metrics::QUEUE_DEPTH.with_label_values(&[status]).set(n);
     }
+    Ok(())
 }
 
-/// Creates and sends SendMessage tasks to the message queue for each watchlist.
-async fn handle_update_message_tasks(
-    config: &Arc<Config>,
-    bot: Arc<Bot>,
-    producer_sender: Sender<MessageTask>,
-) {
-    let watchlists = db::watchlist::get_all(config);
+/// Queues a task, counting it if it was not queued already.
+async fn enqueue(repo: &SharedRepository, task: &MessageTask) -> Result<bool> {
+    let queued = repo.enqueue_task(task).await?;
+    if queued {
+        metrics::TASKS_ENQUEUED
+            .with_label_values(&[task.task_type().as_str()])
+            .inc();
+    }
+    Ok(queued)
+}
 
+/// Queues an UpdateLocation job for each set of locations with a watchlist that is due for
+/// a refresh and schedules the next refresh of those watchlists. The update covers every
+/// watchlist searching the same locations. Failed updates are retried by the job queue.
+///
+/// Returns how many jobs were queued.
+async fn handle_watchlists_tasks(repo: &SharedRepository) -> usize {
+    let watchlists = match repo.get_due_watchlists().await {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Producer Error while fetching watchlists: {:?}", e);
+            return 0;
+        }
+    };
+    let mut queued = 0;
+
+    let mut searches: BTreeMap<Vec<(i32, i32)>, Vec<Watchlist>> = BTreeMap::new();
     for watchlist in watchlists {
+        searches
+            .entry(watchlist.locations.key())
+            .or_default()
+            .push(watchlist);
+    }
+
+    for (key, watchlists) in searches {
+        let task = MessageTask::update_location(&watchlists[0].locations);
+        match enqueue(repo, &task).await {
+            Ok(true) => queued += 1,
+            Ok(false) => {}
+            Err(e) => {
+                error!(
+                    locations = ?key,
+                    "Producer Error while queueing locations: {:?}", e
+                );
+                continue;
+            }
+        }
+
+        for watchlist in watchlists {
+            let next_refresh_at = Utc::now().naive_utc() + watchlist.refresh_interval();
+            if let Err(e) = repo
+                .schedule_watchlist_refresh(watchlist.id, next_refresh_at)
+                .await
+            {
+                error!(
+                    watchlist_id = watchlist.id,
+                    "Producer Error while scheduling watchlist: {:?}", e
+                );
+            }
+        }
+    }
+    queued
+}
+
+/// Queues a SendMessage job for each unsent apartment of each watchlist that is not paused.
+///
+/// Messages whose job died are not queued again, so a message that cannot be sent is not
+/// retried forever. Their apartments stay unsent.
+///
+/// Returns how many jobs were queued.
+async fn handle_update_message_tasks(repo: &SharedRepository) -> usize {
+    let watchlists = match repo.get_all_watchlists().await {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Producer Error while fetching watchlists: {:?}", e);
+            return 0;
+        }
+    };
+    let dead = match repo.get_dead_job_keys(TaskType::SendMessage).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Producer Error while fetching dead messages: {:?}", e);
+            return 0;
+        }
+    };
+    let mut queued = 0;
+
+    // Paused chats cannot be messaged until they resume
+    for watchlist in watchlists.into_iter().filter(|w| !w.is_paused()) {
         let chat_id = watchlist.chat_id;
 
-        match find_apartments_to_send(config, watchlist.clone(), chat_id, bot.clone()).await {
-            // TODO FIX
+        match find_apartments_to_send(repo, &watchlist).await {
             Ok(apartments) => {
                 for ap in apartments {
-                    let task = MessageTask {
-                        task_type: TaskType::SendMessage,
-                        watchlist: watchlist.clone(),
-                        apartment: Some(ap),
-                    };
-                    // TODO fix
-                    let _ = producer_sender.send(task).await;
+                    let task = MessageTask::send_message(watchlist.id, ap.card_id);
+                    if dead.contains(&task.dedup_key()) {
+                        continue;
+                    }
+                    match enqueue(repo, &task).await {
+                        Ok(true) => queued += 1,
+                        Ok(false) => {}
+                        Err(e) => error!(
+                            watchlist_id = watchlist.id,
+                            card_id = ap.card_id,
+                            "Producer Error while queueing message: {:?}",
+                            e
+                        ),
+                    }
                 }
             }
-            Err(_e) => {}
+            Err(e) => error!(
+                watchlist_id = watchlist.id,
+                chat_id, "Producer Error while finding apartments to send: {:?}", e
+            ),
         }
     }
+    queued
 }
 
 /// Finds apartments from given watchlist that matches criteria and has not been sent.
 async fn find_apartments_to_send(
-    config: &Arc<Config>,
-    watchlist: Watchlist,
-    chat_id: i64,
-    bot: Arc<Bot>,
+    repo: &SharedRepository,
+    watchlist: &Watchlist,
 ) -> Result<Vec<Apartment>> {
-    let unsent_apartments =
-        db::apartment_watchlist::get_unsent_apartments(config, &watchlist.clone());
-
-    let new_targets = match unsent_apartments {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Consumer Error while fetching new targets: {:?}", e);
-            bot.send_message(ChatId(chat_id), format!("{}", e)).await?;
-            return Ok(Vec::new());
-        }
-    };
+    let new_targets = repo.get_unsent_matches(watchlist.id).await?;
 
     let mut aps: Vec<Apartment> = Vec::new();
 
     for card_id in new_targets {
-        let ap = db::apartment::get_apartment_by_card_id(config, card_id);
-        match ap {
-            Ok(a) => {
-                if let Some(apartment) = a {
-                    let ap_clone = apartment.clone();
-                    aps.push(ap_clone);
-                }
+        // Sold or removed listings are not announced
+        if let Some(apartment) = repo.get_apartment(card_id).await? {
+            if apartment.is_active() {
+                aps.push(apartment);
             }
-            Err(e) => return Err(e.into()),
         }
     }
     Ok(aps)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::memory::InMemoryRepository,
+        models::watchlist::{Locations, SizeTarget},
+        oikotie::oikotie::Location,
+        services::watchlists,
+        test_support::apartment,
+    };
+
+    #[tokio::test]
+    async fn finds_only_unsent_matches() {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let watchlist = repo
+            .insert_watchlist(location, 42, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+
+        for card_id in [1, 2, 3] {
+            repo.upsert_apartment(apartment(card_id, 8.0), &[])
+                .await
+                .unwrap();
+        }
+        repo.insert_match(watchlist.id, 1).await.unwrap();
+        repo.insert_match(watchlist.id, 2).await.unwrap();
+        repo.set_match_sent(watchlist.id, 2, Some(200000))
+            .await
+            .unwrap();
+
+        let to_send = find_apartments_to_send(&repo, &watchlist).await.unwrap();
+
+        let card_ids: Vec<i32> = to_send.iter().map(|a| a.card_id).collect();
+        assert_eq!(card_ids, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn dead_messages_are_not_queued_again_until_chat_resumes() {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let watchlist = repo
+            .insert_watchlist(location, 42, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(1, 8.0), &[watchlist.id])
+            .await
+            .unwrap();
+
+        assert_eq!(handle_update_message_tasks(&repo).await, 1);
+        let job = repo.claim_job().await.unwrap().unwrap();
+        assert!(repo.fail_job(&job, "Bad Request", false).await.unwrap());
+        assert_eq!(handle_update_message_tasks(&repo).await, 0);
+
+        repo.pause_watchlists_for_chat(42).await.unwrap();
+        assert_eq!(watchlists::resume(&repo, 42).await.unwrap(), 1);
+        assert_eq!(handle_update_message_tasks(&repo).await, 1);
+        let counts = repo.count_jobs_by_status().await.unwrap();
+        assert_eq!(counts, vec![(JobStatus::PENDING.to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn queues_only_due_watchlists() {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let due = repo
+            .insert_watchlist(location.clone(), 42, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        let later = repo
+            .insert_watchlist(location.clone(), 43, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        repo.schedule_watchlist_refresh(
+            later.id,
+            Utc::now().naive_utc() + chrono::Duration::hours(1),
+        )
+        .await
+        .unwrap();
+
+        handle_watchlists_tasks(&repo).await;
+
+        let job = repo.claim_job().await.unwrap().unwrap();
+        assert_eq!(
+            job.task().unwrap(),
+            MessageTask::update_location(&[location])
+        );
+        assert!(repo.claim_job().await.unwrap().is_none());
+
+        // Not due again until its refresh interval has passed
+        repo.complete_job(job.id).await.unwrap();
+        handle_watchlists_tasks(&repo).await;
+        assert!(repo.claim_job().await.unwrap().is_none());
+        let rescheduled = repo.get_watchlist(due.id).await.unwrap().unwrap();
+        assert!(rescheduled.next_refresh_at > Utc::now().naive_utc());
+    }
+
+    #[tokio::test]
+    async fn queues_one_update_per_location_set() {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let location = |id, level, name: &str| Location {
+            id,
+            level,
+            name: name.to_string(),
+        };
+        let searches = [
+            vec![location(1, 5, "00100")],
+            vec![location(1, 5, "00100")],
+            vec![location(2, 5, "00200")],
+            vec![location(1, 4, "Helsinki")],
+            vec![location(1, 5, "00100"), location(2, 5, "00200")],
+            vec![location(2, 5, "00200"), location(1, 5, "00100")],
+        ];
+        for (chat_id, locations) in searches.iter().enumerate() {
+            let watchlist = repo
+                .insert_watchlist(
+                    locations[0].clone(),
+                    chat_id as i64,
+                    Some(5.0),
+                    SizeTarget::empty(),
+                )
+                .await
+                .unwrap();
+            repo.update_watchlist_locations(watchlist.id, &Locations(locations.clone()))
+                .await
+                .unwrap();
+        }
+
+        handle_watchlists_tasks(&repo).await;
+
+        let mut tasks = Vec::new();
+        while let Some(job) = repo.claim_job().await.unwrap() {
+            tasks.push(job.task().unwrap());
+        }
+        assert_eq!(
+            tasks,
+            vec![
+                MessageTask::update_location(&[location(1, 4, "Helsinki")]),
+                MessageTask::update_location(&[location(1, 5, "00100")]),
+                MessageTask::update_location(&[location(1, 5, "00100"), location(2, 5, "00200")]),
+                MessageTask::update_location(&[location(2, 5, "00200")]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    consumer::{
+        apato_consumer::{matching_watchlists, own_financing_yields, score_apartment},
+        calculations::{self, CashFlowAnalysis, Sensitivity},
+    },
+    db::repository::SharedRepository,
+    errors::ApatoError,
+    interest_rate::interest_rate_client,
+    models::{
+        apartment::Apartment,
+        apartment_state::{
+            ApartmentState, ApartmentStateUpdate, ApartmentStatus, MAX_NOTES_LENGTH,
+        },
+        price_history::PriceHistoryEntry,
+    },
+    oikotie::oikotie::Oikotie,
+    services::watchlists,
+};
+
+#[derive(Debug, Serialize)]
+pub struct ApartmentHistory {
+    pub card_id: i32,
+    /// When the listing was first seen by apato.
+    pub first_seen: NaiveDateTime,
+    /// When the listing was sold or removed, if it is no longer active.
+    pub removed_at: Option<NaiveDateTime>,
+    pub days_on_market: i64,
+    pub history: Vec<PriceHistoryEntry>,
+}
+
+/// Price history of a listing in one of the chat's watchlists together with how long it
+/// has been on the market.
+pub async fn get_history(
+    repo: &SharedRepository,
+    chat_id: i64,
+    card_id: i32,
+) -> Result<ApartmentHistory> {
+    let apartment = get_for_chat(repo, chat_id, card_id).await?;
+    let history = repo.get_price_history(card_id).await?;
+
+    let first_seen = history.first().map_or(apartment.created_at, |entry| {
+        entry.observed_at.min(apartment.created_at)
+    });
+    let until = apartment
+        .removed_at
+        .unwrap_or_else(|| Utc::now().naive_utc());
+    let days_on_market = (until - first_seen).num_days();
+
+    Ok(ApartmentHistory {
+        card_id,
+        first_seen,
+        removed_at: apartment.removed_at,
+        days_on_market,
+        history,
+    })
+}
+
+/// Fetches an apartment, making sure it is in the locations of one of the chat's watchlists.
+async fn get_for_chat(repo: &SharedRepository, chat_id: i64, card_id: i32) -> Result<Apartment> {
+    let not_found = || ApatoError::not_found(format!("Apartment {} not found", card_id));
+    let apartment = repo.get_apartment(card_id).await?.ok_or_else(not_found)?;
+    let (Some(location_id), Some(location_level)) =
+        (apartment.location_id, apartment.location_level)
+    else {
+        return Err(not_found().into());
+    };
+
+    let watched = repo
+        .get_watchlists_for_chat(chat_id)
+        .await?
+        .iter()
+        .any(|w| w.locations.contains(location_id, location_level));
+    if !watched {
+        return Err(not_found().into());
+    }
+    Ok(apartment)
+}
+
+async fn get(repo: &SharedRepository, card_id: i32) -> Result<Apartment> {
+    repo.get_apartment(card_id)
+        .await?
+        .ok_or_else(|| ApatoError::not_found(format!("Apartment {} not found", card_id)).into())
+}
+
+/// Price, rent and maintenance fee of the apartment, with the current interest rate.
+async fn yield_inputs(config: &Arc<Config>, apartment: &Apartment) -> Result<(f64, f64, f64, f64)> {
+    let (Some(price), Some(rent), Some(fee)) =
+        (apartment.price, apartment.rent, apartment.additional_costs)
+    else {
+        return Err(ApatoError::user_facing(format!(
+            "Apartment {} has not been scored yet",
+            apartment.card_id
+        ))
+        .into());
+    };
+    let interest_rate = interest_rate_client::get_interest_rate(config).await?;
+
+    Ok((
+        f64::from(price),
+        f64::from(rent),
+        f64::from(fee),
+        interest_rate,
+    ))
+}
+
+/// The yearly cash flows the apartment's yield is calculated from.
+pub async fn cash_flows(
+    config: &Arc<Config>,
+    repo: &SharedRepository,
+    card_id: i32,
+) -> Result<(Apartment, CashFlowAnalysis)> {
+    let apartment = get(repo, card_id).await?;
+    let (price, rent, fee, interest_rate) = yield_inputs(config, &apartment).await?;
+    let analysis = calculations::cash_flows(config, price, rent, fee, interest_rate);
+    Ok((apartment, analysis))
+}
+
+/// The apartment's yield with lower and higher rent and interest rates.
+pub async fn sensitivity(
+    config: &Arc<Config>,
+    repo: &SharedRepository,
+    card_id: i32,
+) -> Result<(Apartment, Sensitivity)> {
+    let apartment = get(repo, card_id).await?;
+    let (price, rent, fee, interest_rate) = yield_inputs(config, &apartment).await?;
+    let sensitivity = calculations::sensitivity(config, price, rent, fee, interest_rate);
+    Ok((apartment, sensitivity))
+}
+
+/// An apartment together with the chat's state of it.
+#[derive(Debug, Serialize)]
+pub struct TrackedApartment {
+    pub apartment: Apartment,
+    pub state: ApartmentState,
+}
+
+/// Sets the chat's status of the apartment, e.g. `ApartmentStatus::SAVED`.
+pub async fn set_status(
+    repo: &SharedRepository,
+    chat_id: i64,
+    card_id: i32,
+    status: &str,
+) -> Result<ApartmentState> {
+    check_status(status)?;
+    get(repo, card_id).await?;
+    repo.set_apartment_status(chat_id, card_id, status).await
+}
+
+/// Replaces the chat's notes on the apartment. Empty notes clear them.
+pub async fn set_notes(
+    repo: &SharedRepository,
+    chat_id: i64,
+    card_id: i32,
+    notes: &str,
+) -> Result<ApartmentState> {
+    let notes = notes.trim();
+    check_notes(notes)?;
+    get(repo, card_id).await?;
+    let notes = (!notes.is_empty()).then_some(notes);
+    repo.set_apartment_notes(chat_id, card_id, notes).await
+}
+
+/// Changes the chat's status of and notes on the apartment.
+pub async fn update_state(
+    repo: &SharedRepository,
+    chat_id: i64,
+    card_id: i32,
+    update: ApartmentStateUpdate,
+) -> Result<ApartmentState> {
+    // Nothing is changed if any of the changes is invalid
+    if let Some(status) = &update.status {
+        check_status(status)?;
+    }
+    if let Some(Some(notes)) = &update.notes {
+        check_notes(notes.trim())?;
+    }
+
+    let mut state = match update.notes {
+        Some(notes) => {
+            Some(set_notes(repo, chat_id, card_id, notes.as_deref().unwrap_or("")).await?)
+        }
+        None => None,
+    };
+    if let Some(status) = update.status {
+        state = Some(set_status(repo, chat_id, card_id, &status).await?);
+    }
+
+    match state {
+        Some(state) => Ok(state),
+        None => Err(ApatoError::user_facing("Nothing to change").into()),
+    }
+}
+
+fn check_status(status: &str) -> Result<()> {
+    if !ApartmentStatus::ALL.contains(&status) {
+        return Err(ApatoError::user_facing(format!(
+            "Status must be one of {}",
+            ApartmentStatus::ALL.join(", ")
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn check_notes(notes: &str) -> Result<()> {
+    if notes.chars().count() > MAX_NOTES_LENGTH {
+        return Err(ApatoError::user_facing(format!(
+            "Notes can be at most {} characters",
+            MAX_NOTES_LENGTH
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// The chat's apartments with one of the statuses, most recently changed first.
+pub async fn get_tracked(
+    repo: &SharedRepository,
+    chat_id: i64,
+    statuses: &[&str],
+) -> Result<Vec<TrackedApartment>> {
+    let mut tracked = Vec::new();
+    for state in repo.get_apartment_states(chat_id).await? {
+        if !statuses.contains(&state.status.as_str()) {
+            continue;
+        }
+        if let Some(apartment) = repo.get_apartment(state.card_id).await? {
+            tracked.push(TrackedApartment { apartment, state });
+        }
+    }
+    Ok(tracked)
+}
+
+/// Whether the chat has dismissed the apartment.
+pub async fn is_dismissed(repo: &SharedRepository, chat_id: i64, card_id: i32) -> Result<bool> {
+    Ok(repo
+        .get_apartment_state(chat_id, card_id)
+        .await?
+        .is_some_and(|state| state.status == ApartmentStatus::DISMISSED))
+}
+
+/// The apartments the chat has not dismissed.
+pub async fn without_dismissed(
+    repo: &SharedRepository,
+    chat_id: i64,
+    apartments: Vec<Apartment>,
+) -> Result<Vec<Apartment>> {
+    let dismissed: HashSet<i32> = repo
+        .get_apartment_states(chat_id)
+        .await?
+        .into_iter()
+        .filter(|state| state.status == ApartmentStatus::DISMISSED)
+        .map(|state| state.card_id)
+        .collect();
+    Ok(apartments
+        .into_iter()
+        .filter(|apartment| !dismissed.contains(&apartment.card_id))
+        .collect())
+}
+
+/// Fetches the listing from Oikotie again and re-scores it. The apartment is matched to the
+/// watchlist if it now meets its criteria, with the yield of the watchlist's own financing
+/// if it has one, and a closed listing is marked removed.
+pub async fn recalculate(
+    config: &Arc<Config>,
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+    card_id: i32,
+) -> Result<Apartment> {
+    let watchlist = watchlists::get_for_chat(repo, chat_id, watchlist_id).await?;
+    let stored = get(repo, card_id).await?;
+
+    let mut oikotie = Oikotie::new().await;
+    let mut apartment = oikotie.refresh_apartment(&stored).await?;
+    if apartment.is_closed() {
+        repo.mark_apartments_removed(&[card_id]).await?;
+        return get(repo, card_id).await;
+    }
+
+    let irr = score_apartment(config, &mut oikotie, &mut apartment).await?;
+    let watched = std::slice::from_ref(&watchlist);
+    let own_yields = own_financing_yields(config, watched, &apartment, irr).await?;
+    let matched = matching_watchlists(watched, &apartment, irr, &own_yields);
+    let mut stored = repo
+        .upsert_apartment_with_yields(apartment, &matched, &own_yields)
+        .await?;
+
+    // Shown with the yield of the watchlist's own financing
+    if let Some(&(_, own_yield)) = own_yields.first() {
+        stored.estimated_yield = Some(own_yield);
+    }
+    Ok(stored)
+}
@@ -0,0 +1,40 @@
+use anyhow::Result;
+
+use crate::{
+    db::repository::SharedRepository,
+    models::api_token::{generate_token, hash_token},
+};
+
+/// Issues a new API token for the chat, revoking its previous one. The token is only
+/// returned here, it cannot be recovered from the database.
+pub async fn issue(repo: &SharedRepository, chat_id: i64) -> Result<String> {
+    let token = generate_token();
+    repo.replace_api_token(chat_id, &hash_token(&token)).await?;
+    Ok(token)
+}
+
+/// Chat the token was issued to, if it is valid.
+pub async fn authenticate(repo: &SharedRepository, token: &str) -> Result<Option<i64>> {
+    repo.use_api_token(&hash_token(token)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory::InMemoryRepository;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn new_token_revokes_the_previous_one() {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+
+        let first = issue(&repo, 1).await.unwrap();
+        assert_eq!(authenticate(&repo, &first).await.unwrap(), Some(1));
+
+        let second = issue(&repo, 1).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(authenticate(&repo, &first).await.unwrap(), None);
+        assert_eq!(authenticate(&repo, &second).await.unwrap(), Some(1));
+        assert_eq!(authenticate(&repo, "guess").await.unwrap(), None);
+    }
+}
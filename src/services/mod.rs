@@ -1 +1,4 @@
+pub mod apartments;
+pub mod api_tokens;
+pub mod sessions;
 pub mod watchlists;
@@ -0,0 +1,200 @@
+//! Sign-in to the web console with one-time links sent by the bot.
+//!
+//! Links and sessions are tokens signed with `session_secret`, so they can be checked
+//! without a database lookup. A token is the base64 encoded payload and its HMAC-SHA256,
+//! separated by a dot. The payload names its purpose, so a link cannot be used as a session.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::{config::Config, errors::ApatoError, models::api_token::generate_token};
+
+/// How long a login link can be opened.
+pub const LOGIN_LINK_MINUTES: i64 = 10;
+/// How long a session lasts before the user has to sign in again.
+pub const SESSION_DAYS: i64 = 7;
+
+const LOGIN_PURPOSE: &str = "login";
+const SESSION_PURPOSE: &str = "session";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub token: String,
+    pub chat_id: i64,
+    pub expires_at: NaiveDateTime,
+}
+
+/// Login links that have been used, so each link signs in only once. Entries are dropped
+/// once their link has expired anyway.
+#[derive(Default)]
+pub struct RedeemedLinks {
+    nonces: Mutex<HashMap<String, i64>>,
+}
+
+impl RedeemedLinks {
+    /// Whether the link was not redeemed before.
+    fn redeem(&self, nonce: &str, expires: i64, now: i64) -> bool {
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, &mut expires| expires >= now);
+        nonces.insert(nonce.to_string(), expires).is_none()
+    }
+}
+
+/// Link to the web console that signs the chat in when opened.
+pub fn login_link(config: &Config, chat_id: i64) -> Result<String> {
+    let (Some(secret), Some(url)) = (
+        secret(config),
+        config.web_console_url.as_deref().filter(|u| !u.is_empty()),
+    ) else {
+        return Err(ApatoError::user_facing("Web console login is not configured.").into());
+    };
+
+    let expires = Utc::now().timestamp() + LOGIN_LINK_MINUTES * 60;
+    let token = sign(
+        secret,
+        &format!(
+            "{}:{}:{}:{}",
+            LOGIN_PURPOSE,
+            chat_id,
+            expires,
+            generate_token()
+        ),
+    );
+    Ok(format!("{}/?login={}", url.trim_end_matches('/'), token))
+}
+
+/// Exchanges the token of a login link for a session of its chat.
+pub fn start_session(
+    config: &Config,
+    redeemed: &RedeemedLinks,
+    login_token: &str,
+) -> Result<Session> {
+    let invalid = || ApatoError::user_facing("The login link is invalid or has expired.");
+    let secret = secret(config).ok_or_else(invalid)?;
+    let now = Utc::now().timestamp();
+
+    let (chat_id, expires, nonce) = verify(secret, login_token)
+        .and_then(|payload| {
+            let mut parts = payload.splitn(4, ':');
+            let purpose = parts.next()?;
+            let chat_id = parts.next()?.parse::<i64>().ok()?;
+            let expires = parts.next()?.parse::<i64>().ok()?;
+            let nonce = parts.next()?.to_string();
+            (purpose == LOGIN_PURPOSE && expires >= now).then_some((chat_id, expires, nonce))
+        })
+        .ok_or_else(invalid)?;
+    if !redeemed.redeem(&nonce, expires, now) {
+        return Err(invalid().into());
+    }
+
+    let expires_at = Utc::now() + Duration::days(SESSION_DAYS);
+    let token = sign(
+        secret,
+        &format!("{}:{}:{}", SESSION_PURPOSE, chat_id, expires_at.timestamp()),
+    );
+    Ok(Session {
+        token,
+        chat_id,
+        expires_at: expires_at.naive_utc(),
+    })
+}
+
+/// Chat of the session token, if it is valid and not expired.
+pub fn session_chat(config: &Config, session_token: &str) -> Option<i64> {
+    let payload = verify(secret(config)?, session_token)?;
+    let mut parts = payload.splitn(3, ':');
+    let purpose = parts.next()?;
+    let chat_id = parts.next()?.parse::<i64>().ok()?;
+    let expires = parts.next()?.parse::<i64>().ok()?;
+    (purpose == SESSION_PURPOSE && expires >= Utc::now().timestamp()).then_some(chat_id)
+}
+
+fn secret(config: &Config) -> Option<&str> {
+    config.session_secret.as_deref().filter(|s| !s.is_empty())
+}
+
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(payload.as_bytes());
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+}
+
+/// The payload of the token, if it was signed with the secret.
+fn verify(secret: &str, token: &str) -> Option<String> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+    String::from_utf8(payload).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::create_test_config;
+
+    fn config() -> Config {
+        let mut config = create_test_config();
+        config.session_secret = Some("secret".to_string());
+        config.web_console_url = Some("https://apato.example/".to_string());
+        config
+    }
+
+    fn login_token(link: &str) -> &str {
+        link.strip_prefix("https://apato.example/?login=").unwrap()
+    }
+
+    #[test]
+    fn login_link_signs_in_once() {
+        let config = config();
+        let redeemed = RedeemedLinks::default();
+        let link = login_link(&config, 42).unwrap();
+
+        let session = start_session(&config, &redeemed, login_token(&link)).unwrap();
+        assert_eq!(session.chat_id, 42);
+        assert_eq!(session_chat(&config, &session.token), Some(42));
+
+        assert!(start_session(&config, &redeemed, login_token(&link)).is_err());
+    }
+
+    #[test]
+    fn tampered_or_expired_tokens_are_rejected() {
+        let config = config();
+        let redeemed = RedeemedLinks::default();
+        let link = login_link(&config, 42).unwrap();
+        let token = login_token(&link);
+
+        // Signed with another secret
+        let mut other = config.clone();
+        other.session_secret = Some("other".to_string());
+        assert!(start_session(&other, &redeemed, token).is_err());
+
+        // A link is not a session
+        assert_eq!(session_chat(&config, token), None);
+
+        let expired = sign("secret", "login:42:1000:nonce");
+        assert!(start_session(&config, &redeemed, &expired).is_err());
+        let expired = sign("secret", "session:42:1000");
+        assert_eq!(session_chat(&config, &expired), None);
+    }
+
+    #[test]
+    fn login_requires_configuration() {
+        assert!(login_link(&create_test_config(), 42).is_err());
+    }
+}
@@ -1,95 +1,877 @@
-use std::sync::Arc;
-
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use tracing::info;
 
 use crate::{
-    config::Config,
-    db,
+    db::repository::SharedRepository,
+    errors::ApatoError,
     models::{
-        apartment::Apartment,
-        watchlist::{SizeTarget, Watchlist},
+        apartment::{Apartment, InsertableApartment},
+        price_history::drop_percent,
+        watchlist::{
+            Financing, ListingCriteria, SizeTarget, Watchlist, WatchlistUpdate, MAX_LOAN_YEARS,
+            MAX_LOCATIONS, MAX_REFRESH_INTERVAL_MINUTES, MIN_REFRESH_INTERVAL_MINUTES,
+        },
     },
     oikotie::oikotie::{Location, Oikotie},
+    services::apartments,
 };
 
+/// Most candidates offered when a location query is ambiguous.
+pub const MAX_LOCATION_CANDIDATES: usize = 8;
+
+/// Location a query resolved to, or the candidates to choose from.
+#[derive(Debug, PartialEq)]
+pub enum LocationMatch {
+    Found(Location),
+    Ambiguous(Vec<Location>),
+}
+
+/// Locations of any level Oikotie finds for the query, best match first.
+pub async fn search_locations(query: &str) -> Result<Vec<Location>> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(ApatoError::user_facing("Please give a location to search for").into());
+    }
+
+    let mut oikotie_client = Oikotie::new().await;
+    let mut locations: Vec<Location> = Vec::new();
+    for location in oikotie_client.search_locations(query).await? {
+        let seen = locations
+            .iter()
+            .any(|l| l.id == location.id && l.level == location.level);
+        if !seen && locations.len() < MAX_LOCATION_CANDIDATES {
+            locations.push(location);
+        }
+    }
+    Ok(locations)
+}
+
+/// Resolves the query to a single location if it is clear which one is meant: a location
+/// the chat already watches by that name, the only location found, or the only one named
+/// exactly like the query. Otherwise the user has to choose.
+pub async fn resolve_location(
+    repo: &SharedRepository,
+    chat_id: i64,
+    query: &str,
+) -> Result<LocationMatch> {
+    let watched = repo
+        .get_watchlists_for_chat_and_location(chat_id, query.trim())
+        .await?;
+    if let Some(watchlist) = watched.iter().find(|w| w.locations.len() == 1) {
+        return Ok(LocationMatch::Found(watchlist.locations[0].clone()));
+    }
+
+    pick_location(query, search_locations(query).await?)
+}
+
+fn pick_location(query: &str, mut candidates: Vec<Location>) -> Result<LocationMatch> {
+    if candidates.len() > 1 {
+        let exact: Vec<&Location> = candidates
+            .iter()
+            .filter(|l| l.name.eq_ignore_ascii_case(query.trim()))
+            .collect();
+        if let [location] = exact[..] {
+            return Ok(LocationMatch::Found(location.clone()));
+        }
+        return Ok(LocationMatch::Ambiguous(candidates));
+    }
+    candidates
+        .pop()
+        .map(LocationMatch::Found)
+        .ok_or_else(|| ApatoError::user_facing("Did not find any location with that query").into())
+}
+
+/// Checks that a location chosen by the user exists on Oikotie, searching for its name.
+/// Locations searched by a watchlist are known to exist. Returns the location with the
+/// name it is stored with or Oikotie gives it.
+pub async fn verify_location(repo: &SharedRepository, location: &Location) -> Result<Location> {
+    let same = |l: &&Location| l.id == location.id && l.level == location.level;
+
+    let watchlists = repo.get_watchlists_for_location(location).await?;
+    if let Some(known) = watchlists
+        .iter()
+        .find_map(|w| w.locations.iter().find(same))
+    {
+        return Ok(known.clone());
+    }
+
+    let mut oikotie_client = Oikotie::new().await;
+    oikotie_client
+        .search_locations(location.name.trim())
+        .await?
+        .iter()
+        .find(same)
+        .cloned()
+        .ok_or_else(|| {
+            ApatoError::user_facing(format!("Did not find location {}", location.name)).into()
+        })
+}
+
+/// Creates a watchlist for the location. If the chat already watches the location alone,
+/// its size range, target yield, minimum price drop, other criteria and financing are
+/// replaced instead.
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe(
-    config: Arc<Config>,
+    repo: &SharedRepository,
     chat_id: i64,
-    location_query: String,
+    location: Location,
     size: (f64, f64),
     target_yield: f64,
+    min_price_drop: Option<f64>,
+    criteria: ListingCriteria,
+    financing: Financing,
 ) -> Result<Watchlist> {
-    let existing = db::watchlist::get_for_chat_and_location(&config, chat_id, &location_query);
-    if let Some(current) = existing.first() {
-        db::watchlist::update_yield(&config, current.id, target_yield).await?;
-        let mut updated = current.clone();
-        updated.target_yield = Some(target_yield);
-        return Ok(updated);
+    if !size.0.is_finite() || !size.1.is_finite() {
+        return Err(ApatoError::user_facing("Size must be a number").into());
+    }
+    let target_size = SizeTarget {
+        min: Some(size.0 as i32),
+        max: Some(size.1 as i32),
+    };
+    check_size(&target_size)?;
+    if min_price_drop.is_some_and(|percent| !(0.0..=100.0).contains(&percent)) {
+        return Err(ApatoError::user_facing("Price drop must be between 0 and 100 percent").into());
     }
+    check_criteria(&criteria)?;
+    check_financing(&financing)?;
 
-    let mut oikotie_client = Oikotie::new().await;
-    let locations = oikotie_client
-        .get_locations_for_zip_code(&location_query)
+    let existing = repo
+        .get_watchlists_for_chat_and_location(chat_id, &location.name)
         .await?;
+    let current = existing
+        .into_iter()
+        .find(|w| w.locations.len() == 1 && w.locations.contains(location.id, location.level));
+    let mut watchlist = match current {
+        Some(mut current) => {
+            // A new size range may need a new search
+            if (current.target_size_min, current.target_size_max)
+                != (target_size.min, target_size.max)
+            {
+                current.target_size_min = target_size.min;
+                current.target_size_max = target_size.max;
+                current.next_refresh_at = Utc::now().naive_utc();
+            }
+            current
+        }
+        None => {
+            repo.insert_watchlist(location, chat_id, Some(target_yield), target_size)
+                .await?
+        }
+    };
 
-    if locations.is_empty() {
-        return Err(anyhow!("Did not find any location with that query"));
+    watchlist.target_yield = Some(target_yield);
+    if let Some(percent) = min_price_drop {
+        watchlist.min_price_drop_percent = percent;
     }
+    watchlist.criteria = criteria;
+    let financing_changed = watchlist.financing != financing;
+    watchlist.financing = financing;
+    repo.update_watchlist_criteria(&watchlist).await?;
+    if financing_changed {
+        repo.clear_match_yields(watchlist.id).await?;
+    }
+    rematch(repo, &watchlist).await?;
 
-    let location_card = &locations[0].card;
-    let watchlist_location = Location {
-        id: location_card.card_id as i32,
-        level: location_card.card_type as i32,
-        name: location_card.name.clone(),
+    Ok(watchlist)
+}
+
+/// Adds the location found with the query to the watchlist, so it is searched together
+/// with the watchlist's other locations.
+pub async fn add_location(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+    location_query: &str,
+) -> Result<Watchlist> {
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    if watchlist.locations.len() >= MAX_LOCATIONS {
+        return Err(ApatoError::user_facing(format!(
+            "A watchlist can have at most {} locations",
+            MAX_LOCATIONS
+        ))
+        .into());
+    }
+
+    match resolve_location(repo, chat_id, location_query).await? {
+        LocationMatch::Found(location) => add_found_location(repo, watchlist, location).await,
+        LocationMatch::Ambiguous(candidates) => Err(ApatoError::user_facing(format!(
+            "Several locations match '{}': {}. Please be more specific.",
+            location_query.trim(),
+            candidates
+                .iter()
+                .map(Location::label)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+        .into()),
+    }
+}
+
+async fn add_found_location(
+    repo: &SharedRepository,
+    mut watchlist: Watchlist,
+    location: Location,
+) -> Result<Watchlist> {
+    if watchlist.locations.contains(location.id, location.level) {
+        return Err(ApatoError::user_facing(format!(
+            "Watchlist {} already has location {}",
+            watchlist.id, location.name
+        ))
+        .into());
+    }
+
+    watchlist.locations.0.push(location);
+    repo.update_watchlist_locations(watchlist.id, &watchlist.locations)
+        .await?;
+    refresh_now(repo, &mut watchlist).await?;
+    rematch(repo, &watchlist).await?;
+    Ok(watchlist)
+}
+
+/// Removes the named location from the watchlist. Unsent matches in it are dropped.
+/// The last location cannot be removed, the watchlist is deleted instead.
+pub async fn remove_location(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+    location_name: &str,
+) -> Result<Watchlist> {
+    let mut watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    let Some(index) = watchlist
+        .locations
+        .iter()
+        .position(|l| l.name.eq_ignore_ascii_case(location_name.trim()))
+    else {
+        return Err(ApatoError::not_found(format!(
+            "Watchlist {} does not have location {}",
+            watchlist.id, location_name
+        ))
+        .into());
     };
-    let location_handle = watchlist_location.clone();
-
-    let mut target_size = SizeTarget::empty();
-    target_size.min = Some(size.0 as i32);
-    target_size.max = Some(size.1 as i32);
-
-    db::watchlist::insert(
-        &config,
-        watchlist_location,
-        chat_id,
-        Some(target_yield),
-        target_size,
+    if watchlist.locations.len() == 1 {
+        return Err(ApatoError::user_facing(
+            "Cannot remove the only location of a watchlist. Delete the watchlist instead.",
+        )
+        .into());
+    }
+
+    let removed = watchlist.locations.0.remove(index);
+    repo.update_watchlist_locations(watchlist.id, &watchlist.locations)
+        .await?;
+    refresh_now(repo, &mut watchlist).await?;
+
+    let mut unmatched = Vec::new();
+    for card_id in repo.get_unsent_matches(watchlist.id).await? {
+        let in_removed = repo.get_apartment(card_id).await?.is_some_and(|a| {
+            a.location_id == Some(removed.id) && a.location_level == Some(removed.level)
+        });
+        if in_removed {
+            unmatched.push(card_id);
+        }
+    }
+    let dropped = repo.delete_unsent_matches(watchlist.id, &unmatched).await?;
+    info!(
+        watchlist_id = watchlist.id,
+        location = removed.name,
+        dropped,
+        "Removed location from watchlist"
     );
+    Ok(watchlist)
+}
 
-    let created = db::watchlist::get_for_chat(&config, chat_id)
-        .into_iter()
-        .find(|w| w.location_id == location_handle.id && w.location_level == location_handle.level)
-        .ok_or_else(|| anyhow!("Failed to create watchlist"))?;
+/// Queues a refresh of the watchlist on the producer's next run, e.g. after its search changed.
+async fn refresh_now(repo: &SharedRepository, watchlist: &mut Watchlist) -> Result<()> {
+    watchlist.next_refresh_at = Utc::now().naive_utc();
+    repo.schedule_watchlist_refresh(watchlist.id, watchlist.next_refresh_at)
+        .await
+}
 
-    Ok(created)
+/// Resumes the chat's paused watchlists. Returns how many were resumed.
+///
+/// Messages that died while the chat could not be reached are queued again.
+pub async fn resume(repo: &SharedRepository, chat_id: i64) -> Result<usize> {
+    let resumed = repo.resume_watchlists_for_chat(chat_id).await?;
+    if resumed > 0 {
+        for watchlist in repo.get_watchlists_for_chat(chat_id).await? {
+            repo.discard_dead_messages(watchlist.id).await?;
+        }
+    }
+    Ok(resumed)
 }
 
-pub fn list(config: &Arc<Config>, chat_id: i64) -> Vec<Watchlist> {
-    db::watchlist::get_for_chat(config, chat_id)
+pub async fn list(repo: &SharedRepository, chat_id: i64) -> Result<Vec<Watchlist>> {
+    repo.get_watchlists_for_chat(chat_id).await
 }
 
-pub fn delete(config: &Arc<Config>, chat_id: i64, watchlist_id: i32) -> Result<()> {
-    let existing = db::watchlist::get_for_chat(config, chat_id);
-    if existing.iter().any(|w| w.id == watchlist_id) {
-        db::watchlist::delete(config, watchlist_id);
-        Ok(())
-    } else {
-        Err(anyhow!("You don't have a watchlist with this ID"))
+/// Fetches a watchlist, making sure it belongs to the given chat.
+pub async fn get_for_chat(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+) -> Result<Watchlist> {
+    match repo.get_watchlist(watchlist_id).await? {
+        Some(watchlist) if watchlist.chat_id == chat_id => Ok(watchlist),
+        _ => Err(ApatoError::not_found("You don't have a watchlist with this ID").into()),
     }
 }
 
-pub fn get_all_apartments(
-    config: &Arc<Config>,
+/// Sets how often the watchlist is refreshed. The next refresh is rescheduled to one new
+/// interval after the last successful refresh.
+pub async fn set_refresh_interval(
+    repo: &SharedRepository,
     chat_id: i64,
     watchlist_id: i32,
+    minutes: i32,
+) -> Result<Watchlist> {
+    check_refresh_interval(minutes)?;
+
+    let mut watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    watchlist.refresh_interval_minutes = minutes;
+    watchlist.next_refresh_at = watchlist
+        .last_refreshed_at
+        .unwrap_or_else(|| Utc::now().naive_utc())
+        + watchlist.refresh_interval();
+
+    repo.update_watchlist_refresh_interval(watchlist.id, minutes, watchlist.next_refresh_at)
+        .await?;
+    Ok(watchlist)
+}
+
+fn check_refresh_interval(minutes: i32) -> Result<()> {
+    if !(MIN_REFRESH_INTERVAL_MINUTES..=MAX_REFRESH_INTERVAL_MINUTES).contains(&minutes) {
+        return Err(ApatoError::user_facing(format!(
+            "Refresh interval must be between {} and {} minutes",
+            MIN_REFRESH_INTERVAL_MINUTES, MAX_REFRESH_INTERVAL_MINUTES
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Changes the criteria of the watchlist, keeping its matches and what has been sent.
+///
+/// Stored apartments are matched again right away: apartments that now meet the criteria
+/// are matched and announced by the producer, unsent matches that no longer do are dropped.
+/// The watchlist is also refreshed right away, as a new size range may need a new search.
+pub async fn update(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+    changes: &WatchlistUpdate,
+) -> Result<Watchlist> {
+    if changes.is_empty() {
+        return Err(ApatoError::user_facing("Nothing to change").into());
+    }
+
+    let mut watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    let financing = watchlist.financing;
+    changes.apply(&mut watchlist);
+
+    if watchlist.target_yield.is_some_and(|y| !y.is_finite()) {
+        return Err(ApatoError::user_facing("Target yield must be a number").into());
+    }
+    check_size(&watchlist.size_target())?;
+    if !(0.0..=100.0).contains(&watchlist.min_price_drop_percent) {
+        return Err(ApatoError::user_facing("Price drop must be between 0 and 100 percent").into());
+    }
+    check_refresh_interval(watchlist.refresh_interval_minutes)?;
+    check_criteria(&watchlist.criteria)?;
+    check_financing(&watchlist.financing)?;
+
+    watchlist.next_refresh_at = Utc::now().naive_utc();
+    repo.update_watchlist_criteria(&watchlist).await?;
+    if watchlist.financing != financing {
+        repo.clear_match_yields(watchlist.id).await?;
+    }
+    rematch(repo, &watchlist).await?;
+    Ok(watchlist)
+}
+
+fn check_size(size: &SizeTarget) -> Result<()> {
+    if [size.min, size.max].iter().flatten().any(|size| *size < 0) {
+        return Err(ApatoError::user_facing("Size cannot be negative").into());
+    }
+    if let (Some(min), Some(max)) = (size.min, size.max) {
+        if min > max {
+            return Err(ApatoError::user_facing("Min size cannot be above max size").into());
+        }
+    }
+    Ok(())
+}
+
+fn check_criteria(criteria: &ListingCriteria) -> Result<()> {
+    let counts = [
+        criteria.min_rooms,
+        criteria.max_rooms,
+        criteria.max_price,
+        criteria.min_build_year,
+    ];
+    let amounts = [criteria.max_fee_per_m2, criteria.max_debt_share_percent];
+    if counts.iter().flatten().any(|value| *value < 0)
+        || amounts
+            .iter()
+            .flatten()
+            .any(|value| !value.is_finite() || *value < 0.0)
+    {
+        return Err(ApatoError::user_facing("Criteria cannot be negative").into());
+    }
+    if let (Some(min), Some(max)) = (criteria.min_rooms, criteria.max_rooms) {
+        if min > max {
+            return Err(ApatoError::user_facing("Min rooms cannot be above max rooms").into());
+        }
+    }
+    if criteria
+        .max_debt_share_percent
+        .is_some_and(|percent| percent > 100.0)
+    {
+        return Err(ApatoError::user_facing("Debt share must be between 0 and 100 percent").into());
+    }
+    Ok(())
+}
+
+fn check_financing(financing: &Financing) -> Result<()> {
+    if financing
+        .down_payment_percent
+        .is_some_and(|percent| !(0..=100).contains(&percent))
+    {
+        return Err(
+            ApatoError::user_facing("Down payment must be between 0 and 100 percent").into(),
+        );
+    }
+    if financing
+        .loan_years
+        .is_some_and(|years| !(1..=MAX_LOAN_YEARS).contains(&years))
+    {
+        return Err(ApatoError::user_facing(format!(
+            "Loan length must be between 1 and {} years",
+            MAX_LOAN_YEARS
+        ))
+        .into());
+    }
+    if financing.renovation_costs.is_some_and(|costs| costs < 0) {
+        return Err(ApatoError::user_facing("Renovation costs cannot be negative").into());
+    }
+    Ok(())
+}
+
+/// Matches the stored active apartments of the watchlist's locations against its criteria.
+///
+/// Stored yields are estimated with the default financing, so a watchlist with its own
+/// financing drops its unsent matches and is refreshed right away instead, matching the
+/// apartments again with yields of its financing.
+async fn rematch(repo: &SharedRepository, watchlist: &Watchlist) -> Result<()> {
+    if !watchlist.financing.is_default() {
+        let unsent = repo.get_unsent_matches(watchlist.id).await?;
+        let removed = repo.delete_unsent_matches(watchlist.id, &unsent).await?;
+        repo.schedule_watchlist_refresh(watchlist.id, Utc::now().naive_utc())
+            .await?;
+        info!(
+            watchlist_id = watchlist.id,
+            removed, "Refreshing watchlist with its own financing to match it again"
+        );
+        return Ok(());
+    }
+
+    let unsent = repo.get_unsent_matches(watchlist.id).await?;
+
+    let mut matched = 0;
+    let mut unmatched = Vec::new();
+    for apartment in repo.get_apartments_for_watchlist(watchlist, false).await? {
+        let estimated_yield = apartment.estimated_yield.unwrap_or_default();
+        if watchlist.matches(&InsertableApartment::from(&apartment), estimated_yield) {
+            repo.insert_match(watchlist.id, apartment.card_id).await?;
+            matched += 1;
+        } else if unsent.contains(&apartment.card_id) {
+            unmatched.push(apartment.card_id);
+        }
+    }
+    let removed = repo.delete_unsent_matches(watchlist.id, &unmatched).await?;
+
+    info!(
+        watchlist_id = watchlist.id,
+        matched, removed, "Matched stored apartments against new criteria"
+    );
+    Ok(())
+}
+
+pub async fn delete(repo: &SharedRepository, chat_id: i64, watchlist_id: i32) -> Result<()> {
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    repo.delete_watchlist(watchlist.id).await
+}
+
+/// Apartments in the watchlist's locations. Sold and removed ones only if `include_removed`,
+/// the ones the chat dismissed only if `include_dismissed`.
+pub async fn get_all_apartments(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+    include_removed: bool,
+    include_dismissed: bool,
 ) -> Result<Vec<Apartment>> {
-    db::apartment::get_all_for_watchlist(config, chat_id, watchlist_id)
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    let apartments = repo
+        .get_apartments_for_watchlist(&watchlist, include_removed)
+        .await?;
+    if include_dismissed {
+        return Ok(apartments);
+    }
+    apartments::without_dismissed(repo, chat_id, apartments).await
 }
 
-pub fn get_matching_apartments(
-    config: &Arc<Config>,
+/// Matched apartments above the target yield. The ones the chat dismissed only if
+/// `include_dismissed`.
+pub async fn get_matching_apartments(
+    repo: &SharedRepository,
     chat_id: i64,
     watchlist_id: i32,
+    include_dismissed: bool,
 ) -> Result<Vec<Apartment>> {
-    db::apartment::get_matching_for_watchlist(config, chat_id, watchlist_id)
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    let apartments = repo.get_matching_apartments(&watchlist).await?;
+    if include_dismissed {
+        return Ok(apartments);
+    }
+    apartments::without_dismissed(repo, chat_id, apartments).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct PriceDrop {
+    pub apartment: Apartment,
+    /// Highest price seen for the listing.
+    pub previous_price: i32,
+    pub drop_percent: f64,
+}
+
+/// Apartments in the watchlist whose price has dropped from the highest price seen by at
+/// least the watchlist's minimum price drop.
+pub async fn get_price_drops(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+) -> Result<Vec<PriceDrop>> {
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+
+    let mut drops = Vec::new();
+    for apartment in repo.get_apartments_for_watchlist(&watchlist, false).await? {
+        let Some(current) = apartment.price else {
+            continue;
+        };
+        let history = repo.get_price_history(apartment.card_id).await?;
+        let Some(previous_price) = history.iter().filter_map(|entry| entry.price).max() else {
+            continue;
+        };
+
+        if let Some(drop) = drop_percent(previous_price, current) {
+            if drop >= watchlist.min_price_drop_percent {
+                drops.push(PriceDrop {
+                    apartment,
+                    previous_price,
+                    drop_percent: drop,
+                });
+            }
+        }
+    }
+
+    Ok(drops)
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MarketStats {
+    pub active: usize,
+    pub removed: usize,
+    /// Median days sold or removed listings were on the market.
+    pub median_days_on_market: Option<i64>,
+    pub average_days_on_market: Option<f64>,
+}
+
+/// Time-on-market statistics of the listings in the watchlist's locations.
+pub async fn get_market_stats(
+    repo: &SharedRepository,
+    chat_id: i64,
+    watchlist_id: i32,
+) -> Result<MarketStats> {
+    let watchlist = get_for_chat(repo, chat_id, watchlist_id).await?;
+    let apartments = repo.get_apartments_for_watchlist(&watchlist, true).await?;
+
+    let mut days: Vec<i64> = apartments
+        .iter()
+        .filter(|a| !a.is_active())
+        .map(|a| a.days_on_market())
+        .collect();
+    days.sort_unstable();
+
+    let median_days_on_market = match days.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((days[n / 2 - 1] + days[n / 2]) / 2),
+        n => Some(days[n / 2]),
+    };
+    let average_days_on_market =
+        (!days.is_empty()).then(|| days.iter().sum::<i64>() as f64 / days.len() as f64);
+
+    Ok(MarketStats {
+        active: apartments.len() - days.len(),
+        removed: days.len(),
+        median_days_on_market,
+        average_days_on_market,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::memory::InMemoryRepository, oikotie::oikotie::Location, test_support};
+    use std::sync::Arc;
+
+    async fn setup() -> (SharedRepository, Watchlist) {
+        let repo: SharedRepository = Arc::new(InMemoryRepository::new());
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let size = SizeTarget {
+            min: Some(40),
+            max: Some(60),
+        };
+        let watchlist = repo
+            .insert_watchlist(location, 42, Some(5.0), size)
+            .await
+            .unwrap();
+        (repo, watchlist)
+    }
+
+    fn apartment(card_id: i32, size: f64, estimated_yield: f64) -> InsertableApartment {
+        InsertableApartment {
+            size: Some(size),
+            ..test_support::apartment(card_id, estimated_yield)
+        }
+    }
+
+    #[tokio::test]
+    async fn update_rematches_stored_apartments() {
+        let (repo, watchlist) = setup().await;
+        repo.upsert_apartment(apartment(10, 50.0, 6.0), &[watchlist.id])
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(11, 70.0, 8.0), &[])
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(12, 45.0, 7.0), &[watchlist.id])
+            .await
+            .unwrap();
+        repo.set_match_sent(watchlist.id, 12, Some(200000))
+            .await
+            .unwrap();
+
+        let changes = WatchlistUpdate {
+            target_yield: Some(7.5),
+            max_size: Some(80),
+            ..Default::default()
+        };
+        let updated = update(&repo, 42, watchlist.id, &changes).await.unwrap();
+        assert_eq!(updated.target_yield, Some(7.5));
+        assert_eq!(updated.size_target().max, Some(80));
+        assert!(updated.next_refresh_at <= Utc::now().naive_utc());
+
+        // 10 is below the new target, 11 is now in range, 12 was already sent
+        let mut unsent = repo.get_unsent_matches(watchlist.id).await.unwrap();
+        unsent.sort_unstable();
+        assert_eq!(unsent, vec![11]);
+        assert!(repo.match_exists(watchlist.id, 12).await.unwrap());
+
+        let stored = repo.get_watchlist(watchlist.id).await.unwrap().unwrap();
+        assert_eq!(stored.target_yield, Some(7.5));
+        assert_eq!(stored.target_size_min, Some(40));
+        assert_eq!(stored.target_size_max, Some(80));
+    }
+
+    #[tokio::test]
+    async fn own_financing_matches_again_on_refresh() {
+        let (repo, watchlist) = setup().await;
+        repo.upsert_apartment(apartment(10, 50.0, 6.0), &[watchlist.id])
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(11, 45.0, 7.0), &[watchlist.id])
+            .await
+            .unwrap();
+        repo.set_match_sent(watchlist.id, 11, Some(200000))
+            .await
+            .unwrap();
+        repo.set_match_yields(11, &[(watchlist.id, 9.0)])
+            .await
+            .unwrap();
+        let matching = repo.get_matching_apartments(&watchlist).await.unwrap();
+        let sent = matching.iter().find(|a| a.card_id == 11).unwrap();
+        assert_eq!(sent.estimated_yield, Some(9.0));
+
+        let invalid = WatchlistUpdate {
+            loan_years: Some(Some(0)),
+            ..Default::default()
+        };
+        assert!(update(&repo, 42, watchlist.id, &invalid).await.is_err());
+
+        let changes = WatchlistUpdate {
+            down_payment_percent: Some(Some(40)),
+            ..Default::default()
+        };
+        let updated = update(&repo, 42, watchlist.id, &changes).await.unwrap();
+        assert_eq!(updated.financing.down_payment_percent, Some(40));
+
+        // Stored yields are of the old financing, so the refresh matches the apartments again
+        assert!(repo
+            .get_unsent_matches(watchlist.id)
+            .await
+            .unwrap()
+            .is_empty());
+        let sent = repo.get_match(watchlist.id, 11).await.unwrap().unwrap();
+        assert_eq!(sent.estimated_yield, None);
+        let due = repo.get_due_watchlists().await.unwrap();
+        assert!(due.iter().any(|w| w.id == watchlist.id));
+    }
+
+    #[tokio::test]
+    async fn subscribing_again_replaces_the_size_range() {
+        let (repo, watchlist) = setup().await;
+        repo.upsert_apartment(apartment(10, 70.0, 8.0), &[])
+            .await
+            .unwrap();
+        let location = watchlist.locations.0[0].clone();
+        let subscribe_with = |size| {
+            subscribe(
+                &repo,
+                42,
+                location.clone(),
+                size,
+                5.0,
+                None,
+                ListingCriteria::default(),
+                Financing::default(),
+            )
+        };
+
+        assert!(subscribe_with((60.0, 40.0)).await.is_err());
+        assert!(subscribe_with((-10.0, 40.0)).await.is_err());
+        assert!(subscribe_with((f64::NAN, 40.0)).await.is_err());
+
+        let updated = subscribe_with((50.0, 80.0)).await.unwrap();
+        assert_eq!(updated.id, watchlist.id);
+        assert!(updated.next_refresh_at <= Utc::now().naive_utc());
+        let stored = repo.get_watchlist(watchlist.id).await.unwrap().unwrap();
+        assert_eq!(stored.target_size_min, Some(50));
+        assert_eq!(stored.target_size_max, Some(80));
+        assert_eq!(
+            repo.get_unsent_matches(watchlist.id).await.unwrap(),
+            vec![10]
+        );
+    }
+
+    #[tokio::test]
+    async fn added_location_is_matched_and_removed_location_dropped() {
+        let (repo, watchlist) = setup().await;
+        let second = Location {
+            id: 2,
+            level: 5,
+            name: "00120".to_string(),
+        };
+        let mut elsewhere = apartment(20, 50.0, 8.0);
+        elsewhere.location_id = Some(2);
+        elsewhere.location_name = Some("00120".to_string());
+        repo.upsert_apartment(elsewhere, &[]).await.unwrap();
+
+        let updated = add_found_location(&repo, watchlist.clone(), second.clone())
+            .await
+            .unwrap();
+        assert_eq!(updated.locations.names(), "00100, 00120");
+        assert_eq!(
+            repo.get_unsent_matches(watchlist.id).await.unwrap(),
+            vec![20]
+        );
+        assert!(add_found_location(&repo, updated, second).await.is_err());
+
+        let updated = remove_location(&repo, 42, watchlist.id, "00120")
+            .await
+            .unwrap();
+        assert_eq!(updated.locations.names(), "00100");
+        assert!(repo
+            .get_unsent_matches(watchlist.id)
+            .await
+            .unwrap()
+            .is_empty());
+        let stored = repo.get_watchlist(watchlist.id).await.unwrap().unwrap();
+        assert_eq!(stored.locations, updated.locations);
+
+        // The last location stays
+        assert!(remove_location(&repo, 42, watchlist.id, "00100")
+            .await
+            .is_err());
+        assert!(remove_location(&repo, 42, watchlist.id, "00120")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn apartment_moves_to_the_location_it_is_found_in() {
+        let (repo, first) = setup().await;
+        let location = Location {
+            id: 2,
+            level: 5,
+            name: "00120".to_string(),
+        };
+        let second = repo
+            .insert_watchlist(location.clone(), 43, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        repo.upsert_apartment(apartment(20, 50.0, 8.0), &[])
+            .await
+            .unwrap();
+
+        let mut found = apartment(20, 50.0, 8.0);
+        found.set_location(&location);
+        repo.upsert_apartment(found, &[]).await.unwrap();
+
+        let listed =
+            |apartments: Vec<Apartment>| apartments.iter().map(|a| a.card_id).collect::<Vec<_>>();
+        let in_first = get_all_apartments(&repo, 42, first.id, false, false)
+            .await
+            .unwrap();
+        assert!(listed(in_first).is_empty());
+        let in_second = get_all_apartments(&repo, 43, second.id, false, false)
+            .await
+            .unwrap();
+        assert_eq!(listed(in_second), vec![20]);
+    }
+
+    #[tokio::test]
+    async fn update_rejects_invalid_criteria() {
+        let (repo, watchlist) = setup().await;
+
+        let invalid = [
+            WatchlistUpdate::default(),
+            WatchlistUpdate {
+                min_size: Some(70),
+                ..Default::default()
+            },
+            WatchlistUpdate {
+                min_price_drop_percent: Some(120.0),
+                ..Default::default()
+            },
+            WatchlistUpdate {
+                refresh_interval_minutes: Some(1),
+                ..Default::default()
+            },
+        ];
+        for changes in invalid {
+            assert!(update(&repo, 42, watchlist.id, &changes).await.is_err());
+        }
+
+        let changes = WatchlistUpdate {
+            target_yield: Some(9.0),
+            ..Default::default()
+        };
+        assert!(update(&repo, 7, watchlist.id, &changes).await.is_err());
+        let stored = repo.get_watchlist(watchlist.id).await.unwrap().unwrap();
+        assert_eq!(stored.target_yield, Some(5.0));
+    }
 }
@@ -0,0 +1,59 @@
+//! Fixtures shared by the unit tests.
+
+use teloxide::Bot;
+
+use crate::models::apartment::InsertableApartment;
+
+/// A 50 m^2 two-room listing in the `00100` postcode (location 1, level 5).
+pub(crate) fn apartment(card_id: i32, estimated_yield: f64) -> InsertableApartment {
+    InsertableApartment {
+        card_id,
+        location_id: Some(1),
+        location_level: Some(5),
+        location_name: Some("00100".to_string()),
+        size: Some(50.0),
+        rooms: Some(2),
+        price: Some(200000),
+        additional_costs: Some(200),
+        rent: Some(900),
+        estimated_yield: Some(estimated_yield),
+        url: None,
+        build_year: None,
+        floor: None,
+        building_type: None,
+        latitude: None,
+        longitude: None,
+        rent_lower: None,
+        rent_upper: None,
+        status: None,
+        debt_share: None,
+        plot_ownership: None,
+    }
+}
+
+/// Bot whose requests go to a fake Telegram API answering every request with `reply`.
+pub(crate) async fn fake_telegram_replying(reply: serde_json::Value) -> Bot {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = axum::Router::new().fallback(move || {
+        let reply = reply.clone();
+        async move { axum::Json(reply) }
+    });
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Bot::new("test-token").set_api_url(url.parse().unwrap())
+}
+
+/// Bot whose requests go to a fake Telegram API that answers every request with a sent
+/// message.
+pub(crate) async fn fake_telegram() -> Bot {
+    fake_telegram_replying(serde_json::json!({
+        "ok": true,
+        "result": {
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": 42, "type": "private", "first_name": "Test" },
+            "text": "ok"
+        }
+    }))
+    .await
+}
@@ -0,0 +1,73 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::error;
+
+use super::AppState;
+use crate::services::{api_tokens, sessions};
+
+/// Chat the request acts for, resolved by [`require_chat`] from the session or API token
+/// sent as `Authorization: Bearer <token>`.
+///
+/// Handlers only ever act on this chat, so callers cannot pick another chat's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatContext {
+    pub chat_id: i64,
+}
+
+/// Resolves the bearer token into a [`ChatContext`] for the handlers. Requests without a
+/// token are rejected with 401, unknown or expired tokens with 403.
+pub async fn require_chat(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(token) = bearer_token(req.headers()) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+        )
+            .into_response();
+    };
+
+    let chat_id = match sessions::session_chat(&state.config, token) {
+        Some(chat_id) => Some(chat_id),
+        None => match api_tokens::authenticate(&state.repo, token).await {
+            Ok(chat_id) => chat_id,
+            Err(e) => {
+                error!("Failed to check API token: {:#}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        },
+    };
+    let Some(chat_id) = chat_id else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    req.extensions_mut().insert(ChatContext { chat_id });
+    next.run(req).await
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ChatContext {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, StatusCode> {
+        // Only missing if the route is not behind `require_chat`
+        parts
+            .extensions
+            .get::<ChatContext>()
+            .copied()
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
@@ -2,24 +2,60 @@ use std::sync::Arc;
 
 use axum::{
     extract::State,
-    http::{HeaderValue, Method, StatusCode},
+    http::{header, HeaderValue, Method, StatusCode},
     middleware::{self, Next},
-    response::Response,
-    routing::{delete, get},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post, put},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
+use teloxide::Bot;
 use tokio::net::TcpListener;
+use tracing::error;
+
+mod auth;
+
+pub use auth::ChatContext;
 
 use crate::{
     config::Config,
-    models::{apartment::Apartment, watchlist::Watchlist},
-    services::watchlists,
+    db::repository::SharedRepository,
+    errors::ApatoError,
+    health::Health,
+    metrics,
+    models::{
+        apartment::Apartment,
+        apartment_state::{ApartmentState, ApartmentStateUpdate, ApartmentStatus},
+        watchlist::{Financing, ListingCriteria, Watchlist, WatchlistUpdate},
+    },
+    oikotie::oikotie::Location,
+    services::{
+        apartments::{self, TrackedApartment},
+        sessions::{self, RedeemedLinks, Session},
+        watchlists::{self, LocationMatch},
+    },
 };
 
+/// Status for an error of the services: 400 for invalid input, 404 for something that does
+/// not exist or belongs to another chat and 500 for everything else.
+fn error_status(err: anyhow::Error) -> StatusCode {
+    match err.downcast_ref::<ApatoError>() {
+        Some(ApatoError::UserFacing(_)) => StatusCode::BAD_REQUEST,
+        Some(ApatoError::NotFound(_)) => StatusCode::NOT_FOUND,
+        _ => {
+            error!("Failed to handle request: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<Config>,
+    pub repo: SharedRepository,
+    pub bot: Arc<Bot>,
+    pub health: Arc<Health>,
+    pub redeemed_links: Arc<RedeemedLinks>,
 }
 
 #[derive(Serialize)]
@@ -28,17 +64,68 @@ pub struct ApiResponse<T> {
 }
 
 #[derive(Deserialize)]
-pub struct QueryChat {
-    pub chat_id: i64,
+pub struct QueryApartments {
+    #[serde(default)]
+    pub include_removed: bool,
+    #[serde(default)]
+    pub include_dismissed: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ApartmentStatesQuery {
+    /// Only apartments with this status. All but dismissed ones by default.
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LocationsQuery {
+    pub q: String,
+}
+
+#[derive(Serialize)]
+pub struct LocationsResponse {
+    pub locations: Vec<Location>,
+}
+
+/// A location from `GET /api/locations`, checked against Oikotie, or a query resolved like
+/// the bot's /sub does.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum LocationInput {
+    Exact(Location),
+    Query(String),
 }
 
 #[derive(Deserialize)]
 pub struct SubscribeRequest {
-    pub chat_id: i64,
-    pub location: String,
+    pub location: LocationInput,
     pub min_size: f64,
     pub max_size: f64,
     pub target_yield: f64,
+    #[serde(default)]
+    pub min_price_drop_percent: Option<f64>,
+    #[serde(flatten)]
+    pub criteria: ListingCriteria,
+    #[serde(flatten)]
+    pub financing: Financing,
+}
+
+#[derive(Deserialize)]
+pub struct SessionRequest {
+    /// Token of the login link sent by the bot's /login command.
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LocationRequest {
+    /// Name or postcode of the location, looked up from Oikotie like on subscribe.
+    pub location: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshIntervalRequest {
+    pub minutes: i32,
 }
 
 #[derive(Serialize)]
@@ -51,16 +138,62 @@ pub struct ApartmentsResponse {
     pub apartments: Vec<Apartment>,
 }
 
+#[derive(Serialize)]
+pub struct ApartmentStatesResponse {
+    pub apartments: Vec<TrackedApartment>,
+}
+
+#[derive(Serialize)]
+pub struct PriceDropsResponse {
+    pub price_drops: Vec<watchlists::PriceDrop>,
+}
+
 pub fn router(state: AppState) -> Router {
-    Router::new()
+    // Everything under /api acts for the chat of the session or API token
+    let api = Router::new()
         .route(
             "/api/watchlists",
             get(list_watchlists).post(subscribe_watchlist),
         )
-        .route("/api/watchlists/:id", delete(delete_watchlist))
+        .route(
+            "/api/watchlists/:id",
+            patch(update_watchlist).delete(delete_watchlist),
+        )
+        .route("/api/watchlists/:id/locations", post(add_location))
+        .route(
+            "/api/watchlists/:id/locations/:location",
+            delete(remove_location),
+        )
         .route("/api/watchlists/:id/apartments", get(get_all_apartments))
         .route("/api/watchlists/:id/matching", get(get_matching_apartments))
-        .layer(middleware::from_fn(cors_layer))
+        .route("/api/watchlists/:id/price_drops", get(get_price_drops))
+        .route("/api/watchlists/:id/market_stats", get(get_market_stats))
+        .route(
+            "/api/watchlists/:id/refresh_interval",
+            put(set_refresh_interval),
+        )
+        .route("/api/locations", get(search_locations))
+        .route("/api/apartments/states", get(get_apartment_states))
+        .route(
+            "/api/apartments/:card_id/history",
+            get(get_apartment_history),
+        )
+        .route(
+            "/api/apartments/:card_id/state",
+            put(update_apartment_state),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_chat,
+        ));
+
+    Router::new()
+        .merge(api)
+        .route("/api/session", post(create_session))
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_liveness))
+        .route("/readyz", get(get_readiness))
+        .layer(middleware::from_fn_with_state(state.clone(), cors_layer))
         .with_state(state)
 }
 
@@ -87,95 +220,1006 @@ pub async fn start_http_server(
         .expect("HTTP server crashed");
 }
 
-async fn cors_layer(req: axum::http::Request<axum::body::Body>, next: Next) -> Response {
-    if req.method() == Method::OPTIONS {
-        let mut response = Response::new(axum::body::Body::empty());
-        apply_cors_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::NO_CONTENT;
-        response
+/// Answers preflight requests and adds CORS headers for the configured origins. Requests
+/// from other origins get no CORS headers, so browsers do not expose the responses.
+async fn cors_layer(
+    State(state): State<AppState>,
+    req: axum::http::Request<axum::body::Body>,
+    next: Next,
+) -> Response {
+    let allowed_origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .filter(|origin| origin_allowed(&state.config, origin))
+        .cloned();
+
+    let mut response = if req.method() == Method::OPTIONS {
+        StatusCode::NO_CONTENT.into_response()
     } else {
-        let mut response = next.run(req).await;
-        apply_cors_headers(response.headers_mut());
-        response
+        next.run(req).await
+    };
+    if let Some(origin) = allowed_origin {
+        apply_cors_headers(response.headers_mut(), origin);
     }
+    response
 }
 
-fn apply_cors_headers(headers: &mut axum::http::HeaderMap) {
-    headers.insert(
-        axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-        HeaderValue::from_static("*"),
-    );
+fn origin_allowed(config: &Config, origin: &HeaderValue) -> bool {
+    config
+        .cors_allowed_origins
+        .iter()
+        .flatten()
+        .any(|allowed| allowed == "*" || origin.as_bytes() == allowed.as_bytes())
+}
+
+fn apply_cors_headers(headers: &mut axum::http::HeaderMap, origin: HeaderValue) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(header::VARY, HeaderValue::from_static("origin"));
     headers.insert(
-        axum::http::header::ACCESS_CONTROL_ALLOW_HEADERS,
-        HeaderValue::from_static("content-type"),
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("authorization, content-type"),
     );
     headers.insert(
-        axum::http::header::ACCESS_CONTROL_ALLOW_METHODS,
-        HeaderValue::from_static("GET, POST, DELETE, OPTIONS"),
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, PUT, PATCH, DELETE, OPTIONS"),
     );
 }
 
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics::render(),
+    )
+}
+
+/// The process is alive and serving requests.
+async fn get_liveness() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Whether the service and its dependencies are working, with 503 when degraded.
+async fn get_readiness(State(state): State<AppState>) -> impl IntoResponse {
+    let readiness = state
+        .health
+        .readiness(&state.config, &state.repo, &state.bot)
+        .await;
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Signs in with a login link, returning a session token to send as bearer token.
+async fn create_session(
+    State(state): State<AppState>,
+    Json(body): Json<SessionRequest>,
+) -> Result<Json<ApiResponse<Session>>, StatusCode> {
+    sessions::start_session(&state.config, &state.redeemed_links, &body.token)
+        .map(|session| Json(ApiResponse { data: session }))
+        .map_err(|_| StatusCode::FORBIDDEN)
+}
+
 async fn list_watchlists(
     State(state): State<AppState>,
-    axum::extract::Query(QueryChat { chat_id }): axum::extract::Query<QueryChat>,
+    ChatContext { chat_id }: ChatContext,
 ) -> Result<Json<ApiResponse<WatchlistsResponse>>, StatusCode> {
-    let watchlists = watchlists::list(&state.config, chat_id);
-    Ok(Json(ApiResponse {
-        data: WatchlistsResponse { watchlists },
-    }))
+    watchlists::list(&state.repo, chat_id)
+        .await
+        .map(|watchlists| {
+            Json(ApiResponse {
+                data: WatchlistsResponse { watchlists },
+            })
+        })
+        .map_err(error_status)
 }
 
+/// Locations of any level matching the query, to choose from when subscribing.
+async fn search_locations(
+    axum::extract::Query(query): axum::extract::Query<LocationsQuery>,
+) -> Result<Json<ApiResponse<LocationsResponse>>, StatusCode> {
+    watchlists::search_locations(&query.q)
+        .await
+        .map(|locations| {
+            Json(ApiResponse {
+                data: LocationsResponse { locations },
+            })
+        })
+        .map_err(error_status)
+}
+
+/// Ambiguous location queries are rejected with 409, the location has to be chosen from
+/// `GET /api/locations` then.
 async fn subscribe_watchlist(
     State(state): State<AppState>,
+    ChatContext { chat_id }: ChatContext,
     Json(body): Json<SubscribeRequest>,
 ) -> Result<Json<ApiResponse<Watchlist>>, StatusCode> {
+    let location = match body.location {
+        LocationInput::Exact(location) => watchlists::verify_location(&state.repo, &location)
+            .await
+            .map_err(error_status)?,
+        LocationInput::Query(query) => {
+            match watchlists::resolve_location(&state.repo, chat_id, &query).await {
+                Ok(LocationMatch::Found(location)) => location,
+                Ok(LocationMatch::Ambiguous(_)) => return Err(StatusCode::CONFLICT),
+                Err(e) => return Err(error_status(e)),
+            }
+        }
+    };
+
     watchlists::subscribe(
-        state.config.clone(),
-        body.chat_id,
-        body.location,
+        &state.repo,
+        chat_id,
+        location,
         (body.min_size, body.max_size),
         body.target_yield,
+        body.min_price_drop_percent,
+        body.criteria,
+        body.financing,
     )
     .await
     .map(|watchlist| Json(ApiResponse { data: watchlist }))
-    .map_err(|_| StatusCode::BAD_REQUEST)
+    .map_err(error_status)
+}
+
+/// Changes the criteria given in the body, keeping the others.
+async fn update_watchlist(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+    Json(body): Json<WatchlistUpdate>,
+) -> Result<Json<ApiResponse<Watchlist>>, StatusCode> {
+    watchlists::update(&state.repo, chat_id, id, &body)
+        .await
+        .map(|watchlist| Json(ApiResponse { data: watchlist }))
+        .map_err(error_status)
 }
 
 async fn delete_watchlist(
     State(state): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<i32>,
-    axum::extract::Query(QueryChat { chat_id }): axum::extract::Query<QueryChat>,
+    ChatContext { chat_id }: ChatContext,
 ) -> StatusCode {
-    match watchlists::delete(&state.config, chat_id, id) {
+    match watchlists::delete(&state.repo, chat_id, id).await {
         Ok(_) => StatusCode::NO_CONTENT,
-        Err(_) => StatusCode::NOT_FOUND,
+        Err(e) => error_status(e),
     }
 }
 
+async fn add_location(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+    Json(body): Json<LocationRequest>,
+) -> Result<Json<ApiResponse<Watchlist>>, StatusCode> {
+    watchlists::add_location(&state.repo, chat_id, id, &body.location)
+        .await
+        .map(|watchlist| Json(ApiResponse { data: watchlist }))
+        .map_err(error_status)
+}
+
+/// Removes the location with the given name. The last location cannot be removed.
+async fn remove_location(
+    State(state): State<AppState>,
+    axum::extract::Path((id, location)): axum::extract::Path<(i32, String)>,
+    ChatContext { chat_id }: ChatContext,
+) -> Result<Json<ApiResponse<Watchlist>>, StatusCode> {
+    watchlists::remove_location(&state.repo, chat_id, id, &location)
+        .await
+        .map(|watchlist| Json(ApiResponse { data: watchlist }))
+        .map_err(error_status)
+}
+
+async fn set_refresh_interval(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+    Json(body): Json<RefreshIntervalRequest>,
+) -> Result<Json<ApiResponse<Watchlist>>, StatusCode> {
+    watchlists::set_refresh_interval(&state.repo, chat_id, id, body.minutes)
+        .await
+        .map(|watchlist| Json(ApiResponse { data: watchlist }))
+        .map_err(error_status)
+}
+
 async fn get_all_apartments(
     State(state): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<i32>,
-    axum::extract::Query(QueryChat { chat_id }): axum::extract::Query<QueryChat>,
+    ChatContext { chat_id }: ChatContext,
+    axum::extract::Query(QueryApartments {
+        include_removed,
+        include_dismissed,
+    }): axum::extract::Query<QueryApartments>,
 ) -> Result<Json<ApiResponse<ApartmentsResponse>>, StatusCode> {
-    watchlists::get_all_apartments(&state.config, chat_id, id)
+    watchlists::get_all_apartments(&state.repo, chat_id, id, include_removed, include_dismissed)
+        .await
         .map(|apartments| {
             Json(ApiResponse {
                 data: ApartmentsResponse { apartments },
             })
         })
-        .map_err(|_| StatusCode::BAD_REQUEST)
+        .map_err(error_status)
 }
 
 async fn get_matching_apartments(
     State(state): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<i32>,
-    axum::extract::Query(QueryChat { chat_id }): axum::extract::Query<QueryChat>,
+    ChatContext { chat_id }: ChatContext,
+    axum::extract::Query(query): axum::extract::Query<QueryApartments>,
 ) -> Result<Json<ApiResponse<ApartmentsResponse>>, StatusCode> {
-    watchlists::get_matching_apartments(&state.config, chat_id, id)
+    watchlists::get_matching_apartments(&state.repo, chat_id, id, query.include_dismissed)
+        .await
         .map(|apartments| {
             Json(ApiResponse {
                 data: ApartmentsResponse { apartments },
             })
         })
-        .map_err(|_| StatusCode::BAD_REQUEST)
+        .map_err(error_status)
+}
+
+async fn get_price_drops(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+) -> Result<Json<ApiResponse<PriceDropsResponse>>, StatusCode> {
+    watchlists::get_price_drops(&state.repo, chat_id, id)
+        .await
+        .map(|price_drops| {
+            Json(ApiResponse {
+                data: PriceDropsResponse { price_drops },
+            })
+        })
+        .map_err(error_status)
+}
+
+async fn get_market_stats(
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+) -> Result<Json<ApiResponse<watchlists::MarketStats>>, StatusCode> {
+    watchlists::get_market_stats(&state.repo, chat_id, id)
+        .await
+        .map(|stats| Json(ApiResponse { data: stats }))
+        .map_err(error_status)
+}
+
+async fn get_apartment_history(
+    State(state): State<AppState>,
+    ChatContext { chat_id }: ChatContext,
+    axum::extract::Path(card_id): axum::extract::Path<i32>,
+) -> Result<Json<ApiResponse<apartments::ApartmentHistory>>, StatusCode> {
+    apartments::get_history(&state.repo, chat_id, card_id)
+        .await
+        .map(|history| Json(ApiResponse { data: history }))
+        .map_err(error_status)
+}
+
+async fn get_apartment_states(
+    State(state): State<AppState>,
+    ChatContext { chat_id }: ChatContext,
+    axum::extract::Query(query): axum::extract::Query<ApartmentStatesQuery>,
+) -> Result<Json<ApiResponse<ApartmentStatesResponse>>, StatusCode> {
+    let statuses = match &query.status {
+        Some(status) => vec![status.as_str()],
+        None => ApartmentStatus::TRACKED.to_vec(),
+    };
+    apartments::get_tracked(&state.repo, chat_id, &statuses)
+        .await
+        .map(|apartments| {
+            Json(ApiResponse {
+                data: ApartmentStatesResponse { apartments },
+            })
+        })
+        .map_err(error_status)
+}
+
+/// Changes the chat's status of and notes on the apartment given in the body.
+async fn update_apartment_state(
+    State(state): State<AppState>,
+    axum::extract::Path(card_id): axum::extract::Path<i32>,
+    ChatContext { chat_id }: ChatContext,
+    Json(body): Json<ApartmentStateUpdate>,
+) -> Result<Json<ApiResponse<ApartmentState>>, StatusCode> {
+    apartments::update_state(&state.repo, chat_id, card_id, body)
+        .await
+        .map(|apartment_state| {
+            Json(ApiResponse {
+                data: apartment_state,
+            })
+        })
+        .map_err(error_status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::create_test_config, db::memory::InMemoryRepository, health::Check,
+        models::watchlist::SizeTarget, oikotie::oikotie::Location, services::api_tokens,
+        test_support::apartment,
+    };
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    fn state() -> AppState {
+        AppState {
+            config: Arc::new(create_test_config()),
+            repo: Arc::new(InMemoryRepository::new()),
+            bot: Arc::new(Bot::new("test-token")),
+            health: Arc::new(Health::default()),
+            redeemed_links: Arc::new(RedeemedLinks::default()),
+        }
+    }
+
+    async fn add_watchlist(state: &AppState, chat_id: i64, target_yield: f64) -> Watchlist {
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        state
+            .repo
+            .insert_watchlist(location, chat_id, Some(target_yield), SizeTarget::empty())
+            .await
+            .unwrap()
+    }
+
+    async fn send(state: &AppState, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        (status, body)
+    }
+
+    /// Sends the request with a new API token of the chat.
+    async fn send_as(
+        state: &AppState,
+        chat_id: i64,
+        mut request: Request<Body>,
+    ) -> (StatusCode, serde_json::Value) {
+        let token = api_tokens::issue(&state.repo, chat_id).await.unwrap();
+        request.headers_mut().insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        send(state, request).await
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exported_as_prometheus_text() {
+        metrics::NOTIFICATIONS_SENT
+            .with_label_values(&["new"])
+            .inc();
+
+        let response = router(state()).oneshot(get("/metrics")).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            prometheus::TEXT_FORMAT
+        );
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("apato_notifications_sent_total{kind=\"new\"}"));
+    }
+
+    #[tokio::test]
+    async fn readiness_reports_checks_and_stuck_workers() {
+        let state = state();
+        // Skip the external services
+        state.health.record("oikotie", Check::ok(None));
+        state.health.record("telegram", Check::ok(None));
+
+        let (status, body) = send(&state, get("/healthz")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+
+        let (status, body) = send(&state, get("/readyz")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["checks"]["database"]["status"], "ok");
+        assert_eq!(body["checks"]["ml_service"]["status"], "disabled");
+        assert_eq!(body["checks"]["producer"]["status"], "failing");
+        assert_eq!(body["checks"]["consumers"]["status"], "failing");
+
+        state.health.producer_heartbeat();
+        state.health.consumer_heartbeat(0);
+        let (status, body) = send(&state, get("/readyz")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn api_requires_a_valid_token() {
+        let state = state();
+        add_watchlist(&state, 1, 5.0).await;
+
+        let (status, _) = send(&state, get("/api/watchlists")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::get("/api/watchlists")
+            .header(header::AUTHORIZATION, "Bearer not-a-token")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&state, request).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // The chat comes from the token, a chat_id parameter is ignored
+        let (status, body) = send_as(&state, 2, get("/api/watchlists?chat_id=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["data"]["watchlists"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn login_link_starts_a_session_for_its_chat() {
+        let mut config = create_test_config();
+        config.session_secret = Some("secret".to_string());
+        config.web_console_url = Some("https://apato.example".to_string());
+        let state = AppState {
+            config: Arc::new(config),
+            ..state()
+        };
+        add_watchlist(&state, 1, 5.0).await;
+        add_watchlist(&state, 2, 5.0).await;
+
+        let link = sessions::login_link(&state.config, 1).unwrap();
+        let (_, token) = link.split_once("?login=").unwrap();
+        let exchange = || {
+            Request::post("/api/session")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "token": token }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let (status, body) = send(&state, exchange()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["chat_id"], 1);
+        let session = body["data"]["token"].as_str().unwrap().to_string();
+
+        let request = Request::get("/api/watchlists")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session))
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&state, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let watchlists = body["data"]["watchlists"].as_array().unwrap();
+        assert_eq!(watchlists.len(), 1);
+        assert_eq!(watchlists[0]["chat_id"], 1);
+
+        // Links are one-time
+        let (status, _) = send(&state, exchange()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn cors_headers_are_only_sent_to_allowed_origins() {
+        let mut config = create_test_config();
+        config.cors_allowed_origins = Some(vec!["https://apato.example".to_string()]);
+        let state = AppState {
+            config: Arc::new(config),
+            ..state()
+        };
+        let preflight = |origin: &str| {
+            Request::options("/api/watchlists")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router(state.clone())
+            .oneshot(preflight("https://apato.example"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://apato.example"
+        );
+
+        let response = router(state)
+            .oneshot(preflight("https://evil.example"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn lists_only_watchlists_of_the_chat() {
+        let state = state();
+        add_watchlist(&state, 1, 5.0).await;
+        add_watchlist(&state, 2, 5.0).await;
+
+        let (status, body) = send_as(&state, 1, get("/api/watchlists")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let watchlists = body["data"]["watchlists"].as_array().unwrap();
+        assert_eq!(watchlists.len(), 1);
+        assert_eq!(watchlists[0]["chat_id"], 1);
+    }
+
+    #[tokio::test]
+    async fn delete_of_other_chats_watchlist_is_not_found() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+
+        let request = Request::delete(format!("/api/watchlists/{}", watchlist.id))
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send_as(&state, 2, request).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn matching_returns_matched_apartments_above_target() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        state
+            .repo
+            .upsert_apartment(apartment(10, 7.0), &[])
+            .await
+            .unwrap();
+        state
+            .repo
+            .upsert_apartment(apartment(11, 3.0), &[])
+            .await
+            .unwrap();
+        state.repo.insert_match(watchlist.id, 10).await.unwrap();
+        state.repo.insert_match(watchlist.id, 11).await.unwrap();
+
+        let uri = format!("/api/watchlists/{}/matching", watchlist.id);
+        let (status, body) = send_as(&state, 1, get(&uri)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let apartments = body["data"]["apartments"].as_array().unwrap();
+        assert_eq!(apartments.len(), 1);
+        assert_eq!(apartments[0]["card_id"], 10);
+
+        let uri = format!("/api/watchlists/{}/apartments", watchlist.id);
+        let (status, body) = send_as(&state, 1, get(&uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["apartments"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn matching_without_target_yield_returns_every_match() {
+        let state = state();
+        let location = Location {
+            id: 1,
+            level: 5,
+            name: "00100".to_string(),
+        };
+        let watchlist = state
+            .repo
+            .insert_watchlist(location, 1, None, SizeTarget::empty())
+            .await
+            .unwrap();
+        for (card_id, estimated_yield) in [(10, 7.0), (11, 3.0)] {
+            state
+                .repo
+                .upsert_apartment(apartment(card_id, estimated_yield), &[watchlist.id])
+                .await
+                .unwrap();
+        }
+
+        let uri = format!("/api/watchlists/{}/matching", watchlist.id);
+        let (status, body) = send_as(&state, 1, get(&uri)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["apartments"].as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn apartment_state_is_kept_per_chat() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        for card_id in [10, 11] {
+            state
+                .repo
+                .upsert_apartment(apartment(card_id, 7.0), &[watchlist.id])
+                .await
+                .unwrap();
+        }
+        let put_state = |card_id: i32, body: serde_json::Value| {
+            Request::put(format!("/api/apartments/{}/state", card_id))
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let body = serde_json::json!({ "status": "contacted", "notes": " viewing on Tuesday " });
+        let (status, body) = send_as(&state, 1, put_state(10, body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["status"], "contacted");
+        assert_eq!(body["data"]["notes"], "viewing on Tuesday");
+
+        let body = serde_json::json!({ "status": "dismissed" });
+        let (status, _) = send_as(&state, 1, put_state(11, body)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Invalid changes leave the state as it was
+        let body = serde_json::json!({ "status": "sold", "notes": null });
+        let (status, _) = send_as(&state, 1, put_state(10, body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send_as(&state, 1, get("/api/apartments/states")).await;
+        assert_eq!(status, StatusCode::OK);
+        let tracked = body["data"]["apartments"].as_array().unwrap();
+        assert_eq!(tracked.len(), 1);
+        assert_eq!(tracked[0]["apartment"]["card_id"], 10);
+        assert_eq!(tracked[0]["state"]["notes"], "viewing on Tuesday");
+
+        let (_, body) = send_as(&state, 1, get("/api/apartments/states?status=dismissed")).await;
+        assert_eq!(body["data"]["apartments"][0]["apartment"]["card_id"], 11);
+
+        // Dismissed apartments are listed only when asked for
+        let uri = format!("/api/watchlists/{}/matching", watchlist.id);
+        let (_, body) = send_as(&state, 1, get(&uri)).await;
+        assert_eq!(body["data"]["apartments"].as_array().unwrap().len(), 1);
+        let uri = format!("{}?include_dismissed=true", uri);
+        let (_, body) = send_as(&state, 1, get(&uri)).await;
+        assert_eq!(body["data"]["apartments"].as_array().unwrap().len(), 2);
+
+        // Other chats have their own state
+        let (_, body) = send_as(&state, 2, get("/api/apartments/states")).await;
+        assert!(body["data"]["apartments"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_to_existing_location_updates_yield() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+
+        let request = Request::post("/api/watchlists")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::json!({
+                    "location": "00100",
+                    "min_size": 30.0,
+                    "max_size": 60.0,
+                    "target_yield": 8.0
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let (status, body) = send_as(&state, 1, request).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["id"], watchlist.id);
+        let stored = state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.target_yield, Some(8.0));
+    }
+
+    #[tokio::test]
+    async fn subscribe_to_chosen_location() {
+        let state = state();
+        // Watched by another chat, so known to exist without asking Oikotie
+        let kallio = Location {
+            id: 1645,
+            level: 4,
+            name: "Kallio, Helsinki".to_string(),
+        };
+        state
+            .repo
+            .insert_watchlist(kallio, 2, Some(5.0), SizeTarget::empty())
+            .await
+            .unwrap();
+        let subscribe = || {
+            Request::post("/api/watchlists")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({
+                        "location": { "id": 1645, "level": 4, "name": "Kallio" },
+                        "min_size": 30.0,
+                        "max_size": 60.0,
+                        "target_yield": 6.0
+                    })
+                    .to_string(),
+                ))
+                .unwrap()
+        };
+
+        let (status, body) = send_as(&state, 1, subscribe()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["data"]["locations"],
+            serde_json::json!([{ "id": 1645, "level": 4, "name": "Kallio, Helsinki" }])
+        );
+
+        // Choosing it again updates the same watchlist
+        let (status, again) = send_as(&state, 1, subscribe()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again["data"]["id"], body["data"]["id"]);
+    }
+
+    #[tokio::test]
+    async fn optional_criteria_are_set_and_cleared() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+
+        let request = Request::post("/api/watchlists")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::json!({
+                    "location": "00100",
+                    "min_size": 30.0,
+                    "max_size": 60.0,
+                    "target_yield": 8.0,
+                    "max_price": 250000,
+                    "exclude_ground_floor": true
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let (status, body) = send_as(&state, 1, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["max_price"], 250000);
+        assert_eq!(body["data"]["exclude_ground_floor"], true);
+
+        let request = Request::patch(format!("/api/watchlists/{}", watchlist.id))
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::json!({ "max_price": null, "min_rooms": 2 }).to_string(),
+            ))
+            .unwrap();
+        let (status, _) = send_as(&state, 1, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let stored = state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.criteria.max_price, None);
+        assert_eq!(stored.criteria.min_rooms, Some(2));
+        assert!(stored.criteria.exclude_ground_floor);
+    }
+
+    #[tokio::test]
+    async fn locations_are_removed_down_to_the_last() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        let mut locations = watchlist.locations.clone();
+        locations.0.push(Location {
+            id: 2,
+            level: 5,
+            name: "00120".to_string(),
+        });
+        state
+            .repo
+            .update_watchlist_locations(watchlist.id, &locations)
+            .await
+            .unwrap();
+        let remove = |name: &str| {
+            Request::delete(format!(
+                "/api/watchlists/{}/locations/{}",
+                watchlist.id, name
+            ))
+            .body(Body::empty())
+            .unwrap()
+        };
+
+        let (status, _) = send_as(&state, 2, remove("00100")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send_as(&state, 1, remove("00200")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send_as(&state, 1, remove("00100")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["data"]["locations"],
+            serde_json::json!([{ "id": 2, "level": 5, "name": "00120" }])
+        );
+
+        let (status, _) = send_as(&state, 1, remove("00120")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let stored = state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.locations.names(), "00120");
+    }
+
+    #[tokio::test]
+    async fn patch_changes_only_given_criteria() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        let patch = |body: serde_json::Value| {
+            Request::patch(format!("/api/watchlists/{}", watchlist.id))
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let request = patch(serde_json::json!({ "min_size": 30, "max_size": 60 }));
+        let (status, body) = send_as(&state, 1, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["target_size_min"], 30);
+        assert_eq!(body["data"]["target_size_max"], 60);
+        assert_eq!(body["data"]["target_yield"], 5.0);
+
+        let (status, _) = send_as(&state, 2, patch(serde_json::json!({ "min_size": 40 }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = patch(serde_json::json!({ "min_size": 80 }));
+        let (status, _) = send_as(&state, 1, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let stored = state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.target_size_min, Some(30));
+    }
+
+    #[test]
+    fn errors_map_to_status() {
+        assert_eq!(
+            error_status(ApatoError::user_facing("Nothing to change").into()),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            error_status(ApatoError::not_found("Apartment 1 not found").into()),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            error_status(anyhow::anyhow!("connection refused")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn history_records_only_listing_changes() {
+        let state = state();
+        add_watchlist(&state, 1, 5.0).await;
+        state
+            .repo
+            .upsert_apartment(apartment(10, 7.0), &[])
+            .await
+            .unwrap();
+        // Same listing, new yield estimate: no history entry
+        state
+            .repo
+            .upsert_apartment(apartment(10, 6.5), &[])
+            .await
+            .unwrap();
+        let mut cut = apartment(10, 7.5);
+        cut.price = Some(180000);
+        state.repo.upsert_apartment(cut, &[]).await.unwrap();
+
+        let (status, body) = send_as(&state, 1, get("/api/apartments/10/history")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["days_on_market"], 0);
+        let history = body["data"]["history"].as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["price"], 200000);
+        assert_eq!(history[1]["price"], 180000);
+
+        let (status, _) = send_as(&state, 1, get("/api/apartments/11/history")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Chats without a watchlist of the location do not see it
+        let (status, _) = send_as(&state, 2, get("/api/apartments/10/history")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn price_drops_use_watchlist_minimum() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        for (card_id, new_price) in [(10, 190000), (11, 196000)] {
+            state
+                .repo
+                .upsert_apartment(apartment(card_id, 7.0), &[])
+                .await
+                .unwrap();
+            let mut cut = apartment(card_id, 7.0);
+            cut.price = Some(new_price);
+            state.repo.upsert_apartment(cut, &[]).await.unwrap();
+        }
+
+        let uri = format!("/api/watchlists/{}/price_drops", watchlist.id);
+        let (status, body) = send_as(&state, 1, get(&uri)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        let drops = body["data"]["price_drops"].as_array().unwrap();
+        assert_eq!(drops.len(), 1);
+        assert_eq!(drops[0]["apartment"]["card_id"], 10);
+        assert_eq!(drops[0]["previous_price"], 200000);
+    }
+
+    #[tokio::test]
+    async fn removed_apartments_are_hidden_by_default() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        for card_id in [10, 11] {
+            state
+                .repo
+                .upsert_apartment(apartment(card_id, 7.0), &[])
+                .await
+                .unwrap();
+        }
+        state.repo.mark_apartments_removed(&[11]).await.unwrap();
+
+        let uri = format!("/api/watchlists/{}/apartments", watchlist.id);
+        let (_, body) = send_as(&state, 1, get(&uri)).await;
+        let apartments = body["data"]["apartments"].as_array().unwrap();
+        assert_eq!(apartments.len(), 1);
+        assert_eq!(apartments[0]["card_id"], 10);
+
+        let uri = format!(
+            "/api/watchlists/{}/apartments?include_removed=true",
+            watchlist.id
+        );
+        let (_, body) = send_as(&state, 1, get(&uri)).await;
+        assert_eq!(body["data"]["apartments"].as_array().unwrap().len(), 2);
+
+        let uri = format!("/api/watchlists/{}/market_stats", watchlist.id);
+        let (status, body) = send_as(&state, 1, get(&uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["active"], 1);
+        assert_eq!(body["data"]["removed"], 1);
+        assert_eq!(body["data"]["median_days_on_market"], 0);
+    }
+
+    #[tokio::test]
+    async fn refresh_interval_can_be_changed_within_bounds() {
+        let state = state();
+        let watchlist = add_watchlist(&state, 1, 5.0).await;
+        let put = |minutes: i32| {
+            Request::put(format!("/api/watchlists/{}/refresh_interval", watchlist.id))
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "minutes": minutes }).to_string(),
+                ))
+                .unwrap()
+        };
+
+        let (status, body) = send_as(&state, 1, put(30)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["refresh_interval_minutes"], 30);
+
+        let (status, _) = send_as(&state, 1, put(1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let stored = state
+            .repo
+            .get_watchlist(watchlist.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.refresh_interval_minutes, 30);
+    }
 }
@@ -5,8 +5,8 @@ mod yield_calculations {
     use apato::{
         config,
         consumer::calculations::{
-            calculate_irr, future_value, interest_payment_for_period, irr, pmt,
-            principal_payment_for_period, valuation_increase,
+            calculate_irr, cash_flows, future_value, interest_payment_for_period, irr, pmt,
+            principal_payment_for_period, sensitivity, valuation_increase,
         },
     };
 
@@ -18,6 +18,36 @@ mod yield_calculations {
         assert_eq!(yield_rounded, 25.996)
     }
 
+    #[test]
+    fn cash_flows_are_the_ones_irr_is_solved_from() {
+        let config = Arc::new(config::create_test_config());
+        let analysis = cash_flows(&config, 100000_f64, 800_f64, 200_f64, 2.00);
+        assert_eq!(analysis.years.len(), config.loan_duration_years as usize);
+        assert_eq!(analysis.years[0].year, 1);
+
+        let mut flows = vec![-analysis.down_payment];
+        flows.extend(analysis.years.iter().map(|year| year.cash_flow));
+        let yield_ = irr(flows).unwrap() * 100.0;
+        assert_eq!(
+            (yield_ * 1000.0).round() / 1000.0,
+            (calculate_irr(&config, 100000_f64, 800_f64, 200_f64, 2.00) * 1000.0).round() / 1000.0
+        );
+    }
+
+    #[test]
+    fn sensitivity_varies_rent_and_interest_rate() {
+        let config = Arc::new(config::create_test_config());
+        let table = sensitivity(&config, 100000_f64, 800_f64, 200_f64, 2.00);
+        assert_eq!(table.interest_rate, 2.00);
+        assert_eq!(
+            table.yields[1][1],
+            calculate_irr(&config, 100000_f64, 800_f64, 200_f64, 2.00)
+        );
+        // More rent and a lower interest rate both raise the yield
+        assert!(table.yields[2][1] > table.yields[1][1]);
+        assert!(table.yields[1][0] > table.yields[1][1]);
+    }
+
     #[test]
     fn test_irr_1() {
         let cash_flow: Vec<f64> = vec![-100000.0, 20000.0, 50000.0, 70000.0];
@@ -1,13 +1,30 @@
-import { FormEvent, useCallback, useMemo, useState } from "react";
+import { FormEvent, useCallback, useEffect, useMemo, useState } from "react";
 
 const API_BASE = import.meta.env.VITE_APATO_API ?? "http://localhost:8080";
 
+interface Location {
+  id: number;
+  level: number;
+  name: string;
+}
+
 interface Watchlist {
   id: number;
-  location_name: string;
+  locations: Location[];
   target_yield: number | null;
   target_size_min: number | null;
   target_size_max: number | null;
+  min_rooms: number | null;
+  max_rooms: number | null;
+  max_price: number | null;
+  max_fee_per_m2: number | null;
+  min_build_year: number | null;
+  exclude_ground_floor: boolean;
+  exclude_rented_plot: boolean;
+  max_debt_share_percent: number | null;
+  down_payment_percent: number | null;
+  loan_years: number | null;
+  renovation_costs: number | null;
   created_at: string;
   updated_at: string;
 }
@@ -25,6 +42,10 @@ interface Apartment {
 
 type ApiResponse<T> = { data: T };
 
+type SessionResponse = ApiResponse<{ token: string; chat_id: number; expires_at: string }>;
+
+const SESSION_KEY = "apato.session";
+
 type WatchlistsResponse = ApiResponse<{ watchlists: Watchlist[] }>;
 
 type ApartmentsResponse = ApiResponse<{ apartments: Apartment[] }>;
@@ -47,36 +68,107 @@ function formatDate(value: string) {
   return new Date(value).toLocaleString();
 }
 
+/** Empty inputs leave the criterion unset. */
+function optionalNumber(value: string) {
+  return value.trim() === "" ? null : Number(value);
+}
+
+function formatCriteria(watchlist: Watchlist) {
+  const criteria: string[] = [];
+  if (watchlist.min_rooms !== null || watchlist.max_rooms !== null) {
+    criteria.push(`${watchlist.min_rooms ?? ""}–${watchlist.max_rooms ?? ""} rooms`);
+  }
+  if (watchlist.max_price !== null) criteria.push(`max ${formatCurrency(watchlist.max_price)}`);
+  if (watchlist.max_fee_per_m2 !== null) criteria.push(`fee ≤ ${watchlist.max_fee_per_m2} €/m²`);
+  if (watchlist.min_build_year !== null) criteria.push(`built ${watchlist.min_build_year} or later`);
+  if (watchlist.max_debt_share_percent !== null) {
+    criteria.push(`debt share ≤ ${watchlist.max_debt_share_percent}%`);
+  }
+  if (watchlist.exclude_ground_floor) criteria.push("no ground floor");
+  if (watchlist.exclude_rented_plot) criteria.push("no rented plot");
+  if (watchlist.down_payment_percent !== null) {
+    criteria.push(`${watchlist.down_payment_percent}% down payment`);
+  }
+  if (watchlist.loan_years !== null) criteria.push(`${watchlist.loan_years}-year loan`);
+  if (watchlist.renovation_costs !== null) {
+    criteria.push(`${formatCurrency(watchlist.renovation_costs)} renovations`);
+  }
+  return criteria.join(", ");
+}
+
+function locationNames(watchlist: Watchlist): string {
+  return watchlist.locations.map((location) => location.name).join(", ");
+}
+
 const App = () => {
-  const [chatId, setChatId] = useState(0);
+  const [apiToken, setApiToken] = useState(() => sessionStorage.getItem(SESSION_KEY) ?? "");
   const [watchlists, setWatchlists] = useState<Watchlist[]>([]);
   const [loading, setLoading] = useState(false);
   const [status, setStatus] = useState<Status>(null);
   const [activeWatchlist, setActiveWatchlist] = useState<number | null>(null);
   const [apartments, setApartments] = useState<Apartment[]>([]);
+  const [candidates, setCandidates] = useState<Location[]>([]);
+  const [chosenLocation, setChosenLocation] = useState<Location | null>(null);
 
   const [form, setForm] = useState({
     location: "",
     minSize: 40,
     maxSize: 60,
     targetYield: 8,
+    minRooms: "",
+    maxRooms: "",
+    maxPrice: "",
+    maxFeePerM2: "",
+    minBuildYear: "",
+    maxDebtSharePercent: "",
+    excludeGroundFloor: false,
+    excludeRentedPlot: false,
   });
 
-  const disabled = useMemo(() => chatId === 0, [chatId]);
+  const disabled = useMemo(() => apiToken.trim() === "", [apiToken]);
+  const authHeaders = useMemo(
+    () => ({ Authorization: `Bearer ${apiToken.trim()}` }),
+    [apiToken]
+  );
 
   const notify = useCallback((message: string, tone: "success" | "error" = "success") => {
     setStatus({ message, tone });
     setTimeout(() => setStatus(null), 4000);
   }, []);
 
+  // Links sent by the bot's /login command carry a one-time token to exchange for a session
+  useEffect(() => {
+    const params = new URLSearchParams(window.location.search);
+    const loginToken = params.get("login");
+    if (!loginToken) return;
+    window.history.replaceState(null, "", window.location.pathname);
+
+    fetch(`${API_BASE}/api/session`, {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ token: loginToken }),
+    })
+      .then(async (response) => {
+        if (!response.ok) throw new Error("Login link rejected");
+        const payload = (await response.json()) as SessionResponse;
+        sessionStorage.setItem(SESSION_KEY, payload.data.token);
+        setApiToken(payload.data.token);
+        notify(`Signed in as chat ${payload.data.chat_id}`);
+      })
+      .catch((error) => {
+        console.error(error);
+        notify("The login link is invalid or has expired. Send /login to the bot again.", "error");
+      });
+  }, [notify]);
+
   const handleLoadWatchlists = useCallback(async () => {
     if (disabled) {
-      notify("Please provide a chat id", "error");
+      notify("Please provide an API token", "error");
       return;
     }
     setLoading(true);
     try {
-      const response = await fetch(`${API_BASE}/api/watchlists?chat_id=${chatId}`);
+      const response = await fetch(`${API_BASE}/api/watchlists`, { headers: authHeaders });
       if (!response.ok) throw new Error("Failed to load watchlists");
       const payload = (await response.json()) as WatchlistsResponse;
       setWatchlists(payload.data.watchlists);
@@ -87,32 +179,66 @@ const App = () => {
     } finally {
       setLoading(false);
     }
-  }, [chatId, disabled, notify]);
+  }, [authHeaders, disabled, notify]);
+
+  const searchLocations = useCallback(async () => {
+    if (disabled) {
+      notify("Please provide an API token", "error");
+      return;
+    }
+    try {
+      const response = await fetch(
+        `${API_BASE}/api/locations?q=${encodeURIComponent(form.location.trim())}`,
+        { headers: authHeaders }
+      );
+      if (!response.ok) throw new Error("Unable to search locations");
+      const payload = (await response.json()) as ApiResponse<{ locations: Location[] }>;
+      setCandidates(payload.data.locations);
+      setChosenLocation(payload.data.locations.length === 1 ? payload.data.locations[0] : null);
+    } catch (error) {
+      console.error(error);
+      setCandidates([]);
+      notify("No locations found", "error");
+    }
+  }, [authHeaders, disabled, form.location, notify]);
 
   const handleSubscribe = useCallback(
     async (event: FormEvent<HTMLFormElement>) => {
       event.preventDefault();
       if (disabled) {
-        notify("Please provide a chat id", "error");
+        notify("Please provide an API token", "error");
         return;
       }
       setLoading(true);
       try {
         const response = await fetch(`${API_BASE}/api/watchlists`, {
           method: "POST",
-          headers: { "Content-Type": "application/json" },
+          headers: { ...authHeaders, "Content-Type": "application/json" },
           body: JSON.stringify({
-            chat_id: chatId,
-            location: form.location,
+            location: chosenLocation ?? form.location,
             min_size: form.minSize,
             max_size: form.maxSize,
             target_yield: form.targetYield,
+            min_rooms: optionalNumber(form.minRooms),
+            max_rooms: optionalNumber(form.maxRooms),
+            max_price: optionalNumber(form.maxPrice),
+            max_fee_per_m2: optionalNumber(form.maxFeePerM2),
+            min_build_year: optionalNumber(form.minBuildYear),
+            max_debt_share_percent: optionalNumber(form.maxDebtSharePercent),
+            exclude_ground_floor: form.excludeGroundFloor,
+            exclude_rented_plot: form.excludeRentedPlot,
           }),
         });
+        if (response.status === 409) {
+          await searchLocations();
+          notify("Several locations match. Please choose one.", "error");
+          return;
+        }
         if (!response.ok) throw new Error("Failed to subscribe");
         const payload = (await response.json()) as ApiResponse<Watchlist>;
         setWatchlists((previous) => [payload.data, ...previous]);
-        notify(`Subscribed to ${payload.data.location_name}`);
+        setCandidates([]);
+        notify(`Subscribed to ${locationNames(payload.data)}`);
       } catch (error) {
         console.error(error);
         notify("Unable to subscribe", "error");
@@ -120,19 +246,19 @@ const App = () => {
         setLoading(false);
       }
     },
-    [chatId, disabled, form.location, form.maxSize, form.minSize, form.targetYield, notify]
+    [authHeaders, chosenLocation, disabled, form, notify, searchLocations]
   );
 
   const handleDelete = useCallback(
     async (watchlistId: number) => {
       if (disabled) {
-        notify("Please provide a chat id", "error");
+        notify("Please provide an API token", "error");
         return;
       }
       try {
         const response = await fetch(
-          `${API_BASE}/api/watchlists/${watchlistId}?chat_id=${chatId}`,
-          { method: "DELETE" }
+          `${API_BASE}/api/watchlists/${watchlistId}`,
+          { method: "DELETE", headers: authHeaders }
         );
         if (!response.ok) throw new Error("Unable to delete watchlist");
         setWatchlists((prev) => prev.filter((watchlist) => watchlist.id !== watchlistId));
@@ -142,20 +268,76 @@ const App = () => {
         notify("Unable to delete watchlist", "error");
       }
     },
-    [chatId, disabled, notify]
+    [authHeaders, disabled, notify]
+  );
+
+  const replaceWatchlist = useCallback((updated: Watchlist) => {
+    setWatchlists((prev) =>
+      prev.map((watchlist) => (watchlist.id === updated.id ? updated : watchlist))
+    );
+  }, []);
+
+  const handleAddLocation = useCallback(
+    async (watchlistId: number) => {
+      if (disabled) {
+        notify("Please provide an API token", "error");
+        return;
+      }
+      const location = window.prompt("Location to add, e.g. 00510")?.trim();
+      if (!location) return;
+      try {
+        const response = await fetch(`${API_BASE}/api/watchlists/${watchlistId}/locations`, {
+          method: "POST",
+          headers: { ...authHeaders, "Content-Type": "application/json" },
+          body: JSON.stringify({ location }),
+        });
+        if (!response.ok) throw new Error("Unable to add location");
+        const payload = (await response.json()) as ApiResponse<Watchlist>;
+        replaceWatchlist(payload.data);
+        notify(`Watchlist now searches ${locationNames(payload.data)}`);
+      } catch (error) {
+        console.error(error);
+        notify("Unable to add location", "error");
+      }
+    },
+    [authHeaders, disabled, notify, replaceWatchlist]
+  );
+
+  const handleRemoveLocation = useCallback(
+    async (watchlistId: number, location: string) => {
+      if (disabled) {
+        notify("Please provide an API token", "error");
+        return;
+      }
+      try {
+        const response = await fetch(
+          `${API_BASE}/api/watchlists/${watchlistId}/locations/${encodeURIComponent(location)}`,
+          { method: "DELETE", headers: authHeaders }
+        );
+        if (!response.ok) throw new Error("Unable to remove location");
+        const payload = (await response.json()) as ApiResponse<Watchlist>;
+        replaceWatchlist(payload.data);
+        notify(`Removed ${location}`);
+      } catch (error) {
+        console.error(error);
+        notify("Unable to remove location. The last location cannot be removed.", "error");
+      }
+    },
+    [authHeaders, disabled, notify, replaceWatchlist]
   );
 
   const fetchApartments = useCallback(
     async (watchlistId: number, matching: boolean) => {
       if (disabled) {
-        notify("Please provide a chat id", "error");
+        notify("Please provide an API token", "error");
         return;
       }
       setLoading(true);
       try {
         const endpoint = matching ? "matching" : "apartments";
         const response = await fetch(
-          `${API_BASE}/api/watchlists/${watchlistId}/${endpoint}?chat_id=${chatId}`
+          `${API_BASE}/api/watchlists/${watchlistId}/${endpoint}`,
+          { headers: authHeaders }
         );
         if (!response.ok) throw new Error("Failed to fetch apartments");
         const payload = (await response.json()) as ApartmentsResponse;
@@ -169,7 +351,7 @@ const App = () => {
         setLoading(false);
       }
     },
-    [chatId, disabled, notify]
+    [authHeaders, disabled, notify]
   );
 
   return (
@@ -178,12 +360,12 @@ const App = () => {
       <section>
         <h2>Identify User</h2>
         <label>
-          Chat ID
+          API Token
           <input
-            type="number"
-            value={chatId === 0 ? "" : chatId}
-            onChange={(event) => setChatId(Number(event.target.value))}
-            placeholder="Enter your Telegram chat id"
+            type="password"
+            value={apiToken}
+            onChange={(event) => setApiToken(event.target.value)}
+            placeholder="Send /login or /apitoken to the bot"
           />
         </label>
         <button onClick={handleLoadWatchlists} disabled={disabled || loading}>
@@ -203,11 +385,32 @@ const App = () => {
             Location Query
             <input
               value={form.location}
-              onChange={(event) => setForm((prev) => ({ ...prev, location: event.target.value }))}
-              placeholder="Zip code or area"
+              onChange={(event) => {
+                setForm((prev) => ({ ...prev, location: event.target.value }));
+                setChosenLocation(null);
+                setCandidates([]);
+              }}
+              placeholder="Zip code, city, district or neighbourhood"
               required
             />
           </label>
+          <button type="button" onClick={searchLocations} disabled={loading || form.location.trim() === ""}>
+            Find Location
+          </button>
+          {candidates.length > 0 && (
+            <div className="watchlist-locations">
+              {candidates.map((location) => (
+                <button
+                  type="button"
+                  key={`${location.id}:${location.level}`}
+                  onClick={() => setChosenLocation(location)}
+                  disabled={chosenLocation?.id === location.id && chosenLocation?.level === location.level}
+                >
+                  {location.name}
+                </button>
+              ))}
+            </div>
+          )}
           <label>
             Min Size (m²)
             <input
@@ -235,6 +438,83 @@ const App = () => {
               required
             />
           </label>
+          <label>
+            Min Rooms
+            <input
+              type="number"
+              value={form.minRooms}
+              onChange={(event) => setForm((prev) => ({ ...prev, minRooms: event.target.value }))}
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            Max Rooms
+            <input
+              type="number"
+              value={form.maxRooms}
+              onChange={(event) => setForm((prev) => ({ ...prev, maxRooms: event.target.value }))}
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            Max Price (€)
+            <input
+              type="number"
+              value={form.maxPrice}
+              onChange={(event) => setForm((prev) => ({ ...prev, maxPrice: event.target.value }))}
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            Max Maintenance Fee (€/m²)
+            <input
+              type="number"
+              step="0.1"
+              value={form.maxFeePerM2}
+              onChange={(event) => setForm((prev) => ({ ...prev, maxFeePerM2: event.target.value }))}
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            Built In or After
+            <input
+              type="number"
+              value={form.minBuildYear}
+              onChange={(event) => setForm((prev) => ({ ...prev, minBuildYear: event.target.value }))}
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            Max Debt Share (%)
+            <input
+              type="number"
+              value={form.maxDebtSharePercent}
+              onChange={(event) =>
+                setForm((prev) => ({ ...prev, maxDebtSharePercent: event.target.value }))
+              }
+              placeholder="Any"
+            />
+          </label>
+          <label>
+            <input
+              type="checkbox"
+              checked={form.excludeGroundFloor}
+              onChange={(event) =>
+                setForm((prev) => ({ ...prev, excludeGroundFloor: event.target.checked }))
+              }
+            />
+            Exclude ground floor
+          </label>
+          <label>
+            <input
+              type="checkbox"
+              checked={form.excludeRentedPlot}
+              onChange={(event) =>
+                setForm((prev) => ({ ...prev, excludeRentedPlot: event.target.checked }))
+              }
+            />
+            Exclude rented plot
+          </label>
           <button type="submit" disabled={disabled || loading}>
             Save Watchlist
           </button>
@@ -248,13 +528,28 @@ const App = () => {
         ) : (
           watchlists.map((watchlist) => (
             <div key={watchlist.id} className="watchlist-card">
-              <strong>{watchlist.location_name}</strong>
+              <strong>{locationNames(watchlist)}</strong>
+              {watchlist.locations.length > 1 && (
+                <div className="watchlist-locations">
+                  {watchlist.locations.map((location) => (
+                    <button
+                      key={`${location.id}:${location.level}`}
+                      onClick={() => handleRemoveLocation(watchlist.id, location.name)}
+                      disabled={loading}
+                      title="Remove location"
+                    >
+                      {location.name} ×
+                    </button>
+                  ))}
+                </div>
+              )}
               <div>
                 Target yield: {formatNumber(watchlist.target_yield, 2)}%
               </div>
               <div>
                 Size range: {watchlist.target_size_min ?? "-"} – {watchlist.target_size_max ?? "-"} m²
               </div>
+              {formatCriteria(watchlist) && <div>Criteria: {formatCriteria(watchlist)}</div>}
               <div className="watchlist-actions">
                 <button onClick={() => fetchApartments(watchlist.id, false)} disabled={loading}>
                   View All
@@ -262,6 +557,9 @@ const App = () => {
                 <button onClick={() => fetchApartments(watchlist.id, true)} disabled={loading}>
                   View Matching
                 </button>
+                <button onClick={() => handleAddLocation(watchlist.id)} disabled={loading}>
+                  Add Location
+                </button>
                 <button onClick={() => handleDelete(watchlist.id)} disabled={loading}>
                   Delete
                 </button>